@@ -107,6 +107,14 @@
             craneLib = nativeCraneLib;
           };
 
+          memfs = callPackage ./src/lib/memfs/package.nix {
+            craneLib = nativeCraneLib;
+          };
+
+          partition-table = callPackage ./src/lib/partition-table/package.nix {
+            craneLib = nativeCraneLib;
+          };
+
           apple-dmg = callPackage ./src/lib/apple-dmg/package.nix {
             craneLib = nativeCraneLib;
           };
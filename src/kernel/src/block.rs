@@ -3,3 +3,24 @@
 pub trait BlockReader: Send + Sync {
     fn read_at(&self, offset: u64, buf: &mut [u8]) -> bool;
 }
+
+/// A disk that can also be written. Writes may be cached by the device
+/// until `flush`.
+pub trait BlockDevice: BlockReader {
+    fn write_at(&self, offset: u64, buf: &[u8]) -> bool;
+
+    /// Make completed writes durable
+    fn flush(&self) -> bool;
+
+    /// Size in bytes
+    fn size(&self) -> u64;
+
+    /// Preferred transfer size in bytes
+    fn block_size(&self) -> u32 {
+        512
+    }
+
+    fn read_only(&self) -> bool {
+        false
+    }
+}
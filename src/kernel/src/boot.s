@@ -15,6 +15,9 @@ _start:
     .long   0                       /* reserved */
 
 real_start:
+    /* Keep the devicetree address from the boot loader */
+    mov x19, x0
+
     /* Read CPU ID, stop custom cores */
     mrs x0, mpidr_el1
     and x0, x0, #3
@@ -42,10 +45,28 @@ zero_bss:
 
     /* Jump to Rust code */
     /* Set stack pointer before jump */
-    ldr x0, =0x40800000
+    ldr x0, ={BOOT_STACK_TOP}
     mov sp, x0
+    mov x0, x19
     bl  kmain
 
 hang:
     wfe
     b   hang
+
+/*
+ * Secondary CPUs are started here by PSCI CPU_ON with the MMU off.
+ * x0 = context id = top of this CPU's boot stack
+ */
+.global secondary_entry
+secondary_entry:
+    mov sp, x0
+
+    /* Enable FPU/SIMD */
+    mov x1, #0x300000
+    msr cpacr_el1, x1
+    isb
+
+    /* x0 is still the stack top */
+    bl  secondary_main
+    b   hang
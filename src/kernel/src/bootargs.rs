@@ -0,0 +1,158 @@
+//! Kernel boot arguments
+//!
+//! They come from /chosen/bootargs, which QEMU fills from `-append`.
+//! Arguments are separated by spaces and are either `key=value` or a flag
+//! like `-v`. The kernel consumes the keys below. Other arguments are passed
+//! to the init program: `key=value` ones in its environment, the rest in
+//! its argv. All of them can be read through `sysctl kern.bootargs`.
+//!
+//! - `init=path`: program to run first, /sbin/launchd by default
+//! - `root=disk`, or Darwin's `rd=disk`: root volume, see `main::mount_root`
+//! - `debug=mask`: DEBUG_* bits, decimal or 0x-prefixed hex
+//! - `tmpfs=options`: mount options for the tmpfs on /tmp, /var/tmp and
+//!   /var/run, e.g. `tmpfs=size=64m`, which each mount gets. Without a
+//!   size they split half of the free heap. See `tmpfs::size_option`.
+//! - `serial=0`: keep kernel messages off the serial console. They still go
+//!   to /dev/klog.
+//! - `-v`: verbose boot. The kernel turns on every DEBUG_* bit, on top of
+//!   `debug=`, and init sees the flag in its argv.
+
+use crate::kprintln;
+use alloc::string::String;
+use alloc::vec::Vec;
+use spin::Once;
+
+pub const DEFAULT_INIT: &str = "/sbin/launchd";
+
+/// Trace BSD syscalls and Mach traps to the kernel log
+pub const DEBUG_SYSCALLS: u64 = 1 << 0;
+
+/// Bits set without a debug= argument
+const DEFAULT_DEBUG: u64 = DEBUG_SYSCALLS;
+/// Bits set by -v: all of them
+const VERBOSE_DEBUG: u64 = u64::MAX;
+
+/// Keys the kernel consumes rather than passing to init
+const KERNEL_KEYS: [&str; 6] = ["init", "root", "rd", "debug", "serial", "tmpfs"];
+
+struct BootArgs {
+    /// The command line as given
+    line: String,
+    /// (key, value) in order. Flags have no value.
+    args: Vec<(String, Option<String>)>,
+    debug: u64,
+}
+
+static BOOT_ARGS: Once<BootArgs> = Once::new();
+
+fn parse_number(value: &str) -> Option<u64> {
+    match value
+        .strip_prefix("0x")
+        .or_else(|| value.strip_prefix("0X"))
+    {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => value.parse().ok(),
+    }
+}
+
+/// Parse the command line from the devicetree. Needs the heap.
+pub fn init() {
+    let line = crate::platform::get().bootargs().trim();
+    let args: Vec<(String, Option<String>)> = line
+        .split_ascii_whitespace()
+        .map(|arg| match arg.split_once('=') {
+            Some((key, value)) => (String::from(key), Some(String::from(value))),
+            None => (String::from(arg), None),
+        })
+        .collect();
+    let boot_args = BOOT_ARGS.call_once(|| {
+        let mut boot_args = BootArgs {
+            line: String::from(line),
+            args,
+            debug: DEFAULT_DEBUG,
+        };
+        if let Some(debug) = value_in(&boot_args, "debug") {
+            match parse_number(debug) {
+                Some(mask) => boot_args.debug = mask,
+                None => kprintln!("Boot args: Bad debug={}", debug),
+            }
+        }
+        if boot_args
+            .args
+            .iter()
+            .any(|(key, value)| key == "-v" && value.is_none())
+        {
+            boot_args.debug |= VERBOSE_DEBUG;
+        }
+        boot_args
+    });
+    kprintln!("Boot args: \"{}\"", boot_args.line);
+
+    if value("serial").and_then(parse_number) == Some(0) {
+        kprintln!("Boot args: Kernel messages are off the serial console");
+        crate::uart::set_kernel_output(false);
+    }
+}
+
+fn value_in<'a>(boot_args: &'a BootArgs, key: &str) -> Option<&'a str> {
+    // The last of repeated keys wins
+    boot_args
+        .args
+        .iter()
+        .rev()
+        .find(|(k, _)| k == key)
+        .and_then(|(_, value)| value.as_deref())
+}
+
+/// Value of `key=value`
+pub fn value(key: &str) -> Option<&'static str> {
+    value_in(BOOT_ARGS.get()?, key)
+}
+
+/// The command line as given, for kern.bootargs
+pub fn line() -> &'static str {
+    BOOT_ARGS.get().map_or("", |boot_args| &boot_args.line)
+}
+
+/// Whether a DEBUG_* bit is set
+pub fn debug(bit: u64) -> bool {
+    BOOT_ARGS
+        .get()
+        .map_or(DEFAULT_DEBUG, |boot_args| boot_args.debug)
+        & bit
+        != 0
+}
+
+/// Path of the init program
+pub fn init_program() -> &'static str {
+    value("init").unwrap_or(DEFAULT_INIT)
+}
+
+/// Root volume named by root=, or else rd=
+pub fn root() -> Option<&'static str> {
+    value("root").or_else(|| value("rd"))
+}
+
+/// Mount options for the boot tmpfs mounts, from tmpfs=
+pub fn tmpfs_options() -> &'static str {
+    value("tmpfs").unwrap_or("")
+}
+
+/// Arguments for init's argv after argv[0], and for its environment
+pub fn init_args() -> (Vec<&'static str>, Vec<&'static str>) {
+    let mut argv = Vec::new();
+    let mut envp = Vec::new();
+    let Some(boot_args) = BOOT_ARGS.get() else {
+        return (argv, envp);
+    };
+    let mut words = boot_args.line.split_ascii_whitespace();
+    for (key, value) in &boot_args.args {
+        let word = words.next().unwrap_or("");
+        match value {
+            Some(_) if KERNEL_KEYS.contains(&key.as_str()) => {}
+            Some(_) => envp.push(word),
+            None => argv.push(word),
+        }
+    }
+    (argv, envp)
+}
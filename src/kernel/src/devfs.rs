@@ -0,0 +1,384 @@
+//! Device filesystem, mounted at /dev
+//!
+//! Drivers register an open function for their major number and create named
+//! nodes that point at a (major, minor) pair, as with BSD's cdevsw/bdevsw.
+
+use crate::block::BlockDevice;
+use crate::errno::{EINVAL, EIO, ENODEV, ENOENT, ENOTDIR, ENOTTY, EROFS};
+use crate::vfs::{
+    Attr, DT_BLK, DT_CHR, DirEntry, DirFile, File, FileSystem, FsStat, Readiness, S_IFBLK, S_IFCHR,
+    S_IFDIR,
+};
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+// Character majors of the built-in drivers, as on Darwin
+const MEM_MAJOR: u32 = 3;
+const RANDOM_MAJOR: u32 = 14;
+/// Block major of disks
+const DISK_MAJOR: u32 = 1;
+
+// Minors of MEM_MAJOR
+const NULL_MINOR: u32 = 2;
+const ZERO_MINOR: u32 = 3;
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum NodeKind {
+    Char,
+    Block,
+}
+
+/// Opens the device with the given minor number
+pub type OpenFn = fn(minor: u32) -> Result<Box<dyn File>, u64>;
+
+struct DevNode {
+    name: String,
+    kind: NodeKind,
+    major: u32,
+    minor: u32,
+    perm: u16,
+}
+
+impl DevNode {
+    fn mode(&self) -> u16 {
+        let kind = match self.kind {
+            NodeKind::Char => S_IFCHR,
+            NodeKind::Block => S_IFBLK,
+        };
+        kind | self.perm
+    }
+
+    fn rdev(&self) -> u32 {
+        makedev(self.major, self.minor)
+    }
+
+    fn dir_entry(&self, ino: u64) -> DirEntry {
+        DirEntry {
+            ino,
+            kind: match self.kind {
+                NodeKind::Char => DT_CHR,
+                NodeKind::Block => DT_BLK,
+            },
+            name: self.name.clone(),
+        }
+    }
+}
+
+/// Inode number of the /dev directory. Nodes are numbered after it.
+const ROOT_INO: u64 = 2;
+
+struct Registry {
+    drivers: BTreeMap<(NodeKind, u32), OpenFn>,
+    nodes: Vec<DevNode>,
+}
+
+static DEVFS: Mutex<Registry> = Mutex::new(Registry {
+    drivers: BTreeMap::new(),
+    nodes: Vec::new(),
+});
+
+/// Darwin dev_t encoding
+pub fn makedev(major: u32, minor: u32) -> u32 {
+    (major << 24) | (minor & 0x00FF_FFFF)
+}
+
+/// Install the open function of a character or block major
+pub fn register_driver(kind: NodeKind, major: u32, open: OpenFn) {
+    DEVFS.lock().drivers.insert((kind, major), open);
+}
+
+/// Create `/dev/<name>`, replacing any node of the same name
+pub fn make_node(name: &str, kind: NodeKind, major: u32, minor: u32, perm: u16) {
+    let mut devfs = DEVFS.lock();
+    devfs.nodes.retain(|n| n.name != name);
+    devfs.nodes.push(DevNode {
+        name: String::from(name),
+        kind,
+        major,
+        minor,
+        perm,
+    });
+}
+
+/// The filesystem view of the registered nodes. Every instance shows the
+/// same nodes.
+pub struct Devfs;
+
+impl FileSystem for Devfs {
+    fn fs_type(&self) -> &'static str {
+        "devfs"
+    }
+
+    fn getattr(&self, path: &str) -> Result<Attr, u64> {
+        let devfs = DEVFS.lock();
+        if path.is_empty() {
+            return Ok(Attr {
+                ino: ROOT_INO,
+                parent_ino: ROOT_INO,
+                mode: S_IFDIR | 0o555,
+                nlink: 2,
+                block_size: 512,
+                entries: devfs.nodes.len() as u32,
+                ..Attr::default()
+            });
+        }
+        let (index, node) = devfs
+            .nodes
+            .iter()
+            .enumerate()
+            .find(|(_, n)| n.name == path)
+            .ok_or(ENOENT)?;
+        Ok(Attr {
+            ino: ROOT_INO + 1 + index as u64,
+            parent_ino: ROOT_INO,
+            mode: node.mode(),
+            nlink: 1,
+            rdev: node.rdev(),
+            block_size: 512,
+            ..Attr::default()
+        })
+    }
+
+    fn readdir(&self, path: &str) -> Result<Vec<DirEntry>, u64> {
+        if !path.is_empty() {
+            self.getattr(path)?;
+            return Err(ENOTDIR);
+        }
+        let devfs = DEVFS.lock();
+        Ok(devfs
+            .nodes
+            .iter()
+            .enumerate()
+            .map(|(index, node)| node.dir_entry(ROOT_INO + 1 + index as u64))
+            .collect())
+    }
+
+    fn open(&self, path: &str, _flags: u32) -> Result<Box<dyn File>, u64> {
+        let attr = self.getattr(path)?;
+        if path.is_empty() {
+            return Ok(Box::new(DirFile::new(attr, self.readdir(path)?)));
+        }
+
+        let (open, minor) = {
+            let devfs = DEVFS.lock();
+            let node = devfs.nodes.iter().find(|n| n.name == path).ok_or(ENOENT)?;
+            let open = devfs
+                .drivers
+                .get(&(node.kind, node.major))
+                .copied()
+                .ok_or(ENODEV)?;
+            (open, node.minor)
+        };
+        // Drivers may block in open, so call it unlocked
+        let inner = open(minor)?;
+        Ok(Box::new(DeviceFile { inner, attr }))
+    }
+
+    fn statfs(&self) -> FsStat {
+        FsStat {
+            block_size: 512,
+            files: DEVFS.lock().nodes.len() as u64,
+            ..FsStat::default()
+        }
+    }
+}
+
+/// An open device node: the driver's file, with the node's attributes
+struct DeviceFile {
+    inner: Box<dyn File>,
+    attr: Attr,
+}
+
+impl File for DeviceFile {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> usize {
+        self.inner.read_at(offset, buf)
+    }
+    fn size(&self) -> u64 {
+        self.inner.size()
+    }
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<usize, u64> {
+        self.inner.write_at(offset, buf)
+    }
+    fn is_stream(&self) -> bool {
+        self.inner.is_stream()
+    }
+    fn read(&self, buf: &mut [u8], nonblock: bool) -> Result<usize, u64> {
+        self.inner.read(buf, nonblock)
+    }
+    fn write(&self, buf: &[u8], nonblock: bool) -> Result<usize, u64> {
+        self.inner.write(buf, nonblock)
+    }
+    fn ioctl(&self, cmd: u32, arg: u64) -> Result<u64, u64> {
+        self.inner.ioctl(cmd, arg)
+    }
+    fn mode(&self) -> u16 {
+        self.attr.mode
+    }
+    fn rdev(&self) -> u32 {
+        self.attr.rdev
+    }
+    fn getattr(&self) -> Attr {
+        self.attr.clone()
+    }
+    fn readiness(&self) -> Readiness {
+        self.inner.readiness()
+    }
+}
+
+/// /dev/null: reads return end-of-file, writes are discarded
+struct NullFile;
+
+impl File for NullFile {
+    fn read_at(&self, _offset: u64, _buf: &mut [u8]) -> usize {
+        0
+    }
+    fn size(&self) -> u64 {
+        0
+    }
+    fn write_at(&self, _offset: u64, buf: &[u8]) -> Result<usize, u64> {
+        Ok(buf.len())
+    }
+}
+
+/// /dev/zero: reads return zeroes, writes are discarded
+struct ZeroFile;
+
+impl File for ZeroFile {
+    fn read_at(&self, _offset: u64, buf: &mut [u8]) -> usize {
+        buf.fill(0);
+        buf.len()
+    }
+    fn size(&self) -> u64 {
+        0
+    }
+    fn write_at(&self, _offset: u64, buf: &[u8]) -> Result<usize, u64> {
+        Ok(buf.len())
+    }
+}
+
+fn open_mem(minor: u32) -> Result<Box<dyn File>, u64> {
+    match minor {
+        NULL_MINOR => Ok(Box::new(NullFile)),
+        ZERO_MINOR => Ok(Box::new(ZeroFile)),
+        _ => Err(ENODEV),
+    }
+}
+
+/// /dev/random and /dev/urandom, both backed by the kernel CSPRNG
+struct RandomFile;
+
+impl File for RandomFile {
+    fn read_at(&self, _offset: u64, buf: &mut [u8]) -> usize {
+        crate::entropy::fill(buf);
+        buf.len()
+    }
+    fn size(&self) -> u64 {
+        u64::MAX
+    }
+    fn write_at(&self, _offset: u64, buf: &[u8]) -> Result<usize, u64> {
+        // Mixed in, but not credited, since anyone can write it
+        crate::entropy::add_entropy(buf, 0);
+        Ok(buf.len())
+    }
+}
+
+fn open_random(_minor: u32) -> Result<Box<dyn File>, u64> {
+    Ok(Box::new(RandomFile))
+}
+
+/// Disks registered with `add_disk`, indexed by minor
+static DISKS: Mutex<Vec<Arc<dyn BlockDevice>>> = Mutex::new(Vec::new());
+
+// Darwin disk ioctls
+const DKIOCSYNCHRONIZECACHE: u32 = 0x2000_6416;
+const DKIOCGETBLOCKSIZE: u32 = 0x4004_6418;
+const DKIOCGETBLOCKCOUNT: u32 = 0x4008_6419;
+const DKIOCISWRITABLE: u32 = 0x4004_641d;
+
+/// A block device node. Reads and writes are positional, like a regular
+/// file.
+struct BlockFile {
+    device: Arc<dyn BlockDevice>,
+    size: u64,
+}
+
+impl File for BlockFile {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> usize {
+        if offset >= self.size {
+            return 0;
+        }
+        let len = buf.len().min((self.size - offset) as usize);
+        if self.device.read_at(offset, &mut buf[..len]) {
+            len
+        } else {
+            0
+        }
+    }
+    fn size(&self) -> u64 {
+        self.size
+    }
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<usize, u64> {
+        if self.device.read_only() {
+            return Err(EROFS);
+        }
+        if offset >= self.size {
+            return Err(EINVAL);
+        }
+        let len = buf.len().min((self.size - offset) as usize);
+        if self.device.write_at(offset, &buf[..len]) {
+            Ok(len)
+        } else {
+            Err(EIO)
+        }
+    }
+    fn ioctl(&self, cmd: u32, arg: u64) -> Result<u64, u64> {
+        let block_size = self.device.block_size();
+        match cmd {
+            DKIOCSYNCHRONIZECACHE => {
+                if !self.device.flush() {
+                    return Err(EIO);
+                }
+            }
+            DKIOCGETBLOCKSIZE => unsafe { *(arg as *mut u32) = block_size },
+            DKIOCGETBLOCKCOUNT => unsafe { *(arg as *mut u64) = self.size / block_size as u64 },
+            DKIOCISWRITABLE => unsafe { *(arg as *mut u32) = !self.device.read_only() as u32 },
+            _ => return Err(ENOTTY),
+        }
+        Ok(0)
+    }
+}
+
+fn open_disk(minor: u32) -> Result<Box<dyn File>, u64> {
+    let disks = DISKS.lock();
+    let device = disks.get(minor as usize).ok_or(EIO)?;
+    Ok(Box::new(BlockFile {
+        device: Arc::clone(device),
+        size: device.size(),
+    }))
+}
+
+/// Register a block device as `/dev/<name>`
+pub fn add_disk(name: &str, device: Arc<dyn BlockDevice>) {
+    let minor = {
+        let mut disks = DISKS.lock();
+        disks.push(device);
+        disks.len() as u32 - 1
+    };
+    make_node(name, NodeKind::Block, DISK_MAJOR, minor, 0o640);
+}
+
+/// Register the built-in devices. Must be called after the heap is up.
+pub fn init() {
+    register_driver(NodeKind::Char, MEM_MAJOR, open_mem);
+    register_driver(NodeKind::Char, RANDOM_MAJOR, open_random);
+    register_driver(NodeKind::Block, DISK_MAJOR, open_disk);
+
+    make_node("null", NodeKind::Char, MEM_MAJOR, NULL_MINOR, 0o666);
+    make_node("zero", NodeKind::Char, MEM_MAJOR, ZERO_MINOR, 0o666);
+    make_node("random", NodeKind::Char, RANDOM_MAJOR, 0, 0o666);
+    make_node("urandom", NodeKind::Char, RANDOM_MAJOR, 1, 0o666);
+}
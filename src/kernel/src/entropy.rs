@@ -0,0 +1,185 @@
+//! Kernel entropy pool and CSPRNG
+//!
+//! Entropy sources are compressed into a 256-bit pool. Output comes from a
+//! ChaCha20 generator that is reseeded from the pool once enough new entropy
+//! has arrived, and rekeyed from its own output after every request, or
+//! every 4 KiB of a large one, so that earlier output can't be recovered
+//! from a later state.
+//!
+//! Sources, best first: a virtio-rng device, jitter in the arrival of timer
+//! interrupts, and CNTVCT samples taken at boot.
+
+use crate::kprintln;
+use crate::timer;
+use crate::virtio::VirtioRng;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use rand_chacha::ChaCha20Rng;
+use rand_core::{RngCore, SeedableRng};
+use spin::Mutex;
+
+/// Reseed when the pool has been credited with this many bits
+const RESEED_BITS: usize = 256;
+/// Reseed from the hardware source after generating this many bytes
+const RESEED_BYTES: usize = 1024 * 1024;
+/// Counter samples taken at boot when there is no hardware source
+const BOOT_SAMPLES: usize = 256;
+/// Most bytes generated under one hold of the pool lock
+const FILL_CHUNK: usize = 4096;
+
+struct Pool {
+    state: [u8; 32],
+    /// Bits credited to `state` since the last reseed
+    bits: usize,
+    /// None until the first reseed
+    generator: Option<ChaCha20Rng>,
+    /// Bytes generated since the last reseed
+    generated: usize,
+}
+
+static POOL: Mutex<Pool> = Mutex::new(Pool {
+    state: [0; 32],
+    bits: 0,
+    generator: None,
+    generated: 0,
+});
+
+static HWRNG: Mutex<Option<VirtioRng>> = Mutex::new(None);
+
+/// Timer jitter, gathered without locks since it is added from interrupts
+static JITTER: AtomicU64 = AtomicU64::new(0);
+static JITTER_SAMPLES: AtomicUsize = AtomicUsize::new(0);
+
+/// One-way compression of `input` into `state`: `state` XOR the input keys
+/// ChaCha20, and its first 32 bytes of output are fed forward into `state`.
+fn mix(state: &mut [u8; 32], input: &[u8]) {
+    for chunk in input.chunks(32) {
+        let mut key = *state;
+        for (k, b) in key.iter_mut().zip(chunk) {
+            *k ^= b;
+        }
+        let mut out = [0u8; 32];
+        ChaCha20Rng::from_seed(key).fill_bytes(&mut out);
+        for (s, o) in state.iter_mut().zip(out) {
+            *s ^= o;
+        }
+    }
+}
+
+impl Pool {
+    fn add(&mut self, input: &[u8], bits: usize) {
+        mix(&mut self.state, input);
+        self.bits += bits;
+    }
+
+    /// Fold in timer jitter gathered since the last call
+    fn collect_jitter(&mut self) {
+        let samples = JITTER_SAMPLES.swap(0, Ordering::Relaxed);
+        if samples > 0 {
+            let jitter = JITTER.swap(0, Ordering::Relaxed);
+            // The low bits of a tick's arrival time vary by a few cycles:
+            // credit one bit per 8 ticks
+            self.add(&jitter.to_le_bytes(), samples / 8);
+        }
+    }
+
+    fn reseed(&mut self) {
+        let mut key = [0u8; 32];
+        if let Some(generator) = self.generator.as_mut() {
+            generator.fill_bytes(&mut key);
+        }
+        mix(&mut key, &self.state);
+        // Keep the pool chained, so later input builds on all earlier input
+        mix(&mut self.state, &key);
+        self.bits = 0;
+        self.generated = 0;
+        self.generator = Some(ChaCha20Rng::from_seed(key));
+    }
+
+    fn fill(&mut self, buf: &mut [u8]) {
+        self.collect_jitter();
+        let unseeded = self.generator.is_none();
+        if unseeded || self.generated >= RESEED_BYTES {
+            if let Some(rng) = HWRNG.lock().as_mut() {
+                let mut seed = [0u8; 32];
+                let n = rng.read(&mut seed);
+                self.add(&seed[..n], n * 8);
+            }
+            if unseeded && self.bits < RESEED_BITS {
+                self.add_boot_samples();
+            }
+            self.reseed();
+        } else if self.bits >= RESEED_BITS {
+            self.reseed();
+        }
+
+        self.generated += buf.len();
+        let generator = self.generator.as_mut().unwrap();
+        generator.fill_bytes(buf);
+        // Fast key erasure
+        let mut key = [0u8; 32];
+        generator.fill_bytes(&mut key);
+        *generator = ChaCha20Rng::from_seed(key);
+    }
+
+    /// Sample the counter around work whose duration varies with cache and
+    /// bus contention. Weak, so only used without a hardware source.
+    fn add_boot_samples(&mut self) {
+        let mut samples = [0u8; BOOT_SAMPLES];
+        let mut last = timer::counter();
+        for sample in samples.iter_mut() {
+            let mut scratch = [0u8; 32];
+            mix(&mut scratch, &last.to_le_bytes());
+            let now = timer::counter();
+            *sample = now.wrapping_sub(last) as u8 ^ scratch[0];
+            last = now;
+        }
+        // Credit one bit per 4 samples
+        self.add(&samples, BOOT_SAMPLES / 4);
+    }
+}
+
+/// Mix caller-provided data into the pool, crediting `bits` of entropy
+pub fn add_entropy(input: &[u8], bits: usize) {
+    POOL.lock().add(input, bits);
+}
+
+/// Record the counter value at a timer interrupt. Called from the tick, so
+/// it doesn't take the pool lock; racing CPUs may lose a sample.
+pub fn add_timer_sample(counter: u64) {
+    let jitter = JITTER.load(Ordering::Relaxed);
+    JITTER.store(jitter.rotate_left(7) ^ counter, Ordering::Relaxed);
+    JITTER_SAMPLES.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Fill `buf` with cryptographically secure random bytes. Large buffers are
+/// filled a chunk at a time, so other CPUs can get at the pool in between.
+pub fn fill(buf: &mut [u8]) {
+    for chunk in buf.chunks_mut(FILL_CHUNK) {
+        POOL.lock().fill(chunk);
+    }
+}
+
+/// A uniformly random value below `bound`
+pub fn below(bound: u64) -> u64 {
+    // Rejection sampling, to avoid modulo bias
+    let limit = u64::MAX - u64::MAX % bound;
+    loop {
+        let mut bytes = [0u8; 8];
+        fill(&mut bytes);
+        let value = u64::from_le_bytes(bytes);
+        if value < limit {
+            return value % bound;
+        }
+    }
+}
+
+/// Look for a virtio-rng device and seed the pool. Must be called after the
+/// heap is up.
+pub fn init() {
+    match VirtioRng::new() {
+        Some(rng) => *HWRNG.lock() = Some(rng),
+        None => kprintln!("Entropy: no virtio-rng device, using timer jitter"),
+    }
+    let mut discard = [0u8; 32];
+    fill(&mut discard);
+}
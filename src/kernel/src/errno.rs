@@ -0,0 +1,47 @@
+//! Darwin errno values returned by BSD syscalls
+
+pub const EPERM: u64 = 1;
+pub const ENOENT: u64 = 2;
+pub const ESRCH: u64 = 3;
+pub const EINTR: u64 = 4;
+pub const EIO: u64 = 5;
+pub const ENXIO: u64 = 6;
+pub const EBADF: u64 = 9;
+pub const ENOMEM: u64 = 12;
+pub const EACCES: u64 = 13;
+pub const EBUSY: u64 = 16;
+pub const EEXIST: u64 = 17;
+pub const EXDEV: u64 = 18;
+pub const ENODEV: u64 = 19;
+pub const ENOTDIR: u64 = 20;
+pub const EISDIR: u64 = 21;
+pub const EINVAL: u64 = 22;
+pub const EMFILE: u64 = 24;
+pub const ENOTTY: u64 = 25;
+pub const ENOSPC: u64 = 28;
+pub const ESPIPE: u64 = 29;
+pub const EROFS: u64 = 30;
+pub const EPIPE: u64 = 32;
+pub const EAGAIN: u64 = 35;
+pub const EINPROGRESS: u64 = 36;
+pub const EALREADY: u64 = 37;
+pub const ENOTSOCK: u64 = 38;
+pub const EDESTADDRREQ: u64 = 39;
+pub const EMSGSIZE: u64 = 40;
+pub const EPROTOTYPE: u64 = 41;
+pub const ENOPROTOOPT: u64 = 42;
+pub const EPROTONOSUPPORT: u64 = 43;
+pub const ENOTSUP: u64 = 45;
+pub const EAFNOSUPPORT: u64 = 47;
+pub const EADDRINUSE: u64 = 48;
+pub const EADDRNOTAVAIL: u64 = 49;
+pub const ENETDOWN: u64 = 50;
+pub const ENETUNREACH: u64 = 51;
+pub const EISCONN: u64 = 56;
+pub const ENOTCONN: u64 = 57;
+pub const ETIMEDOUT: u64 = 60;
+pub const ECONNREFUSED: u64 = 61;
+pub const ELOOP: u64 = 62;
+pub const ENAMETOOLONG: u64 = 63;
+pub const ENOTEMPTY: u64 = 66;
+pub const EOPNOTSUPP: u64 = 102;
@@ -0,0 +1,257 @@
+//! Flattened devicetree parsing
+//!
+//! The boot loader passes the address of a devicetree blob in x0. This walks
+//! the blob in place without allocating, so it can run before the heap and
+//! the MMU are set up.
+
+const FDT_MAGIC: u32 = 0xd00d_feed;
+
+// Structure block tokens
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_NOP: u32 = 4;
+
+/// Deepest nesting whose #address-cells and #size-cells are tracked
+const MAX_DEPTH: usize = 16;
+
+/// Cells of a `reg` address and size when the parent doesn't say
+const DEFAULT_CELLS: (u32, u32) = (2, 1);
+
+fn be32(buf: &[u8], offset: usize) -> Option<u32> {
+    let bytes = buf.get(offset..offset.checked_add(4)?)?;
+    Some(u32::from_be_bytes(bytes.try_into().unwrap()))
+}
+
+fn align4(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+/// The NUL-terminated string at `offset`
+fn c_str(buf: &[u8], offset: usize) -> Option<&str> {
+    let bytes = buf.get(offset..)?;
+    let len = bytes.iter().position(|&b| b == 0)?;
+    core::str::from_utf8(&bytes[..len]).ok()
+}
+
+/// Big-endian cells of a property value
+pub fn cells(value: &[u8]) -> impl Iterator<Item = u32> + '_ {
+    value
+        .chunks_exact(4)
+        .map(|c| u32::from_be_bytes(c.try_into().unwrap()))
+}
+
+/// A number spread over `count` cells, most significant first. Cells that
+/// don't fit in 64 bits are dropped from the top.
+pub fn read_number(cells: &mut impl Iterator<Item = u32>, count: u32) -> Option<u64> {
+    let mut value = 0u64;
+    for _ in 0..count {
+        value = value << 32 | cells.next()? as u64;
+    }
+    Some(value)
+}
+
+#[derive(Clone, Copy)]
+pub struct Fdt<'a> {
+    data: &'a [u8],
+    structs: &'a [u8],
+    strings: &'a [u8],
+}
+
+impl Fdt<'static> {
+    /// The blob at `addr`, if it holds one
+    ///
+    /// # Safety
+    /// `addr` must be zero or readable for the size given in the header.
+    pub unsafe fn from_addr(addr: usize) -> Option<Self> {
+        if addr == 0 || !addr.is_multiple_of(8) {
+            return None;
+        }
+        let header = unsafe { core::slice::from_raw_parts(addr as *const u8, 8) };
+        if be32(header, 0)? != FDT_MAGIC {
+            return None;
+        }
+        let size = be32(header, 4)? as usize;
+        Self::new(unsafe { core::slice::from_raw_parts(addr as *const u8, size) })
+    }
+}
+
+impl<'a> Fdt<'a> {
+    pub fn new(data: &'a [u8]) -> Option<Self> {
+        if be32(data, 0)? != FDT_MAGIC {
+            return None;
+        }
+        let data = data.get(..be32(data, 4)? as usize)?;
+        let struct_offset = be32(data, 8)? as usize;
+        let strings_offset = be32(data, 12)? as usize;
+        let strings_size = be32(data, 32)? as usize;
+        let struct_size = be32(data, 36)? as usize;
+        Some(Self {
+            data,
+            structs: data.get(struct_offset..struct_offset.checked_add(struct_size)?)?,
+            strings: data.get(strings_offset..strings_offset.checked_add(strings_size)?)?,
+        })
+    }
+
+    /// Address and size of the blob
+    pub fn region(&self) -> (u64, u64) {
+        (self.data.as_ptr() as u64, self.data.len() as u64)
+    }
+
+    /// Every node, depth first
+    pub fn nodes(&self) -> Nodes<'a> {
+        Nodes {
+            fdt: *self,
+            offset: 0,
+            depth: 0,
+            cells: [DEFAULT_CELLS; MAX_DEPTH + 1],
+        }
+    }
+
+    /// The node whose `phandle` is `phandle`
+    pub fn node_by_phandle(&self, phandle: u32) -> Option<Node<'a>> {
+        self.nodes()
+            .find(|node| node.prop_u32("phandle") == Some(phandle))
+    }
+}
+
+pub struct Nodes<'a> {
+    fdt: Fdt<'a>,
+    offset: usize,
+    depth: usize,
+    /// #address-cells and #size-cells declared by the node at each depth,
+    /// for its children
+    cells: [(u32, u32); MAX_DEPTH + 1],
+}
+
+impl<'a> Iterator for Nodes<'a> {
+    type Item = Node<'a>;
+
+    fn next(&mut self) -> Option<Node<'a>> {
+        let structs = self.fdt.structs;
+        loop {
+            let token = be32(structs, self.offset)?;
+            self.offset += 4;
+            match token {
+                FDT_BEGIN_NODE => {
+                    let name = c_str(structs, self.offset)?;
+                    self.offset += align4(name.len() + 1);
+                    let parent = self.cells[self.depth.min(MAX_DEPTH)];
+                    self.depth += 1;
+                    let node = Node {
+                        fdt: self.fdt,
+                        name,
+                        depth: self.depth,
+                        props: self.offset,
+                        address_cells: parent.0,
+                        size_cells: parent.1,
+                    };
+                    if self.depth <= MAX_DEPTH {
+                        self.cells[self.depth] = (
+                            node.prop_u32("#address-cells").unwrap_or(DEFAULT_CELLS.0),
+                            node.prop_u32("#size-cells").unwrap_or(DEFAULT_CELLS.1),
+                        );
+                    }
+                    return Some(node);
+                }
+                FDT_END_NODE => self.depth = self.depth.checked_sub(1)?,
+                FDT_PROP => self.offset += 8 + align4(be32(structs, self.offset)? as usize),
+                FDT_NOP => {}
+                // FDT_END, or garbage
+                _ => return None,
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct Node<'a> {
+    fdt: Fdt<'a>,
+    /// Name with its unit address, as `pl011@9000000`
+    pub name: &'a str,
+    /// 1 for the root node
+    pub depth: usize,
+    /// Offset of the first property in the structure block
+    props: usize,
+    /// Cells of addresses and sizes in `reg`, as declared by the parent
+    pub address_cells: u32,
+    pub size_cells: u32,
+}
+
+impl<'a> Node<'a> {
+    /// Name without the unit address
+    pub fn base_name(&self) -> &'a str {
+        self.name.split('@').next().unwrap_or(self.name)
+    }
+
+    /// Properties as (name, value) pairs
+    pub fn props(&self) -> Props<'a> {
+        Props {
+            fdt: self.fdt,
+            offset: self.props,
+        }
+    }
+
+    pub fn prop(&self, name: &str) -> Option<&'a [u8]> {
+        self.props()
+            .find(|&(prop, _)| prop == name)
+            .map(|(_, value)| value)
+    }
+
+    pub fn prop_u32(&self, name: &str) -> Option<u32> {
+        be32(self.prop(name)?, 0)
+    }
+
+    /// A string property, without its terminating NUL
+    pub fn prop_str(&self, name: &str) -> Option<&'a str> {
+        let value = self.prop(name)?;
+        let value = value.strip_suffix(&[0]).unwrap_or(value);
+        core::str::from_utf8(value).ok()
+    }
+
+    /// Whether `compatible` lists `model`
+    pub fn is_compatible(&self, model: &str) -> bool {
+        self.prop("compatible").is_some_and(|list| {
+            list.split(|&b| b == 0)
+                .any(|entry| entry == model.as_bytes())
+        })
+    }
+
+    /// (address, size) pairs of `reg`
+    pub fn reg(&self) -> impl Iterator<Item = (u64, u64)> + 'a {
+        let (address_cells, size_cells) = (self.address_cells, self.size_cells);
+        let mut cells = cells(self.prop("reg").unwrap_or(&[]));
+        core::iter::from_fn(move || {
+            let address = read_number(&mut cells, address_cells)?;
+            let size = read_number(&mut cells, size_cells)?;
+            Some((address, size))
+        })
+    }
+}
+
+pub struct Props<'a> {
+    fdt: Fdt<'a>,
+    offset: usize,
+}
+
+impl<'a> Iterator for Props<'a> {
+    type Item = (&'a str, &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let structs = self.fdt.structs;
+        loop {
+            match be32(structs, self.offset)? {
+                FDT_PROP => {
+                    let len = be32(structs, self.offset + 4)? as usize;
+                    let name = c_str(self.fdt.strings, be32(structs, self.offset + 8)? as usize)?;
+                    let start = self.offset + 12;
+                    let value = structs.get(start..start + len)?;
+                    self.offset = start + align4(len);
+                    return Some((name, value));
+                }
+                FDT_NOP => self.offset += 4,
+                _ => return None,
+            }
+        }
+    }
+}
@@ -0,0 +1,202 @@
+//! Per-process file descriptor table
+
+use crate::errno::{EBADF, EINVAL, EMFILE};
+use crate::vfs::{FileHandle, O_RDWR};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+// fcntl(2) commands
+pub const F_DUPFD: u32 = 0;
+pub const F_GETFD: u32 = 1;
+pub const F_SETFD: u32 = 2;
+pub const F_GETFL: u32 = 3;
+pub const F_SETFL: u32 = 4;
+pub const F_DUPFD_CLOEXEC: u32 = 67;
+
+/// Per-descriptor close-on-exec flag
+pub const FD_CLOEXEC: u32 = 1;
+
+/// Default soft and hard RLIMIT_NOFILE
+pub const NOFILE_SOFT_LIMIT: u64 = 256;
+pub const NOFILE_HARD_LIMIT: u64 = 10240;
+
+/// Table slots are added in chunks of this many descriptors
+const GROW_CHUNK: usize = 32;
+
+#[derive(Clone)]
+struct FdEntry {
+    file: Arc<FileHandle>,
+    cloexec: bool,
+}
+
+/// Maps descriptor numbers to shared open-file descriptions. Descriptors
+/// created by `dup`/`dup2`/`fork` point at the same `FileHandle`, and so share
+/// its offset and status flags; the close-on-exec flag is per descriptor.
+pub struct FdTable {
+    entries: Vec<Option<FdEntry>>,
+    soft_limit: u64,
+    hard_limit: u64,
+    /// Descriptors closed while the table was locked. Dropping the last
+    /// reference to a description can wake other processes, so callers drop
+    /// these with `take_released` once the run queue is unlocked.
+    released: Vec<Arc<FileHandle>>,
+}
+
+impl FdTable {
+    pub fn new() -> Self {
+        Self {
+            entries: Vec::new(),
+            soft_limit: NOFILE_SOFT_LIMIT,
+            hard_limit: NOFILE_HARD_LIMIT,
+            released: Vec::new(),
+        }
+    }
+
+    /// A table with descriptors 0, 1 and 2 open on the console
+    pub fn with_stdio() -> Self {
+        let mut table = Self::new();
+        if let Ok(console) = crate::vfs::open("/dev/console", O_RDWR, 0) {
+            let console = Arc::new(console);
+            for _ in 0..3 {
+                let _ = table.insert(0, Arc::clone(&console), false);
+            }
+        }
+        table
+    }
+
+    pub fn get(&self, fd: usize) -> Option<Arc<FileHandle>> {
+        self.entries
+            .get(fd)
+            .and_then(|e| e.as_ref())
+            .map(|e| Arc::clone(&e.file))
+    }
+
+    /// Install `file` at the lowest free descriptor >= `min_fd`
+    pub fn insert(
+        &mut self,
+        min_fd: usize,
+        file: Arc<FileHandle>,
+        cloexec: bool,
+    ) -> Result<usize, u64> {
+        let fd = (min_fd..self.entries.len())
+            .find(|&fd| self.entries[fd].is_none())
+            .unwrap_or(self.entries.len().max(min_fd));
+        self.install(fd, FdEntry { file, cloexec })?;
+        Ok(fd)
+    }
+
+    fn install(&mut self, fd: usize, entry: FdEntry) -> Result<(), u64> {
+        if fd as u64 >= self.soft_limit {
+            self.released.push(entry.file);
+            return Err(EMFILE);
+        }
+        if fd >= self.entries.len() {
+            let new_len = (fd + 1).next_multiple_of(GROW_CHUNK);
+            self.entries.resize(new_len, None);
+        }
+        if let Some(old) = self.entries[fd].replace(entry) {
+            self.released.push(old.file);
+        }
+        Ok(())
+    }
+
+    pub fn close(&mut self, fd: usize) -> Result<(), u64> {
+        match self.entries.get_mut(fd).and_then(|e| e.take()) {
+            Some(entry) => {
+                self.released.push(entry.file);
+                Ok(())
+            }
+            None => Err(EBADF),
+        }
+    }
+
+    /// Descriptions closed since the last call; see `released`
+    pub fn take_released(&mut self) -> Vec<Arc<FileHandle>> {
+        core::mem::take(&mut self.released)
+    }
+
+    pub fn dup(&mut self, fd: usize) -> Result<usize, u64> {
+        let file = self.get(fd).ok_or(EBADF)?;
+        self.insert(0, file, false)
+    }
+
+    pub fn dup2(&mut self, old_fd: usize, new_fd: usize) -> Result<usize, u64> {
+        let file = self.get(old_fd).ok_or(EBADF)?;
+        if new_fd as u64 >= self.soft_limit {
+            return Err(EBADF);
+        }
+        if old_fd != new_fd {
+            self.install(
+                new_fd,
+                FdEntry {
+                    file,
+                    cloexec: false,
+                },
+            )?;
+        }
+        Ok(new_fd)
+    }
+
+    pub fn fcntl(&mut self, fd: usize, cmd: u32, arg: u64) -> Result<u64, u64> {
+        let entry = self
+            .entries
+            .get_mut(fd)
+            .and_then(|e| e.as_mut())
+            .ok_or(EBADF)?;
+        match cmd {
+            F_DUPFD | F_DUPFD_CLOEXEC => {
+                let file = Arc::clone(&entry.file);
+                if arg >= self.soft_limit {
+                    return Err(EINVAL);
+                }
+                self.insert(arg as usize, file, cmd == F_DUPFD_CLOEXEC)
+                    .map(|fd| fd as u64)
+            }
+            F_GETFD => Ok(if entry.cloexec { FD_CLOEXEC as u64 } else { 0 }),
+            F_SETFD => {
+                entry.cloexec = (arg as u32 & FD_CLOEXEC) != 0;
+                Ok(0)
+            }
+            F_GETFL => Ok(entry.file.status_flags() as u64),
+            F_SETFL => {
+                entry.file.set_status_flags(arg as u32);
+                Ok(0)
+            }
+            _ => Err(EINVAL),
+        }
+    }
+
+    /// RLIMIT_NOFILE as (soft, hard)
+    pub fn limits(&self) -> (u64, u64) {
+        (self.soft_limit, self.hard_limit)
+    }
+
+    pub fn set_limits(&mut self, soft: u64, hard: u64) -> Result<(), u64> {
+        if soft > hard || hard > self.hard_limit {
+            return Err(EINVAL);
+        }
+        self.soft_limit = soft;
+        self.hard_limit = hard;
+        Ok(())
+    }
+
+    /// Copy of the table for a child process. Both tables share the same
+    /// open-file descriptions.
+    pub fn fork(&self) -> Self {
+        Self {
+            entries: self.entries.clone(),
+            soft_limit: self.soft_limit,
+            hard_limit: self.hard_limit,
+            released: Vec::new(),
+        }
+    }
+
+    /// Close every descriptor marked close-on-exec
+    pub fn close_on_exec(&mut self) {
+        for slot in self.entries.iter_mut() {
+            if let Some(entry) = slot.take_if(|e| e.cloexec) {
+                self.released.push(entry.file);
+            }
+        }
+    }
+}
@@ -0,0 +1,120 @@
+//! GICv2 interrupt controller for QEMU virt machine
+
+use core::ptr::{read_volatile, write_volatile};
+
+// Distributor register offsets
+const GICD_CTLR: usize = 0x000;
+const GICD_TYPER: usize = 0x004;
+const GICD_ISENABLER: usize = 0x100;
+const GICD_ICENABLER: usize = 0x180;
+const GICD_ICPENDR: usize = 0x280;
+const GICD_IPRIORITYR: usize = 0x400;
+const GICD_ITARGETSR: usize = 0x800;
+const GICD_ICFGR: usize = 0xC00;
+const GICD_SGIR: usize = 0xF00;
+
+// CPU interface register offsets
+const GICC_CTLR: usize = 0x000;
+const GICC_PMR: usize = 0x004;
+const GICC_BPR: usize = 0x008;
+const GICC_IAR: usize = 0x00C;
+const GICC_EOIR: usize = 0x010;
+
+/// Interrupt IDs >= 1020 are special (1023 = spurious)
+pub const FIRST_SPECIAL_IRQ: u32 = 1020;
+
+const DEFAULT_PRIORITY: u8 = 0xA0;
+
+fn gicd_base() -> usize {
+    crate::platform::get().gic_distributor.base as usize
+}
+
+fn gicc_base() -> usize {
+    crate::platform::get().gic_cpu_interface.base as usize
+}
+
+fn gicd_read32(offset: usize) -> u32 {
+    unsafe { read_volatile((gicd_base() + offset) as *const u32) }
+}
+
+fn gicd_write32(offset: usize, val: u32) {
+    unsafe { write_volatile((gicd_base() + offset) as *mut u32, val) }
+}
+
+fn gicd_write8(offset: usize, val: u8) {
+    unsafe { write_volatile((gicd_base() + offset) as *mut u8, val) }
+}
+
+fn gicc_read32(offset: usize) -> u32 {
+    unsafe { read_volatile((gicc_base() + offset) as *const u32) }
+}
+
+fn gicc_write32(offset: usize, val: u32) {
+    unsafe { write_volatile((gicc_base() + offset) as *mut u32, val) }
+}
+
+/// Number of interrupt lines implemented by the distributor
+pub fn num_irqs() -> u32 {
+    ((gicd_read32(GICD_TYPER) & 0x1F) + 1) * 32
+}
+
+/// Initialize the distributor. Called once by the boot CPU.
+pub fn init_distributor() {
+    gicd_write32(GICD_CTLR, 0);
+
+    let irqs = num_irqs();
+    crate::kprintln!("GIC: Distributor supports {} interrupts", irqs);
+
+    // Disable and clear all shared peripheral interrupts, route them to CPU 0
+    for i in (32..irqs).step_by(32) {
+        gicd_write32(GICD_ICENABLER + (i as usize / 32) * 4, 0xFFFF_FFFF);
+        gicd_write32(GICD_ICPENDR + (i as usize / 32) * 4, 0xFFFF_FFFF);
+    }
+    for i in 32..irqs {
+        gicd_write8(GICD_IPRIORITYR + i as usize, DEFAULT_PRIORITY);
+        gicd_write8(GICD_ITARGETSR + i as usize, 1);
+    }
+    // Level-triggered SPIs by default
+    for i in (32..irqs).step_by(16) {
+        gicd_write32(GICD_ICFGR + (i as usize / 16) * 4, 0);
+    }
+
+    gicd_write32(GICD_CTLR, 1);
+}
+
+/// Initialize the banked SGI/PPI state and the CPU interface of the calling CPU.
+pub fn init_cpu_interface() {
+    // SGIs and PPIs are banked per CPU
+    gicd_write32(GICD_ICENABLER, 0xFFFF_0000);
+    gicd_write32(GICD_ISENABLER, 0x0000_FFFF);
+    for i in 0..32 {
+        gicd_write8(GICD_IPRIORITYR + i, DEFAULT_PRIORITY);
+    }
+
+    gicc_write32(GICC_PMR, 0xF0);
+    gicc_write32(GICC_BPR, 0);
+    gicc_write32(GICC_CTLR, 1);
+}
+
+/// Enable delivery of an interrupt line
+pub fn enable_irq(irq: u32) {
+    gicd_write32(GICD_ISENABLER + (irq as usize / 32) * 4, 1 << (irq % 32));
+}
+
+/// Send a software generated interrupt to the CPUs in `target_mask`
+pub fn send_sgi(sgi: u32, target_mask: u8) {
+    unsafe {
+        core::arch::asm!("dsb ish");
+    }
+    gicd_write32(GICD_SGIR, ((target_mask as u32) << 16) | (sgi & 0xF));
+}
+
+/// Acknowledge the highest priority pending interrupt. Returns the raw IAR value.
+pub fn acknowledge() -> u32 {
+    gicc_read32(GICC_IAR)
+}
+
+/// Signal end of interrupt for a value returned by `acknowledge`
+pub fn end_of_interrupt(iar: u32) {
+    gicc_write32(GICC_EOIR, iar);
+}
@@ -3,9 +3,32 @@ use linked_list_allocator::LockedHeap;
 #[global_allocator]
 static ALLOCATOR: LockedHeap = LockedHeap::empty();
 
+/// Lowest address of the heap. The kernel image and its boot stack are
+/// below it.
+const HEAP_BASE: u64 = 0x6000_0000;
+/// The heap must be identity mapped, which stops at the CommPage
+const HEAP_LIMIT: u64 = 0xFFFF_0000;
+
+/// Use the largest stretch of RAM between HEAP_BASE and HEAP_LIMIT
 pub fn init_heap() {
-    let heap_start = 0x6000_0000u64;
-    let heap_size = 512 * 1024 * 1024; // 512MiB
+    let platform = crate::platform::get();
+    let (heap_start, heap_end) = platform
+        .memory()
+        .iter()
+        .map(|region| (region.base.max(HEAP_BASE), region.end().min(HEAP_LIMIT)))
+        .flat_map(|(start, end)| {
+            // The initrd is used where the boot loader put it
+            match &platform.initrd {
+                Some(initrd) if start < end && initrd.overlaps(start, end - start) => {
+                    [(start, initrd.base), (initrd.end(), end)]
+                }
+                _ => [(start, end), (0, 0)],
+            }
+        })
+        .filter(|(start, end)| start < end)
+        .max_by_key(|(start, end)| end - start)
+        .expect("No RAM for the heap");
+    let heap_size = (heap_end - heap_start) as usize;
     crate::kprintln!(
         "GRAVITY HEAP: Initializing at {:x} (size {:x})",
         heap_start,
@@ -15,3 +38,8 @@ pub fn init_heap() {
         ALLOCATOR.lock().init(heap_start as *mut u8, heap_size);
     }
 }
+
+/// Bytes of heap not currently allocated
+pub fn free_bytes() -> usize {
+    ALLOCATOR.lock().free()
+}
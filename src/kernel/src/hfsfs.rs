@@ -1,13 +1,25 @@
 //! HFS+ filesystem reader
 
 use crate::block::BlockReader;
+use crate::errno::{EINVAL, EIO, ENOENT, ENOTDIR};
 use crate::kprintln;
+use crate::vfs::{
+    Attr, DT_DIR, DT_LNK, DT_REG, DirEntry, DirFile, File, FileSystem, FsStat, S_IFDIR, S_IFLNK,
+    S_IFMT, S_IFREG,
+};
+use crate::waitqueue::SleepLock;
 use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::format;
 use alloc::string::String;
 use alloc::sync::Arc;
 use alloc::vec;
 use alloc::vec::Vec;
-use hfsplus::{CatalogBody, Error, Fork, HFSVolume, Read, Result, Seek, SeekFrom};
+use hfsplus::journal::{self, Journaled, Overlay};
+use hfsplus::{
+    CatalogBody, CatalogRecord, Error, Fork, HFSPlusBSDInfo, HFSPlusCatalogFile, HFSPlusForkData,
+    HFSVolume, Read, Result, Seek, SeekFrom,
+};
 use spin::Mutex;
 
 pub struct DeviceWrapper {
@@ -106,12 +118,57 @@ impl<R: Read + Seek> Seek for BufReader<R> {
     }
 }
 
+/// Volume header signatures of HFS+ and HFSX
+const HFSP_SIGNATURE: u16 = 0x482B;
+const HFSX_SIGNATURE: u16 = 0x4858;
+
+/// CNID of the root folder
+const ROOT_FOLDER_ID: u32 = 2;
+
+/// Metadata in the root folder that is hidden from directory listings
+const HIDDEN_ROOT_ENTRIES: [&str; 4] = [
+    "\0\0\0\0HFS+ Private Data",
+    ".HFS+ Private Directory Data\r",
+    ".journal",
+    ".journal_info_block",
+];
+
+/// Finder type and creator of symbolic links, whose data fork holds the
+/// target path
+const SYMLINK_TYPE: u32 = u32::from_be_bytes(*b"slnk");
+const SYMLINK_CREATOR: u32 = u32::from_be_bytes(*b"rhap");
+
+/// BSD flag of files whose data is stored compressed in the resource fork
+const UF_COMPRESSED: u8 = 0x20;
+
+/// Seconds from the HFS epoch, 1904-01-01, to the Unix epoch
+const HFS_EPOCH_OFFSET: i64 = 2_082_844_800;
+
+/// Most folder IDs remembered by path before starting over
+const FOLDER_CACHE_SIZE: usize = 1024;
+
+/// The disk as the volume reads it, with its journal replayed
+type Disk = Journaled<BufReader<DeviceWrapper>>;
+type Volume = Arc<Mutex<HFSVolume<Disk>>>;
+
 pub struct HfsFs {
-    volume: Arc<Mutex<HFSVolume<BufReader<DeviceWrapper>>>>,
+    volume: Volume,
+    /// Held across every read of the volume. The volume, its B-trees and
+    /// the forks of open files share one reader behind spin locks, which
+    /// mustn't be contended while their holder sleeps on the disk.
+    io: Arc<SleepLock<()>>,
+    block_size: u32,
+    /// Decompressed sizes of compressed files opened so far, by file ID
+    compressed_sizes: Mutex<BTreeMap<u32, u64>>,
+    /// Folder IDs by path, so a lookup only searches the catalog for its
+    /// last component. The volume is read-only, so they never go stale.
+    folders: Mutex<BTreeMap<String, u32>>,
 }
 
 impl HfsFs {
-    pub fn new(device: Arc<dyn BlockReader>, base_offset: u64) -> Self {
+    /// Load the volume at `base_offset` on `device`. Fails with EINVAL if it
+    /// isn't an HFS+ volume.
+    pub fn new(device: Arc<dyn BlockReader>, base_offset: u64) -> core::result::Result<Self, u64> {
         kprintln!("HfsFs: Initializing with offset {:x}", base_offset);
         let wrapper = DeviceWrapper::new(device, base_offset);
         let buffered = BufReader::with_capacity(64 * 1024, wrapper);
@@ -119,9 +176,35 @@ impl HfsFs {
 
         // Manual HFSVolume::load but with kernel logs
         let mut file = buffered;
-        file.seek(hfsplus::SeekFrom::Start(1024)).unwrap();
-        let header = hfsplus::HFSPlusVolumeHeader::import(&mut file).unwrap();
+        file.seek(hfsplus::SeekFrom::Start(1024)).map_err(|_| EIO)?;
+        let header = hfsplus::HFSPlusVolumeHeader::import(&mut file).map_err(|_| EIO)?;
+        if header.signature != HFSP_SIGNATURE && header.signature != HFSX_SIGNATURE {
+            kprintln!("HfsFs: Bad volume signature {:x}", header.signature);
+            return Err(EINVAL);
+        }
+
+        // The volume is mounted read-only, so the journal is replayed into an
+        // overlay rather than onto the disk. A damaged journal only loses the
+        // transactions it holds.
+        let overlay = match journal::replay(&mut file, &header) {
+            Ok(overlay) => overlay,
+            Err(err) => {
+                kprintln!("HFS+: Not replaying the journal: {:?}", err);
+                Overlay::default()
+            }
+        };
+        if !overlay.is_empty() {
+            kprintln!(
+                "HFS+: Replayed {} journal transactions",
+                overlay.transactions
+            );
+        }
+        let mut file = Journaled::new(file, overlay).map_err(|_| EIO)?;
+        // The journal may have a newer volume header
+        file.seek(hfsplus::SeekFrom::Start(1024)).map_err(|_| EIO)?;
+        let header = hfsplus::HFSPlusVolumeHeader::import(&mut file).map_err(|_| EIO)?;
 
+        let block_size = header.block_size;
         let file_arc = Arc::new(Mutex::new(file));
         let volume = Arc::new(Mutex::new(hfsplus::HFSVolume {
             file: Arc::clone(&file_arc),
@@ -139,7 +222,7 @@ impl HfsFs {
             &*volume.lock(),
             &catalog_data,
         )
-        .unwrap();
+        .map_err(|_| EINVAL)?;
 
         kprintln!("HFS+: Loading extents fork...");
         let extents_data = volume.lock().header.extents_file;
@@ -150,13 +233,13 @@ impl HfsFs {
             &*volume.lock(),
             &extents_data,
         )
-        .unwrap();
+        .map_err(|_| EINVAL)?;
 
         kprintln!("HFS+: Opening catalog B-tree...");
         let temp_btree = hfsplus::BTree::<_, hfsplus::CatalogKey, hfsplus::CatalogRecord>::open(
             catalog_fork.clone(),
         )
-        .unwrap();
+        .map_err(|_| EINVAL)?;
         let compare_type = temp_btree.header.header.key_compare_type;
         let catalog_enum = if compare_type == 0xBC {
             let btree = hfsplus::BTree::<
@@ -164,7 +247,7 @@ impl HfsFs {
                 hfsplus::CatalogKey<hfsplus::HFSStringBinary>,
                 hfsplus::CatalogRecord<hfsplus::HFSStringBinary>,
             >::open(catalog_fork)
-            .unwrap();
+            .map_err(|_| EINVAL)?;
             hfsplus::CatalogBTreeEnum::Binary(Arc::new(Mutex::new(btree)))
         } else {
             hfsplus::CatalogBTreeEnum::CaseFolding(Arc::new(Mutex::new(temp_btree)))
@@ -172,55 +255,386 @@ impl HfsFs {
         volume.lock().catalog_btree = Some(catalog_enum);
         kprintln!("HFS+: Opening extents B-tree...");
         volume.lock().extents_btree = Some(Arc::new(Mutex::new(
-            hfsplus::BTree::open(extents_fork).unwrap(),
+            hfsplus::BTree::open(extents_fork).map_err(|_| EINVAL)?,
         )));
 
         kprintln!("HfsFs: HFS+ volume loaded successfully");
-        Self { volume }
+        Ok(Self {
+            volume,
+            io: Arc::new(SleepLock::new(())),
+            block_size,
+            compressed_sizes: Mutex::new(BTreeMap::new()),
+            folders: Mutex::new(BTreeMap::new()),
+        })
     }
 
-    pub fn open(&self, path: &str) -> Option<Box<dyn crate::vfs::File>> {
-        kprintln!("HfsFs: open '{}'", path);
-        let vol = self.volume.lock();
-        let record = vol.get_path_record(path).ok()?;
-        if let CatalogBody::File(file_info) = record.body {
-            let mut fork_data = &file_info.data_fork;
-            let mut fork_type = 0;
-            if fork_data.logical_size == 0 && file_info.resource_fork.logical_size > 0 {
-                fork_data = &file_info.resource_fork;
-                fork_type = 0xFF; // Usually resource fork is different type in extents btree, but Fork::load handles it?
-                // Actually, Fork::load takes fork_type. Catalog data fork is 0, resource is 0xFF.
+    /// Name of the volume, which is the name of its root folder
+    pub fn volume_name(&self) -> Option<String> {
+        self.record("")
+            .ok()
+            .map(|record| format!("{}", record.key.node_name))
+    }
+
+    fn record(&self, path: &str) -> core::result::Result<CatalogRecord, u64> {
+        let _io = self.io.lock();
+        let volume = self.volume.lock();
+        if path.is_empty() {
+            return volume.get_path_record(path).map_err(|_| ENOENT);
+        }
+        let (dir, name) = path.rsplit_once('/').unwrap_or(("", path));
+        let parent = self.folder_id(&volume, dir)?;
+        let record = volume.get_child_record(parent, name).map_err(|_| ENOENT)?;
+        if let CatalogBody::Folder(folder) = &record.body {
+            self.remember_folder(path, folder.folder_id);
+        }
+        Ok(record)
+    }
+
+    /// ID of the folder at `path`, looked up a component at a time from the
+    /// deepest folder already known
+    fn folder_id(&self, volume: &HFSVolume<Disk>, path: &str) -> core::result::Result<u32, u64> {
+        if path.is_empty() {
+            return Ok(ROOT_FOLDER_ID);
+        }
+        if let Some(&id) = self.folders.lock().get(path) {
+            return Ok(id);
+        }
+        let (dir, name) = path.rsplit_once('/').unwrap_or(("", path));
+        let parent = self.folder_id(volume, dir)?;
+        match volume
+            .get_child_record(parent, name)
+            .map_err(|_| ENOENT)?
+            .body
+        {
+            CatalogBody::Folder(folder) => {
+                self.remember_folder(path, folder.folder_id);
+                Ok(folder.folder_id)
             }
+            _ => Err(ENOENT),
+        }
+    }
 
-            let fork = Fork::load(
-                Arc::clone(&vol.file),
-                file_info.file_id,
-                fork_type,
-                &*vol,
-                fork_data,
-            )
-            .ok()?;
-
-            Some(Box::new(HfsFileHandle {
-                fork: Mutex::new(fork),
-            }))
+    fn remember_folder(&self, path: &str, id: u32) {
+        let mut folders = self.folders.lock();
+        if folders.len() >= FOLDER_CACHE_SIZE {
+            folders.clear();
+        }
+        folders.insert(String::from(path), id);
+    }
+
+    /// Attributes of a file or folder record. The size of a compressed file
+    /// is that of its compressed data.
+    fn record_attr(&self, record: &CatalogRecord) -> core::result::Result<Attr, u64> {
+        let alloc = |fork: &HFSPlusForkData| fork.total_blocks as u64 * self.block_size as u64;
+        let mut attr = match &record.body {
+            CatalogBody::Folder(folder) => {
+                let mut attr = Attr {
+                    ino: folder.folder_id as u64,
+                    mode: record_mode(folder.permissions.file_mode, S_IFDIR | 0o755),
+                    // HFS+ counts all children rather than subfolders, so
+                    // this overestimates the link count of most folders
+                    nlink: folder.valence + 2,
+                    entries: folder.valence,
+                    ..bsd_attr(
+                        &folder.permissions,
+                        [
+                            folder.created_at,
+                            folder.content_modified_at,
+                            folder.attribute_modified_at,
+                            folder.accessed_at,
+                            folder.backed_up_at,
+                        ],
+                    )
+                };
+                let info = &folder.user_info;
+                let bounds = &info.window_bounds;
+                let fields: [(usize, u16); 10] = [
+                    (0, bounds.top as u16),
+                    (2, bounds.left as u16),
+                    (4, bounds.bottom as u16),
+                    (6, bounds.right as u16),
+                    (8, info.finder_flags),
+                    (10, info.location.v as u16),
+                    (12, info.location.h as u16),
+                    (16, folder.finder_info.scroll_position.v as u16),
+                    (18, folder.finder_info.scroll_position.h as u16),
+                    (24, folder.finder_info.extended_finder_flags),
+                ];
+                for (offset, value) in fields {
+                    attr.finder_info[offset..offset + 2].copy_from_slice(&value.to_be_bytes());
+                }
+                attr
+            }
+            CatalogBody::File(file) => {
+                let mut attr = Attr {
+                    ino: file.file_id as u64,
+                    mode: file_mode(file),
+                    nlink: 1,
+                    size: file.data_fork.logical_size,
+                    blocks: (alloc(&file.data_fork) + alloc(&file.resource_fork)) / 512,
+                    rsrc_size: file.resource_fork.logical_size,
+                    rsrc_alloc: alloc(&file.resource_fork),
+                    ..bsd_attr(
+                        &file.permissions,
+                        [
+                            file.created_at,
+                            file.content_modified_at,
+                            file.attribute_modified_at,
+                            file.accessed_at,
+                            file.backed_up_at,
+                        ],
+                    )
+                };
+                let info = &file.user_info;
+                attr.finder_info[0..4].copy_from_slice(&info.file_type.to_be_bytes());
+                attr.finder_info[4..8].copy_from_slice(&info.file_creator.to_be_bytes());
+                let fields: [(usize, u16); 4] = [
+                    (8, info.finder_flags),
+                    (10, info.location.v as u16),
+                    (12, info.location.h as u16),
+                    (24, file.finder_info.extended_finder_flags),
+                ];
+                for (offset, value) in fields {
+                    attr.finder_info[offset..offset + 2].copy_from_slice(&value.to_be_bytes());
+                }
+                attr
+            }
+            _ => return Err(ENOENT),
+        };
+        // The root folder's parent is the volume's invisible parent
+        attr.parent_ino = if attr.ino == ROOT_FOLDER_ID as u64 {
+            attr.ino
         } else {
-            None
+            record.key.parent_id as u64
+        };
+        attr.block_size = self.block_size;
+        Ok(attr)
+    }
+}
+
+fn unix_time(hfs_time: u32) -> i64 {
+    hfs_time as i64 - HFS_EPOCH_OFFSET
+}
+
+/// Ownership, flags and times shared by files and folders. `dates` are the
+/// creation, content modification, attribute modification, access and
+/// backup dates.
+fn bsd_attr(bsd: &HFSPlusBSDInfo, dates: [u32; 5]) -> Attr {
+    Attr {
+        uid: bsd.owner_id,
+        gid: bsd.group_id,
+        flags: bsd.owner_flags as u32 | (bsd.admin_flags as u32) << 16,
+        birthtime: unix_time(dates[0]),
+        mtime: unix_time(dates[1]),
+        ctime: unix_time(dates[2]),
+        atime: unix_time(dates[3]),
+        backup_time: unix_time(dates[4]),
+        ..Attr::default()
+    }
+}
+
+/// st_mode from the catalog's BSD info, which is zero on files that were
+/// never given permissions
+fn record_mode(file_mode: u16, default: u16) -> u16 {
+    if file_mode & S_IFMT == 0 {
+        default
+    } else {
+        file_mode
+    }
+}
+
+fn is_symlink(file: &HFSPlusCatalogFile) -> bool {
+    file.user_info.file_type == SYMLINK_TYPE && file.user_info.file_creator == SYMLINK_CREATOR
+}
+
+/// st_mode of a file record. Symbolic links are recognised by their Finder
+/// type, since their BSD mode may not have been set.
+fn file_mode(file: &HFSPlusCatalogFile) -> u16 {
+    let mode = file.permissions.file_mode;
+    if is_symlink(file) {
+        S_IFLNK
+            | if mode & !S_IFMT == 0 {
+                0o755
+            } else {
+                mode & !S_IFMT
+            }
+    } else {
+        record_mode(mode, S_IFREG | 0o644)
+    }
+}
+
+impl FileSystem for HfsFs {
+    fn fs_type(&self) -> &'static str {
+        "hfs"
+    }
+
+    fn getattr(&self, path: &str) -> core::result::Result<Attr, u64> {
+        let attr = self.record_attr(&self.record(path)?)?;
+        // The size of a compressed file is only known once it is opened
+        if attr.flags & UF_COMPRESSED as u32 != 0 {
+            if let Some(&size) = self.compressed_sizes.lock().get(&(attr.ino as u32)) {
+                return Ok(Attr { size, ..attr });
+            }
+            return Ok(self.open(path, 0)?.getattr());
+        }
+        Ok(attr)
+    }
+
+    fn mode(&self, path: &str) -> core::result::Result<u16, u64> {
+        Ok(self.record_attr(&self.record(path)?)?.mode)
+    }
+
+    fn readlink(&self, path: &str) -> core::result::Result<String, u64> {
+        let record = self.record(path)?;
+        let CatalogBody::File(file) = &record.body else {
+            return Err(EINVAL);
+        };
+        if !is_symlink(file) {
+            return Err(EINVAL);
+        }
+        let _io = self.io.lock();
+        let vol = self.volume.lock();
+        let mut fork = Fork::load(
+            Arc::clone(&vol.file),
+            file.file_id,
+            0,
+            &*vol,
+            &file.data_fork,
+        )
+        .map_err(|_| EIO)?;
+        let mut target = vec![0u8; file.data_fork.logical_size as usize];
+        fork.read_exact(&mut target).map_err(|_| EIO)?;
+        String::from_utf8(target).map_err(|_| EIO)
+    }
+
+    fn readdir(&self, path: &str) -> core::result::Result<Vec<DirEntry>, u64> {
+        let _io = self.io.lock();
+        let entries = self.volume.lock().list_dir(path).map_err(|e| match e {
+            Error::InvalidRecordType => ENOTDIR,
+            _ => ENOENT,
+        })?;
+        let is_root = path.is_empty();
+        Ok(entries
+            .into_iter()
+            .filter(|(name, _)| !(is_root && HIDDEN_ROOT_ENTRIES.contains(&name.as_str())))
+            .filter_map(|(name, record)| {
+                let (ino, kind) = match record.body {
+                    CatalogBody::Folder(folder) => (folder.folder_id, DT_DIR),
+                    CatalogBody::File(file) if is_symlink(&file) => (file.file_id, DT_LNK),
+                    CatalogBody::File(file) => (file.file_id, DT_REG),
+                    // The directory's own thread record
+                    _ => return None,
+                };
+                Some(DirEntry {
+                    ino: ino as u64,
+                    kind,
+                    name,
+                })
+            })
+            .collect())
+    }
+
+    fn open(&self, path: &str, _flags: u32) -> core::result::Result<Box<dyn File>, u64> {
+        kprintln!("HfsFs: open '{}'", path);
+        let record = self.record(path)?;
+        let attr = self.record_attr(&record)?;
+        if let CatalogBody::Folder(_) = &record.body {
+            return Ok(Box::new(DirFile::new(attr, self.readdir(path)?)));
+        }
+        let CatalogBody::File(file) = record.body else {
+            return Err(ENOENT);
+        };
+        let mut handle = HfsFileHandle {
+            volume: Arc::clone(&self.volume),
+            io: Arc::clone(&self.io),
+            file,
+            fork: Mutex::new(None),
+            size: file.data_fork.logical_size,
+            attr,
+        };
+        if handle.attr.flags & UF_COMPRESSED as u32 != 0 {
+            // Decompress to learn the size, unless an earlier open did
+            let size = self.compressed_sizes.lock().get(&file.file_id).copied();
+            match size {
+                Some(size) => handle.size = size,
+                None => {
+                    let fork = {
+                        let _io = self.io.lock();
+                        handle.load()?
+                    };
+                    handle.size = fork.logical_size;
+                    self.compressed_sizes
+                        .lock()
+                        .insert(file.file_id, fork.logical_size);
+                    *handle.fork.get_mut() = Some(fork);
+                }
+            }
+        }
+        Ok(Box::new(handle))
+    }
+
+    fn statfs(&self) -> FsStat {
+        let header = &self.volume.lock().header;
+        FsStat {
+            block_size: header.block_size,
+            blocks: header.total_blocks as u64,
+            free_blocks: header.free_blocks as u64,
+            files: header.file_count as u64 + header.folder_count as u64,
+            free_files: header.free_blocks as u64,
         }
     }
 }
 
+/// An open file. The fork is loaded on the first read, so files that are
+/// already in the page cache aren't read, or decompressed, again.
 pub struct HfsFileHandle {
-    fork: Mutex<Fork<BufReader<DeviceWrapper>>>,
+    volume: Volume,
+    /// The volume's I/O lock
+    io: Arc<SleepLock<()>>,
+    file: HFSPlusCatalogFile,
+    fork: Mutex<Option<Fork<Disk>>>,
+    size: u64,
+    attr: Attr,
 }
 
-impl crate::vfs::File for HfsFileHandle {
-    fn read(&mut self, buf: &mut [u8]) -> usize {
-        self.fork.lock().read(buf).unwrap_or(0)
+impl HfsFileHandle {
+    /// Load the fork. The caller holds the I/O lock.
+    fn load(&self) -> core::result::Result<Fork<Disk>, u64> {
+        let file = &self.file;
+        let (fork_data, fork_type) =
+            if file.data_fork.logical_size == 0 && file.resource_fork.logical_size > 0 {
+                (&file.resource_fork, 0xFF)
+            } else {
+                (&file.data_fork, 0)
+            };
+        let vol = self.volume.lock();
+        let mut fork = Fork::load(
+            Arc::clone(&vol.file),
+            file.file_id,
+            fork_type,
+            &*vol,
+            fork_data,
+        )
+        .map_err(|_| EIO)?;
+        drop(vol);
+        // Compression is only detected by a read at position 0, so do it
+        // now for later reads to see the decompressed file
+        fork.read(&mut []).map_err(|_| EIO)?;
+        Ok(fork)
     }
+}
 
+impl File for HfsFileHandle {
     fn read_at(&self, offset: u64, buf: &mut [u8]) -> usize {
+        let _io = self.io.lock();
         let mut fork = self.fork.lock();
+        if fork.is_none() {
+            match self.load() {
+                Ok(loaded) => *fork = Some(loaded),
+                Err(_) => return 0,
+            }
+        }
+        let fork = fork.as_mut().unwrap();
         let old_pos = fork.position;
         fork.seek(SeekFrom::Start(offset)).unwrap_or(0);
         let read = fork.read(buf).unwrap_or(0);
@@ -228,11 +642,17 @@ impl crate::vfs::File for HfsFileHandle {
         read
     }
 
-    fn seek(&mut self, pos: u64) {
-        self.fork.lock().seek(SeekFrom::Start(pos)).unwrap_or(0);
+    fn size(&self) -> u64 {
+        self.size
+    }
+    fn mode(&self) -> u16 {
+        self.attr.mode
     }
 
-    fn size(&self) -> u64 {
-        self.fork.lock().logical_size
+    fn getattr(&self) -> Attr {
+        Attr {
+            size: self.size(),
+            ..self.attr.clone()
+        }
     }
 }
@@ -0,0 +1,822 @@
+//! AF_INET and AF_INET6 sockets over the stack in `net`
+//!
+//! Each socket holds the smoltcp sockets it uses: one for a connection or a
+//! UDP socket, or a backlog of listening ones for a TCP listener, which get
+//! replaced as connections are accepted from them. An AF_INET6 socket also
+//! reaches IPv4 peers through IPv4-mapped addresses.
+//!
+//! `state` is locked inside `net::with`, never around it.
+
+use crate::errno::{
+    EADDRINUSE, EADDRNOTAVAIL, EAFNOSUPPORT, EAGAIN, EALREADY, ECONNREFUSED, EDESTADDRREQ,
+    EINPROGRESS, EINVAL, EISCONN, EMSGSIZE, ENETUNREACH, ENOPROTOOPT, ENOTCONN, EOPNOTSUPP, EPIPE,
+    EPROTONOSUPPORT, ETIMEDOUT,
+};
+use crate::net::{self, Net};
+use crate::socket::{
+    AF_INET, AF_INET6, MSG_PEEK, MSG_WAITALL, SHUT_RD, SHUT_RDWR, SHUT_WR, SO_BROADCAST, SO_ERROR,
+    SO_KEEPALIVE, SO_NOSIGPIPE, SO_RCVBUF, SO_REUSEADDR, SO_REUSEPORT, SO_SNDBUF, SO_TYPE,
+    SOCK_DGRAM, SOCK_STREAM, SOL_SOCKET, SockAddr, Socket,
+};
+use crate::timer;
+use crate::vfs::{File, Readiness, S_IFSOCK};
+use alloc::boxed::Box;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use smoltcp::iface::SocketHandle;
+use smoltcp::socket::{tcp, udp};
+use smoltcp::time::Duration;
+use smoltcp::wire::{IpEndpoint, IpListenEndpoint, IpProtocol};
+use spin::Mutex;
+
+pub const IPPROTO_TCP: u32 = 6;
+pub const IPPROTO_UDP: u32 = 17;
+
+/// IPPROTO_TCP option
+pub const TCP_NODELAY: u32 = 0x01;
+
+/// Size of each direction's buffer of a TCP connection
+const TCP_BUFFER_SIZE: usize = 65536;
+/// Datagrams and bytes each direction of a UDP socket can hold
+const UDP_PACKETS: usize = 32;
+const UDP_BUFFER_SIZE: usize = 65536;
+/// Largest UDP payload that fits in an IP packet
+const UDP_PAYLOAD_MAX: usize = 65507;
+/// Most connections a listener keeps waiting to be accepted
+const BACKLOG_MAX: u32 = 16;
+/// How long connect waits for the peer to answer, in seconds
+const CONNECT_TIMEOUT_SECS: u64 = 75;
+/// Idle time before a keepalive probe, with SO_KEEPALIVE
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(7200);
+
+#[derive(Clone, Copy, PartialEq)]
+enum Kind {
+    Tcp,
+    Udp,
+}
+
+enum Conn {
+    /// Neither connected nor listening, and for UDP not bound
+    Idle,
+    /// A TCP connection, possibly still being set up
+    Stream(SocketHandle),
+    /// A TCP listener's backlog
+    Listener(Vec<SocketHandle>),
+    /// A bound UDP socket
+    Datagram(SocketHandle),
+}
+
+/// Boolean socket options
+#[derive(Clone, Copy, Default)]
+struct Options {
+    reuse_addr: bool,
+    reuse_port: bool,
+    keep_alive: bool,
+    broadcast: bool,
+    no_sigpipe: bool,
+    no_delay: bool,
+}
+
+impl Options {
+    fn flag(&mut self, level: u32, name: u32) -> Option<&mut bool> {
+        match (level, name) {
+            (SOL_SOCKET, SO_REUSEADDR) => Some(&mut self.reuse_addr),
+            (SOL_SOCKET, SO_REUSEPORT) => Some(&mut self.reuse_port),
+            (SOL_SOCKET, SO_KEEPALIVE) => Some(&mut self.keep_alive),
+            (SOL_SOCKET, SO_BROADCAST) => Some(&mut self.broadcast),
+            (SOL_SOCKET, SO_NOSIGPIPE) => Some(&mut self.no_sigpipe),
+            (IPPROTO_TCP, TCP_NODELAY) => Some(&mut self.no_delay),
+            _ => None,
+        }
+    }
+
+    /// Apply the options smoltcp implements to a TCP socket
+    fn configure(&self, socket: &mut tcp::Socket) {
+        socket.set_nagle_enabled(!self.no_delay);
+        socket.set_keep_alive(self.keep_alive.then_some(KEEPALIVE_INTERVAL));
+    }
+}
+
+struct State {
+    conn: Conn,
+    /// Local address once bound
+    local: Option<SocketAddr>,
+    /// Whether the socket holds the local port, rather than sharing its
+    /// listener's
+    owns_port: bool,
+    /// Default peer of a UDP socket, set by connect
+    peer: Option<SocketAddr>,
+    /// Counter value at which a pending connect gives up, if one is pending
+    connect_deadline: Option<u64>,
+    /// Error of a failed nonblocking connect, for SO_ERROR
+    error: u64,
+    read_shut: bool,
+    write_shut: bool,
+    options: Options,
+}
+
+pub struct InetSocket {
+    family: u32,
+    kind: Kind,
+    state: Mutex<State>,
+}
+
+/// The address smoltcp uses for `addr`: IPv4-mapped IPv6 addresses are IPv4
+fn to_stack(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V6(v6) => match v6.ip().to_ipv4_mapped() {
+            Some(v4) => SocketAddr::V4(SocketAddrV4::new(v4, v6.port())),
+            None => addr,
+        },
+        SocketAddr::V4(_) => addr,
+    }
+}
+
+fn endpoint_addr(endpoint: IpEndpoint) -> SocketAddr {
+    SocketAddr::new(endpoint.addr.into(), endpoint.port)
+}
+
+fn listen_endpoint(addr: SocketAddr) -> IpListenEndpoint {
+    IpListenEndpoint {
+        addr: (!addr.ip().is_unspecified()).then(|| addr.ip().into()),
+        port: addr.port(),
+    }
+}
+
+fn new_tcp_socket(options: &Options) -> tcp::Socket<'static> {
+    let mut socket = tcp::Socket::new(
+        tcp::SocketBuffer::new(vec![0; TCP_BUFFER_SIZE]),
+        tcp::SocketBuffer::new(vec![0; TCP_BUFFER_SIZE]),
+    );
+    options.configure(&mut socket);
+    socket
+}
+
+/// A TCP socket listening on `local` for one connection
+fn new_listening(net: &mut Net, local: SocketAddr, options: &Options) -> Result<SocketHandle, u64> {
+    let mut socket = new_tcp_socket(options);
+    socket.listen(listen_endpoint(local)).map_err(|_| EINVAL)?;
+    Ok(net.sockets.add(socket))
+}
+
+impl InetSocket {
+    pub fn new(family: u32, ty: u32, protocol: u32) -> Result<Self, u64> {
+        let kind = match (ty, protocol) {
+            (SOCK_STREAM, 0 | IPPROTO_TCP) => Kind::Tcp,
+            (SOCK_DGRAM, 0 | IPPROTO_UDP) => Kind::Udp,
+            _ => return Err(EPROTONOSUPPORT),
+        };
+        Ok(Self::with_state(
+            family,
+            kind,
+            Conn::Idle,
+            None,
+            Options::default(),
+        ))
+    }
+
+    fn with_state(
+        family: u32,
+        kind: Kind,
+        conn: Conn,
+        local: Option<SocketAddr>,
+        options: Options,
+    ) -> Self {
+        Self {
+            family,
+            kind,
+            state: Mutex::new(State {
+                conn,
+                local,
+                owns_port: false,
+                peer: None,
+                connect_deadline: None,
+                error: 0,
+                read_shut: false,
+                write_shut: false,
+                options,
+            }),
+        }
+    }
+
+    fn protocol(&self) -> IpProtocol {
+        match self.kind {
+            Kind::Tcp => IpProtocol::Tcp,
+            Kind::Udp => IpProtocol::Udp,
+        }
+    }
+
+    /// The address the stack uses for `addr` given to this socket
+    fn inet_addr(&self, addr: SockAddr) -> Result<SocketAddr, u64> {
+        let SockAddr::Inet(addr) = addr else {
+            return Err(EAFNOSUPPORT);
+        };
+        match (self.family, addr) {
+            (AF_INET, SocketAddr::V4(_)) | (AF_INET6, SocketAddr::V6(_)) => Ok(to_stack(addr)),
+            _ => Err(EAFNOSUPPORT),
+        }
+    }
+
+    /// `addr` as this socket's family reports it
+    fn sock_addr(&self, addr: SocketAddr) -> SockAddr {
+        match addr {
+            SocketAddr::V4(v4) if self.family == AF_INET6 => SockAddr::Inet(SocketAddr::V6(
+                SocketAddrV6::new(v4.ip().to_ipv6_mapped(), v4.port(), 0, 0),
+            )),
+            _ => SockAddr::Inet(addr),
+        }
+    }
+
+    fn unspecified(&self) -> SocketAddr {
+        match self.family {
+            AF_INET => SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0),
+            _ => SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0),
+        }
+    }
+
+    /// Take the local address `addr` for the socket
+    fn bind_locked(
+        &self,
+        net: &mut Net,
+        state: &mut State,
+        addr: SocketAddr,
+    ) -> Result<SocketAddr, u64> {
+        if state.local.is_some() {
+            return Err(EINVAL);
+        }
+        if !addr.ip().is_unspecified() && !net.iface.has_ip_addr(addr.ip()) {
+            return Err(EADDRNOTAVAIL);
+        }
+        let port = net
+            .bind_port(self.protocol(), addr.port())
+            .ok_or(EADDRINUSE)?;
+        let local = SocketAddr::new(addr.ip(), port);
+        if self.kind == Kind::Udp {
+            let mut socket = udp::Socket::new(
+                udp::PacketBuffer::new(
+                    vec![udp::PacketMetadata::EMPTY; UDP_PACKETS],
+                    vec![0; UDP_BUFFER_SIZE],
+                ),
+                udp::PacketBuffer::new(
+                    vec![udp::PacketMetadata::EMPTY; UDP_PACKETS],
+                    vec![0; UDP_BUFFER_SIZE],
+                ),
+            );
+            if socket.bind(listen_endpoint(local)).is_err() {
+                net.release_port(self.protocol(), port);
+                return Err(EINVAL);
+            }
+            state.conn = Conn::Datagram(net.sockets.add(socket));
+        }
+        state.local = Some(local);
+        state.owns_port = true;
+        Ok(local)
+    }
+
+    /// The local address, binding to an ephemeral port if unbound
+    fn local_or_bind(&self, net: &mut Net, state: &mut State) -> Result<SocketAddr, u64> {
+        match state.local {
+            Some(local) => Ok(local),
+            None => self.bind_locked(net, state, self.unspecified()),
+        }
+    }
+
+    /// Whether a pending connect has finished, and how. Clears the pending
+    /// connect once it has, dropping the connection if it failed.
+    fn poll_connect(net: &mut Net, state: &mut State) -> Option<Result<(), u64>> {
+        let deadline = state.connect_deadline?;
+        let Conn::Stream(handle) = state.conn else {
+            return None;
+        };
+        let socket = net.sockets.get_mut::<tcp::Socket>(handle);
+        let result = match socket.state() {
+            tcp::State::SynSent | tcp::State::SynReceived => return None,
+            tcp::State::Closed if timer::counter() >= deadline => Err(ETIMEDOUT),
+            tcp::State::Closed => Err(ECONNREFUSED),
+            _ => {
+                socket.set_timeout(None);
+                Ok(())
+            }
+        };
+        state.connect_deadline = None;
+        if result.is_err() {
+            net.sockets.remove(handle);
+            state.conn = Conn::Idle;
+        }
+        Some(result)
+    }
+
+    fn send_stream(&self, buf: &[u8], nonblock: bool) -> Result<usize, u64> {
+        let mut sent = 0;
+        while sent < buf.len() {
+            let result = net::wait_for(nonblock, |net| {
+                let mut state = self.state.lock();
+                let Conn::Stream(handle) = state.conn else {
+                    return Some(Err(ENOTCONN));
+                };
+                if state.connect_deadline.is_some()
+                    && let Err(err) = Self::poll_connect(net, &mut state)?
+                {
+                    return Some(Err(err));
+                }
+                let socket = net.sockets.get_mut::<tcp::Socket>(handle);
+                if state.write_shut || !socket.may_send() {
+                    Some(Err(EPIPE))
+                } else if socket.can_send() {
+                    Some(socket.send_slice(&buf[sent..]).map_err(|_| EPIPE))
+                } else {
+                    None
+                }
+            });
+            match result {
+                Ok(n) => sent += n,
+                Err(_) if sent > 0 => break,
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(sent)
+    }
+
+    fn recv_stream(&self, buf: &mut [u8], flags: u32, nonblock: bool) -> Result<usize, u64> {
+        let mut received = 0;
+        loop {
+            let result = net::wait_for(nonblock, |net| {
+                let mut state = self.state.lock();
+                let Conn::Stream(handle) = state.conn else {
+                    return Some(Err(ENOTCONN));
+                };
+                if state.read_shut {
+                    return Some(Ok(0));
+                }
+                if state.connect_deadline.is_some()
+                    && let Err(err) = Self::poll_connect(net, &mut state)?
+                {
+                    return Some(Err(err));
+                }
+                let socket = net.sockets.get_mut::<tcp::Socket>(handle);
+                if socket.can_recv() {
+                    let result = if flags & MSG_PEEK != 0 {
+                        socket.peek_slice(&mut buf[received..])
+                    } else {
+                        socket.recv_slice(&mut buf[received..])
+                    };
+                    Some(result.map_err(|_| ENOTCONN))
+                } else if !socket.may_recv() {
+                    Some(Ok(0))
+                } else {
+                    None
+                }
+            });
+            match result {
+                Ok(0) => break,
+                Ok(n) => received += n,
+                Err(_) if received > 0 => break,
+                Err(err) => return Err(err),
+            }
+            if received == buf.len() || flags & (MSG_WAITALL | MSG_PEEK) != MSG_WAITALL {
+                break;
+            }
+        }
+        Ok(received)
+    }
+
+    fn send_datagram(
+        &self,
+        buf: &[u8],
+        to: Option<SockAddr>,
+        nonblock: bool,
+    ) -> Result<usize, u64> {
+        if buf.len() > UDP_PAYLOAD_MAX {
+            return Err(EMSGSIZE);
+        }
+        let to = to.map(|to| self.inet_addr(to)).transpose()?;
+        net::wait_for(nonblock, |net| {
+            let mut state = self.state.lock();
+            if state.write_shut {
+                return Some(Err(EPIPE));
+            }
+            let Some(to) = to.or(state.peer) else {
+                return Some(Err(EDESTADDRREQ));
+            };
+            if let Err(err) = self.local_or_bind(net, &mut state) {
+                return Some(Err(err));
+            }
+            let Conn::Datagram(handle) = state.conn else {
+                return Some(Err(EINVAL));
+            };
+            let socket = net.sockets.get_mut::<udp::Socket>(handle);
+            match socket.send_slice(buf, IpEndpoint::from(to)) {
+                Ok(()) => Some(Ok(buf.len())),
+                Err(udp::SendError::BufferFull) => None,
+                Err(udp::SendError::Unaddressable) => Some(Err(ENETUNREACH)),
+            }
+        })
+    }
+
+    fn recv_datagram(
+        &self,
+        buf: &mut [u8],
+        flags: u32,
+        nonblock: bool,
+    ) -> Result<(usize, Option<SockAddr>), u64> {
+        net::wait_for(nonblock, |net| {
+            let mut state = self.state.lock();
+            if state.read_shut {
+                return Some(Ok((0, None)));
+            }
+            if let Err(err) = self.local_or_bind(net, &mut state) {
+                return Some(Err(err));
+            }
+            let Conn::Datagram(handle) = state.conn else {
+                return Some(Err(EINVAL));
+            };
+            let socket = net.sockets.get_mut::<udp::Socket>(handle);
+            loop {
+                let (data, from) = if flags & MSG_PEEK != 0 {
+                    socket
+                        .peek()
+                        .map(|(data, meta)| (data, meta.endpoint))
+                        .ok()?
+                } else {
+                    socket
+                        .recv()
+                        .map(|(data, meta)| (data, meta.endpoint))
+                        .ok()?
+                };
+                let from = endpoint_addr(from);
+                // A connected socket only takes datagrams from its peer
+                if state.peer.is_some_and(|peer| peer != from) {
+                    if flags & MSG_PEEK != 0 {
+                        let _ = socket.recv();
+                    }
+                    continue;
+                }
+                let len = data.len().min(buf.len());
+                buf[..len].copy_from_slice(&data[..len]);
+                return Some(Ok((len, Some(self.sock_addr(from)))));
+            }
+        })
+    }
+}
+
+impl Socket for InetSocket {
+    fn bind(&self, addr: SockAddr) -> Result<(), u64> {
+        let addr = self.inet_addr(addr)?;
+        net::with(|net| {
+            self.bind_locked(net, &mut self.state.lock(), addr)
+                .map(|_| ())
+        })?
+    }
+
+    fn connect(&self, addr: SockAddr, nonblock: bool) -> Result<(), u64> {
+        let remote = self.inet_addr(addr)?;
+        if self.kind == Kind::Udp {
+            return net::with(|net| {
+                let mut state = self.state.lock();
+                self.local_or_bind(net, &mut state)?;
+                state.peer = (!remote.ip().is_unspecified()).then_some(remote);
+                Ok(())
+            })?;
+        }
+        if remote.ip().is_unspecified() || remote.port() == 0 {
+            return Err(EADDRNOTAVAIL);
+        }
+        net::with(|net| {
+            let mut state = self.state.lock();
+            match state.conn {
+                Conn::Stream(_) if state.connect_deadline.is_some() => return Err(EALREADY),
+                Conn::Stream(_) => return Err(EISCONN),
+                Conn::Listener(_) => return Err(EINVAL),
+                Conn::Idle | Conn::Datagram(_) => {}
+            }
+            let local = self.local_or_bind(net, &mut state)?;
+            let mut socket = new_tcp_socket(&state.options);
+            socket.set_timeout(Some(Duration::from_secs(CONNECT_TIMEOUT_SECS)));
+            socket
+                .connect(
+                    net.iface.context(),
+                    IpEndpoint::from(remote),
+                    listen_endpoint(local),
+                )
+                .map_err(|_| ENETUNREACH)?;
+            state.conn = Conn::Stream(net.sockets.add(socket));
+            state.connect_deadline =
+                Some(timer::counter() + CONNECT_TIMEOUT_SECS * timer::frequency());
+            Ok(())
+        })??;
+        net::wait_for(nonblock, |net| {
+            Self::poll_connect(net, &mut self.state.lock())
+        })
+        .map_err(|err| if err == EAGAIN { EINPROGRESS } else { err })
+    }
+
+    fn listen(&self, backlog: u32) -> Result<(), u64> {
+        if self.kind != Kind::Tcp {
+            return Err(EOPNOTSUPP);
+        }
+        net::with(|net| {
+            let mut state = self.state.lock();
+            match state.conn {
+                Conn::Idle => {}
+                Conn::Listener(_) => return Ok(()),
+                Conn::Stream(_) | Conn::Datagram(_) => return Err(EINVAL),
+            }
+            let local = self.local_or_bind(net, &mut state)?;
+            let options = state.options;
+            let mut handles = Vec::new();
+            for _ in 0..backlog.clamp(1, BACKLOG_MAX) {
+                match new_listening(net, local, &options) {
+                    Ok(handle) => handles.push(handle),
+                    Err(err) => {
+                        for handle in handles {
+                            net.sockets.remove(handle);
+                        }
+                        return Err(err);
+                    }
+                }
+            }
+            state.conn = Conn::Listener(handles);
+            Ok(())
+        })?
+    }
+
+    fn accept(&self, nonblock: bool) -> Result<(Box<dyn File>, SockAddr), u64> {
+        let (handle, local, peer, options) = net::wait_for(nonblock, |net| {
+            let mut state = self.state.lock();
+            let options = state.options;
+            let (Some(listen_addr), Conn::Listener(backlog)) = (state.local, &mut state.conn)
+            else {
+                return Some(Err(EINVAL));
+            };
+            let index = backlog.iter().position(|&handle| {
+                let state = net.sockets.get::<tcp::Socket>(handle).state();
+                !matches!(state, tcp::State::Listen | tcp::State::SynReceived)
+            })?;
+            // Keep the backlog full
+            let replacement = match new_listening(net, listen_addr, &options) {
+                Ok(handle) => handle,
+                Err(err) => return Some(Err(err)),
+            };
+            let handle = core::mem::replace(&mut backlog[index], replacement);
+            let socket = net.sockets.get::<tcp::Socket>(handle);
+            let local = socket.local_endpoint().map_or(listen_addr, endpoint_addr);
+            let peer = socket
+                .remote_endpoint()
+                .map_or(self.unspecified(), endpoint_addr);
+            Some(Ok((handle, local, peer, options)))
+        })?;
+        let socket = Self::with_state(
+            self.family,
+            Kind::Tcp,
+            Conn::Stream(handle),
+            Some(local),
+            options,
+        );
+        Ok((Box::new(socket), self.sock_addr(peer)))
+    }
+
+    fn send_to(
+        &self,
+        buf: &[u8],
+        _flags: u32,
+        to: Option<SockAddr>,
+        nonblock: bool,
+    ) -> Result<usize, u64> {
+        match self.kind {
+            Kind::Tcp if to.is_some() => Err(EISCONN),
+            Kind::Tcp => self.send_stream(buf, nonblock),
+            Kind::Udp => self.send_datagram(buf, to, nonblock),
+        }
+    }
+
+    fn recv_from(
+        &self,
+        buf: &mut [u8],
+        flags: u32,
+        nonblock: bool,
+    ) -> Result<(usize, Option<SockAddr>), u64> {
+        match self.kind {
+            Kind::Tcp => self
+                .recv_stream(buf, flags, nonblock)
+                .map(|len| (len, None)),
+            Kind::Udp => self.recv_datagram(buf, flags, nonblock),
+        }
+    }
+
+    fn shutdown(&self, how: u32) -> Result<(), u64> {
+        if how > SHUT_RDWR {
+            return Err(EINVAL);
+        }
+        net::with(|net| {
+            let mut state = self.state.lock();
+            match state.conn {
+                Conn::Stream(handle) => {
+                    if how != SHUT_RD && !state.write_shut {
+                        net.sockets.get_mut::<tcp::Socket>(handle).close();
+                    }
+                }
+                Conn::Datagram(_) if state.peer.is_some() => {}
+                _ => return Err(ENOTCONN),
+            }
+            state.read_shut |= how != SHUT_WR;
+            state.write_shut |= how != SHUT_RD;
+            Ok(())
+        })?
+    }
+
+    fn local_addr(&self) -> Result<SockAddr, u64> {
+        let local = self.state.lock().local;
+        Ok(self.sock_addr(local.unwrap_or(self.unspecified())))
+    }
+
+    fn peer_addr(&self) -> Result<SockAddr, u64> {
+        let peer = net::with(|net| {
+            let state = self.state.lock();
+            match state.conn {
+                Conn::Stream(handle) if state.connect_deadline.is_none() => net
+                    .sockets
+                    .get::<tcp::Socket>(handle)
+                    .remote_endpoint()
+                    .map(endpoint_addr),
+                Conn::Datagram(_) => state.peer,
+                _ => None,
+            }
+        })?;
+        peer.map(|peer| self.sock_addr(peer)).ok_or(ENOTCONN)
+    }
+
+    fn set_option(&self, level: u32, name: u32, value: i32) -> Result<(), u64> {
+        let (handle, options) = {
+            let mut state = self.state.lock();
+            match state.options.flag(level, name) {
+                Some(flag) => *flag = value != 0,
+                // Buffers have a fixed size
+                None if level == SOL_SOCKET && matches!(name, SO_SNDBUF | SO_RCVBUF) => {
+                    return Ok(());
+                }
+                None => return Err(ENOPROTOOPT),
+            }
+            let handle = match state.conn {
+                Conn::Stream(handle) => Some(handle),
+                _ => None,
+            };
+            (handle, state.options)
+        };
+        if let Some(handle) = handle {
+            net::with(|net| options.configure(net.sockets.get_mut::<tcp::Socket>(handle)))?;
+        }
+        Ok(())
+    }
+
+    fn get_option(&self, level: u32, name: u32) -> Result<i32, u64> {
+        match (level, name) {
+            (SOL_SOCKET, SO_TYPE) => Ok(match self.kind {
+                Kind::Tcp => SOCK_STREAM as i32,
+                Kind::Udp => SOCK_DGRAM as i32,
+            }),
+            (SOL_SOCKET, SO_SNDBUF | SO_RCVBUF) => Ok(match self.kind {
+                Kind::Tcp => TCP_BUFFER_SIZE as i32,
+                Kind::Udp => UDP_BUFFER_SIZE as i32,
+            }),
+            (SOL_SOCKET, SO_ERROR) => {
+                let error = net::with(|net| {
+                    let mut state = self.state.lock();
+                    if let Some(Err(err)) = Self::poll_connect(net, &mut state) {
+                        state.error = err;
+                    }
+                    core::mem::take(&mut state.error)
+                });
+                Ok(error.unwrap_or(0) as i32)
+            }
+            _ => {
+                let mut options = self.state.lock().options;
+                let flag = options.flag(level, name).ok_or(ENOPROTOOPT)?;
+                Ok(*flag as i32)
+            }
+        }
+    }
+}
+
+impl File for InetSocket {
+    fn read_at(&self, _offset: u64, _buf: &mut [u8]) -> usize {
+        0
+    }
+
+    fn size(&self) -> u64 {
+        0
+    }
+
+    fn is_stream(&self) -> bool {
+        true
+    }
+
+    fn read(&self, buf: &mut [u8], nonblock: bool) -> Result<usize, u64> {
+        self.recv_from(buf, 0, nonblock).map(|(len, _)| len)
+    }
+
+    fn write(&self, buf: &[u8], nonblock: bool) -> Result<usize, u64> {
+        self.send_to(buf, 0, None, nonblock)
+    }
+
+    fn mode(&self) -> u16 {
+        S_IFSOCK | 0o777
+    }
+
+    fn readiness(&self) -> Readiness {
+        let readiness = net::with(|net| {
+            let state = self.state.lock();
+            let mut readiness = match &state.conn {
+                Conn::Idle => Readiness {
+                    write: (self.kind == Kind::Udp).then_some(UDP_PAYLOAD_MAX),
+                    ..Readiness::default()
+                },
+                Conn::Stream(handle) => {
+                    let socket = net.sockets.get::<tcp::Socket>(*handle);
+                    match socket.state() {
+                        tcp::State::SynSent | tcp::State::SynReceived => Readiness::default(),
+                        _ => Readiness {
+                            read: (socket.can_recv() || !socket.may_recv())
+                                .then(|| socket.recv_queue()),
+                            write: if !socket.may_send() {
+                                Some(0)
+                            } else {
+                                socket
+                                    .can_send()
+                                    .then(|| socket.send_capacity() - socket.send_queue())
+                            },
+                            eof: !socket.may_recv(),
+                        },
+                    }
+                }
+                Conn::Listener(backlog) => {
+                    let pending = backlog
+                        .iter()
+                        .filter(|&&handle| {
+                            let state = net.sockets.get::<tcp::Socket>(handle).state();
+                            !matches!(state, tcp::State::Listen | tcp::State::SynReceived)
+                        })
+                        .count();
+                    Readiness {
+                        read: (pending > 0).then_some(pending),
+                        ..Readiness::default()
+                    }
+                }
+                Conn::Datagram(handle) => {
+                    let socket = net.sockets.get_mut::<udp::Socket>(*handle);
+                    Readiness {
+                        read: socket.peek().ok().map(|(data, _)| data.len()),
+                        write: socket.can_send().then_some(UDP_PAYLOAD_MAX),
+                        eof: false,
+                    }
+                }
+            };
+            if state.read_shut {
+                readiness.read = Some(readiness.read.unwrap_or(0));
+            }
+            if state.write_shut {
+                readiness.write = Some(0);
+            }
+            readiness
+        });
+        // Without a stack every call fails at once
+        readiness.unwrap_or(Readiness {
+            read: Some(0),
+            write: Some(0),
+            eof: true,
+        })
+    }
+
+    fn socket(&self) -> Option<&dyn Socket> {
+        Some(self)
+    }
+}
+
+impl Drop for InetSocket {
+    fn drop(&mut self) {
+        let protocol = self.protocol();
+        let state = self.state.get_mut();
+        let conn = core::mem::replace(&mut state.conn, Conn::Idle);
+        let port = state
+            .local
+            .filter(|_| state.owns_port)
+            .map(|local| local.port());
+        let _ = net::with(|net| {
+            match conn {
+                Conn::Idle => {}
+                Conn::Stream(handle) => net.orphan(handle),
+                Conn::Listener(backlog) => {
+                    for handle in backlog {
+                        net.orphan(handle);
+                    }
+                }
+                Conn::Datagram(handle) => {
+                    net.sockets.remove(handle);
+                }
+            }
+            if let Some(port) = port {
+                net.release_port(protocol, port);
+            }
+        });
+    }
+}
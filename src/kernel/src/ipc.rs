@@ -176,9 +176,12 @@ fn handle_host_rpc(header: &MachMsgHeader, _msg: *mut MachMsgHeader, space: &mut
                         core::ptr::write_bytes(data_ptr, 0, 24);
 
                         // host_basic_info (6 fields = 24 bytes)
-                        *data_ptr = 1; // max_cpus
-                        *data_ptr.add(1) = 1; // avail_cpus
-                        *data_ptr.add(2) = 1024 * 1024 * 1024; // memory_size
+                        let ncpus = crate::smp::ncpus() as i32;
+                        *data_ptr = ncpus; // max_cpus
+                        *data_ptr.add(1) = ncpus; // avail_cpus
+                        // memory_size, which saturates at 2 GiB
+                        *data_ptr.add(2) =
+                            crate::platform::get().memory_size().min(i32::MAX as u64) as i32;
                         *data_ptr.add(3) = 12; // cpu_type: ARM
                         *data_ptr.add(4) = 9; // cpu_subtype: V7
                         *data_ptr.add(5) = 1; // cpu_threadtype
@@ -0,0 +1,45 @@
+//! IRQ dispatch
+
+use crate::gic;
+use crate::process::TrapFrame;
+use spin::Mutex;
+
+const MAX_IRQS: usize = gic::FIRST_SPECIAL_IRQ as usize;
+
+pub type IrqHandler = fn(irq: u32);
+
+static HANDLERS: Mutex<[Option<IrqHandler>; MAX_IRQS]> = Mutex::new([None; MAX_IRQS]);
+
+/// Install `handler` for `irq` and unmask it at the distributor
+pub fn register_handler(irq: u32, handler: IrqHandler) {
+    HANDLERS.lock()[irq as usize] = Some(handler);
+    gic::enable_irq(irq);
+}
+
+/// Returns true if the exception was taken from user mode (EL0t or AArch32 USR)
+fn from_user(spsr: u64) -> bool {
+    let mode = spsr & 0x1F;
+    mode == 0 || mode == 0x10
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn handle_irq_exception(frame: &mut TrapFrame) {
+    loop {
+        let iar = gic::acknowledge();
+        let irq = iar & 0x3FF;
+        if irq >= gic::FIRST_SPECIAL_IRQ {
+            break;
+        }
+
+        let handler = HANDLERS.lock()[irq as usize];
+        match handler {
+            Some(h) => h(irq),
+            None => crate::kprintln!("IRQ: Unhandled interrupt {}", irq),
+        }
+        gic::end_of_interrupt(iar);
+    }
+
+    if from_user(frame.spsr) {
+        crate::scheduler::preempt();
+    }
+}
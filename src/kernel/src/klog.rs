@@ -0,0 +1,116 @@
+//! Kernel message buffer, readable through /dev/klog
+
+use crate::devfs::{self, NodeKind};
+use crate::errno::{EAGAIN, EBUSY};
+use crate::vfs::{File, Readiness};
+use crate::waitqueue::WaitQueue;
+use alloc::boxed::Box;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+
+const KLOG_SIZE: usize = 16 * 1024;
+
+/// Character major of /dev/klog
+const KLOG_MAJOR: u32 = 6;
+
+/// Ring of the most recent kernel output. Fixed size, so that it can be
+/// written before the heap is up.
+struct Ring {
+    buf: [u8; KLOG_SIZE],
+    start: usize,
+    len: usize,
+}
+
+static RING: Mutex<Ring> = Mutex::new(Ring {
+    buf: [0; KLOG_SIZE],
+    start: 0,
+    len: 0,
+});
+
+static READERS: WaitQueue = WaitQueue::new();
+/// Set when there is output a blocked reader hasn't been woken for
+static PENDING: AtomicBool = AtomicBool::new(false);
+static OPEN: AtomicBool = AtomicBool::new(false);
+
+/// Record kernel output. The oldest bytes are dropped when the ring is full.
+pub fn append(bytes: &[u8]) {
+    let mut ring = RING.lock();
+    for &b in bytes {
+        let end = (ring.start + ring.len) % KLOG_SIZE;
+        ring.buf[end] = b;
+        if ring.len == KLOG_SIZE {
+            ring.start = (ring.start + 1) % KLOG_SIZE;
+        } else {
+            ring.len += 1;
+        }
+    }
+    PENDING.store(true, Ordering::Release);
+}
+
+/// Wake blocked readers if there is new output. Called from the timer tick:
+/// `append` runs under arbitrary locks, including the run queue's, so it
+/// can't wake processes itself.
+pub fn wake_readers() {
+    if PENDING.swap(false, Ordering::AcqRel) {
+        READERS.wake_all();
+    }
+}
+
+/// /dev/klog. Reads consume the buffer, so only one opener is allowed.
+struct KlogFile;
+
+impl Drop for KlogFile {
+    fn drop(&mut self) {
+        OPEN.store(false, Ordering::Release);
+    }
+}
+
+impl File for KlogFile {
+    fn read_at(&self, _offset: u64, _buf: &mut [u8]) -> usize {
+        0
+    }
+    fn size(&self) -> u64 {
+        RING.lock().len as u64
+    }
+    fn is_stream(&self) -> bool {
+        true
+    }
+    fn read(&self, buf: &mut [u8], nonblock: bool) -> Result<usize, u64> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        READERS.wait_until(|| {
+            let mut ring = RING.lock();
+            if ring.len == 0 {
+                return if nonblock { Some(Err(EAGAIN)) } else { None };
+            }
+            let n = buf.len().min(ring.len);
+            for b in buf[..n].iter_mut() {
+                *b = ring.buf[ring.start];
+                ring.start = (ring.start + 1) % KLOG_SIZE;
+            }
+            ring.len -= n;
+            Some(Ok(n))
+        })
+    }
+    fn readiness(&self) -> Readiness {
+        let len = RING.lock().len;
+        Readiness {
+            read: (len > 0).then_some(len),
+            ..Readiness::default()
+        }
+    }
+}
+
+fn open_klog(_minor: u32) -> Result<Box<dyn File>, u64> {
+    if OPEN.swap(true, Ordering::AcqRel) {
+        return Err(EBUSY);
+    }
+    Ok(Box::new(KlogFile))
+}
+
+/// Register /dev/klog
+pub fn init() {
+    devfs::register_driver(NodeKind::Char, KLOG_MAJOR, open_klog);
+    devfs::make_node("klog", NodeKind::Char, KLOG_MAJOR, 0, 0o600);
+}
@@ -0,0 +1,670 @@
+//! kqueue and kevent
+//!
+//! A kqueue is a file holding knotes, each watching one event source through
+//! a filter. kevent checks the knotes for events and, if there are none,
+//! sleeps in `waitqueue::wait_any` until some wait queue is woken or the
+//! next timer is due, then checks again. Descriptor and Mach port filters
+//! look at the object's state each time. Process exits, signals and vnode
+//! changes are recorded as they happen in the knotes watching them, which
+//! are found through `KQUEUES`.
+//!
+//! A knote on a descriptor goes away once the descriptor no longer refers
+//! to the file it was added for, which kevent notices when it next looks.
+
+use crate::errno::{EBADF, EINVAL, ENOENT, ESRCH};
+use crate::ipc::Port;
+use crate::scheduler;
+use crate::timer;
+use crate::vfs::{File, FileHandle, O_RDWR, Readiness, S_IFIFO};
+use crate::waitqueue::{self, SleepLock};
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Mutex;
+
+// Filters
+pub const EVFILT_READ: i16 = -1;
+pub const EVFILT_WRITE: i16 = -2;
+pub const EVFILT_VNODE: i16 = -4;
+pub const EVFILT_PROC: i16 = -5;
+pub const EVFILT_SIGNAL: i16 = -6;
+pub const EVFILT_TIMER: i16 = -7;
+pub const EVFILT_MACHPORT: i16 = -8;
+pub const EVFILT_USER: i16 = -10;
+
+// kevent flags
+pub const EV_ADD: u16 = 0x0001;
+pub const EV_DELETE: u16 = 0x0002;
+pub const EV_ENABLE: u16 = 0x0004;
+pub const EV_DISABLE: u16 = 0x0008;
+pub const EV_ONESHOT: u16 = 0x0010;
+pub const EV_CLEAR: u16 = 0x0020;
+pub const EV_RECEIPT: u16 = 0x0040;
+pub const EV_DISPATCH: u16 = 0x0080;
+pub const EV_ERROR: u16 = 0x4000;
+pub const EV_EOF: u16 = 0x8000;
+
+/// Flags a knote keeps from the change that added it
+const EV_KEPT: u16 = EV_ONESHOT | EV_CLEAR | EV_DISPATCH;
+
+// EVFILT_USER fflags
+pub const NOTE_TRIGGER: u32 = 0x0100_0000;
+pub const NOTE_FFAND: u32 = 0x4000_0000;
+pub const NOTE_FFOR: u32 = 0x8000_0000;
+pub const NOTE_FFCOPY: u32 = 0xc000_0000;
+pub const NOTE_FFCTRLMASK: u32 = 0xc000_0000;
+pub const NOTE_FFLAGSMASK: u32 = 0x00ff_ffff;
+
+// EVFILT_PROC fflags
+pub const NOTE_EXIT: u32 = 0x8000_0000;
+
+// EVFILT_VNODE fflags
+pub const NOTE_DELETE: u32 = 0x0000_0001;
+pub const NOTE_WRITE: u32 = 0x0000_0002;
+pub const NOTE_EXTEND: u32 = 0x0000_0004;
+pub const NOTE_ATTRIB: u32 = 0x0000_0008;
+pub const NOTE_RENAME: u32 = 0x0000_0020;
+
+// EVFILT_TIMER fflags, selecting the unit of the period. The default is
+// milliseconds.
+pub const NOTE_SECONDS: u32 = 0x0000_0001;
+pub const NOTE_USECONDS: u32 = 0x0000_0002;
+pub const NOTE_NSECONDS: u32 = 0x0000_0004;
+
+// kevent64 and kevent_qos flags
+pub const KEVENT_FLAG_IMMEDIATE: u32 = 0x0001;
+pub const KEVENT_FLAG_ERROR_EVENTS: u32 = 0x0002;
+
+/// An event or change, whichever struct it came in
+#[derive(Clone, Copy, Default)]
+pub struct KEvent {
+    pub ident: u64,
+    pub filter: i16,
+    pub flags: u16,
+    pub fflags: u32,
+    pub data: i64,
+    pub udata: u64,
+}
+
+/// The event structs of the kevent syscalls, as laid out for 32-bit
+/// processes
+#[derive(Clone, Copy)]
+pub enum Format {
+    /// struct kevent, for kevent
+    Kevent,
+    /// struct kevent64_s, for kevent64
+    Kevent64,
+    /// struct kevent_qos_s, for kevent_qos
+    Qos,
+}
+
+impl Format {
+    fn size(self) -> usize {
+        match self {
+            Self::Kevent => 20,
+            Self::Kevent64 => 48,
+            Self::Qos => 72,
+        }
+    }
+
+    /// Read entry `index` of the array at `ptr`
+    pub fn read(self, ptr: *const u8, index: usize) -> KEvent {
+        let mut buf = [0u8; 72];
+        let size = self.size();
+        unsafe { core::ptr::copy_nonoverlapping(ptr.add(index * size), buf.as_mut_ptr(), size) };
+        let u16_at = |off: usize| u16::from_le_bytes(buf[off..off + 2].try_into().unwrap());
+        let u32_at = |off: usize| u32::from_le_bytes(buf[off..off + 4].try_into().unwrap());
+        let u64_at = |off: usize| u64::from_le_bytes(buf[off..off + 8].try_into().unwrap());
+        match self {
+            Self::Kevent => KEvent {
+                ident: u32_at(0) as u64,
+                filter: u16_at(4) as i16,
+                flags: u16_at(6),
+                fflags: u32_at(8),
+                data: u32_at(12) as i32 as i64,
+                udata: u32_at(16) as u64,
+            },
+            Self::Kevent64 => KEvent {
+                ident: u64_at(0),
+                filter: u16_at(8) as i16,
+                flags: u16_at(10),
+                fflags: u32_at(12),
+                data: u64_at(16) as i64,
+                udata: u64_at(24),
+            },
+            Self::Qos => KEvent {
+                ident: u64_at(0),
+                filter: u16_at(8) as i16,
+                flags: u16_at(10),
+                udata: u64_at(16),
+                fflags: u32_at(24),
+                data: u64_at(32) as i64,
+            },
+        }
+    }
+
+    /// Store `event` as entry `index` of the array at `ptr`. Fields this
+    /// kernel doesn't use are zeroed.
+    pub fn write(self, ptr: *mut u8, index: usize, event: &KEvent) {
+        let mut buf = [0u8; 72];
+        let mut put = |off: usize, bytes: &[u8]| buf[off..off + bytes.len()].copy_from_slice(bytes);
+        match self {
+            Self::Kevent => {
+                put(0, &(event.ident as u32).to_le_bytes());
+                put(4, &event.filter.to_le_bytes());
+                put(6, &event.flags.to_le_bytes());
+                put(8, &event.fflags.to_le_bytes());
+                put(12, &(event.data as i32).to_le_bytes());
+                put(16, &(event.udata as u32).to_le_bytes());
+            }
+            Self::Kevent64 => {
+                put(0, &event.ident.to_le_bytes());
+                put(8, &event.filter.to_le_bytes());
+                put(10, &event.flags.to_le_bytes());
+                put(12, &event.fflags.to_le_bytes());
+                put(16, &event.data.to_le_bytes());
+                put(24, &event.udata.to_le_bytes());
+            }
+            Self::Qos => {
+                put(0, &event.ident.to_le_bytes());
+                put(8, &event.filter.to_le_bytes());
+                put(10, &event.flags.to_le_bytes());
+                put(16, &event.udata.to_le_bytes());
+                put(24, &event.fflags.to_le_bytes());
+                put(32, &event.data.to_le_bytes());
+            }
+        }
+        let size = self.size();
+        unsafe { core::ptr::copy_nonoverlapping(buf.as_ptr(), ptr.add(index * size), size) };
+    }
+}
+
+/// What a knote watches
+enum Source {
+    /// A descriptor, for EVFILT_READ and EVFILT_WRITE
+    File(Weak<FileHandle>),
+    /// The canonical path of a file
+    Vnode(String),
+    Proc(u64),
+    Signal(u32),
+    /// Counter ticks between expirations, and counter value of the next
+    Timer {
+        period: u64,
+        next: u64,
+    },
+    User,
+    MachPort(Arc<Mutex<Port>>),
+}
+
+struct Knote {
+    source: Source,
+    /// EV_ADD and the kept flags of the change that added it
+    flags: u16,
+    enabled: bool,
+    udata: u64,
+    /// fflags the knote was added with, for EVFILT_PROC and EVFILT_VNODE
+    sfflags: u32,
+    /// Undelivered fflags of pushed events, or an EVFILT_USER event's
+    fflags: u32,
+    /// Pushed events since the last delivery
+    count: i64,
+    /// An EVFILT_USER event has been triggered
+    triggered: bool,
+    /// Data when last delivered, so that with EV_CLEAR the level-triggered
+    /// filters only fire again on a change
+    last: Option<i64>,
+}
+
+/// Number of EVFILT_VNODE knotes, so that file writes only look for them
+/// when there are some
+static VNODE_KNOTES: AtomicUsize = AtomicUsize::new(0);
+
+impl Knote {
+    fn new(source: Source) -> Self {
+        if matches!(source, Source::Vnode(_)) {
+            VNODE_KNOTES.fetch_add(1, Ordering::Relaxed);
+        }
+        Self {
+            source,
+            flags: EV_ADD,
+            enabled: true,
+            udata: 0,
+            sfflags: 0,
+            fflags: 0,
+            count: 0,
+            triggered: false,
+            last: None,
+        }
+    }
+
+    /// The event the knote has for `key` now, if any. `file` is what a
+    /// descriptor knote watches.
+    fn poll(&self, key: (u64, i16), file: Option<&FileHandle>, now: u64) -> Option<KEvent> {
+        let (ident, filter) = key;
+        let mut event = KEvent {
+            ident,
+            filter,
+            flags: self.flags,
+            udata: self.udata,
+            ..KEvent::default()
+        };
+        match &self.source {
+            Source::File(_) => {
+                let file = file?;
+                let Readiness { read, write, eof } = file.readiness();
+                let data = if filter == EVFILT_READ {
+                    read.filter(|&n| n > 0 || eof || file.file.is_stream())?
+                } else {
+                    write?
+                };
+                event.data = data as i64;
+                if eof {
+                    event.flags |= EV_EOF;
+                }
+            }
+            Source::MachPort(port) => {
+                event.data = port.lock().messages.last()?.len() as i64;
+            }
+            Source::Timer { period, next } => {
+                if now < *next {
+                    return None;
+                }
+                event.data = 1 + ((now - next) / period) as i64;
+            }
+            Source::User => {
+                if !self.triggered {
+                    return None;
+                }
+                event.fflags = self.fflags;
+            }
+            Source::Proc(_) | Source::Signal(_) | Source::Vnode(_) => {
+                if self.count == 0 {
+                    return None;
+                }
+                event.fflags = self.fflags;
+                event.data = if filter == EVFILT_SIGNAL {
+                    self.count
+                } else {
+                    0
+                };
+                if filter == EVFILT_PROC {
+                    event.flags |= EV_EOF;
+                }
+            }
+        }
+        let level = matches!(self.source, Source::File(_) | Source::MachPort(_));
+        if level && self.flags & EV_CLEAR != 0 && self.last == Some(event.data) {
+            return None;
+        }
+        Some(event)
+    }
+
+    /// Reset the knote after delivering `event`
+    fn delivered(&mut self, event: &KEvent) {
+        match &mut self.source {
+            Source::Timer { period, next } => *next += *period * event.data as u64,
+            Source::User if self.flags & EV_CLEAR != 0 => {
+                self.triggered = false;
+                self.fflags = 0;
+            }
+            Source::User => {}
+            Source::Proc(_) | Source::Signal(_) | Source::Vnode(_) => {
+                self.count = 0;
+                self.fflags = 0;
+            }
+            Source::File(_) | Source::MachPort(_) => {
+                if self.flags & EV_CLEAR != 0 {
+                    self.last = Some(event.data);
+                }
+            }
+        }
+        if self.flags & EV_DISPATCH != 0 {
+            self.enabled = false;
+        }
+    }
+}
+
+impl Drop for Knote {
+    fn drop(&mut self) {
+        if matches!(self.source, Source::Vnode(_)) {
+            VNODE_KNOTES.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Counter ticks in an EVFILT_TIMER period of `data` in the unit `fflags`
+/// selects
+fn timer_period(fflags: u32, data: i64) -> Result<u64, u64> {
+    let data = u64::try_from(data).map_err(|_| EINVAL)?;
+    let nanos = match fflags & (NOTE_SECONDS | NOTE_USECONDS | NOTE_NSECONDS) {
+        NOTE_SECONDS => data.saturating_mul(1_000_000_000),
+        NOTE_USECONDS => data.saturating_mul(1_000),
+        NOTE_NSECONDS => data,
+        0 => data.saturating_mul(1_000_000),
+        _ => return Err(EINVAL),
+    };
+    // A zero period fires as often as kevent looks
+    Ok(timer::ticks_from_nanos(nanos).max(1))
+}
+
+/// Looks up a descriptor of the process calling kevent
+pub type Files<'a> = &'a dyn Fn(usize) -> Option<Arc<FileHandle>>;
+
+pub struct KQueue {
+    /// Process that created it, whose signals its EVFILT_SIGNAL knotes see
+    owner: u64,
+    /// Knotes by ident and filter
+    knotes: SleepLock<BTreeMap<(u64, i16), Knote>>,
+}
+
+/// Every kqueue, for pushing events to their knotes
+static KQUEUES: Mutex<Vec<Weak<KQueue>>> = Mutex::new(Vec::new());
+
+impl KQueue {
+    /// The source a new knote for `change` watches
+    fn attach(&self, change: &KEvent, files: Files) -> Result<Source, u64> {
+        let file = || files(change.ident as usize).ok_or(EBADF);
+        match change.filter {
+            EVFILT_READ | EVFILT_WRITE => {
+                let file = file()?;
+                if file.file.kqueue().is_some_and(|kq| core::ptr::eq(kq, self)) {
+                    return Err(EINVAL);
+                }
+                Ok(Source::File(Arc::downgrade(&file)))
+            }
+            EVFILT_VNODE => Ok(Source::Vnode(String::from(file()?.path().ok_or(EINVAL)?))),
+            // An exit after this check finds the knote, since it is added
+            // with the knotes still locked
+            EVFILT_PROC if scheduler::exists(change.ident) => Ok(Source::Proc(change.ident)),
+            EVFILT_PROC => Err(ESRCH),
+            EVFILT_SIGNAL if (1..32).contains(&change.ident) => {
+                Ok(Source::Signal(change.ident as u32))
+            }
+            EVFILT_TIMER => {
+                let period = timer_period(change.fflags, change.data)?;
+                Ok(Source::Timer {
+                    period,
+                    next: timer::counter() + period,
+                })
+            }
+            EVFILT_USER => Ok(Source::User),
+            EVFILT_MACHPORT => scheduler::this_cpu()
+                .lock()
+                .current_ipc_space()
+                .and_then(|space| space.get_port(change.ident as u32))
+                .map(Source::MachPort)
+                .ok_or(ENOENT),
+            _ => Err(EINVAL),
+        }
+    }
+
+    /// Apply one entry of a changelist
+    fn apply(&self, change: &KEvent, files: Files) -> Result<(), u64> {
+        let key = (change.ident, change.filter);
+        let mut knotes = self.knotes.lock();
+        if change.flags & EV_DELETE != 0 {
+            return knotes.remove(&key).map(|_| ()).ok_or(ENOENT);
+        }
+        if change.flags & EV_ADD != 0 && !knotes.contains_key(&key) {
+            let source = self.attach(change, files)?;
+            knotes.insert(key, Knote::new(source));
+        }
+        let knote = knotes.get_mut(&key).ok_or(ENOENT)?;
+        if change.flags & EV_ADD != 0 {
+            knote.flags = EV_ADD | (change.flags & EV_KEPT);
+            knote.udata = change.udata;
+            knote.sfflags = change.fflags;
+            knote.enabled = true;
+            if let Source::Timer { period, next } = &mut knote.source {
+                *period = timer_period(change.fflags, change.data)?;
+                *next = timer::counter() + *period;
+            }
+        }
+        if change.flags & EV_ENABLE != 0 {
+            knote.enabled = true;
+            knote.last = None;
+        }
+        if change.flags & EV_DISABLE != 0 {
+            knote.enabled = false;
+        }
+        if matches!(knote.source, Source::User) {
+            let fflags = change.fflags & NOTE_FFLAGSMASK;
+            match change.fflags & NOTE_FFCTRLMASK {
+                NOTE_FFAND => knote.fflags &= fflags,
+                NOTE_FFOR => knote.fflags |= fflags,
+                NOTE_FFCOPY => knote.fflags = fflags,
+                _ => {}
+            }
+            if change.fflags & NOTE_TRIGGER != 0 {
+                knote.triggered = true;
+                drop(knotes);
+                // Another process may be waiting on this kqueue
+                waitqueue::wake_any();
+            }
+        }
+        Ok(())
+    }
+
+    /// Take up to `max` events. Returns them and when the next timer is due.
+    fn scan(&self, max: usize, files: Files) -> (Vec<KEvent>, Option<u64>) {
+        let now = timer::counter();
+        let mut events = Vec::new();
+        let mut next_timer: Option<u64> = None;
+        let mut gone = Vec::new();
+        let mut knotes = self.knotes.lock();
+        for (&key, knote) in knotes.iter_mut() {
+            if events.len() == max {
+                break;
+            }
+            if !knote.enabled {
+                continue;
+            }
+            let file = match &knote.source {
+                Source::File(weak) => {
+                    match files(key.0 as usize)
+                        .filter(|file| Weak::as_ptr(weak) == Arc::as_ptr(file))
+                    {
+                        Some(file) => Some(file),
+                        None => {
+                            gone.push(key);
+                            continue;
+                        }
+                    }
+                }
+                _ => None,
+            };
+            let Some(event) = knote.poll(key, file.as_deref(), now) else {
+                if let Source::Timer { next, .. } = knote.source {
+                    next_timer = Some(next_timer.map_or(next, |t| t.min(next)));
+                }
+                continue;
+            };
+            knote.delivered(&event);
+            if knote.flags & EV_ONESHOT != 0 {
+                gone.push(key);
+            }
+            events.push(event);
+        }
+        for key in gone {
+            knotes.remove(&key);
+        }
+        (events, next_timer)
+    }
+
+    /// kevent: apply `changes`, then wait until there are events or the
+    /// counter reaches `deadline`, and return up to `max` of them. Changes
+    /// that fail, or have EV_RECEIPT, are reported as EV_ERROR events while
+    /// there is room; then no events are waited for. Without room a failed
+    /// change fails the call. With `errors_only`, only such events are
+    /// returned.
+    pub fn kevent(
+        &self,
+        changes: &[KEvent],
+        max: usize,
+        deadline: Option<u64>,
+        errors_only: bool,
+        files: Files,
+    ) -> Result<Vec<KEvent>, u64> {
+        let mut events = Vec::new();
+        for change in changes {
+            let result = self.apply(change, files);
+            if result.is_err() || change.flags & EV_RECEIPT != 0 {
+                if events.len() == max {
+                    result?;
+                    continue;
+                }
+                events.push(KEvent {
+                    flags: EV_ERROR,
+                    data: result.err().unwrap_or(0) as i64,
+                    ..*change
+                });
+            }
+        }
+        if !events.is_empty() || errors_only || max == 0 {
+            return Ok(events);
+        }
+        loop {
+            let since = waitqueue::wakeups();
+            let (events, next_timer) = self.scan(max, files);
+            if !events.is_empty() || deadline.is_some_and(|d| timer::counter() >= d) {
+                return Ok(events);
+            }
+            let wake_at = match (deadline, next_timer) {
+                (Some(d), Some(t)) => Some(d.min(t)),
+                (d, t) => d.or(t),
+            };
+            waitqueue::wait_any(since, wake_at);
+        }
+    }
+}
+
+/// The file a kqueue descriptor refers to
+struct KQueueFile {
+    queue: Arc<KQueue>,
+}
+
+impl File for KQueueFile {
+    fn read_at(&self, _offset: u64, _buf: &mut [u8]) -> usize {
+        0
+    }
+
+    fn size(&self) -> u64 {
+        0
+    }
+
+    fn is_stream(&self) -> bool {
+        true
+    }
+
+    fn mode(&self) -> u16 {
+        S_IFIFO
+    }
+
+    /// Readable while it has events. A kqueue busy in kevent, including one
+    /// asked about itself, counts as having none.
+    fn readiness(&self) -> Readiness {
+        let Some(knotes) = self.queue.knotes.try_lock() else {
+            return Readiness::default();
+        };
+        let now = timer::counter();
+        let pending = knotes
+            .iter()
+            .filter(|(_, knote)| knote.enabled)
+            .filter(|&(&key, knote)| {
+                let file = match &knote.source {
+                    Source::File(weak) => match weak.upgrade() {
+                        Some(file) => Some(file),
+                        None => return false,
+                    },
+                    _ => None,
+                };
+                knote.poll(key, file.as_deref(), now).is_some()
+            })
+            .count();
+        Readiness {
+            read: (pending > 0).then_some(pending),
+            ..Readiness::default()
+        }
+    }
+
+    fn kqueue(&self) -> Option<&KQueue> {
+        Some(&self.queue)
+    }
+}
+
+/// kqueue(2), for the process `owner`
+pub fn create(owner: u64) -> FileHandle {
+    let queue = Arc::new(KQueue {
+        owner,
+        knotes: SleepLock::new(BTreeMap::new()),
+    });
+    {
+        let mut kqueues = KQUEUES.lock();
+        kqueues.retain(|kq| kq.strong_count() > 0);
+        kqueues.push(Arc::downgrade(&queue));
+    }
+    FileHandle::new(Box::new(KQueueFile { queue }), O_RDWR)
+}
+
+/// Record an event with `fflags` in the knotes of `filter` that `matches`
+/// picks, in the kqueues of `owner` or in every kqueue
+fn push(owner: Option<u64>, filter: i16, fflags: u32, matches: impl Fn(&Source) -> bool) {
+    let kqueues: Vec<Arc<KQueue>> = KQUEUES
+        .lock()
+        .iter()
+        .filter_map(Weak::upgrade)
+        .filter(|kq| owner.is_none_or(|owner| kq.owner == owner))
+        .collect();
+    let mut pushed = false;
+    for kq in kqueues {
+        for ((_, knote_filter), knote) in kq.knotes.lock().iter_mut() {
+            if *knote_filter == filter && matches(&knote.source) {
+                let fflags = fflags & knote.sfflags;
+                if filter == EVFILT_SIGNAL || fflags != 0 {
+                    knote.fflags |= fflags;
+                    knote.count += 1;
+                    pushed = true;
+                }
+            }
+        }
+    }
+    if pushed {
+        waitqueue::wake_any();
+    }
+}
+
+/// Process `pid` has exited
+pub fn process_exited(pid: u64) {
+    push(
+        None,
+        EVFILT_PROC,
+        NOTE_EXIT,
+        |source| matches!(source, Source::Proc(p) if *p == pid),
+    );
+}
+
+/// Signal `sig` was sent to process `pid`, whether or not it is delivered
+pub fn signal_sent(pid: u64, sig: u32) {
+    push(
+        Some(pid),
+        EVFILT_SIGNAL,
+        0,
+        |source| matches!(source, Source::Signal(s) if *s == sig),
+    );
+}
+
+/// The file at canonical path `path` changed as NOTE_* `fflags` describe
+pub fn vnode_changed(path: &str, fflags: u32) {
+    if VNODE_KNOTES.load(Ordering::Relaxed) == 0 {
+        return;
+    }
+    push(
+        None,
+        EVFILT_VNODE,
+        fflags,
+        |source| matches!(source, Source::Vnode(p) if p == path),
+    );
+}
@@ -1,6 +1,8 @@
 use crate::kprintln;
+use alloc::format;
 use alloc::string::String;
 use alloc::vec;
+use alloc::vec::Vec;
 use goblin::mach::{Mach, MachO};
 
 pub struct MachOLoader {
@@ -10,83 +12,91 @@ pub struct MachOLoader {
     pub is_64bit: bool,
 }
 
-pub fn setup_stack(sp: u64, exec_path: &str, mh_addr: u64, is_64bit: bool) -> u64 {
+/// Where `setup_stack` put the vectors the program starts with
+pub struct InitialStack {
+    pub sp: u64,
+    pub argc: u64,
+    pub argv: u64,
+    pub envp: u64,
+    pub apple: u64,
+}
+
+pub fn setup_stack(
+    sp: u64,
+    exec_path: &str,
+    argv: &[&str],
+    envp: &[&str],
+    mh_addr: u64,
+    is_64bit: bool,
+) -> InitialStack {
     let mut current_sp = sp;
 
+    // Seeds for libc's stack protector and malloc randomization
+    let mut seeds = [0u8; 24];
+    crate::entropy::fill(&mut seeds);
+    let seed = |i: usize| u64::from_le_bytes(seeds[i * 8..i * 8 + 8].try_into().unwrap());
+    let stack_guard = format!("stack_guard=0x{:x}", seed(0));
+    let malloc_entropy = format!("malloc_entropy=0x{:x},0x{:x}", seed(1), seed(2));
+
     // Copy strings to stack
-    let strings = vec![
+    let apple = [
         exec_path,
         "dyld_shared_cache_base_address=0x30000000",
         "executable_path=/bin/initial",
+        &stack_guard,
+        &malloc_entropy,
     ];
-    let mut string_ptrs = vec![];
-
-    for s in strings {
+    let mut push_str = |s: &str| {
         let bytes = s.as_bytes();
         current_sp -= (bytes.len() + 1) as u64;
         unsafe {
             core::ptr::copy_nonoverlapping(bytes.as_ptr(), current_sp as *mut u8, bytes.len());
             core::ptr::write((current_sp + bytes.len() as u64) as *mut u8, 0);
         }
-        string_ptrs.push(current_sp);
-    }
+        current_sp
+    };
+    let argv_ptrs: Vec<u64> = argv.iter().map(|s| push_str(s)).collect();
+    let envp_ptrs: Vec<u64> = envp.iter().map(|s| push_str(s)).collect();
+    let apple_ptrs: Vec<u64> = apple.iter().map(|s| push_str(s)).collect();
 
-    // Align SP
-    if is_64bit {
-        current_sp &= !15;
-    } else {
-        current_sp &= !3;
+    // mach_header, argc, argv[0...n], NULL, envp[0...m], NULL,
+    // apple[0...k], NULL
+    let mut values = vec![mh_addr, argv.len() as u64];
+    values.extend(&argv_ptrs);
+    values.push(0);
+    let envp_index = values.len();
+    values.extend(&envp_ptrs);
+    values.push(0);
+    let apple_index = values.len();
+    values.extend(&apple_ptrs[..3]);
+    if !is_64bit {
+        // apple[3] (mach_header) on ARMv7
+        values.push(mh_addr);
     }
+    values.extend(&apple_ptrs[3..]);
+    values.push(0);
 
-    if is_64bit {
-        // 64-bit values
-        let values = [
-            mh_addr,        // mach_header (at sp)
-            1u64,           // argc (at sp+8)
-            string_ptrs[0], // argv[0]
-            0u64,           // argv[1] (NULL)
-            0u64,           // envp[0] (NULL)
-            string_ptrs[0], // apple[0] (exec path)
-            string_ptrs[1], // apple[1] (cache base)
-            string_ptrs[2], // apple[2] (exec path again)
-            0u64,           // apple[3] (NULL)
-        ];
+    let word = if is_64bit { 8 } else { 4 };
+    current_sp -= (values.len() * word) as u64;
+    // Ensure 16-byte alignment for the whole stack frame
+    current_sp &= !15;
 
-        current_sp -= (values.len() * 8) as u64;
-        let stack_top = current_sp;
-        unsafe {
-            core::ptr::copy_nonoverlapping(values.as_ptr(), current_sp as *mut u64, values.len());
-        }
-        stack_top
-    } else {
-        // 32-bit values for ARMv7 Darwin
-        // Order: mach_header, argc, argv[0...n], NULL, envp[0...m], NULL, apple[0...k], NULL
-        let values = [
-            mh_addr as u32,        // mach_header (at sp)
-            1u32,                  // argc (at sp+4)
-            string_ptrs[0] as u32, // argv[0]
-            0u32,                  // argv[1] (NULL)
-            0u32,                  // envp[0] (NULL)
-            string_ptrs[0] as u32, // apple[0] (exec path)
-            string_ptrs[1] as u32, // apple[1] (dyld_shared_cache_base_address)
-            string_ptrs[2] as u32, // apple[2] (executable_path)
-            mh_addr as u32,        // apple[3] (mach_header)
-            0u32,                  // apple[4] (NULL)
-        ];
-
-        // Ensure 16-byte alignment for the whole stack frame
-        let bytes_needed = values.len() * 4;
-        current_sp -= bytes_needed as u64;
-        current_sp &= !15;
-
-        let stack_top = current_sp;
-        unsafe {
-            let p = stack_top as *mut u32;
-            for (i, &v) in values.iter().enumerate() {
-                core::ptr::write(p.add(i), v);
+    let stack_top = current_sp;
+    unsafe {
+        for (i, &v) in values.iter().enumerate() {
+            if is_64bit {
+                core::ptr::write((stack_top as *mut u64).add(i), v);
+            } else {
+                core::ptr::write((stack_top as *mut u32).add(i), v as u32);
             }
         }
-        stack_top
+    }
+    InitialStack {
+        sp: stack_top,
+        argc: stack_top + word as u64,
+        argv: stack_top + 2 * word as u64,
+        envp: stack_top + (envp_index * word) as u64,
+        apple: stack_top + (apple_index * word) as u64,
     }
 }
 
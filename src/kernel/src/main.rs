@@ -5,54 +5,112 @@
 extern crate alloc;
 
 mod block;
+mod bootargs;
+mod devfs;
+mod entropy;
+mod errno;
+mod fdt;
+mod fdtable;
+mod gic;
 mod heap;
 mod hfsfs;
+mod inet;
 mod ipc;
+mod irq;
+mod klog;
+mod kqueue;
 mod macho;
 mod mem;
 mod mmu;
+mod net;
+mod pagecache;
+mod partition;
+mod percpu;
+mod pipe;
+mod platform;
 mod process;
+mod psci;
+mod ramdisk;
 mod scheduler;
+mod smp;
+mod socket;
+mod timer;
+mod tmpfs;
+mod tty;
 mod uart;
+mod unix;
 mod vfs;
 mod virtio;
+mod virtqueue;
+mod waitqueue;
 
-use crate::scheduler::{Process, SCHEDULER};
+use crate::scheduler::Process;
+use alloc::format;
 use alloc::string::String;
+use alloc::sync::Arc;
 use alloc::vec;
+use alloc::vec::Vec;
 use core::arch::asm;
 use core::arch::global_asm;
 use core::panic::PanicInfo;
 
-global_asm!(include_str!("boot.s"));
+/// Range of the random slide applied to dyld, in pages
+const DYLD_SLIDE_PAGES: u64 = 256;
+/// Top of the boot CPU's stack, which boot.s sets up below the heap
+const BOOT_STACK_TOP: u64 = 0x4080_0000;
+
+global_asm!(include_str!("boot.s"), BOOT_STACK_TOP = const BOOT_STACK_TOP);
 global_asm!(include_str!("vectors.s"));
 global_asm!(include_str!("switch.s"));
 
 #[unsafe(no_mangle)]
-pub extern "C" fn kmain() {
+pub extern "C" fn kmain(fdt_addr: usize) -> ! {
     kprintln!("Hello from GravityOS. Spawning AArch64 processes...");
 
+    platform::init(fdt_addr);
+
     process::init_vectors();
 
     mmu::init();
 
     heap::init_heap();
+    bootargs::init();
 
     kprintln!("Heap initialized");
 
     kprintln!("Vectors initialized");
 
+    gic::init_distributor();
+    gic::init_cpu_interface();
+    uart::init();
+    devfs::init();
+    vfs::mount("/dev", Arc::new(devfs::Devfs), "devfs", 0).expect("Failed to mount /dev");
+    tty::init();
+    klog::init();
+    percpu::init_cpu(BOOT_STACK_TOP);
+    timer::init_cpu();
+    smp::init();
+    entropy::init();
+    net::init();
+
     // Initialize virtio block device and load shared cache
     // let mut shared_cache_data: &[u8] = &[];
 
-    if let Some(blk) = virtio::init() {
+    // The initrd comes first, so that it is the root unless root= says
+    // otherwise
+    let mut disks: Vec<(&str, Arc<dyn block::BlockDevice>)> = Vec::new();
+    if let Some(ramdisk) = ramdisk::init() {
+        disks.push(("md0", Arc::new(ramdisk)));
+    }
+    match virtio::init() {
+        Some(blk) => disks.push(("disk0", Arc::new(blk))),
+        None => kprintln!("No virtio disk found"),
+    }
+    if !disks.is_empty() {
         kprintln!("Initializing VFS from disk...");
-        let blk_shared = alloc::sync::Arc::new(spin::Mutex::new(blk));
-        let hfsfs = hfsfs::HfsFs::new(blk_shared, 400 * 1024 * 1024);
-        vfs::init(hfsfs);
+        mount_root(&disks, bootargs::root());
+        mount_tmpfs();
         kprintln!("VFS initialized");
-    } else {
-        kprintln!("No virtio disk found");
     }
 
     // Note: 0x40000000 seems to be used by dyld for CommPage or similar absolute reference.
@@ -74,7 +132,7 @@ pub extern "C" fn kmain() {
         *version_ptr = 13;
 
         // Set ncpus at 0x22 (u8)
-        crate::mmu::COMMPAGE_STORAGE[0x22] = 1;
+        crate::mmu::COMMPAGE_STORAGE[0x22] = smp::ncpus() as u8;
 
         // Set pagesize at 0x24 (u32)
         let pgsize_ptr = &mut crate::mmu::COMMPAGE_STORAGE[0x24] as *mut u8 as *mut u32;
@@ -93,18 +151,13 @@ pub extern "C" fn kmain() {
         );
     }
 
-    kprintln!("Opening /sbin/launchd...");
-    let main_bin = {
-        let mut file = vfs::open("/sbin/launchd").expect("Failed to open launchd");
-        kprintln!("Reading /sbin/launchd ({} bytes)...", file.size());
-        file.read_to_end()
-    };
-    kprintln!("Opening /usr/lib/dyld...");
-    let dyld_bin = {
-        let mut file = vfs::open("/usr/lib/dyld").expect("Failed to open dyld");
-        kprintln!("Reading /usr/lib/dyld ({} bytes)...", file.size());
-        file.read_to_end()
-    };
+    let init_path = bootargs::init_program();
+    let main_bin = read_program(init_path).unwrap_or_else(|errno| {
+        panic!(
+            "Cannot read init program {}: errno {} (set init=)",
+            init_path, errno
+        )
+    });
     kprintln!("Parsing Mach-O binaries...");
     let main_load_offset = 0; // Use linked address for launchd if possible
     let main_loader = macho::MachOLoader::load(&main_bin, main_load_offset);
@@ -112,7 +165,11 @@ pub extern "C" fn kmain() {
         let mut loader_is_64bit = loader.is_64bit;
         let (entry, path, dyld_mh, _dyld_slide) = if let Some(dyld_path) = loader.dylinker {
             kprintln!("Binary requests dylinker: {}", dyld_path);
-            let dyld_load_offset = 0x2fe00000;
+            let dyld_bin = read_program(&dyld_path)
+                .unwrap_or_else(|errno| panic!("Cannot read {}: errno {}", dyld_path, errno));
+            // Load dyld a random number of pages below its usual base,
+            // staying clear of the shared cache at 0x30000000
+            let dyld_load_offset = 0x2fe00000 - entropy::below(DYLD_SLIDE_PAGES) * 0x1000;
             let dyld_loader =
                 macho::MachOLoader::load(&dyld_bin, dyld_load_offset).expect("Failed to load dyld");
             loader_is_64bit = dyld_loader.is_64bit;
@@ -144,8 +201,18 @@ pub extern "C" fn kmain() {
         // Setup BSD/Mach stack layout
         kprintln!("Initial User SP: {:x}", user_sp_initial);
         // Pass the actual address where the Mach-O header was loaded (mapped)
-        let new_sp =
-            macho::setup_stack(user_sp_initial, &path, loader.header_addr, loader_is_64bit);
+        let (args, env) = bootargs::init_args();
+        let mut argv = vec![init_path];
+        argv.extend(args);
+        let stack = macho::setup_stack(
+            user_sp_initial,
+            &path,
+            &argv,
+            &env,
+            loader.header_addr,
+            loader_is_64bit,
+        );
+        let new_sp = stack.sp;
         kprintln!("Stack setup complete. New User SP: {:x}", new_sp);
 
         // Prepare args for dyld bootstrap:
@@ -154,19 +221,19 @@ pub extern "C" fn kmain() {
             [
                 loader.header_addr,
                 0, // slide
-                new_sp + 8,
-                new_sp + 16,
-                new_sp + 32,
-                new_sp + 40,
+                stack.argc,
+                stack.argv,
+                stack.envp,
+                stack.apple,
             ]
         } else {
             [
                 dyld_mh,     // r0: dyld's own mach_header
                 0,           // r1: dyld's own slide
-                new_sp + 4,  // r2: &argc (sp+4 points to argc=1)
-                new_sp + 8,  // r3: argv
-                new_sp + 16, // r4: envp
-                new_sp + 20, // r5: apple
+                stack.argc,  // r2: &argc
+                stack.argv,  // r3: argv
+                stack.envp,  // r4: envp
+                stack.apple, // r5: apple
             ]
         };
 
@@ -195,58 +262,143 @@ pub extern "C" fn kmain() {
 
         let process = Process::new(entry, new_sp, &args, tls_base, loader_is_64bit);
         let pid = process.pid;
+        let spsr = process.context.regs[8];
 
-        let mut sched = SCHEDULER.lock();
-        sched.add_process(process);
-        sched.schedule_next();
+        scheduler::spawn(process);
 
         kprintln!(
             "Ready to switch to PID {} at {:x} (SP: {:x}, SPSR: {:x})",
             pid,
             entry,
             new_sp,
-            sched.current_process.as_ref().unwrap().context.regs[8]
+            spsr
         );
     } else {
         panic!("Failed to parse MAIN_BIN");
     }
 
-    kprintln!("Ready to switch context...");
+    kprintln!("Switching to first process...");
 
-    let mut boot_ctx = process::CpuContext::default();
-    unsafe {
-        let next_ctx_ptr = {
-            let sched = SCHEDULER.lock();
-            if let Some(curr) = &sched.current_process {
-                &curr.context as *const _
-            } else {
-                kprintln!("No process to run! Hanging...");
-                loop {
-                    asm!("wfe");
-                }
-            }
-        };
+    // The boot CPU becomes an idle thread like the secondaries
+    scheduler::idle_loop()
+}
 
-        kprintln!("Switching to first process...");
-        unsafe extern "C" {
-            fn __switch_to(prev: *mut process::CpuContext, next: *const process::CpuContext);
+/// Read the whole of a program file
+fn read_program(path: &str) -> Result<Vec<u8>, u64> {
+    kprintln!("Opening {}...", path);
+    let file = vfs::open(path, vfs::O_RDONLY, 0)?;
+    kprintln!("Reading {} ({} bytes)...", path, file.size());
+    Ok(file.read_to_end())
+}
+
+/// Images made before disks were partitioned keep the volume here
+const LEGACY_ROOT_OFFSET: u64 = 400 * 1024 * 1024;
+
+/// Whether `root` is `name` or `/dev/name`
+fn names_device(root: &str, name: &str) -> bool {
+    root.strip_prefix("/dev/").unwrap_or(root) == name
+}
+
+/// Register the boot disks and their partitions, and mount one volume as the
+/// root. `root`, from the root= boot argument, names it as a disk such as
+/// `md0` or a partition such as `disk0s2`, by its name in the partition table
+/// or by its volume name. Without it the first HFS+ volume is used, trying
+/// disks in order.
+fn mount_root(disks: &[(&str, Arc<dyn block::BlockDevice>)], root: Option<&str>) {
+    let mut candidates: Vec<(String, Arc<dyn block::BlockDevice>, bool)> = Vec::new();
+    let mut unpartitioned = Vec::new();
+    for (disk_name, disk) in disks {
+        devfs::add_disk(disk_name, disk.clone());
+        let partitions = partition::scan(&**disk);
+        for info in &partitions {
+            let name = format!("{}s{}", disk_name, info.index);
+            let device: Arc<dyn block::BlockDevice> =
+                Arc::new(partition::Partition::new(disk.clone(), info));
+            devfs::add_disk(&name, device.clone());
+            kprintln!(
+                "{}: {} bytes at {:x} \"{}\"",
+                name,
+                info.size,
+                info.start,
+                info.name
+            );
+            let named = root.is_some_and(|root| names_device(root, &name) || root == info.name);
+            if named || info.is_hfs() {
+                candidates.push((name, device, named));
+            }
+        }
+        if partitions.is_empty() {
+            let named = root.is_some_and(|root| names_device(root, disk_name));
+            candidates.push((String::from(*disk_name), disk.clone(), named));
+            if root.is_none() || named {
+                unpartitioned.push((*disk_name, disk.clone()));
+            }
         }
-        __switch_to(&mut boot_ctx, next_ctx_ptr);
+    }
 
-        kprintln!("ERROR: __switch_to returned to kmain!");
+    // Volumes named outright come first
+    candidates.sort_by_key(|(_, _, named)| !named);
+    for (name, device, named) in candidates {
+        let Ok(fs) = hfsfs::HfsFs::new(device, 0) else {
+            continue;
+        };
+        if let Some(root) = root
+            && !named
+            && fs.volume_name().as_deref() != Some(root)
+        {
+            continue;
+        }
+        let source = format!("/dev/{}", name);
+        vfs::mount("/", Arc::new(fs), &source, vfs::MNT_RDONLY).expect("Failed to mount root");
+        kprintln!("Root is {}", source);
+        return;
     }
 
-    kprintln!(
-        "Returned to kmain loop? This shouldn't happen for the first switch unless it returns."
-    );
+    for (name, disk) in unpartitioned {
+        if let Ok(fs) = hfsfs::HfsFs::new(disk, LEGACY_ROOT_OFFSET) {
+            let source = format!("/dev/{}", name);
+            vfs::mount("/", Arc::new(fs), &source, vfs::MNT_RDONLY).expect("Failed to mount root");
+            kprintln!(
+                "Root is {} at legacy offset {:x}",
+                source,
+                LEGACY_ROOT_OFFSET
+            );
+            return;
+        }
+    }
+    panic!("No root volume found");
+}
 
-    loop {
-        unsafe { asm!("wfe") };
+/// Directories that get a tmpfs at boot, since the root is read-only
+const TMPFS_MOUNTS: [&str; 3] = ["/tmp", "/var/tmp", "/var/run"];
+
+/// Mount a tmpfs on each of TMPFS_MOUNTS that is a directory on the root,
+/// with the options from tmpfs=. Without a size the mounts share one
+/// default budget, so together they can't promise more than it.
+fn mount_tmpfs() {
+    let default = tmpfs::default_size() / TMPFS_MOUNTS.len() as u64;
+    let size = match tmpfs::size_option(bootargs::tmpfs_options(), default) {
+        Ok(size) => size,
+        Err(_) => {
+            kprintln!("Boot args: Bad tmpfs={}", bootargs::tmpfs_options());
+            default
+        }
+    };
+    for path in TMPFS_MOUNTS {
+        if !vfs::stat(path).is_ok_and(|attr| attr.mode & vfs::S_IFMT == vfs::S_IFDIR) {
+            kprintln!("No {} to mount tmpfs on", path);
+            continue;
+        }
+        match vfs::mount(path, Arc::new(tmpfs::Tmpfs::new(size)), "tmpfs", 0) {
+            Ok(()) => kprintln!("Mounted tmpfs on {}", path),
+            Err(errno) => kprintln!("Cannot mount tmpfs on {}: errno {}", path, errno),
+        }
     }
 }
 
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
+    uart::set_kernel_output(true);
     kprintln!("Kernel Panic: {:?}", info);
     loop {
         unsafe { asm!("wfe") };
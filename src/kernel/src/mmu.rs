@@ -1,4 +1,4 @@
-use crate::kprintln;
+use crate::{kprintln, platform};
 use core::arch::asm;
 
 // Basic AArch64 paging (4KB pages, 32-bit VA space)
@@ -28,6 +28,10 @@ const MAIR_MEM: u64 = 1 << 2;
 const UXN: u64 = 1 << 54;
 const PXN: u64 = 1 << 53;
 
+/// Bytes of the PCI memory window mapped, as each 2 MiB takes a table from
+/// L3_POOL. BARs are assigned within it.
+pub const PCI_MMIO_MAPPED: u64 = 0x1000_0000;
+
 #[repr(C, align(4096))]
 #[derive(Copy, Clone)]
 struct PageTable([u64; 512]);
@@ -45,11 +49,7 @@ pub fn init() {
     kprintln!("MMU: Initializing 3-level hierarchy (T0SZ=32)...");
 
     unsafe {
-        // 1. MAIR_EL1: Index 0=Device, Index 1=Normal
-        let mair: u64 = 0x0000_0000_0000_FF04; // Attr0=Device, Attr1=Normal
-        asm!("msr mair_el1, {}", in(reg) mair);
-
-        // 2. Map 4GB using L1 -> L2 (2MB blocks)
+        // Map 4GB using L1 -> L2 (2MB blocks)
         for i in 0..512 {
             L1_TABLE.0[i] = 0;
         }
@@ -60,9 +60,11 @@ pub fn init() {
             for j in 0..512 {
                 let paddr = (i as u64 * 1024 * 1024 * 1024) + (j as u64 * 0x200000);
 
-                // Skip mapping PCI space (0x10000000 - 0x40000000) as Normal memory
+                // Skip mapping PCI space as Normal memory
                 // We will map these specifically as Device memory later
-                if paddr >= 0x10000000 && paddr < 0x40000000 {
+                if let Some(pci) = &platform::get().pci
+                    && (pci.ecam.overlaps(paddr, 0x200000) || pci.mmio.overlaps(paddr, 0x200000))
+                {
                     continue;
                 }
 
@@ -74,19 +76,94 @@ pub fn init() {
         }
 
         asm!("dsb sy");
+    }
+
+    enable();
+    kprintln!("MMU: Enabled.");
+
+    // Map UART specifically as Device memory
+    let platform = platform::get();
+    map_device(
+        platform.uart.region.base,
+        platform.uart.region.size.max(4096),
+    );
+
+    // Map GIC distributor and CPU interface
+    map_device(platform.gic_distributor.base, platform.gic_distributor.size);
+    map_device(
+        platform.gic_cpu_interface.base,
+        platform.gic_cpu_interface.size,
+    );
+
+    // Map PCI ECAM and the start of the MMIO window BARs are assigned from
+    if let Some(pci) = &platform.pci {
+        map_device(pci.ecam.base, pci.ecam.size);
+        map_device(pci.mmio.base, pci.mmio.size.min(PCI_MMIO_MAPPED));
+    }
+
+    // Map the virtio-mmio slots, a run of neighbouring ones at a time
+    let mut run: Option<(u64, u64)> = None;
+    for slot in platform.virtio_mmio() {
+        let (base, end) = (slot.region.base, slot.region.end());
+        run = match run {
+            Some((start, stop)) if base <= stop + 0x1000 && start <= end + 0x1000 => {
+                Some((start.min(base), stop.max(end)))
+            }
+            _ => {
+                if let Some((start, stop)) = run {
+                    map_device(start, stop - start);
+                }
+                Some((base, end))
+            }
+        };
+    }
+    if let Some((start, stop)) = run {
+        map_device(start, stop - start);
+    }
+
+    // Map CommPage at 0xFFFF0000
+    // CommPage needs to be UserRO. Map 16KB (4 pages).
+    populate_commpage();
+    map_range(
+        0xFFFF0000,
+        core::ptr::addr_of!(COMMPAGE_STORAGE) as u64,
+        16384,
+        MapPermission::UserRO,
+    );
+}
 
-        // 3. TCR_EL1: T0SZ=32, TG0=4KB, EPD1=1, WBWA, Shareable, etc.
+/// Identity map a device's registers, if they are in the 32-bit address space
+fn map_device(base: u64, size: u64) {
+    if base + size > 1 << 32 {
+        kprintln!("MMU: Device at {:x} is out of reach", base);
+        return;
+    }
+    map_range(base, base, size, MapPermission::KernelRWDevice);
+}
+
+/// Enable the MMU on a secondary CPU using the tables built by `init`
+pub fn init_secondary() {
+    enable();
+}
+
+fn enable() {
+    unsafe {
+        // 1. MAIR_EL1: Index 0=Device, Index 1=Normal
+        let mair: u64 = 0x0000_0000_0000_FF04; // Attr0=Device, Attr1=Normal
+        asm!("msr mair_el1, {}", in(reg) mair);
+
+        // 2. TCR_EL1: T0SZ=32, TG0=4KB, EPD1=1, WBWA, Shareable, etc.
         let tcr: u64 = 0x5b5103520;
         asm!("msr tcr_el1, {}", in(reg) tcr);
 
-        // 4. TTBR0_EL1: Point to L1_TABLE
+        // 3. TTBR0_EL1: Point to L1_TABLE
         let ttbr0 = core::ptr::addr_of!(L1_TABLE) as u64;
         asm!("msr ttbr0_el1, {}", in(reg) ttbr0);
 
-        // 5. Invalidate TLB and I-cache
+        // 4. Invalidate TLB and I-cache
         asm!("tlbi vmalle1is", "ic ialluis", "dsb sy", "isb");
 
-        // 6. Enable MMU with RES1 bits
+        // 5. Enable MMU with RES1 bits
         let mut sctlr: u64;
         asm!("mrs {}, sctlr_el1", out(reg) sctlr);
 
@@ -107,41 +184,9 @@ pub fn init() {
         asm!("msr sctlr_el1, {}", in(reg) sctlr);
         asm!("isb");
 
-        kprintln!("MMU: Enabled.");
-
         // Finalize identity mapping for 0-4GB before adding specialized regions
         asm!("dsb sy", "isb");
     }
-
-    // Map UART specifically as Device memory
-    // UART is at 0x09000000
-    map_range(0x09000000, 0x09000000, 4096, MapPermission::KernelRWDevice);
-
-    // Map PCI ECAM (0x3f000000)
-    map_range(
-        0x3f000000,
-        0x3f000000,
-        0x1000000,
-        MapPermission::KernelRWDevice,
-    );
-
-    // Map PCI MMIO space (0x10000000+)
-    map_range(
-        0x10000000,
-        0x10000000,
-        0x10000000,
-        MapPermission::KernelRWDevice,
-    );
-
-    // Map CommPage at 0xFFFF0000
-    // CommPage needs to be UserRO. Map 16KB (4 pages).
-    populate_commpage();
-    map_range(
-        0xFFFF0000,
-        core::ptr::addr_of!(COMMPAGE_STORAGE) as u64,
-        16384,
-        MapPermission::UserRO,
-    );
 }
 
 fn populate_commpage() {
@@ -149,12 +194,20 @@ fn populate_commpage() {
         let cp = core::ptr::addr_of_mut!(COMMPAGE_STORAGE) as *mut u8;
         // iOS 5 commpage version 13
         core::ptr::write(cp.add(0x1E) as *mut u16, 13);
-        // Active CPUs
-        core::ptr::write(cp.add(0x22) as *mut u8, 1);
-        // Physical CPUs
-        core::ptr::write(cp.add(0x23) as *mut u8, 1);
-        // Logical CPUs
-        core::ptr::write(cp.add(0x24) as *mut u8, 1);
+    }
+    commpage_set_ncpus(1);
+}
+
+/// Publish the CPU count to user space
+pub fn commpage_set_ncpus(ncpus: u8) {
+    unsafe {
+        let cp = core::ptr::addr_of_mut!(COMMPAGE_STORAGE) as *mut u8;
+        // _COMM_PAGE_NCPUS
+        core::ptr::write_volatile(cp.add(0x22), ncpus);
+        // Active, physical and logical CPUs
+        core::ptr::write_volatile(cp.add(0x34), ncpus);
+        core::ptr::write_volatile(cp.add(0x35), ncpus);
+        core::ptr::write_volatile(cp.add(0x36), ncpus);
     }
 }
 
@@ -198,109 +251,144 @@ pub fn map_range(vaddr: u64, paddr: u64, size: u64, perm: MapPermission) {
     let mut curr_p = paddr & !0xFFF;
 
     for curr_v in (start_v..end_v).step_by(0x1000) {
-        let l1_idx = (curr_v >> 30) as usize;
-        let l2_idx = ((curr_v >> 21) & 0x1FF) as usize;
-        let l3_idx = ((curr_v >> 12) & 0x1FF) as usize;
-
         unsafe {
-            if l1_idx >= 512 {
+            let Some(entry) = l3_entry(curr_v) else {
                 continue;
+            };
+            core::ptr::write_volatile(entry, curr_p | page_attrs(perm));
+
+            // Debug: verify first and last mapping
+            if curr_v == start_v || curr_v + 0x1000 >= end_v {
+                kprintln!(
+                    "map_range: v={:x} p={:x} entry={:016x}",
+                    curr_v,
+                    curr_p,
+                    core::ptr::read_volatile(entry)
+                );
             }
 
-            // Ensure L2 exists
-            if (L1_TABLE.0[l1_idx] & DESC_VALID) == 0 {
-                // Allocate from L2_POOL
-                if L2_ALLOC_IDX >= 16 {
-                    panic!("Out of L2 tables!");
-                }
-                let l2_table_ptr = &mut L2_POOL[L2_ALLOC_IDX];
-                L2_ALLOC_IDX += 1;
-                // Zero out the new table
-                for entry in l2_table_ptr.0.iter_mut() {
-                    *entry = 0;
-                }
-                let l2_table_addr = l2_table_ptr as *const _ as u64;
-                L1_TABLE.0[l1_idx] = l2_table_addr | DESC_VALID | DESC_TABLE;
+            curr_p += 0x1000;
+        }
+    }
+
+    crate::smp::tlb_shootdown();
+}
+
+/// Map consecutive pages from `vaddr` to the given physical pages
+pub fn map_pages(vaddr: u64, paddrs: impl Iterator<Item = u64>, perm: MapPermission) {
+    for (i, paddr) in paddrs.enumerate() {
+        unsafe {
+            if let Some(entry) = l3_entry((vaddr & !0xFFF) + i as u64 * 0x1000) {
+                core::ptr::write_volatile(entry, (paddr & !0xFFF) | page_attrs(perm));
+            }
+        }
+    }
+    crate::smp::tlb_shootdown();
+}
+
+/// Remove user mappings of `size` bytes from `vaddr`. The pages go back to
+/// the kernel's identity mapping, which user mappings replace.
+pub fn unmap_range(vaddr: u64, size: u64) {
+    let start_v = vaddr & !0xFFF;
+    let end_v = (vaddr + size + 0xFFF) & !0xFFF;
+    for curr_v in (start_v..end_v).step_by(0x1000) {
+        unsafe {
+            if let Some(entry) = l3_entry(curr_v) {
+                let identity = curr_v | DESC_VALID | DESC_PAGE | MAIR_MEM | AF | AP_RW_EL1;
+                core::ptr::write_volatile(entry, identity);
             }
+        }
+    }
+    crate::smp::tlb_shootdown();
+}
 
-            let l2_ptr = (L1_TABLE.0[l1_idx] & TABLE_ADDR_MASK) as *mut PageTable;
+/// Descriptor bits of a normal or device page with `perm`
+fn page_attrs(perm: MapPermission) -> u64 {
+    let ap = get_ap_bits(perm);
+    let xn = get_xn_bits(perm);
+    let is_device = perm == MapPermission::KernelRWDevice;
+    let attr = if is_device { MAIR_DEV } else { MAIR_MEM };
+    let sh = if is_device { 0 } else { SH_INNER };
+    DESC_VALID | DESC_PAGE | attr | AF | sh | ap | xn
+}
 
-            // Ensure L3 exists (pointing from L2)
-            let l2_entry = (*l2_ptr).0[l2_idx];
-            if (l2_entry & DESC_VALID) == 0 {
-                // New table
-                if L3_ALLOC_IDX >= 256 {
-                    panic!("Out of L3 tables!");
-                }
-                let l3_table_ptr = &mut L3_POOL[L3_ALLOC_IDX];
-                L3_ALLOC_IDX += 1;
-                // Zero out the new table
-                for entry in l3_table_ptr.0.iter_mut() {
-                    *entry = 0;
-                }
-                let l3_table_addr = l3_table_ptr as *const _ as u64;
-
-                (*l2_ptr).0[l2_idx] = l3_table_addr | DESC_VALID | DESC_TABLE;
-            } else if (l2_entry & DESC_TABLE) == 0 {
-                // It was a block mapping! Split it.
-                kprintln!("MMU: Splitting block at vaddr {:x}", curr_v & !0x1FFFFF);
-                // For 2MB blocks, the address is at bits [47:21]
-                let block_paddr = l2_entry & BLOCK_ADDR_MASK;
-                let block_flags = l2_entry & !TABLE_ADDR_MASK;
-
-                let l3_idx_to_use = L3_ALLOC_IDX;
-                L3_ALLOC_IDX += 1;
-                if L3_ALLOC_IDX >= 256 {
-                    panic!("Out of L3 tables!");
-                }
+/// The L3 entry for the page at `vaddr`, creating tables and splitting
+/// 2MB blocks as needed. None if `vaddr` is outside the address space.
+unsafe fn l3_entry(curr_v: u64) -> Option<*mut u64> {
+    let l1_idx = (curr_v >> 30) as usize;
+    let l2_idx = ((curr_v >> 21) & 0x1FF) as usize;
+    let l3_idx = ((curr_v >> 12) & 0x1FF) as usize;
 
-                // Populate the new L3 table with 512 pages from the block
-                for p in 0..512 {
-                    core::ptr::write_volatile(
-                        &mut L3_POOL[l3_idx_to_use].0[p],
-                        (block_paddr + (p as u64 * 0x1000))
-                            | DESC_VALID
-                            | DESC_PAGE
-                            | (block_flags & !DESC_TABLE),
-                    );
-                }
+    unsafe {
+        if l1_idx >= 512 {
+            return None;
+        }
 
-                core::ptr::write_volatile(
-                    &mut (*l2_ptr).0[l2_idx],
-                    (&L3_POOL[l3_idx_to_use] as *const _ as u64) | DESC_VALID | DESC_TABLE,
-                );
+        // Ensure L2 exists
+        if (L1_TABLE.0[l1_idx] & DESC_VALID) == 0 {
+            // Allocate from L2_POOL
+            if L2_ALLOC_IDX >= 16 {
+                panic!("Out of L2 tables!");
             }
+            let l2_table_ptr = &mut L2_POOL[L2_ALLOC_IDX];
+            L2_ALLOC_IDX += 1;
+            // Zero out the new table
+            for entry in l2_table_ptr.0.iter_mut() {
+                *entry = 0;
+            }
+            let l2_table_addr = l2_table_ptr as *const _ as u64;
+            L1_TABLE.0[l1_idx] = l2_table_addr | DESC_VALID | DESC_TABLE;
+        }
 
-            let l3_ptr = ((*l2_ptr).0[l2_idx] & TABLE_ADDR_MASK) as *mut PageTable;
-            let ap = get_ap_bits(perm);
-            let xn = get_xn_bits(perm);
-            let is_device = perm == MapPermission::KernelRWDevice;
-            let attr = if is_device { MAIR_DEV } else { MAIR_MEM };
-            let sh = if is_device { 0 } else { SH_INNER };
+        let l2_ptr = (L1_TABLE.0[l1_idx] & TABLE_ADDR_MASK) as *mut PageTable;
 
-            core::ptr::write_volatile(
-                &mut (*l3_ptr).0[l3_idx],
-                curr_p | DESC_VALID | DESC_PAGE | attr | AF | sh | ap | xn,
-            );
+        // Ensure L3 exists (pointing from L2)
+        let l2_entry = (*l2_ptr).0[l2_idx];
+        if (l2_entry & DESC_VALID) == 0 {
+            // New table
+            if L3_ALLOC_IDX >= 256 {
+                panic!("Out of L3 tables!");
+            }
+            let l3_table_ptr = &mut L3_POOL[L3_ALLOC_IDX];
+            L3_ALLOC_IDX += 1;
+            // Zero out the new table
+            for entry in l3_table_ptr.0.iter_mut() {
+                *entry = 0;
+            }
+            let l3_table_addr = l3_table_ptr as *const _ as u64;
+
+            (*l2_ptr).0[l2_idx] = l3_table_addr | DESC_VALID | DESC_TABLE;
+        } else if (l2_entry & DESC_TABLE) == 0 {
+            // It was a block mapping! Split it.
+            kprintln!("MMU: Splitting block at vaddr {:x}", curr_v & !0x1FFFFF);
+            // For 2MB blocks, the address is at bits [47:21]
+            let block_paddr = l2_entry & BLOCK_ADDR_MASK;
+            let block_flags = l2_entry & !TABLE_ADDR_MASK;
+
+            let l3_idx_to_use = L3_ALLOC_IDX;
+            L3_ALLOC_IDX += 1;
+            if L3_ALLOC_IDX >= 256 {
+                panic!("Out of L3 tables!");
+            }
 
-            // Debug: verify first and last mapping
-            if curr_v == start_v || curr_v + 0x1000 >= end_v {
-                kprintln!(
-                    "map_range: v={:x} p={:x} L1={} L2={} L3={} entry={:016x}",
-                    curr_v,
-                    curr_p,
-                    l1_idx,
-                    l2_idx,
-                    l3_idx,
-                    core::ptr::read_volatile(&(*l3_ptr).0[l3_idx])
+            // Populate the new L3 table with 512 pages from the block
+            for p in 0..512 {
+                core::ptr::write_volatile(
+                    &mut L3_POOL[l3_idx_to_use].0[p],
+                    (block_paddr + (p as u64 * 0x1000))
+                        | DESC_VALID
+                        | DESC_PAGE
+                        | (block_flags & !DESC_TABLE),
                 );
             }
 
-            curr_p += 0x1000;
+            core::ptr::write_volatile(
+                &mut (*l2_ptr).0[l2_idx],
+                (&L3_POOL[l3_idx_to_use] as *const _ as u64) | DESC_VALID | DESC_TABLE,
+            );
         }
-    }
 
-    unsafe {
-        asm!("dsb ish", "tlbi vmalle1is", "dsb ish", "isb");
+        let l3_ptr = ((*l2_ptr).0[l2_idx] & TABLE_ADDR_MASK) as *mut PageTable;
+        Some(&mut (*l3_ptr).0[l3_idx] as *mut u64)
     }
 }
@@ -0,0 +1,441 @@
+//! The network interface and the TCP/IP stack behind AF_INET sockets
+//!
+//! The first virtio-net card is the only interface. smoltcp does the protocol
+//! work: ARP and IPv6 neighbor discovery, IPv4, IPv6, TCP and UDP. The IPv4
+//! address comes from DHCP. IPv6 starts with a link-local address and adds a
+//! global one from router advertisements. QEMU's user-mode network provides
+//! both.
+//!
+//! The stack runs whenever a socket call uses it, when the card interrupts,
+//! and from the timer tick once one of its timers is due. Socket calls that
+//! block wait in `wait_for` until a run may have changed socket state.
+
+use crate::errno::{EAGAIN, ENETDOWN};
+use crate::virtio::{ETHERNET_FRAME_MAX, VirtioNet};
+use crate::virtqueue::QUEUE_SIZE;
+use crate::waitqueue::WaitQueue;
+use crate::{entropy, kprintln, timer};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use smoltcp::iface::{Config, Interface, PollResult, SocketHandle, SocketSet};
+use smoltcp::phy::{self, Device, DeviceCapabilities, Medium};
+use smoltcp::socket::{dhcpv4, raw, tcp};
+use smoltcp::time::Instant;
+use smoltcp::wire::{
+    EthernetAddress, Icmpv6Packet, Icmpv6Repr, IpCidr, IpProtocol, IpVersion, Ipv4Cidr,
+    Ipv6Address, Ipv6Cidr, Ipv6Packet, Ipv6Repr, NdiscPrefixInfoFlags, NdiscRepr,
+    RawHardwareAddress,
+};
+use spin::Mutex;
+
+/// Ports bind hands out when asked for port 0
+const EPHEMERAL_PORTS: core::ops::RangeInclusive<u16> = 49152..=65535;
+
+/// Router advertisements and other ICMPv6 messages queued for `poll_router_adverts`
+const ICMPV6_PACKETS: usize = 4;
+const ICMPV6_BUFFER_SIZE: usize = 2048;
+
+pub struct Net {
+    pub iface: Interface,
+    device: VirtioNet,
+    pub sockets: SocketSet<'static>,
+    mac: EthernetAddress,
+    dhcp: SocketHandle,
+    /// Copies of incoming ICMPv6 messages, for router advertisements
+    icmpv6: SocketHandle,
+    /// Local ports held by sockets, as (protocol, port)
+    bound: Vec<(IpProtocol, u16)>,
+    next_port: u16,
+    /// TCP sockets closed by their owners that are still shutting down
+    orphans: Vec<SocketHandle>,
+}
+
+static NET: Mutex<Option<Net>> = Mutex::new(None);
+
+/// Processes waiting for socket state to change
+static EVENTS: WaitQueue = WaitQueue::new();
+/// Bumped whenever socket state may have changed, so that waiters can't
+/// miss a change between checking their condition and sleeping
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// Counter value at which the stack next needs to run, u64::MAX if idle
+static POLL_AT: AtomicU64 = AtomicU64::new(u64::MAX);
+/// The card interrupted while the stack was in use on another CPU
+static PENDING: AtomicBool = AtomicBool::new(false);
+
+/// The current time, for smoltcp
+fn now() -> Instant {
+    let micros = timer::counter() as u128 * 1_000_000 / timer::frequency() as u128;
+    Instant::from_micros(micros as i64)
+}
+
+/// Received frame, handed to smoltcp
+pub struct RxToken(Vec<u8>);
+
+/// Room to send a frame
+pub struct TxToken<'a>(&'a mut VirtioNet);
+
+impl phy::RxToken for RxToken {
+    fn consume<R, F: FnOnce(&[u8]) -> R>(self, f: F) -> R {
+        f(&self.0)
+    }
+}
+
+impl phy::TxToken for TxToken<'_> {
+    fn consume<R, F: FnOnce(&mut [u8]) -> R>(self, len: usize, f: F) -> R {
+        self.0.send_frame(len, f)
+    }
+}
+
+impl Device for VirtioNet {
+    type RxToken<'a> = RxToken;
+    type TxToken<'a> = TxToken<'a>;
+
+    fn receive(&mut self, _timestamp: Instant) -> Option<(RxToken, TxToken<'_>)> {
+        let frame = self.take_frame()?;
+        Some((RxToken(frame), TxToken(self)))
+    }
+
+    fn transmit(&mut self, _timestamp: Instant) -> Option<TxToken<'_>> {
+        self.can_send().then_some(TxToken(self))
+    }
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        let mut caps = DeviceCapabilities::default();
+        caps.medium = Medium::Ethernet;
+        caps.max_transmission_unit = ETHERNET_FRAME_MAX;
+        caps.max_burst_size = Some(QUEUE_SIZE);
+        caps
+    }
+}
+
+/// The stateless address with interface ID from `mac`, in `prefix`
+fn eui64_addr(prefix: Ipv6Address, mac: EthernetAddress) -> Ipv6Address {
+    let mut octets = prefix.octets();
+    let m = mac.0;
+    octets[8..].copy_from_slice(&[m[0] ^ 0x02, m[1], m[2], 0xff, 0xfe, m[3], m[4], m[5]]);
+    Ipv6Address::from(octets)
+}
+
+impl Net {
+    /// Let the stack process received frames, send what sockets have
+    /// queued and run timers. Returns whether socket state may have changed.
+    fn poll(&mut self) -> bool {
+        PENDING.store(false, Ordering::Relaxed);
+        let timestamp = now();
+        let result = self
+            .iface
+            .poll(timestamp, &mut self.device, &mut self.sockets);
+        self.poll_dhcp();
+        self.poll_router_adverts();
+        self.reap_orphans();
+        let poll_at = match self.iface.poll_delay(timestamp, &self.sockets) {
+            Some(delay) => timer::counter() + delay.total_micros() * timer::frequency() / 1_000_000,
+            None => u64::MAX,
+        };
+        POLL_AT.store(poll_at, Ordering::Relaxed);
+        matches!(result, PollResult::SocketStateChanged)
+    }
+
+    fn poll_dhcp(&mut self) {
+        let event = match self.sockets.get_mut::<dhcpv4::Socket>(self.dhcp).poll() {
+            Some(dhcpv4::Event::Configured(config)) => {
+                Some((config.address, config.router, config.dns_servers.clone()))
+            }
+            Some(dhcpv4::Event::Deconfigured) => None,
+            None => return,
+        };
+        match event {
+            Some((address, router, dns_servers)) => {
+                kprintln!("Net: DHCP address {}", address);
+                self.set_ipv4(Some(address));
+                match router {
+                    Some(router) => {
+                        kprintln!("Net: Default router {}", router);
+                        let _ = self.iface.routes_mut().add_default_ipv4_route(router);
+                    }
+                    None => {
+                        self.iface.routes_mut().remove_default_ipv4_route();
+                    }
+                }
+                for server in &dns_servers {
+                    kprintln!("Net: DNS server {}", server);
+                }
+            }
+            None => {
+                kprintln!("Net: DHCP lease lost");
+                self.set_ipv4(None);
+                self.iface.routes_mut().remove_default_ipv4_route();
+            }
+        }
+    }
+
+    fn set_ipv4(&mut self, cidr: Option<Ipv4Cidr>) {
+        self.iface.update_ip_addrs(|addrs| {
+            addrs.retain(|addr| !matches!(addr, IpCidr::Ipv4(_)));
+            if let Some(cidr) = cidr {
+                let _ = addrs.push(IpCidr::Ipv4(cidr));
+            }
+        });
+    }
+
+    /// Take a global address and default route from router advertisements
+    fn poll_router_adverts(&mut self) {
+        let mac = self.mac;
+        let mut buf = [0u8; ICMPV6_BUFFER_SIZE];
+        loop {
+            let len = match self
+                .sockets
+                .get_mut::<raw::Socket>(self.icmpv6)
+                .recv_slice(&mut buf)
+            {
+                Ok(len) => len,
+                Err(_) => return,
+            };
+            let Ok(ip) = Ipv6Packet::new_checked(&buf[..len]) else {
+                continue;
+            };
+            let Ok(icmp) = Icmpv6Packet::new_checked(ip.payload()) else {
+                continue;
+            };
+            let repr = Icmpv6Repr::parse(
+                &ip.src_addr(),
+                &ip.dst_addr(),
+                &icmp,
+                &self.device.capabilities().checksum,
+            );
+            let Ok(Icmpv6Repr::Ndisc(NdiscRepr::RouterAdvert {
+                router_lifetime,
+                prefix_info,
+                ..
+            })) = repr
+            else {
+                continue;
+            };
+            if let Some(info) = prefix_info
+                && info.flags.contains(NdiscPrefixInfoFlags::ADDRCONF)
+                && info.prefix_len == 64
+            {
+                let addr = eui64_addr(info.prefix, mac);
+                let cidr = IpCidr::Ipv6(Ipv6Cidr::new(addr, 64));
+                if !self.iface.ip_addrs().contains(&cidr) {
+                    kprintln!("Net: IPv6 address {}", cidr);
+                    self.iface.update_ip_addrs(|addrs| {
+                        addrs.retain(|addr| match addr {
+                            IpCidr::Ipv6(v6) => v6.address().is_unicast_link_local(),
+                            _ => true,
+                        });
+                        let _ = addrs.push(cidr);
+                    });
+                }
+            }
+            if router_lifetime.total_millis() > 0 {
+                let _ = self
+                    .iface
+                    .routes_mut()
+                    .add_default_ipv6_route(ip.src_addr());
+            } else {
+                self.iface.routes_mut().remove_default_ipv6_route();
+            }
+        }
+    }
+
+    /// Ask routers to advertise themselves now rather than in a few minutes
+    fn solicit_routers(&mut self, link_local: Ipv6Address) {
+        let icmp = Icmpv6Repr::Ndisc(NdiscRepr::RouterSolicit {
+            lladdr: Some(RawHardwareAddress::from(self.mac)),
+        });
+        let ip = Ipv6Repr {
+            src_addr: link_local,
+            dst_addr: smoltcp::wire::IPV6_LINK_LOCAL_ALL_ROUTERS,
+            next_header: IpProtocol::Icmpv6,
+            payload_len: icmp.buffer_len(),
+            hop_limit: 255,
+        };
+        let checksum = self.device.capabilities().checksum;
+        let socket = self.sockets.get_mut::<raw::Socket>(self.icmpv6);
+        if let Ok(buf) = socket.send(ip.buffer_len() + icmp.buffer_len()) {
+            let mut packet = Ipv6Packet::new_unchecked(buf);
+            ip.emit(&mut packet);
+            icmp.emit(
+                &ip.src_addr,
+                &ip.dst_addr,
+                &mut Icmpv6Packet::new_unchecked(packet.payload_mut()),
+                &checksum,
+            );
+        }
+    }
+
+    /// Remove orphaned TCP sockets that have finished closing
+    fn reap_orphans(&mut self) {
+        let sockets = &mut self.sockets;
+        self.orphans.retain(|&handle| {
+            let closed = sockets.get::<tcp::Socket>(handle).state() == tcp::State::Closed;
+            if closed {
+                sockets.remove(handle);
+            }
+            !closed
+        });
+    }
+
+    /// Close a TCP socket once its connection has shut down
+    pub fn orphan(&mut self, handle: SocketHandle) {
+        let socket = self.sockets.get_mut::<tcp::Socket>(handle);
+        socket.close();
+        if socket.state() == tcp::State::Closed {
+            self.sockets.remove(handle);
+        } else {
+            self.orphans.push(handle);
+        }
+    }
+
+    /// Hold local `port` for `protocol`, or a free ephemeral port if `port`
+    /// is 0. Returns the port, or None if it is taken.
+    pub fn bind_port(&mut self, protocol: IpProtocol, port: u16) -> Option<u16> {
+        let port = if port != 0 {
+            port
+        } else {
+            let count = EPHEMERAL_PORTS.len();
+            let free = (0..count)
+                .map(|i| {
+                    let offset = (self.next_port - EPHEMERAL_PORTS.start()) as usize;
+                    EPHEMERAL_PORTS.start() + ((offset + i) % count) as u16
+                })
+                .find(|&port| !self.bound.contains(&(protocol, port)))?;
+            self.next_port = if free == *EPHEMERAL_PORTS.end() {
+                *EPHEMERAL_PORTS.start()
+            } else {
+                free + 1
+            };
+            free
+        };
+        if self.bound.contains(&(protocol, port)) {
+            return None;
+        }
+        self.bound.push((protocol, port));
+        Some(port)
+    }
+
+    pub fn release_port(&mut self, protocol: IpProtocol, port: u16) {
+        if let Some(index) = self.bound.iter().position(|&b| b == (protocol, port)) {
+            self.bound.swap_remove(index);
+        }
+    }
+}
+
+/// Tell waiters that socket state may have changed
+fn notify() {
+    GENERATION.fetch_add(1, Ordering::Release);
+    EVENTS.wake_all();
+}
+
+/// Run `f` on the stack, then let the stack act on what it did. Fails with
+/// ENETDOWN without a network card.
+pub fn with<R>(f: impl FnOnce(&mut Net) -> R) -> Result<R, u64> {
+    let (result, changed) = {
+        let mut net = NET.lock();
+        let net = net.as_mut().ok_or(ENETDOWN)?;
+        let result = f(net);
+        (result, net.poll())
+    };
+    if changed {
+        notify();
+    }
+    Ok(result)
+}
+
+/// Block until `cond` returns Some, re-checking it whenever socket state may
+/// have changed. Fails with EAGAIN instead of blocking if `nonblock`.
+pub fn wait_for<T>(
+    nonblock: bool,
+    mut cond: impl FnMut(&mut Net) -> Option<Result<T, u64>>,
+) -> Result<T, u64> {
+    loop {
+        let generation = GENERATION.load(Ordering::Acquire);
+        if let Some(result) = with(&mut cond)? {
+            return result;
+        }
+        if nonblock {
+            return Err(EAGAIN);
+        }
+        EVENTS.wait_until(|| (GENERATION.load(Ordering::Acquire) != generation).then_some(()));
+    }
+}
+
+/// Run the stack from an interrupt handler, unless another CPU is already
+/// running it
+fn poll_from_interrupt() {
+    let changed = match NET.try_lock() {
+        Some(mut net) => net.as_mut().is_some_and(|net| net.poll()),
+        None => {
+            PENDING.store(true, Ordering::Relaxed);
+            return;
+        }
+    };
+    if changed {
+        notify();
+    }
+}
+
+/// The card received frames
+fn handle_interrupt() {
+    poll_from_interrupt();
+}
+
+/// Called from the timer tick to run the stack's timers
+pub fn tick() {
+    if PENDING.load(Ordering::Relaxed) || timer::counter() >= POLL_AT.load(Ordering::Relaxed) {
+        poll_from_interrupt();
+    }
+}
+
+/// Bring up the network card, if there is one, and start DHCP and IPv6
+/// autoconfiguration. Must be called after the entropy pool is seeded.
+pub fn init() {
+    let Some(mut device) = VirtioNet::new(handle_interrupt) else {
+        kprintln!("Net: No network card");
+        return;
+    };
+    let mac = EthernetAddress(device.mac());
+    let mut config = Config::new(mac.into());
+    let mut seed = [0u8; 8];
+    entropy::fill(&mut seed);
+    config.random_seed = u64::from_le_bytes(seed);
+    let mut iface = Interface::new(config, &mut device, now());
+
+    let link_local = eui64_addr(Ipv6Address::new(0xfe80, 0, 0, 0, 0, 0, 0, 0), mac);
+    iface.update_ip_addrs(|addrs| {
+        let _ = addrs.push(IpCidr::Ipv6(Ipv6Cidr::new(link_local, 64)));
+    });
+
+    let mut sockets = SocketSet::new(Vec::new());
+    let dhcp = sockets.add(dhcpv4::Socket::new());
+    let icmpv6 = sockets.add(raw::Socket::new(
+        IpVersion::Ipv6,
+        IpProtocol::Icmpv6,
+        raw::PacketBuffer::new(
+            vec![raw::PacketMetadata::EMPTY; ICMPV6_PACKETS],
+            vec![0; ICMPV6_PACKETS * ICMPV6_BUFFER_SIZE],
+        ),
+        raw::PacketBuffer::new(
+            vec![raw::PacketMetadata::EMPTY; 1],
+            vec![0; ICMPV6_BUFFER_SIZE],
+        ),
+    ));
+    let mut net = Net {
+        iface,
+        device,
+        sockets,
+        mac,
+        dhcp,
+        icmpv6,
+        bound: Vec::new(),
+        next_port: *EPHEMERAL_PORTS.start() + (entropy::below(EPHEMERAL_PORTS.len() as u64) as u16),
+        orphans: Vec::new(),
+    };
+    kprintln!("Net: IPv6 link-local address {}", link_local);
+    net.solicit_routers(link_local);
+    *NET.lock() = Some(net);
+    let _ = with(|_| ());
+}
@@ -0,0 +1,462 @@
+//! Page cache of file contents
+//!
+//! Regular files are cached in 4 KiB pages keyed by the file's device and
+//! inode numbers and the page index, so every open of a file, and read,
+//! pread and mmap alike, share the same pages. Pages are evicted least
+//! recently used first when the cache is over its budget or the heap runs
+//! low. Pages mapped into user space are pinned until they are unmapped.
+//!
+//! Private pages mapped into user space, anonymous or copied from a file,
+//! are tracked here too, and freed when they are unmapped.
+
+use crate::errno::{EINVAL, EIO, ENOMEM};
+use crate::mmu::{self, MapPermission};
+use crate::vfs::File;
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::alloc::Layout;
+use core::ptr::NonNull;
+use spin::Mutex;
+
+pub const PAGE_SIZE: usize = 4096;
+
+/// Most pages kept before eviction starts: 128 MiB
+const MAX_PAGES: usize = 32 * 1024;
+/// Evict while less than this much of the heap is free
+const LOW_HEAP: usize = 64 * 1024 * 1024;
+/// Pages read past a miss by a sequential reader
+const READAHEAD_PAGES: u64 = 16;
+
+/// The file a cached page belongs to
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct FileId {
+    pub dev: u32,
+    pub ino: u64,
+}
+
+/// A page-aligned page of heap memory. The heap is identity mapped, so its
+/// address is also its physical address.
+pub struct Page {
+    ptr: NonNull<u8>,
+}
+
+// Pages are only written before they are shared
+unsafe impl Send for Page {}
+unsafe impl Sync for Page {}
+
+const PAGE_LAYOUT: Layout = match Layout::from_size_align(PAGE_SIZE, PAGE_SIZE) {
+    Ok(layout) => layout,
+    Err(_) => panic!("bad page layout"),
+};
+
+impl Page {
+    /// A zeroed page, evicting cached pages if the heap is exhausted
+    fn new() -> Option<Self> {
+        let alloc = || NonNull::new(unsafe { alloc::alloc::alloc_zeroed(PAGE_LAYOUT) });
+        let ptr = alloc().or_else(|| {
+            CACHE.lock().evict(READAHEAD_PAGES as usize);
+            alloc()
+        })?;
+        Some(Self { ptr })
+    }
+
+    pub fn paddr(&self) -> u64 {
+        self.ptr.as_ptr() as u64
+    }
+
+    pub fn data(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self.ptr.as_ptr(), PAGE_SIZE) }
+    }
+
+    fn data_mut(&mut self) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self.ptr.as_ptr(), PAGE_SIZE) }
+    }
+}
+
+impl Drop for Page {
+    fn drop(&mut self) {
+        unsafe { alloc::alloc::dealloc(self.ptr.as_ptr(), PAGE_LAYOUT) };
+    }
+}
+
+type Key = (FileId, u64);
+
+struct Entry {
+    page: Arc<Page>,
+    /// Position in `Cache::lru`
+    last_used: u64,
+    /// Number of user mappings of the page. Mapped pages aren't in
+    /// `Cache::lru`, so they are neither evicted nor counted in the budget.
+    maps: usize,
+}
+
+struct Cache {
+    pages: BTreeMap<Key, Entry>,
+    /// Keys of unmapped pages by time of last use, least recent first
+    lru: BTreeMap<u64, Key>,
+    clock: u64,
+    /// Times the pages of each file were invalidated, so that a page read
+    /// before a write isn't cached after it
+    generations: BTreeMap<FileId, u64>,
+}
+
+static CACHE: Mutex<Cache> = Mutex::new(Cache {
+    pages: BTreeMap::new(),
+    lru: BTreeMap::new(),
+    clock: 0,
+    generations: BTreeMap::new(),
+});
+
+impl Cache {
+    fn get(&mut self, key: Key) -> Option<Arc<Page>> {
+        self.clock += 1;
+        let entry = self.pages.get_mut(&key)?;
+        if entry.maps == 0 {
+            self.lru.remove(&entry.last_used);
+            self.lru.insert(self.clock, key);
+        }
+        entry.last_used = self.clock;
+        Some(Arc::clone(&entry.page))
+    }
+
+    fn generation(&self, id: FileId) -> u64 {
+        self.generations.get(&id).copied().unwrap_or(0)
+    }
+
+    /// Add a page read while the file was at `generation`, unless another
+    /// reader got there first. Returns the cached page, or the new one
+    /// uncached if the file has been invalidated since.
+    fn insert(&mut self, key: Key, page: Page, generation: u64) -> Arc<Page> {
+        if let Some(page) = self.get(key) {
+            return page;
+        }
+        let page = Arc::new(page);
+        if self.generation(key.0) != generation {
+            return page;
+        }
+        self.pages.insert(
+            key,
+            Entry {
+                page: Arc::clone(&page),
+                last_used: self.clock,
+                maps: 0,
+            },
+        );
+        self.lru.insert(self.clock, key);
+        page
+    }
+
+    fn remove(&mut self, key: &Key) {
+        if let Some(entry) = self.pages.remove(key)
+            && entry.maps == 0
+        {
+            self.lru.remove(&entry.last_used);
+        }
+    }
+
+    fn pin(&mut self, key: Key) {
+        if let Some(entry) = self.pages.get_mut(&key) {
+            entry.maps += 1;
+            if entry.maps == 1 {
+                self.lru.remove(&entry.last_used);
+            }
+        }
+    }
+
+    /// Undo `pin` for a mapping of `page`, unless the page has been dropped
+    /// from the cache since
+    fn unpin(&mut self, key: Key, page: &Arc<Page>) {
+        self.clock += 1;
+        if let Some(entry) = self.pages.get_mut(&key)
+            && Arc::ptr_eq(&entry.page, page)
+        {
+            entry.maps -= 1;
+            if entry.maps == 0 {
+                entry.last_used = self.clock;
+                self.lru.insert(self.clock, key);
+            }
+        }
+    }
+
+    /// Evict at least `count` pages if possible, more if the cache is over
+    /// budget or the heap is low. Pages being read are skipped.
+    fn evict(&mut self, count: usize) {
+        let over_budget = self.lru.len().saturating_sub(MAX_PAGES);
+        let heap_short = LOW_HEAP.saturating_sub(crate::heap::free_bytes()) / PAGE_SIZE;
+        let wanted = count.max(over_budget).max(heap_short);
+        if wanted == 0 {
+            return;
+        }
+        let victims: Vec<Key> = self
+            .lru
+            .values()
+            .filter(|key| Arc::strong_count(&self.pages[key].page) == 1)
+            .take(wanted)
+            .copied()
+            .collect();
+        for key in &victims {
+            self.remove(key);
+        }
+    }
+}
+
+/// Read page `index` of `file` into a new page. None if the read came up
+/// short before the end of the file.
+fn fill(file: &dyn File, index: u64, size: u64) -> Option<Page> {
+    let mut page = Page::new()?;
+    let offset = index * PAGE_SIZE as u64;
+    let expected = (size - offset).min(PAGE_SIZE as u64) as usize;
+    let read = file.read_at(offset, &mut page.data_mut()[..expected]);
+    (read == expected).then_some(page)
+}
+
+/// Page `index` of `file`, read in on a miss together with up to `ahead`
+/// following pages that aren't cached yet
+fn get(id: FileId, file: &dyn File, index: u64, ahead: u64) -> Option<Arc<Page>> {
+    let generation = {
+        let mut cache = CACHE.lock();
+        if let Some(page) = cache.get((id, index)) {
+            return Some(page);
+        }
+        cache.generation(id)
+    };
+    // Read without the cache locked; a racing reader's page wins, and a
+    // racing write's invalidate keeps the page out
+    let size = file.size();
+    let page = fill(file, index, size)?;
+    let page = CACHE.lock().insert((id, index), page, generation);
+
+    let pages = size.div_ceil(PAGE_SIZE as u64);
+    for next in index + 1..(index + 1 + ahead).min(pages) {
+        if CACHE.lock().pages.contains_key(&(id, next)) {
+            break;
+        }
+        let Some(page) = fill(file, next, size) else {
+            break;
+        };
+        CACHE.lock().insert((id, next), page, generation);
+    }
+    CACHE.lock().evict(0);
+    Some(page)
+}
+
+/// Read from `file` at `offset` through the cache, reading ahead if the
+/// caller is reading sequentially
+pub fn read(id: FileId, file: &dyn File, offset: u64, buf: &mut [u8], sequential: bool) -> usize {
+    let size = file.size();
+    if offset >= size {
+        return 0;
+    }
+    let len = buf.len().min((size - offset) as usize);
+    let ahead = if sequential { READAHEAD_PAGES } else { 0 };
+    let mut done = 0;
+    while done < len {
+        let pos = offset + done as u64;
+        let start = pos as usize % PAGE_SIZE;
+        let count = (PAGE_SIZE - start).min(len - done);
+        match get(id, file, pos / PAGE_SIZE as u64, ahead) {
+            Some(page) => {
+                buf[done..done + count].copy_from_slice(&page.data()[start..start + count])
+            }
+            // Out of memory or a failed read: go to the file directly
+            None => return done + file.read_at(pos, &mut buf[done..len]),
+        }
+        done += count;
+    }
+    done
+}
+
+/// Drop the cached pages of `id` overlapping `len` bytes at `offset`, after
+/// the file was written
+pub fn invalidate(id: FileId, offset: u64, len: u64) {
+    let mut cache = CACHE.lock();
+    *cache.generations.entry(id).or_default() += 1;
+    if len == 0 {
+        return;
+    }
+    let first = offset / PAGE_SIZE as u64;
+    let last = (offset + len - 1) / PAGE_SIZE as u64;
+    for index in first..=last {
+        cache.remove(&(id, index));
+    }
+}
+
+/// Drop every cached page of the filesystem with device number `dev`
+pub fn purge(dev: u32) {
+    let mut cache = CACHE.lock();
+    let keys: Vec<Key> = cache
+        .pages
+        .keys()
+        .filter(|(id, _)| id.dev == dev)
+        .copied()
+        .collect();
+    for key in &keys {
+        cache.remove(key);
+    }
+    cache.generations.retain(|id, _| id.dev != dev);
+}
+
+/// Evict cached pages to make room for an allocation of `bytes`
+pub fn reclaim(bytes: usize) {
+    CACHE.lock().evict(bytes.div_ceil(PAGE_SIZE));
+}
+
+/// A page mapped into user space
+struct Mapping {
+    page: Arc<Page>,
+    /// Key of a cached page, None for a private copy
+    key: Option<Key>,
+}
+
+/// User mappings of cached and private pages, by page address. There is one
+/// address space, so they aren't per process.
+static MAPPINGS: Mutex<BTreeMap<u64, Mapping>> = Mutex::new(BTreeMap::new());
+
+/// Map `len` bytes of `file` at `offset` to `vaddr`, both page aligned.
+/// Read-only mappings share the cached pages; writable ones get private
+/// copies, since writes never reach the file.
+pub fn map(
+    id: FileId,
+    file: &dyn File,
+    vaddr: u64,
+    offset: u64,
+    len: u64,
+    writable: bool,
+    exec: bool,
+) -> Result<(), u64> {
+    if !vaddr.is_multiple_of(PAGE_SIZE as u64) || !offset.is_multiple_of(PAGE_SIZE as u64) {
+        return Err(EINVAL);
+    }
+    let first = offset / PAGE_SIZE as u64;
+    let count = len.div_ceil(PAGE_SIZE as u64);
+    let file_pages = file.size().div_ceil(PAGE_SIZE as u64);
+    let mut mappings = Vec::new();
+    for index in first..first + count {
+        let mapping = if index >= file_pages {
+            // Past the end of the file
+            Mapping {
+                page: Arc::new(Page::new().ok_or(ENOMEM)?),
+                key: None,
+            }
+        } else {
+            let ahead = (first + count - index - 1).min(READAHEAD_PAGES);
+            let page = get(id, file, index, ahead).ok_or(EIO)?;
+            if writable {
+                Mapping {
+                    page: Arc::new(copy(&page)?),
+                    key: None,
+                }
+            } else {
+                Mapping {
+                    page,
+                    key: Some((id, index)),
+                }
+            }
+        };
+        mappings.push(mapping);
+    }
+
+    let perm = match (writable, exec) {
+        (true, _) => MapPermission::UserRWX,
+        (false, true) => MapPermission::UserRX,
+        (false, false) => MapPermission::UserRO,
+    };
+    install(vaddr, mappings, perm);
+    Ok(())
+}
+
+/// Map `len` bytes of zeroed private pages at `vaddr`, rounded out to
+/// whole pages. They are freed when unmapped.
+pub fn map_anon(vaddr: u64, len: u64) -> Result<(), u64> {
+    let start = vaddr & !(PAGE_SIZE as u64 - 1);
+    let count = (vaddr + len - start).div_ceil(PAGE_SIZE as u64);
+    let mut mappings = Vec::new();
+    for _ in 0..count {
+        mappings.push(Mapping {
+            page: Arc::new(Page::new().ok_or(ENOMEM)?),
+            key: None,
+        });
+    }
+    install(start, mappings, MapPermission::UserRWX);
+    Ok(())
+}
+
+/// Map the pages of `mappings` from `vaddr` on, pinning the cached ones and
+/// dropping the mappings they replace
+fn install(vaddr: u64, mappings: Vec<Mapping>, perm: MapPermission) {
+    {
+        let mut cache = CACHE.lock();
+        for key in mappings.iter().filter_map(|mapping| mapping.key) {
+            cache.pin(key);
+        }
+    }
+    mmu::map_pages(vaddr, mappings.iter().map(|m| m.page.paddr()), perm);
+    let replaced: Vec<Mapping> = {
+        let mut all = MAPPINGS.lock();
+        let addrs = (0..).map(|i| vaddr + i * PAGE_SIZE as u64);
+        addrs
+            .zip(mappings)
+            .filter_map(|(addr, mapping)| all.insert(addr, mapping))
+            .collect()
+    };
+    release(replaced);
+}
+
+fn copy(page: &Page) -> Result<Page, u64> {
+    let mut copy = Page::new().ok_or(ENOMEM)?;
+    copy.data_mut().copy_from_slice(page.data());
+    Ok(copy)
+}
+
+/// Unpin the cached pages of mappings that are gone
+fn release(mappings: Vec<Mapping>) {
+    let mut cache = CACHE.lock();
+    for mapping in mappings {
+        if let Some(key) = mapping.key {
+            cache.unpin(key, &mapping.page);
+        }
+    }
+}
+
+/// Give the shared pages mapped in `len` bytes at `vaddr` private, writable
+/// copies, for mprotect adding PROT_WRITE
+pub fn make_writable(vaddr: u64, len: u64) -> Result<(), u64> {
+    let start = vaddr & !(PAGE_SIZE as u64 - 1);
+    let mut replaced = Vec::new();
+    let result = (|| {
+        let mut mappings = MAPPINGS.lock();
+        for (&addr, mapping) in mappings.range_mut(start..vaddr + len) {
+            if mapping.key.is_some() {
+                let copy = Mapping {
+                    page: Arc::new(copy(&mapping.page)?),
+                    key: None,
+                };
+                replaced.push(core::mem::replace(mapping, copy));
+            }
+            let paddr = mapping.page.paddr();
+            mmu::map_pages(addr, core::iter::once(paddr), MapPermission::UserRWX);
+        }
+        Ok(())
+    })();
+    release(replaced);
+    result
+}
+
+/// Forget the mappings in `len` bytes at `vaddr` once they have been
+/// unmapped, freeing their private pages
+pub fn unmap(vaddr: u64, len: u64) {
+    let start = vaddr & !(PAGE_SIZE as u64 - 1);
+    let removed: Vec<Mapping> = {
+        let mut mappings = MAPPINGS.lock();
+        let addrs: Vec<u64> = mappings
+            .range(start..vaddr + len)
+            .map(|(&a, _)| a)
+            .collect();
+        addrs
+            .iter()
+            .filter_map(|addr| mappings.remove(addr))
+            .collect()
+    };
+    release(removed);
+}
@@ -0,0 +1,79 @@
+//! Partitions of kernel disks, found by the `partition-table` crate
+
+use crate::block::{BlockDevice, BlockReader};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+pub use partition_table::PartitionInfo;
+
+/// A kernel disk as the partition table parser reads it
+struct TableDisk<'a>(&'a dyn BlockDevice);
+
+impl partition_table::Disk for TableDisk<'_> {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> bool {
+        self.0.read_at(offset, buf)
+    }
+
+    fn size(&self) -> u64 {
+        self.0.size()
+    }
+
+    fn block_size(&self) -> u32 {
+        self.0.block_size()
+    }
+}
+
+/// The partitions of `disk`, empty if it has no partition table
+pub fn scan(disk: &dyn BlockDevice) -> Vec<PartitionInfo> {
+    partition_table::scan(&TableDisk(disk))
+}
+
+/// A partition used as a disk of its own
+pub struct Partition {
+    disk: Arc<dyn BlockDevice>,
+    start: u64,
+    size: u64,
+}
+
+impl Partition {
+    pub fn new(disk: Arc<dyn BlockDevice>, info: &PartitionInfo) -> Self {
+        Self {
+            disk,
+            start: info.start,
+            size: info.size,
+        }
+    }
+
+    fn contains(&self, offset: u64, len: usize) -> bool {
+        offset
+            .checked_add(len as u64)
+            .is_some_and(|end| end <= self.size)
+    }
+}
+
+impl BlockReader for Partition {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> bool {
+        self.contains(offset, buf.len()) && self.disk.read_at(self.start + offset, buf)
+    }
+}
+
+impl BlockDevice for Partition {
+    fn write_at(&self, offset: u64, buf: &[u8]) -> bool {
+        self.contains(offset, buf.len()) && self.disk.write_at(self.start + offset, buf)
+    }
+
+    fn flush(&self) -> bool {
+        self.disk.flush()
+    }
+
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn block_size(&self) -> u32 {
+        self.disk.block_size()
+    }
+
+    fn read_only(&self) -> bool {
+        self.disk.read_only()
+    }
+}
@@ -0,0 +1,75 @@
+//! Per-CPU data
+
+use core::arch::asm;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+/// Maximum number of CPUs supported (GICv2 can target at most 8)
+pub const MAX_CPUS: usize = 8;
+
+pub struct PerCpu {
+    pub id: usize,
+    pub online: AtomicBool,
+    /// Set by the timer tick or a reschedule IPI, consumed on return to user mode
+    pub need_resched: AtomicBool,
+    /// Top of the stack the CPU idles on
+    pub idle_stack_top: AtomicU64,
+    /// Last TLB shootdown generation this CPU has flushed for
+    pub tlb_generation: AtomicU64,
+    pub ticks: AtomicU64,
+}
+
+impl PerCpu {
+    const fn new(id: usize) -> Self {
+        Self {
+            id,
+            online: AtomicBool::new(false),
+            need_resched: AtomicBool::new(false),
+            idle_stack_top: AtomicU64::new(0),
+            tlb_generation: AtomicU64::new(0),
+            ticks: AtomicU64::new(0),
+        }
+    }
+}
+
+static PERCPU: [PerCpu; MAX_CPUS] = {
+    let mut cpus = [const { PerCpu::new(0) }; MAX_CPUS];
+    let mut i = 0;
+    while i < MAX_CPUS {
+        cpus[i] = PerCpu::new(i);
+        i += 1;
+    }
+    cpus
+};
+
+/// Index of the calling CPU (MPIDR_EL1.Aff0)
+pub fn cpu_id() -> usize {
+    let mpidr: u64;
+    unsafe {
+        asm!("mrs {}, mpidr_el1", out(reg) mpidr);
+    }
+    (mpidr & 0xFF) as usize
+}
+
+/// Per-CPU data of the calling CPU
+pub fn current() -> &'static PerCpu {
+    &PERCPU[cpu_id()]
+}
+
+pub fn get(cpu: usize) -> &'static PerCpu {
+    &PERCPU[cpu]
+}
+
+/// Mark the calling CPU as online and record its idle stack
+pub fn init_cpu(idle_stack_top: u64) {
+    let cpu = current();
+    cpu.idle_stack_top.store(idle_stack_top, Ordering::Relaxed);
+    cpu.online.store(true, Ordering::Release);
+}
+
+/// Iterator over the ids of online CPUs
+pub fn online_cpus() -> impl Iterator<Item = usize> {
+    PERCPU
+        .iter()
+        .filter(|c| c.online.load(Ordering::Acquire))
+        .map(|c| c.id)
+}
@@ -0,0 +1,255 @@
+//! Pipes and named FIFOs
+
+use crate::errno::{EAGAIN, EINVAL, ENXIO, EPIPE};
+use crate::pagecache::FileId;
+use crate::vfs::{
+    File, FileHandle, O_ACCMODE, O_NONBLOCK, O_RDONLY, O_RDWR, O_WRONLY, Readiness, S_IFIFO,
+};
+use crate::waitqueue::WaitQueue;
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::sync::{Arc, Weak};
+use spin::Mutex;
+
+/// Capacity of a pipe buffer
+const PIPE_SIZE: usize = 16384;
+/// Writes of at most this many bytes are atomic
+const PIPE_BUF: usize = 512;
+
+struct PipeState {
+    buf: VecDeque<u8>,
+    readers: usize,
+    writers: usize,
+    /// Number of times each end has been opened, for blocking FIFO opens
+    reader_opens: u64,
+    writer_opens: u64,
+}
+
+pub struct Pipe {
+    state: Mutex<PipeState>,
+    /// Readers waiting for data or for a writer to open the FIFO
+    readable: WaitQueue,
+    /// Writers waiting for space or for a reader to open the FIFO
+    writable: WaitQueue,
+}
+
+impl Pipe {
+    fn new() -> Arc<Self> {
+        Arc::new(Self {
+            state: Mutex::new(PipeState {
+                buf: VecDeque::new(),
+                readers: 0,
+                writers: 0,
+                reader_opens: 0,
+                writer_opens: 0,
+            }),
+            readable: WaitQueue::new(),
+            writable: WaitQueue::new(),
+        })
+    }
+}
+
+/// One end of a pipe, or both for a FIFO opened O_RDWR. The pipe sees EOF
+/// or EPIPE once every end of the other kind has been dropped.
+struct PipeEnd {
+    pipe: Arc<Pipe>,
+    reader: bool,
+    writer: bool,
+}
+
+impl PipeEnd {
+    fn new(pipe: &Arc<Pipe>, reader: bool, writer: bool) -> Self {
+        {
+            let mut state = pipe.state.lock();
+            if reader {
+                state.readers += 1;
+                state.reader_opens += 1;
+            }
+            if writer {
+                state.writers += 1;
+                state.writer_opens += 1;
+            }
+        }
+        // Let a blocked FIFO open on the other end proceed
+        if writer {
+            pipe.readable.wake_all();
+        }
+        if reader {
+            pipe.writable.wake_all();
+        }
+        Self {
+            pipe: Arc::clone(pipe),
+            reader,
+            writer,
+        }
+    }
+}
+
+impl Drop for PipeEnd {
+    fn drop(&mut self) {
+        if self.reader {
+            self.pipe.state.lock().readers -= 1;
+            self.pipe.writable.wake_all();
+        }
+        if self.writer {
+            self.pipe.state.lock().writers -= 1;
+            self.pipe.readable.wake_all();
+        }
+    }
+}
+
+impl File for PipeEnd {
+    fn read_at(&self, _offset: u64, _buf: &mut [u8]) -> usize {
+        0
+    }
+
+    fn size(&self) -> u64 {
+        self.pipe.state.lock().buf.len() as u64
+    }
+
+    fn is_stream(&self) -> bool {
+        true
+    }
+
+    fn read(&self, buf: &mut [u8], nonblock: bool) -> Result<usize, u64> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let result = self.pipe.readable.wait_until(|| {
+            let mut state = self.pipe.state.lock();
+            if !state.buf.is_empty() {
+                let n = buf.len().min(state.buf.len());
+                for (dst, src) in buf.iter_mut().zip(state.buf.drain(..n)) {
+                    *dst = src;
+                }
+                Some(Ok(n))
+            } else if state.writers == 0 {
+                Some(Ok(0))
+            } else if nonblock {
+                Some(Err(EAGAIN))
+            } else {
+                None
+            }
+        });
+        if matches!(result, Ok(n) if n > 0) {
+            self.pipe.writable.wake_all();
+        }
+        result
+    }
+
+    fn write(&self, buf: &[u8], nonblock: bool) -> Result<usize, u64> {
+        let mut written = 0;
+        while written < buf.len() {
+            let remaining = &buf[written..];
+            let result = self.pipe.writable.wait_until(|| {
+                let mut state = self.pipe.state.lock();
+                if state.readers == 0 {
+                    return Some(Err(EPIPE));
+                }
+                let space = PIPE_SIZE - state.buf.len();
+                // Small writes go in all at once or not at all
+                let needed = if buf.len() <= PIPE_BUF {
+                    remaining.len()
+                } else {
+                    1
+                };
+                if space >= needed {
+                    let n = space.min(remaining.len());
+                    state.buf.extend(&remaining[..n]);
+                    Some(Ok(n))
+                } else if nonblock {
+                    Some(Err(EAGAIN))
+                } else {
+                    None
+                }
+            });
+            match result {
+                Ok(n) => {
+                    written += n;
+                    self.pipe.readable.wake_all();
+                }
+                Err(_) if written > 0 => break,
+                Err(errno) => return Err(errno),
+            }
+        }
+        Ok(written)
+    }
+
+    fn mode(&self) -> u16 {
+        S_IFIFO | 0o600
+    }
+
+    fn readiness(&self) -> Readiness {
+        let state = self.pipe.state.lock();
+        let space = PIPE_SIZE - state.buf.len();
+        let readable = !state.buf.is_empty() || state.writers == 0;
+        let writable = state.readers == 0 || space > 0;
+        Readiness {
+            read: (self.reader && readable).then_some(state.buf.len()),
+            write: (self.writer && writable).then_some(space),
+            eof: (self.reader && state.writers == 0) || (self.writer && state.readers == 0),
+        }
+    }
+}
+
+/// Create an anonymous pipe. Returns the (read, write) ends.
+pub fn pipe() -> (FileHandle, FileHandle) {
+    let pipe = Pipe::new();
+    let reader = FileHandle::new(Box::new(PipeEnd::new(&pipe, true, false)), O_RDONLY);
+    let writer = FileHandle::new(Box::new(PipeEnd::new(&pipe, false, true)), O_WRONLY);
+    (reader, writer)
+}
+
+/// Pipes of the FIFO nodes that are open, by the node's file ID. A pipe
+/// lasts while an end is open; its node lives on the filesystem.
+static FIFOS: Mutex<BTreeMap<FileId, Weak<Pipe>>> = Mutex::new(BTreeMap::new());
+
+/// The pipe of the FIFO node `id`, made when the first end opens
+pub fn fifo(id: FileId) -> Arc<Pipe> {
+    let mut fifos = FIFOS.lock();
+    if let Some(pipe) = fifos.get(&id).and_then(Weak::upgrade) {
+        return pipe;
+    }
+    fifos.retain(|_, pipe| pipe.strong_count() > 0);
+    let pipe = Pipe::new();
+    fifos.insert(id, Arc::downgrade(&pipe));
+    pipe
+}
+
+/// Open one end of a FIFO. Blocks until the other end is opened, unless
+/// O_NONBLOCK is set: then a reader opens immediately and a writer fails
+/// with ENXIO if there is no reader. O_RDWR opens both ends at once, as on
+/// Darwin.
+pub fn open_fifo(pipe: Arc<Pipe>, flags: u32) -> Result<FileHandle, u64> {
+    let nonblock = flags & O_NONBLOCK != 0;
+    match flags & O_ACCMODE {
+        O_RDONLY => {
+            let start = pipe.state.lock().writer_opens;
+            let end = PipeEnd::new(&pipe, true, false);
+            if !nonblock {
+                pipe.readable.wait_until(|| {
+                    let state = pipe.state.lock();
+                    (state.writers > 0 || state.writer_opens != start).then_some(())
+                });
+            }
+            Ok(FileHandle::new(Box::new(end), flags))
+        }
+        O_WRONLY => {
+            if nonblock && pipe.state.lock().readers == 0 {
+                return Err(ENXIO);
+            }
+            let start = pipe.state.lock().reader_opens;
+            let end = PipeEnd::new(&pipe, false, true);
+            pipe.writable.wait_until(|| {
+                let state = pipe.state.lock();
+                (state.readers > 0 || state.reader_opens != start).then_some(())
+            });
+            Ok(FileHandle::new(Box::new(end), flags))
+        }
+        O_RDWR => Ok(FileHandle::new(
+            Box::new(PipeEnd::new(&pipe, true, true)),
+            flags,
+        )),
+        _ => Err(EINVAL),
+    }
+}
@@ -0,0 +1,515 @@
+//! Description of the machine, read from the devicetree the boot loader
+//! passes
+//!
+//! It is parsed before the heap exists, so lists have fixed capacities.
+//! Anything the devicetree doesn't describe keeps the layout of QEMU's virt
+//! machine, which is also used whole when booted without one.
+
+use crate::fdt::{self, Fdt, Node};
+use crate::kprintln;
+use spin::Once;
+
+const MAX_MEMORY_REGIONS: usize = 8;
+const MAX_VIRTIO_MMIO: usize = 32;
+const MAX_INTX_ROUTES: usize = 32;
+const MAX_BOOTARGS: usize = 1024;
+
+const GIC_COMPATIBLE: [&str; 3] = ["arm,cortex-a15-gic", "arm,gic-400", "arm,cortex-a9-gic"];
+const TIMER_COMPATIBLE: [&str; 2] = ["arm,armv8-timer", "arm,armv7-timer"];
+const UART_COMPATIBLE: &str = "arm,pl011";
+const PCI_COMPATIBLE: &str = "pci-host-ecam-generic";
+const VIRTIO_MMIO_COMPATIBLE: &str = "virtio,mmio";
+
+// GIC interrupt specifier types
+const GIC_SPI: u32 = 0;
+const GIC_PPI: u32 = 1;
+
+/// Index of the virtual timer among the timer node's interrupts
+const TIMER_VIRTUAL: usize = 2;
+
+/// Space code of 32-bit memory in the high cell of a PCI address
+const PCI_SPACE_MEM32: u32 = 0x0200_0000;
+const PCI_SPACE_MASK: u32 = 0x0300_0000;
+
+/// GIC interrupt of PCI INTA at slot 0 on QEMU virt, which swizzles the
+/// four INTx pins of each slot over four consecutive SPIs
+const QEMU_PCI_INTX_IRQ_BASE: u32 = 32 + 3;
+
+#[derive(Clone, Copy)]
+pub struct Region {
+    pub base: u64,
+    pub size: u64,
+}
+
+impl Region {
+    const EMPTY: Region = Region { base: 0, size: 0 };
+
+    pub fn end(&self) -> u64 {
+        self.base + self.size
+    }
+
+    pub fn overlaps(&self, base: u64, size: u64) -> bool {
+        base < self.end() && self.base < base + size
+    }
+}
+
+/// A memory-mapped device and its GIC interrupt
+#[derive(Clone, Copy)]
+pub struct MmioDevice {
+    pub region: Region,
+    pub irq: u32,
+}
+
+impl MmioDevice {
+    const EMPTY: MmioDevice = MmioDevice {
+        region: Region::EMPTY,
+        irq: 0,
+    };
+}
+
+/// A list with a fixed capacity. Entries past it are dropped.
+struct List<T: Copy, const N: usize> {
+    items: [T; N],
+    len: usize,
+}
+
+impl<T: Copy, const N: usize> List<T, N> {
+    const fn new(empty: T) -> Self {
+        Self {
+            items: [empty; N],
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, item: T) {
+        if self.len < N {
+            self.items[self.len] = item;
+            self.len += 1;
+        }
+    }
+
+    fn as_slice(&self) -> &[T] {
+        &self.items[..self.len]
+    }
+}
+
+/// Where a PCI interrupt pin is wired, from the host bridge's interrupt-map
+#[derive(Clone, Copy)]
+struct IntxRoute {
+    /// High cell of the device's address, masked
+    address: u32,
+    /// Pin, 1 for INTA, masked
+    pin: u32,
+    irq: u32,
+}
+
+pub struct PciHost {
+    /// Memory-mapped configuration space
+    pub ecam: Region,
+    pub first_bus: u8,
+    /// Window of 32-bit memory BARs
+    pub mmio: Region,
+    intx_routes: List<IntxRoute, MAX_INTX_ROUTES>,
+    /// interrupt-map-mask of the address high cell and of the pin
+    intx_mask: (u32, u32),
+}
+
+impl PciHost {
+    /// GIC interrupt of a device's INTx `pin`, 1 for INTA
+    pub fn intx_irq(&self, bus: u8, dev: u8, func: u8, pin: u8) -> Option<u32> {
+        if self.intx_routes.as_slice().is_empty() {
+            let swizzled = (dev as u32 + pin as u32 - 1) % 4;
+            return Some(QEMU_PCI_INTX_IRQ_BASE + swizzled);
+        }
+        let address = (bus as u32) << 16 | (dev as u32) << 11 | (func as u32) << 8;
+        let (address_mask, pin_mask) = self.intx_mask;
+        self.intx_routes
+            .as_slice()
+            .iter()
+            .find(|route| {
+                route.address == address & address_mask && route.pin == pin as u32 & pin_mask
+            })
+            .map(|route| route.irq)
+    }
+}
+
+pub struct Platform {
+    memory: List<Region, MAX_MEMORY_REGIONS>,
+    pub uart: MmioDevice,
+    pub gic_distributor: Region,
+    pub gic_cpu_interface: Region,
+    /// PPI of the virtual timer
+    pub timer_irq: u32,
+    pub pci: Option<PciHost>,
+    virtio_mmio: List<MmioDevice, MAX_VIRTIO_MMIO>,
+    bootargs: List<u8, MAX_BOOTARGS>,
+    /// The devicetree blob itself, if there was one
+    pub fdt: Option<Region>,
+    /// Initial ramdisk loaded by the boot loader
+    pub initrd: Option<Region>,
+}
+
+impl Platform {
+    /// RAM regions
+    pub fn memory(&self) -> &[Region] {
+        self.memory.as_slice()
+    }
+
+    /// Bytes of RAM
+    pub fn memory_size(&self) -> u64 {
+        self.memory().iter().map(|region| region.size).sum()
+    }
+
+    /// virtio-mmio transports, present or not
+    pub fn virtio_mmio(&self) -> &[MmioDevice] {
+        self.virtio_mmio.as_slice()
+    }
+
+    /// Kernel command line from /chosen
+    pub fn bootargs(&self) -> &str {
+        core::str::from_utf8(self.bootargs.as_slice()).unwrap_or("")
+    }
+}
+
+/// QEMU virt with 1 GiB of RAM and highmem=off
+const QEMU_VIRT: Platform = Platform {
+    memory: {
+        let mut memory = List::new(Region::EMPTY);
+        memory.items[0] = Region {
+            base: 0x4000_0000,
+            size: 0x4000_0000,
+        };
+        memory.len = 1;
+        memory
+    },
+    uart: MmioDevice {
+        region: Region {
+            base: 0x0900_0000,
+            size: 0x1000,
+        },
+        irq: 32 + 1,
+    },
+    gic_distributor: Region {
+        base: 0x0800_0000,
+        size: 0x1_0000,
+    },
+    gic_cpu_interface: Region {
+        base: 0x0801_0000,
+        size: 0x1_0000,
+    },
+    timer_irq: 16 + 11,
+    pci: Some(PciHost {
+        ecam: Region {
+            base: 0x3f00_0000,
+            size: 0x100_0000,
+        },
+        first_bus: 0,
+        mmio: Region {
+            base: 0x1000_0000,
+            size: 0x2eff_0000,
+        },
+        intx_routes: List::new(IntxRoute {
+            address: 0,
+            pin: 0,
+            irq: 0,
+        }),
+        intx_mask: (0, 0),
+    }),
+    virtio_mmio: List::new(MmioDevice::EMPTY),
+    bootargs: List::new(0),
+    fdt: None,
+    initrd: None,
+};
+
+static PLATFORM: Once<Platform> = Once::new();
+
+/// The machine, as found by `init`
+pub fn get() -> &'static Platform {
+    PLATFORM.get().unwrap_or(&QEMU_VIRT)
+}
+
+/// Read the devicetree at `fdt_addr`, the x0 of the boot loader. Must be
+/// called before anything uses `get`, while only the boot CPU runs.
+pub fn init(fdt_addr: usize) {
+    let fdt = unsafe { Fdt::from_addr(fdt_addr) };
+    let platform = PLATFORM.call_once(|| match fdt {
+        Some(fdt) => parse(&fdt),
+        None => QEMU_VIRT,
+    });
+    if fdt.is_none() {
+        kprintln!(
+            "Platform: No devicetree at {:x}, assuming QEMU virt",
+            fdt_addr
+        );
+        return;
+    }
+    for region in platform.memory() {
+        kprintln!("Platform: RAM {:x}-{:x}", region.base, region.end());
+    }
+    kprintln!(
+        "Platform: UART at {:x} irq {}, GIC at {:x}/{:x}, timer irq {}",
+        platform.uart.region.base,
+        platform.uart.irq,
+        platform.gic_distributor.base,
+        platform.gic_cpu_interface.base,
+        platform.timer_irq
+    );
+    if let Some(pci) = &platform.pci {
+        kprintln!(
+            "Platform: PCI ECAM at {:x}, memory window {:x}-{:x}",
+            pci.ecam.base,
+            pci.mmio.base,
+            pci.mmio.end()
+        );
+    }
+    if let Some(initrd) = &platform.initrd {
+        kprintln!("Platform: initrd at {:x}-{:x}", initrd.base, initrd.end());
+    }
+    kprintln!(
+        "Platform: {} virtio-mmio transports, bootargs \"{}\"",
+        platform.virtio_mmio().len(),
+        platform.bootargs()
+    );
+}
+
+/// The interrupt controller, which is assumed to be the parent of every
+/// interrupt
+struct Gic {
+    phandle: Option<u32>,
+    address_cells: u32,
+    interrupt_cells: u32,
+}
+
+/// GIC interrupt numbers of an `interrupts` property
+fn interrupts<'a>(node: &Node<'a>, gic: &Gic) -> impl Iterator<Item = u32> + 'a {
+    let mut cells = fdt::cells(node.prop("interrupts").unwrap_or(&[]));
+    let count = gic.interrupt_cells;
+    core::iter::from_fn(move || {
+        let mut spec = [0u32; 3];
+        for i in 0..count as usize {
+            let cell = cells.next()?;
+            if let Some(slot) = spec.get_mut(i) {
+                *slot = cell;
+            }
+        }
+        gic_irq(spec[0], spec[1])
+    })
+}
+
+fn gic_irq(kind: u32, number: u32) -> Option<u32> {
+    match kind {
+        GIC_SPI => Some(32 + number),
+        GIC_PPI => Some(16 + number),
+        _ => None,
+    }
+}
+
+/// An address property, which may be one or two cells
+fn address(value: &[u8]) -> Option<u64> {
+    fdt::read_number(&mut fdt::cells(value), value.len() as u32 / 4)
+}
+
+fn first_device(node: &Node, gic: &Gic) -> Option<MmioDevice> {
+    let (base, size) = node.reg().next()?;
+    Some(MmioDevice {
+        region: Region { base, size },
+        irq: interrupts(node, gic).next().unwrap_or(0),
+    })
+}
+
+fn parse(fdt: &Fdt) -> Platform {
+    let gic_node = fdt
+        .nodes()
+        .find(|node| GIC_COMPATIBLE.iter().any(|model| node.is_compatible(model)));
+    let gic = Gic {
+        phandle: gic_node.and_then(|node| node.prop_u32("phandle")),
+        address_cells: gic_node
+            .and_then(|node| node.prop_u32("#address-cells"))
+            .unwrap_or(0),
+        interrupt_cells: gic_node
+            .and_then(|node| node.prop_u32("#interrupt-cells"))
+            .unwrap_or(3),
+    };
+
+    let (fdt_base, fdt_size) = fdt.region();
+    let mut platform = QEMU_VIRT;
+    platform.memory = List::new(Region::EMPTY);
+    platform.pci = None;
+    platform.fdt = Some(Region {
+        base: fdt_base,
+        size: fdt_size,
+    });
+
+    let stdout = fdt
+        .nodes()
+        .find(|node| node.depth == 2 && node.name == "chosen")
+        .and_then(|chosen| {
+            if let Some(bootargs) = chosen.prop_str("bootargs") {
+                for &byte in bootargs.as_bytes() {
+                    platform.bootargs.push(byte);
+                }
+            }
+            if let (Some(start), Some(end)) = (
+                chosen.prop("linux,initrd-start").and_then(address),
+                chosen.prop("linux,initrd-end").and_then(address),
+            ) && start < end
+            {
+                platform.initrd = Some(Region {
+                    base: start,
+                    size: end - start,
+                });
+            }
+            chosen.prop_str("stdout-path")
+        })
+        .map(|path| {
+            // "/pl011@9000000:115200n8" names the last node of the path
+            let path = path.split(':').next().unwrap_or(path);
+            path.rsplit('/').next().unwrap_or(path)
+        });
+    let mut found_uart = false;
+    let mut uart_chosen = false;
+
+    for node in fdt.nodes() {
+        if node.depth == 2
+            && (node.base_name() == "memory" || node.prop_str("device_type") == Some("memory"))
+        {
+            for (base, size) in node.reg() {
+                platform.memory.push(Region { base, size });
+            }
+        } else if GIC_COMPATIBLE.iter().any(|model| node.is_compatible(model)) {
+            let mut reg = node.reg();
+            if let (Some(distributor), Some(cpu_interface)) = (reg.next(), reg.next()) {
+                platform.gic_distributor = Region {
+                    base: distributor.0,
+                    size: distributor.1,
+                };
+                platform.gic_cpu_interface = Region {
+                    base: cpu_interface.0,
+                    size: cpu_interface.1,
+                };
+            }
+        } else if TIMER_COMPATIBLE
+            .iter()
+            .any(|model| node.is_compatible(model))
+        {
+            if let Some(irq) = interrupts(&node, &gic).nth(TIMER_VIRTUAL) {
+                platform.timer_irq = irq;
+            }
+        } else if node.is_compatible(UART_COMPATIBLE) {
+            // The console named by stdout-path, or else the first
+            let preferred = stdout == Some(node.name);
+            if !uart_chosen
+                && (preferred || !found_uart)
+                && let Some(uart) = first_device(&node, &gic)
+            {
+                platform.uart = uart;
+                found_uart = true;
+                uart_chosen = preferred;
+            }
+        } else if node.is_compatible(PCI_COMPATIBLE) && platform.pci.is_none() {
+            platform.pci = parse_pci(fdt, &node, &gic);
+        } else if node.is_compatible(VIRTIO_MMIO_COMPATIBLE)
+            && let Some(device) = first_device(&node, &gic)
+        {
+            platform.virtio_mmio.push(device);
+        }
+    }
+
+    if platform.memory().is_empty() {
+        platform.memory = QEMU_VIRT.memory;
+    }
+    platform
+}
+
+fn parse_pci(fdt: &Fdt, node: &Node, gic: &Gic) -> Option<PciHost> {
+    let (ecam_base, ecam_size) = node.reg().next()?;
+    let first_bus = node
+        .prop("bus-range")
+        .and_then(|range| fdt::cells(range).next())
+        .unwrap_or(0) as u8;
+
+    // The host bridge's own cells, for its children's addresses
+    let address_cells = node.prop_u32("#address-cells").unwrap_or(3);
+    let size_cells = node.prop_u32("#size-cells").unwrap_or(2);
+
+    // ranges: (PCI address, CPU address, size)
+    let mut mmio = Region::EMPTY;
+    let mut cells = fdt::cells(node.prop("ranges").unwrap_or(&[]));
+    while let Some(space) = cells.next() {
+        let (Some(pci_address), Some(cpu_address), Some(size)) = (
+            fdt::read_number(&mut cells, address_cells.saturating_sub(1)),
+            fdt::read_number(&mut cells, node.address_cells),
+            fdt::read_number(&mut cells, size_cells),
+        ) else {
+            break;
+        };
+        if space & PCI_SPACE_MASK == PCI_SPACE_MEM32 && pci_address == cpu_address {
+            mmio = Region {
+                base: cpu_address,
+                size,
+            };
+        }
+    }
+
+    let mut intx_routes = List::new(IntxRoute {
+        address: 0,
+        pin: 0,
+        irq: 0,
+    });
+    let mask: [u32; 4] = node
+        .prop("interrupt-map-mask")
+        .and_then(|mask| {
+            let mut cells = fdt::cells(mask);
+            Some([cells.next()?, cells.next()?, cells.next()?, cells.next()?])
+        })
+        .unwrap_or([0; 4]);
+    let interrupt_cells = node.prop_u32("#interrupt-cells").unwrap_or(1);
+    let mut cells = fdt::cells(node.prop("interrupt-map").unwrap_or(&[]));
+    // Entries: child address, pin, parent phandle, parent address, parent
+    // interrupt specifier
+    while let Some(address) = cells.next() {
+        let mut entry = [0u32; 3];
+        let (Some(_), Some(pin), Some(parent)) = (
+            fdt::read_number(&mut cells, address_cells.saturating_sub(1)),
+            fdt::read_number(&mut cells, interrupt_cells),
+            cells.next(),
+        ) else {
+            break;
+        };
+        let pin = pin as u32;
+        let parent_address_cells = if Some(parent) == gic.phandle {
+            gic.address_cells
+        } else {
+            fdt.node_by_phandle(parent)
+                .and_then(|node| node.prop_u32("#address-cells"))
+                .unwrap_or(0)
+        };
+        if fdt::read_number(&mut cells, parent_address_cells).is_none() {
+            break;
+        }
+        for i in 0..gic.interrupt_cells as usize {
+            let Some(cell) = cells.next() else {
+                break;
+            };
+            if let Some(slot) = entry.get_mut(i) {
+                *slot = cell;
+            }
+        }
+        if Some(parent) == gic.phandle
+            && let Some(irq) = gic_irq(entry[0], entry[1])
+        {
+            intx_routes.push(IntxRoute { address, pin, irq });
+        }
+    }
+
+    Some(PciHost {
+        ecam: Region {
+            base: ecam_base,
+            size: ecam_size,
+        },
+        first_bus,
+        mmio,
+        intx_routes,
+        intx_mask: (mask[0], mask[3]),
+    })
+}
@@ -1,6 +1,28 @@
+use crate::bootargs;
+use crate::errno::{
+    EACCES, EBADF, EINTR, EINVAL, EIO, EMFILE, EMSGSIZE, ENAMETOOLONG, ENOENT, ENOMEM, ENOTDIR,
+    ENOTSUP, EPIPE, EROFS, ESRCH,
+};
+use crate::fdtable::{F_SETFD, FD_CLOEXEC, FdTable};
+use crate::hfsfs::HfsFs;
 use crate::kprintln;
-use crate::scheduler::SCHEDULER;
+use crate::kqueue::{self, KEVENT_FLAG_ERROR_EVENTS, KEVENT_FLAG_IMMEDIATE};
+use crate::mmu;
+use crate::pagecache;
+use crate::scheduler;
+use crate::socket::{self, Control, Creds, MSG_CTRUNC, MSG_TRUNC, SOL_SOCKET, SockAddr, Socket};
+use crate::timer;
+use crate::vfs::{
+    Attr, FileHandle, FileSystem, MNT_RDONLY, MNT_UPDATE, MountInfo, O_CLOEXEC, O_RDONLY, O_RDWR,
+    S_IFBLK, S_IFCHR, S_IFDIR, S_IFIFO, S_IFLNK, S_IFMT, S_IFREG, S_IFSOCK,
+};
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
 use core::arch::asm;
+use spin::Mutex;
 
 #[repr(C)]
 #[derive(Debug, Default)]
@@ -156,7 +178,7 @@ fn handle_a32_syscall(frame: &mut TrapFrame, iss: u32) {
     // SVC 0 (ISS=0) usually uses R7 for BSD syscalls.
     let syscall_num = if iss == 0x80 { r12 } else { r7 };
 
-    if syscall_num != 4 {
+    if syscall_num != 4 && bootargs::debug(bootargs::DEBUG_SYSCALLS) {
         // Don't spam write
         kprintln!(
             "A32 Syscall: num={} (ISS={:x}) R0={:x} R1={:x} R2={:x} R3={:x} R4={:x} PC={:x}",
@@ -198,7 +220,7 @@ fn handle_a32_mach_trap(frame: &mut TrapFrame, syscall_num: i32, _iss: u32) {
         }
         -26 => {
             // mach_reply_port
-            let mut sched = SCHEDULER.lock();
+            let mut sched = scheduler::this_cpu().lock();
             if let Some(space) = sched.current_ipc_space() {
                 space.allocate_port() as u64
             } else {
@@ -226,11 +248,12 @@ fn handle_a32_mach_trap(frame: &mut TrapFrame, syscall_num: i32, _iss: u32) {
             let rcv_name = frame.x[4] as u32;
             let timeout = frame.x[5] as u32;
 
-            let mut sched = SCHEDULER.lock();
+            let mut sched = scheduler::this_cpu().lock();
             if let Some(space) = sched.current_ipc_space() {
                 let res = crate::ipc::mach_msg(
                     msg, option, send_size, rcv_size, rcv_name, timeout, 0, space,
                 );
+                drop(sched);
 
                 if res == 0 && (option & crate::ipc::MACH_RCV_MSG) != 0 {
                     unsafe {
@@ -239,6 +262,11 @@ fn handle_a32_mach_trap(frame: &mut TrapFrame, syscall_num: i32, _iss: u32) {
                         core::ptr::write_volatile(msg, header);
                     }
                 }
+                // A kqueue may be watching the port. Waking has to wait
+                // until the run queue is unlocked.
+                if res == 0 && (option & crate::ipc::MACH_SEND_MSG) != 0 {
+                    crate::waitqueue::wake_any();
+                }
                 res as u64
             } else {
                 0x10000003
@@ -265,7 +293,9 @@ fn handle_a32_mach_trap(frame: &mut TrapFrame, syscall_num: i32, _iss: u32) {
         }
     };
     frame.x[0] = res;
-    kprintln!("Mach trap {} returned {:x}", syscall_num, res);
+    if bootargs::debug(bootargs::DEBUG_SYSCALLS) {
+        kprintln!("Mach trap {} returned {:x}", syscall_num, res);
+    }
 }
 
 fn handle_a32_syscall_internal(frame: &mut TrapFrame, syscall_num: i32) {
@@ -286,76 +316,58 @@ fn handle_a32_syscall_internal(frame: &mut TrapFrame, syscall_num: i32) {
             let buf_ptr = frame.x[1] as *mut u8;
             let len = frame.x[2] as usize;
 
-            let mut sched = SCHEDULER.lock();
-            let mut read_len = 0;
-            if let Some(proc) = sched.current_process.as_mut() {
-                if fd < proc.files.len() {
-                    if let Some(handle) = &mut proc.files[fd] {
-                        let slice = unsafe { core::slice::from_raw_parts_mut(buf_ptr, len) };
-                        read_len = handle.read(slice);
-                    }
-                }
-            }
-            frame.x[0] = read_len as u64;
-            frame.spsr &= !0x20000000;
+            let result = current_file(fd).ok_or(EBADF).and_then(|handle| {
+                let slice = unsafe { core::slice::from_raw_parts_mut(buf_ptr, len) };
+                handle.read(slice).map(|n| n as u64)
+            });
+            set_result(frame, result);
+        }
+        4 => {
+            let result = sys_write(frame.x[0], frame.x[1], frame.x[2]);
+            set_result(frame, result);
         }
-        4 => sys_write(frame.x[0], frame.x[1], frame.x[2]),
         5 => {
             // open(path, flags, mode)
-            let path_ptr = frame.x[0] as *const u8;
-            let mut path_buf = [0u8; 128];
-            let mut i = 0;
-            while i < 127 {
-                let c = unsafe { core::ptr::read(path_ptr.add(i)) };
-                if c == 0 {
-                    break;
-                }
-                path_buf[i] = c;
-                i += 1;
-            }
-            let path_str = core::str::from_utf8(&path_buf[..i]).unwrap_or("invalid");
-            kprintln!("sys_open: {}", path_str);
-
-            if let Some(handle) = crate::vfs::open(path_str) {
-                let mut sched = SCHEDULER.lock();
-                if let Some(proc) = sched.current_process.as_mut() {
-                    let mut found_fd = None;
-                    for (fd, slot) in proc.files.iter_mut().enumerate() {
-                        if slot.is_none() {
-                            *slot = Some(handle);
-                            found_fd = Some(fd);
-                            break;
-                        }
-                    }
-                    if let Some(fd) = found_fd {
-                        frame.x[0] = fd as u64;
-                        frame.spsr &= !0x20000000;
-                    } else {
-                        frame.x[0] = 24; // EMFILE
-                        frame.spsr |= 0x20000000;
-                    }
-                }
-            } else {
-                frame.x[0] = 2; // ENOENT
-                frame.spsr |= 0x20000000;
-            }
+            let path = read_user_str(frame.x[0] as *const u8);
+            kprintln!("sys_open: {}", path);
+            let result = sys_open(AT_FDCWD, &path, frame.x[1] as u32, frame.x[2] as u16);
+            set_result(frame, result);
         }
         6 => {
             // close(fd)
             let fd = frame.x[0] as usize;
-            let mut sched = SCHEDULER.lock();
-            if let Some(proc) = sched.current_process.as_mut() {
-                if fd < proc.files.len() {
-                    proc.files[fd] = None;
-                }
-            }
-            frame.x[0] = 0;
-            frame.spsr &= !0x20000000;
+            let result = with_files(|files| files.close(fd)).unwrap_or(Err(EBADF));
+            set_result(frame, result.map(|()| 0));
+        }
+        10 => {
+            // unlink(path)
+            let path = read_user_str(frame.x[0] as *const u8);
+            let result = sys_unlink(AT_FDCWD, &path, 0);
+            set_result(frame, result);
+        }
+        12 => {
+            // chdir(path)
+            let path = read_user_str(frame.x[0] as *const u8);
+            let result = at_path(AT_FDCWD, &path).and_then(|path| set_current_dir(&path));
+            set_result(frame, result);
+        }
+        13 => {
+            // fchdir(fd)
+            let result = current_file(frame.x[0] as usize)
+                .ok_or(EBADF)
+                .and_then(|handle| set_current_dir(handle.path().ok_or(ENOTDIR)?));
+            set_result(frame, result);
+        }
+        15 => {
+            // chmod(path, mode)
+            let path = read_user_str(frame.x[0] as *const u8);
+            let result = sys_chmod(AT_FDCWD, &path, frame.x[1] as u16, 0);
+            set_result(frame, result);
         }
         20 => {
             // getpid
             let pid = {
-                let sched = SCHEDULER.lock();
+                let sched = scheduler::this_cpu().lock();
                 sched.current_process.as_ref().map(|p| p.pid).unwrap_or(0)
             };
             frame.x[0] = pid as u64;
@@ -373,29 +385,122 @@ fn handle_a32_syscall_internal(frame: &mut TrapFrame, syscall_num: i32) {
             frame.x[0] = 20;
             frame.spsr &= !0x20000000;
         } // getgid
+        27 => {
+            // recvmsg(fd, msg, flags)
+            let result = sys_recvmsg(frame.x[0], frame.x[1] as *mut MsgHdr, frame.x[2] as u32);
+            set_result(frame, result);
+        }
+        28 => {
+            // sendmsg(fd, msg, flags)
+            let result = sys_sendmsg(frame.x[0], frame.x[1] as *const MsgHdr, frame.x[2] as u32);
+            set_result(frame, result);
+        }
+        29 => {
+            // recvfrom(fd, buf, len, flags, from, fromlen)
+            let result = sys_recvfrom(
+                frame.x[0],
+                frame.x[1] as *mut u8,
+                frame.x[2] as usize,
+                frame.x[3] as u32,
+                frame.x[4] as *mut u8,
+                frame.x[5] as *mut u32,
+            );
+            set_result(frame, result);
+        }
+        30 => {
+            // accept(fd, addr, addrlen)
+            let result = sys_accept(frame.x[0], frame.x[1] as *mut u8, frame.x[2] as *mut u32);
+            set_result(frame, result);
+        }
+        31 | 32 => {
+            // getpeername(fd, addr, addrlen) and getsockname(fd, addr, addrlen)
+            let (addr, lenp) = (frame.x[1] as *mut u8, frame.x[2] as *mut u32);
+            let result = with_socket(frame.x[0], |_, socket| {
+                let name = if syscall_num == 31 {
+                    socket.peer_addr()?
+                } else {
+                    socket.local_addr()?
+                };
+                socket::write_sockaddr(&name, addr, lenp);
+                Ok(0)
+            });
+            set_result(frame, result);
+        }
         33 => {
             // access(path, mode)
-            frame.x[0] = 2; // ENOENT
-            frame.spsr |= 0x20000000;
+            let path = read_user_str(frame.x[0] as *const u8);
+            let result = sys_access(AT_FDCWD, &path, frame.x[1] as u32, 0);
+            set_result(frame, result);
         }
         37 => {
-            // kill
+            // kill(pid, sig). Signals aren't delivered, but kqueues see them.
             kprintln!(
                 "A32 Syscall: num=37 (kill) pid={} sig={}",
                 frame.x[0],
                 frame.x[1]
             );
-            frame.x[0] = 0;
-            frame.spsr &= !0x20000000;
+            let result = sys_kill(frame.x[0] as i32, frame.x[1] as u32);
+            set_result(frame, result);
+        }
+        41 => {
+            // dup(fd)
+            let fd = frame.x[0] as usize;
+            let result = with_files(|files| files.dup(fd)).unwrap_or(Err(EBADF));
+            set_result(frame, result.map(|fd| fd as u64));
         }
         43 => {
             frame.x[0] = 20;
             frame.spsr &= !0x20000000;
         } // getegid
+        42 => {
+            // pipe() returns the read and write descriptors in r0 and r1
+            let (reader, writer) = crate::pipe::pipe();
+            let result = with_files(|files| {
+                let rfd = files.insert(0, Arc::new(reader), false)?;
+                match files.insert(0, Arc::new(writer), false) {
+                    Ok(wfd) => Ok((rfd, wfd)),
+                    Err(errno) => {
+                        let _ = files.close(rfd);
+                        Err(errno)
+                    }
+                }
+            })
+            .unwrap_or(Err(EMFILE));
+            match result {
+                Ok((rfd, wfd)) => {
+                    frame.x[1] = wfd as u64;
+                    set_result(frame, Ok(rfd as u64));
+                }
+                Err(errno) => set_result(frame, Err(errno)),
+            }
+        }
         46 => {
-            // sigaction
-            frame.x[0] = 0;
-            frame.spsr &= !0x20000000;
+            // sigaction(sig, act, oact)
+            let sig = frame.x[0] as usize;
+            let act = frame.x[1] as *const u32;
+            let oact = frame.x[2] as *mut u32;
+            if sig == 0 || sig >= 32 || (!act.is_null() && (sig == 9 || sig == 17)) {
+                // SIGKILL and SIGSTOP can't be caught or ignored
+                set_result(frame, Err(EINVAL));
+                return;
+            }
+
+            let mut sched = scheduler::this_cpu().lock();
+            if let Some(proc) = sched.current_process.as_mut() {
+                if !oact.is_null() {
+                    // struct sigaction { handler, mask, flags }
+                    unsafe {
+                        *oact = proc.signal_handlers[sig];
+                        *oact.add(1) = 0;
+                        *oact.add(2) = 0;
+                    }
+                }
+                if !act.is_null() {
+                    // struct __sigaction { handler, trampoline, mask, flags }
+                    proc.signal_handlers[sig] = unsafe { *act };
+                }
+            }
+            set_result(frame, Ok(0));
         }
         48 => {
             // sigprocmask(how, set, oset)
@@ -403,7 +508,7 @@ fn handle_a32_syscall_internal(frame: &mut TrapFrame, syscall_num: i32) {
             let set_ptr = frame.x[1] as *const u32;
             let oset_ptr = frame.x[2] as *mut u32;
 
-            let mut sched = SCHEDULER.lock();
+            let mut sched = scheduler::this_cpu().lock();
             if let Some(proc) = sched.current_process.as_mut() {
                 if !oset_ptr.is_null() {
                     unsafe {
@@ -432,40 +537,144 @@ fn handle_a32_syscall_internal(frame: &mut TrapFrame, syscall_num: i32) {
             }
         }
         54 => {
-            // ioctl
-            frame.x[0] = 0;
-            frame.spsr &= !0x20000000;
+            // ioctl(fd, cmd, arg)
+            let fd = frame.x[0] as usize;
+            let cmd = frame.x[1] as u32;
+            let arg = frame.x[2];
+            let result = match cmd {
+                FIOCLEX | FIONCLEX => {
+                    let cloexec = if cmd == FIOCLEX { FD_CLOEXEC } else { 0 };
+                    with_files(|files| files.fcntl(fd, F_SETFD, cloexec as u64))
+                        .unwrap_or(Err(EBADF))
+                }
+                _ => current_file(fd)
+                    .ok_or(EBADF)
+                    .and_then(|handle| handle.ioctl(cmd, arg)),
+            };
+            set_result(frame, result);
         }
         58 => {
-            // readlink
-            frame.x[0] = 2; // ENOENT
-            frame.spsr |= 0x20000000;
+            // readlink(path, buf, bufsize)
+            let path = read_user_str(frame.x[0] as *const u8);
+            let result = sys_readlink(AT_FDCWD, &path, frame.x[1] as *mut u8, frame.x[2] as usize);
+            set_result(frame, result);
+        }
+        57 => {
+            // symlink(target, path)
+            let target = read_user_str(frame.x[0] as *const u8);
+            let path = read_user_str(frame.x[1] as *const u8);
+            let result = sys_symlink(&target, AT_FDCWD, &path);
+            set_result(frame, result);
         }
         73 => {
             // munmap(addr, len)
-            frame.x[0] = 0;
-            frame.spsr &= !0x20000000;
+            let (addr, len) = (frame.x[0], frame.x[1]);
+            mmu::unmap_range(addr, len);
+            pagecache::unmap(addr, len);
+            set_result(frame, Ok(0));
         }
         74 => {
-            // mprotect(addr, len, prot)
-            frame.x[0] = 0;
-            frame.spsr &= !0x20000000;
+            // mprotect(addr, len, prot). Mappings stay accessible; only
+            // shared file pages need copying before they can be written.
+            let (addr, len, prot) = (frame.x[0], frame.x[1], frame.x[2] as u32);
+            let result = if prot & PROT_WRITE != 0 {
+                pagecache::make_writable(addr, len).map(|_| 0)
+            } else {
+                Ok(0)
+            };
+            set_result(frame, result);
+        }
+        90 => {
+            // dup2(old_fd, new_fd)
+            let old_fd = frame.x[0] as usize;
+            let new_fd = frame.x[1] as usize;
+            let result = with_files(|files| files.dup2(old_fd, new_fd)).unwrap_or(Err(EBADF));
+            set_result(frame, result.map(|fd| fd as u64));
         }
         92 => {
-            // fcntl
-            frame.x[0] = 0;
-            frame.spsr &= !0x20000000;
+            // fcntl(fd, cmd, arg)
+            let fd = frame.x[0] as usize;
+            let cmd = frame.x[1] as u32;
+            let arg = frame.x[2];
+            let result = if cmd == F_GETPATH {
+                sys_getpath(fd, arg as *mut u8)
+            } else {
+                with_files(|files| files.fcntl(fd, cmd, arg)).unwrap_or(Err(EBADF))
+            };
+            set_result(frame, result);
+        }
+        93 | 407 => {
+            // select(nfds, readfds, writefds, errorfds, timeout), select_nocancel
+            let sets = [frame.x[1], frame.x[2], frame.x[3]].map(|set| set as *mut u32);
+            let a32 = frame.spsr & 0x10 != 0;
+            let result = sys_select(
+                frame.x[0] as i32,
+                sets,
+                frame.x[4] as *const u8,
+                true,
+                core::ptr::null(),
+                a32,
+            );
+            set_result(frame, result);
+        }
+        97 => {
+            // socket(domain, type, protocol)
+            let result = socket::create(frame.x[0] as u32, frame.x[1] as u32, frame.x[2] as u32)
+                .and_then(|handle| {
+                    with_files(|files| files.insert(0, Arc::new(handle), false))
+                        .unwrap_or(Err(EBADF))
+                });
+            set_result(frame, result.map(|fd| fd as u64));
+        }
+        98 => {
+            // connect(fd, addr, addrlen)
+            let (addr, len) = (frame.x[1] as *const u8, frame.x[2] as usize);
+            let result = with_socket(frame.x[0], |handle, socket| {
+                let addr = read_sockaddr(addr, len)?;
+                socket
+                    .connect(addr, socket::nonblocking(handle, 0))
+                    .map(|()| 0)
+            });
+            set_result(frame, result);
         }
         100 => {
             // getpriority
             frame.x[0] = 0;
             frame.spsr &= !0x20000000;
         }
+        104 => {
+            // bind(fd, addr, addrlen)
+            let (addr, len) = (frame.x[1] as *const u8, frame.x[2] as usize);
+            let result = with_socket(frame.x[0], |_, socket| {
+                socket.bind(read_sockaddr(addr, len)?).map(|()| 0)
+            });
+            set_result(frame, result);
+        }
+        105 => {
+            // setsockopt(fd, level, name, value, len). Every option we
+            // support is an int.
+            let (level, name) = (frame.x[1] as u32, frame.x[2] as u32);
+            let (value, len) = (frame.x[3] as *const i32, frame.x[4] as usize);
+            let result = with_socket(frame.x[0], |_, socket| {
+                if value.is_null() || len < size_of::<i32>() {
+                    return Err(EINVAL);
+                }
+                let value = unsafe { core::ptr::read_unaligned(value) };
+                socket.set_option(level, name, value).map(|()| 0)
+            });
+            set_result(frame, result);
+        }
+        106 => {
+            // listen(fd, backlog)
+            let backlog = frame.x[1] as u32;
+            let result = with_socket(frame.x[0], |_, socket| socket.listen(backlog).map(|()| 0));
+            set_result(frame, result);
+        }
         116 => {
             // gettimeofday
             let tv = frame.x[0] as *mut u32;
             if !tv.is_null() {
-                static mut SEC: u32 = 1643328000; // Jan 27 2022-ish
+                static mut SEC: u32 = timer::BOOT_TIME as u32;
                 static mut USEC: u32 = 0;
                 unsafe {
                     USEC += 1000;
@@ -480,40 +689,226 @@ fn handle_a32_syscall_internal(frame: &mut TrapFrame, syscall_num: i32) {
             frame.x[0] = 0;
             frame.spsr &= !0x20000000;
         }
+        118 => {
+            // getsockopt(fd, level, name, value, len)
+            let (level, name) = (frame.x[1] as u32, frame.x[2] as u32);
+            let (value, lenp) = (frame.x[3] as *mut i32, frame.x[4] as *mut u32);
+            let result = with_socket(frame.x[0], |_, socket| {
+                if value.is_null() || lenp.is_null() || unsafe { *lenp } < size_of::<i32>() as u32 {
+                    return Err(EINVAL);
+                }
+                let option = socket.get_option(level, name)?;
+                unsafe {
+                    core::ptr::write_unaligned(value, option);
+                    *lenp = size_of::<i32>() as u32;
+                }
+                Ok(0)
+            });
+            set_result(frame, result);
+        }
+        124 => {
+            // fchmod(fd, mode)
+            let result = current_file(frame.x[0] as usize)
+                .ok_or(EBADF)
+                .and_then(|handle| {
+                    crate::vfs::chmod(handle.path().ok_or(EINVAL)?, frame.x[1] as u16)
+                });
+            set_result(frame, result.map(|()| 0));
+        }
         126 => {
             // setreuid
             frame.x[0] = 0;
             frame.spsr &= !0x20000000;
         }
+        128 => {
+            // rename(from, to)
+            let from = read_user_str(frame.x[0] as *const u8);
+            let to = read_user_str(frame.x[1] as *const u8);
+            let result = sys_rename(AT_FDCWD, &from, AT_FDCWD, &to);
+            set_result(frame, result);
+        }
+        132 => {
+            // mkfifo(path, mode)
+            let path = read_user_str(frame.x[0] as *const u8);
+            let mode = frame.x[1] as u16;
+            let result = at_path(AT_FDCWD, &path).and_then(|path| crate::vfs::mkfifo(&path, mode));
+            set_result(frame, result.map(|()| 0));
+        }
+        133 => {
+            // sendto(fd, buf, len, flags, to, tolen)
+            let result = sys_sendto(
+                frame.x[0],
+                frame.x[1] as *const u8,
+                frame.x[2] as usize,
+                frame.x[3] as u32,
+                frame.x[4] as *const u8,
+                frame.x[5] as usize,
+            );
+            set_result(frame, result);
+        }
+        134 => {
+            // shutdown(fd, how)
+            let how = frame.x[1] as u32;
+            let result = with_socket(frame.x[0], |_, socket| socket.shutdown(how).map(|()| 0));
+            set_result(frame, result);
+        }
+        135 => {
+            // socketpair(domain, type, protocol, fds)
+            let fds = frame.x[3] as *mut i32;
+            let result = socket::pair(frame.x[0] as u32, frame.x[1] as u32, frame.x[2] as u32)
+                .and_then(|(a, b)| {
+                    with_files(|files| {
+                        let afd = files.insert(0, Arc::new(a), false)?;
+                        match files.insert(0, Arc::new(b), false) {
+                            Ok(bfd) => Ok((afd, bfd)),
+                            Err(errno) => {
+                                let _ = files.close(afd);
+                                Err(errno)
+                            }
+                        }
+                    })
+                    .unwrap_or(Err(EBADF))
+                });
+            let result = result.map(|(afd, bfd)| {
+                unsafe {
+                    core::ptr::write_unaligned(fds, afd as i32);
+                    core::ptr::write_unaligned(fds.add(1), bfd as i32);
+                }
+                0
+            });
+            set_result(frame, result);
+        }
+        136 => {
+            // mkdir(path, mode)
+            let path = read_user_str(frame.x[0] as *const u8);
+            let result = sys_mkdir(AT_FDCWD, &path, frame.x[1] as u16);
+            set_result(frame, result);
+        }
+        137 => {
+            // rmdir(path)
+            let path = read_user_str(frame.x[0] as *const u8);
+            let result = sys_unlink(AT_FDCWD, &path, AT_REMOVEDIR);
+            set_result(frame, result);
+        }
+        138 => {
+            // utimes(path, times)
+            let path = read_user_str(frame.x[0] as *const u8);
+            let a32 = frame.spsr & 0x10 != 0;
+            let result = at_path(AT_FDCWD, &path)
+                .and_then(|path| sys_utimes(&path, frame.x[1] as *const u8, a32));
+            set_result(frame, result);
+        }
+        139 => {
+            // futimes(fd, times)
+            let a32 = frame.spsr & 0x10 != 0;
+            let result = current_file(frame.x[0] as usize)
+                .ok_or(EBADF)
+                .and_then(|handle| {
+                    sys_utimes(handle.path().ok_or(EINVAL)?, frame.x[1] as *const u8, a32)
+                });
+            set_result(frame, result);
+        }
+        159 => {
+            // unmount(path, flags)
+            let path = read_user_str(frame.x[0] as *const u8);
+            let result = at_path(AT_FDCWD, &path).and_then(|path| crate::vfs::unmount(&path));
+            set_result(frame, result.map(|()| 0));
+        }
+        167 => {
+            // mount(type, path, flags, data)
+            let fs_type = read_user_str(frame.x[0] as *const u8);
+            let path = read_user_str(frame.x[1] as *const u8);
+            let flags = frame.x[2] as u32;
+            let result = at_path(AT_FDCWD, &path)
+                .and_then(|path| sys_mount(&fs_type, &path, flags, frame.x[3] as *const u8));
+            set_result(frame, result);
+        }
         153 => {
             // pread(fd, buf, len, offset)
             let fd = frame.x[0] as usize;
             let buf_ptr = frame.x[1] as *mut u8;
             let len = frame.x[2] as usize;
+            // 64-bit offset in an aligned register pair
+            let offset = frame.x[4] | (frame.x[5] << 32);
 
-            let mut sched = SCHEDULER.lock();
-            let mut read_len = 0;
-            if let Some(proc) = sched.current_process.as_mut() {
-                if fd < proc.files.len() {
-                    if let Some(handle) = &mut proc.files[fd] {
-                        let slice = unsafe { core::slice::from_raw_parts_mut(buf_ptr, len) };
-                        read_len = handle.read(slice);
-                    }
+            let result = current_file(fd).ok_or(EBADF).map(|handle| {
+                let slice = unsafe { core::slice::from_raw_parts_mut(buf_ptr, len) };
+                handle.read_at(offset, slice) as u64
+            });
+            set_result(frame, result);
+        }
+        194 => {
+            // getrlimit(resource, rlp)
+            let resource = frame.x[0] as u32;
+            let rlp = frame.x[1] as *mut u64;
+            let limits = if resource == RLIMIT_NOFILE {
+                with_files(|files| files.limits())
+            } else {
+                Some((RLIM_INFINITY, RLIM_INFINITY))
+            };
+            if let Some((cur, max)) = limits {
+                unsafe {
+                    *rlp = cur;
+                    *rlp.add(1) = max;
                 }
             }
-            frame.x[0] = read_len as u64;
-            frame.spsr &= !0x20000000;
+            set_result(frame, Ok(0));
+        }
+        195 => {
+            // setrlimit(resource, rlp)
+            let resource = frame.x[0] as u32;
+            let rlp = frame.x[1] as *const u64;
+            let (cur, max) = unsafe { (*rlp, *rlp.add(1)) };
+            let result = if resource == RLIMIT_NOFILE {
+                with_files(|files| files.set_limits(cur, max)).unwrap_or(Err(EINVAL))
+            } else {
+                Ok(())
+            };
+            set_result(frame, result.map(|()| 0));
         }
         196 => {
-            // getdirentries
-            frame.x[0] = 0; // End of entries
-            frame.spsr &= !0x20000000;
+            // getdirentries(fd, buf, nbytes, basep)
+            let basep = frame.x[3] as *mut u32;
+            let result = sys_getdirentries(
+                frame.x[0] as usize,
+                frame.x[1] as *mut u8,
+                frame.x[2] as usize,
+                false,
+            );
+            set_result(
+                frame,
+                result.map(|(used, base)| {
+                    if !basep.is_null() {
+                        unsafe { *basep = base as u32 };
+                    }
+                    used
+                }),
+            );
         }
         197 => {
-            // mmap(addr, len, prot, flags, fd, offset)
+            // mmap(addr, len, prot, flags, fd, offset). Only the low word of
+            // the offset is read; the high word is passed on the user stack.
             let addr = frame.x[0];
             let len = frame.x[1];
+            let prot = frame.x[2] as u32;
+            let flags = frame.x[3] as u32;
             let fd = frame.x[4] as i32;
+            let offset = frame.x[6] & 0xFFFF_FFFF;
+            if len == 0 {
+                set_result(frame, Err(EINVAL));
+                return;
+            }
+            let file = if flags & MAP_ANON != 0 {
+                None
+            } else {
+                match current_file(fd as usize) {
+                    Some(handle) => Some(handle),
+                    None => {
+                        set_result(frame, Err(EBADF));
+                        return;
+                    }
+                }
+            };
 
             let map_addr = if addr == 0 {
                 static mut NEXT_MMAP: u64 = 0x70000000;
@@ -526,35 +921,42 @@ fn handle_a32_syscall_internal(frame: &mut TrapFrame, syscall_num: i32) {
                 addr
             };
 
-            // Allocate physical memory for the mapping from heap
-            let layout = core::alloc::Layout::from_size_align(len as usize, 4096).unwrap();
-            let phys_ptr = unsafe { alloc::alloc::alloc_zeroed(layout) };
-            if phys_ptr.is_null() {
-                frame.x[0] = 12; // ENOMEM
-                frame.spsr |= 0x20000000;
-                return;
-            }
-            let paddr = phys_ptr as u64;
-
-            crate::mmu::map_range(map_addr, paddr, len, crate::mmu::MapPermission::UserRWX);
-
-            if fd != -1 {
-                let mut sched = SCHEDULER.lock();
-                if let Some(proc) = sched.current_process.as_mut() {
-                    let fd = fd as usize;
-                    if fd < proc.files.len() {
-                        if let Some(handle) = &mut proc.files[fd] {
-                            let slice = unsafe {
-                                core::slice::from_raw_parts_mut(map_addr as *mut u8, len as usize)
-                            };
-                            handle.read(slice);
-                        }
-                    }
-                }
+            let result = match file {
+                Some(handle) => map_file(&handle, map_addr, offset, len, prot),
+                None => pagecache::map_anon(map_addr, len),
+            };
+            set_result(frame, result.map(|_| map_addr));
+        }
+        199 => {
+            // lseek(fd, offset, whence)
+            let fd = frame.x[0] as usize;
+            let offset = (frame.x[2] | (frame.x[3] << 32)) as i64;
+            let whence = frame.x[4] as u32;
+            let result = current_file(fd)
+                .ok_or(EBADF)
+                .and_then(|handle| handle.seek(offset, whence));
+            set_result(frame, result);
+            if result.is_ok() {
+                // off_t is returned in r0:r1
+                frame.x[1] = frame.x[0] >> 32;
+                frame.x[0] &= 0xFFFF_FFFF;
             }
-
-            frame.x[0] = map_addr;
-            frame.spsr &= !0x20000000;
+        }
+        200 => {
+            // truncate(path, length), with the length in an aligned register pair
+            let path = read_user_str(frame.x[0] as *const u8);
+            let length = frame.x[2] | (frame.x[3] << 32);
+            let result =
+                at_path(AT_FDCWD, &path).and_then(|path| crate::vfs::truncate(&path, length));
+            set_result(frame, result.map(|()| 0));
+        }
+        201 => {
+            // ftruncate(fd, length)
+            let length = frame.x[2] | (frame.x[3] << 32);
+            let result = current_file(frame.x[0] as usize)
+                .ok_or(EBADF)
+                .and_then(|handle| handle.truncate(length));
+            set_result(frame, result.map(|()| 0));
         }
         202 => {
             // sysctl
@@ -566,9 +968,29 @@ fn handle_a32_syscall_internal(frame: &mut TrapFrame, syscall_num: i32) {
             if namelen >= 2 {
                 let m0 = unsafe { *name };
                 let m1 = unsafe { *name.add(1) };
+                if m0 == 0 && m1 == 3 {
+                    // CTL_SYSCTL name2oid, with the name in newp
+                    let newp = frame.x[4] as *const u8;
+                    let newlen = frame.x[5] as usize;
+                    let requested = unsafe { core::slice::from_raw_parts(newp, newlen) };
+                    if requested == b"kern.bootargs" && !oldp.is_null() {
+                        unsafe {
+                            *(oldp as *mut [i32; 2]) = [1, KERN_BOOTARGS];
+                            if !oldlenp.is_null() {
+                                *oldlenp = 8;
+                            }
+                        }
+                        set_result(frame, Ok(0));
+                        return;
+                    }
+                }
                 if m0 == 1 {
                     // CTL_KERN
                     match m1 {
+                        KERN_BOOTARGS => {
+                            set_result(frame, sysctl_string(bootargs::line(), oldp, oldlenp));
+                            return;
+                        }
                         1 => {
                             // KERN_OSTYPE
                             if !oldp.is_null() {
@@ -630,11 +1052,11 @@ fn handle_a32_syscall_internal(frame: &mut TrapFrame, syscall_num: i32) {
                                 }
                             }
                         }
-                        3 => {
-                            // HW_NCPU
+                        3 | 25 => {
+                            // HW_NCPU, HW_AVAILCPU
                             if !oldp.is_null() {
                                 unsafe {
-                                    *(oldp as *mut u32) = 1;
+                                    *(oldp as *mut u32) = crate::smp::ncpus() as u32;
                                 }
                             }
                             if !oldlenp.is_null() {
@@ -647,7 +1069,7 @@ fn handle_a32_syscall_internal(frame: &mut TrapFrame, syscall_num: i32) {
                             // HW_MEMSIZE (int64)
                             if !oldp.is_null() {
                                 unsafe {
-                                    *(oldp as *mut u64) = 1024 * 1024 * 1024;
+                                    *(oldp as *mut u64) = crate::platform::get().memory_size();
                                 }
                             }
                             if !oldlenp.is_null() {
@@ -664,12 +1086,43 @@ fn handle_a32_syscall_internal(frame: &mut TrapFrame, syscall_num: i32) {
             frame.spsr &= !0x20000000;
         }
         220 => {
-            // getattrlist
-            frame.x[0] = 2; // ENOENT
-            frame.spsr |= 0x20000000;
+            // getattrlist(path, attrlist, attrbuf, bufsize, options)
+            let path = read_user_str(frame.x[0] as *const u8);
+            let result = at_path(AT_FDCWD, &path).and_then(|path| {
+                sys_getattrlist(
+                    &path,
+                    frame.x[1] as *const u32,
+                    frame.x[2] as *mut u8,
+                    frame.x[3] as usize,
+                    frame.x[4] as u32,
+                    frame.spsr & 0x10 != 0,
+                )
+            });
+            set_result(frame, result);
+        }
+        230 | 417 => {
+            // poll(fds, nfds, timeout), poll_nocancel
+            let result = sys_poll(
+                frame.x[0] as *mut PollFd,
+                frame.x[1] as usize,
+                frame.x[2] as i32,
+            );
+            set_result(frame, result);
         }
         274 => {
-            // sysctlbyname
+            // sysctlbyname(name, namelen, oldp, oldlenp, newp, newlen)
+            let name = unsafe {
+                core::slice::from_raw_parts(frame.x[0] as *const u8, frame.x[1] as usize)
+            };
+            if name == b"kern.bootargs" {
+                let result = sysctl_string(
+                    bootargs::line(),
+                    frame.x[2] as *mut u8,
+                    frame.x[3] as *mut u32,
+                );
+                set_result(frame, result);
+                return;
+            }
             frame.x[0] = 0;
             frame.spsr &= !0x20000000;
         }
@@ -693,12 +1146,14 @@ fn handle_a32_syscall_internal(frame: &mut TrapFrame, syscall_num: i32) {
             // getentropy(buf, len)
             let buf_ptr = frame.x[0] as *mut u8;
             let len = frame.x[1] as usize;
-            let slice = unsafe { core::slice::from_raw_parts_mut(buf_ptr, len) };
-            for b in slice.iter_mut() {
-                *b = 0x42; // "Random"
-            }
-            frame.x[0] = 0;
-            frame.spsr &= !0x20000000;
+            let result = if len > GETENTROPY_MAX {
+                Err(EIO)
+            } else {
+                let slice = unsafe { core::slice::from_raw_parts_mut(buf_ptr, len) };
+                crate::entropy::fill(slice);
+                Ok(0)
+            };
+            set_result(frame, result);
         }
         327 => {
             // issetugid
@@ -708,83 +1163,200 @@ fn handle_a32_syscall_internal(frame: &mut TrapFrame, syscall_num: i32) {
         }
         338 => {
             // stat64(path, buf)
-            let path_ptr = frame.x[0] as *const u8;
-            let stat_ptr = frame.x[1] as *mut u8;
-            let mut path_buf = [0u8; 128];
-            let mut i = 0;
-            while i < 127 {
-                let c = unsafe { core::ptr::read(path_ptr.add(i)) };
-                if c == 0 {
-                    break;
-                }
-                path_buf[i] = c;
-                i += 1;
-            }
-            let path_str = core::str::from_utf8(&path_buf[..i]).unwrap_or("");
-            if let Some(handle) = crate::vfs::open(path_str) {
-                unsafe {
-                    core::ptr::write_bytes(stat_ptr, 0, 100);
-                    // Darwin stat64: st_size is at offset 64 (8 bytes)
-                    *(stat_ptr.add(64) as *mut u64) = handle.size();
-                    // st_mode is at offset 4 (2 bytes)
-                    *(stat_ptr.add(4) as *mut u16) = 0o100644;
-                }
-                frame.x[0] = 0;
-                frame.spsr &= !0x20000000;
-            } else {
-                frame.x[0] = 2; // ENOENT
-                frame.spsr |= 0x20000000;
-            }
+            let path = read_user_str(frame.x[0] as *const u8);
+            let a32 = frame.spsr & 0x10 != 0;
+            let result = sys_stat(AT_FDCWD, &path, frame.x[1] as *mut u8, 0, a32);
+            set_result(frame, result);
         }
         339 => {
             // fstat64(fd, buf)
             let fd = frame.x[0] as usize;
             let stat_ptr = frame.x[1] as *mut u8;
-            let mut sched = SCHEDULER.lock();
-            if let Some(proc) = sched.current_process.as_mut() {
-                if fd < proc.files.len() {
-                    if let Some(handle) = &proc.files[fd] {
-                        unsafe {
-                            core::ptr::write_bytes(stat_ptr, 0, 100);
-                            *(stat_ptr.add(64) as *mut u64) = handle.size();
-                            *(stat_ptr.add(4) as *mut u16) = 0o100644;
-                        }
-                        frame.x[0] = 0;
-                        frame.spsr &= !0x20000000;
-                        return;
-                    }
-                }
-            }
-            frame.x[0] = 9; // EBADF
-            frame.spsr |= 0x20000000;
+            let a32 = frame.spsr & 0x10 != 0;
+            let result = current_file(fd).ok_or(EBADF).map(|handle| {
+                write_stat64(stat_ptr, &handle.getattr(), a32);
+                0
+            });
+            set_result(frame, result);
         }
         340 => {
-            // lstat64
-            let path_ptr = frame.x[0] as *const u8;
-            let stat_ptr = frame.x[1] as *mut u8;
-            let mut path_buf = [0u8; 128];
-            let mut i = 0;
-            while i < 127 {
-                let c = unsafe { core::ptr::read(path_ptr.add(i)) };
-                if c == 0 {
-                    break;
+            // lstat64(path, buf)
+            let path = read_user_str(frame.x[0] as *const u8);
+            let a32 = frame.spsr & 0x10 != 0;
+            let flags = AT_SYMLINK_NOFOLLOW;
+            let result = sys_stat(AT_FDCWD, &path, frame.x[1] as *mut u8, flags, a32);
+            set_result(frame, result);
+        }
+        344 => {
+            // getdirentries64(fd, buf, bufsize, position)
+            let position = frame.x[3] as *mut u64;
+            let bufsize = frame.x[2] as usize;
+            let result = if bufsize < dirent64_len(MAXNAMLEN) {
+                Err(EINVAL)
+            } else {
+                sys_getdirentries(frame.x[0] as usize, frame.x[1] as *mut u8, bufsize, true)
+            };
+            set_result(
+                frame,
+                result.map(|(used, base)| {
+                    if !position.is_null() {
+                        unsafe { *position = base };
+                    }
+                    used
+                }),
+            );
+        }
+        345 => {
+            // statfs64(path, buf)
+            let path = read_user_str(frame.x[0] as *const u8);
+            let result = at_path(AT_FDCWD, &path).and_then(|path| crate::vfs::statfs(&path));
+            let result = result.map(|mount| {
+                write_statfs64(frame.x[1] as *mut u8, &mount);
+                0
+            });
+            set_result(frame, result);
+        }
+        347 => {
+            // getfsstat64(buf, bufsize, flags)
+            let buf = frame.x[0] as *mut u8;
+            let count = frame.x[1] as usize / STATFS64_SIZE;
+            let mounts = crate::vfs::mounts();
+            if buf.is_null() {
+                set_result(frame, Ok(mounts.len() as u64));
+            } else {
+                let filled = mounts.len().min(count);
+                for (i, mount) in mounts.iter().take(filled).enumerate() {
+                    write_statfs64(unsafe { buf.add(i * STATFS64_SIZE) }, mount);
                 }
-                path_buf[i] = c;
-                i += 1;
+                set_result(frame, Ok(filled as u64));
             }
-            let path_str = core::str::from_utf8(&path_buf[..i]).unwrap_or("");
-            if let Some(handle) = crate::vfs::open(path_str) {
-                unsafe {
-                    core::ptr::write_bytes(stat_ptr, 0, 100);
-                    *(stat_ptr.add(64) as *mut u64) = handle.size();
-                    *(stat_ptr.add(4) as *mut u16) = 0o100644;
-                }
-                frame.x[0] = 0;
-                frame.spsr &= !0x20000000;
+        }
+        362 => {
+            // kqueue()
+            let pid = scheduler::this_cpu().lock().current_pid();
+            let handle = kqueue::create(pid);
+            let result =
+                with_files(|files| files.insert(0, Arc::new(handle), false)).unwrap_or(Err(EBADF));
+            set_result(frame, result.map(|fd| fd as u64));
+        }
+        363 => {
+            // kevent(kq, changelist, nchanges, eventlist, nevents, timeout)
+            let result = sys_kevent(
+                frame.x[0],
+                (frame.x[1] as *const u8, frame.x[2] as usize),
+                (frame.x[3] as *mut u8, frame.x[4] as usize),
+                0,
+                frame.x[5] as *const u8,
+                kqueue::Format::Kevent,
+            );
+            set_result(frame, result);
+        }
+        369 => {
+            // kevent64(kq, changelist, nchanges, eventlist, nevents, flags, timeout)
+            let result = sys_kevent(
+                frame.x[0],
+                (frame.x[1] as *const u8, frame.x[2] as usize),
+                (frame.x[3] as *mut u8, frame.x[4] as usize),
+                frame.x[5] as u32,
+                frame.x[6] as *const u8,
+                kqueue::Format::Kevent64,
+            );
+            set_result(frame, result);
+        }
+        374 => {
+            // kevent_qos(kq, changelist, nchanges, eventlist, nevents, data_out,
+            // data_available, flags). The eighth argument comes in r8.
+            let result = if frame.x[5] != 0 {
+                // Workloop output buffers aren't supported
+                Err(EINVAL)
             } else {
-                frame.x[0] = 2; // ENOENT
-                frame.spsr |= 0x20000000;
-            }
+                sys_kevent(
+                    frame.x[0],
+                    (frame.x[1] as *const u8, frame.x[2] as usize),
+                    (frame.x[3] as *mut u8, frame.x[4] as usize),
+                    frame.x[8] as u32,
+                    core::ptr::null(),
+                    kqueue::Format::Qos,
+                )
+            };
+            set_result(frame, result);
+        }
+        394 | 395 => {
+            // pselect(nfds, readfds, writefds, errorfds, timeout, sigmask),
+            // pselect_nocancel
+            let sets = [frame.x[1], frame.x[2], frame.x[3]].map(|set| set as *mut u32);
+            let a32 = frame.spsr & 0x10 != 0;
+            let result = sys_select(
+                frame.x[0] as i32,
+                sets,
+                frame.x[4] as *const u8,
+                false,
+                frame.x[5] as *const u32,
+                a32,
+            );
+            set_result(frame, result);
+        }
+        463 | 464 => {
+            // openat(dirfd, path, flags, mode), openat_nocancel
+            let path = read_user_str(frame.x[1] as *const u8);
+            let (flags, mode) = (frame.x[2] as u32, frame.x[3] as u16);
+            let result = sys_open(frame.x[0] as i32, &path, flags, mode);
+            set_result(frame, result);
+        }
+        465 => {
+            // renameat(fromfd, from, tofd, to)
+            let from = read_user_str(frame.x[1] as *const u8);
+            let to = read_user_str(frame.x[3] as *const u8);
+            let result = sys_rename(frame.x[0] as i32, &from, frame.x[2] as i32, &to);
+            set_result(frame, result);
+        }
+        466 => {
+            // faccessat(dirfd, path, mode, flags)
+            let path = read_user_str(frame.x[1] as *const u8);
+            let (mode, flags) = (frame.x[2] as u32, frame.x[3] as u32);
+            let result = sys_access(frame.x[0] as i32, &path, mode, flags);
+            set_result(frame, result);
+        }
+        467 => {
+            // fchmodat(dirfd, path, mode, flags)
+            let path = read_user_str(frame.x[1] as *const u8);
+            let (mode, flags) = (frame.x[2] as u16, frame.x[3] as u32);
+            let result = sys_chmod(frame.x[0] as i32, &path, mode, flags);
+            set_result(frame, result);
+        }
+        470 => {
+            // fstatat64(dirfd, path, buf, flags)
+            let path = read_user_str(frame.x[1] as *const u8);
+            let a32 = frame.spsr & 0x10 != 0;
+            let (buf, flags) = (frame.x[2] as *mut u8, frame.x[3] as u32);
+            let result = sys_stat(frame.x[0] as i32, &path, buf, flags, a32);
+            set_result(frame, result);
+        }
+        472 => {
+            // unlinkat(dirfd, path, flags)
+            let path = read_user_str(frame.x[1] as *const u8);
+            let result = sys_unlink(frame.x[0] as i32, &path, frame.x[2] as u32);
+            set_result(frame, result);
+        }
+        473 => {
+            // readlinkat(dirfd, path, buf, bufsize)
+            let path = read_user_str(frame.x[1] as *const u8);
+            let (buf, bufsize) = (frame.x[2] as *mut u8, frame.x[3] as usize);
+            let result = sys_readlink(frame.x[0] as i32, &path, buf, bufsize);
+            set_result(frame, result);
+        }
+        474 => {
+            // symlinkat(target, dirfd, path)
+            let target = read_user_str(frame.x[0] as *const u8);
+            let path = read_user_str(frame.x[2] as *const u8);
+            let result = sys_symlink(&target, frame.x[1] as i32, &path);
+            set_result(frame, result);
+        }
+        475 => {
+            // mkdirat(dirfd, path, mode)
+            let path = read_user_str(frame.x[1] as *const u8);
+            let result = sys_mkdir(frame.x[0] as i32, &path, frame.x[2] as u16);
+            set_result(frame, result);
         }
         423 => {
             // csops
@@ -808,78 +1380,44 @@ fn handle_a32_syscall_internal(frame: &mut TrapFrame, syscall_num: i32) {
                 count
             );
 
-            let mut sched = SCHEDULER.lock();
-            if let Some(proc) = sched.current_process.as_mut() {
-                if fd < proc.files.len() {
-                    if let Some(handle) = &mut proc.files[fd] {
-                        let file_size = handle.size();
-                        for i in 0..count {
-                            let m = unsafe { core::ptr::read(mappings.add(i)) };
-                            kprintln!(
-                                "  Mapping SR segment: addr={:x} size={:x} off={:x} prot={:x}",
-                                m.address,
-                                m.size,
-                                m.file_offset,
-                                m.init_prot
-                            );
-
-                            // Only allocate/load what's actually in the file
-                            let data_size = if m.file_offset < file_size {
-                                core::cmp::min(m.size, file_size - m.file_offset)
-                            } else {
-                                0
-                            };
-
-                            if data_size > 0 {
-                                // Allocate physical memory for the data part from heap
-                                let layout =
-                                    core::alloc::Layout::from_size_align(data_size as usize, 4096)
-                                        .unwrap();
-                                let phys_ptr = unsafe { alloc::alloc::alloc_zeroed(layout) };
-                                if phys_ptr.is_null() {
-                                    panic!(
-                                        "Failed to allocate physical memory for shared region segment (data size={:x})",
-                                        data_size
-                                    );
-                                }
-                                let paddr = phys_ptr as u64;
-
-                                // Map it
-                                crate::mmu::map_range(
-                                    m.address,
-                                    paddr,
-                                    data_size,
-                                    crate::mmu::MapPermission::UserRWX,
-                                );
-
-                                // Read data from shared cache file in 4KB chunks
-                                handle.seek(m.file_offset);
-                                let total_to_read = data_size as usize;
-                                let mut offset = 0;
-                                while offset < total_to_read {
-                                    let chunk_size = core::cmp::min(4096, total_to_read - offset);
-                                    let slice = unsafe {
-                                        core::slice::from_raw_parts_mut(
-                                            (m.address as usize + offset) as *mut u8,
-                                            chunk_size,
-                                        )
-                                    };
-                                    handle.read(slice);
-                                    offset += chunk_size;
-                                }
-                            }
+            if let Some(handle) = current_file(fd) {
+                let file_size = handle.size();
+                for i in 0..count {
+                    let m = unsafe { core::ptr::read(mappings.add(i)) };
+                    kprintln!(
+                        "  Mapping SR segment: addr={:x} size={:x} off={:x} prot={:x}",
+                        m.address,
+                        m.size,
+                        m.file_offset,
+                        m.init_prot
+                    );
 
-                            if m.size > data_size {
-                                kprintln!(
-                                    "  Segment {:x} has {:x} bytes of virtual padding",
-                                    m.address,
-                                    m.size - data_size
-                                );
-                            }
+                    // Only map what's actually in the file
+                    let data_size = if m.file_offset < file_size {
+                        core::cmp::min(m.size, file_size - m.file_offset)
+                    } else {
+                        0
+                    };
 
-                            kprintln!("  Mapped segment {:x} successfully", m.address);
-                        }
+                    if data_size > 0
+                        && let Err(errno) =
+                            map_file(&handle, m.address, m.file_offset, data_size, m.init_prot)
+                    {
+                        panic!(
+                            "Failed to map shared region segment (data size={:x}): errno {}",
+                            data_size, errno
+                        );
+                    }
+
+                    if m.size > data_size {
+                        kprintln!(
+                            "  Segment {:x} has {:x} bytes of virtual padding",
+                            m.address,
+                            m.size - data_size
+                        );
                     }
+
+                    kprintln!("  Mapped segment {:x} successfully", m.address);
                 }
             }
 
@@ -905,7 +1443,12 @@ fn handle_a64_syscall(frame: &mut TrapFrame) {
     match syscall_num {
         0 => sys_yield(),
         1 => sys_exit(),
-        2 => sys_write(frame.x[0], frame.x[1], frame.x[2]),
+        2 => {
+            frame.x[0] = match sys_write(frame.x[0], frame.x[1], frame.x[2]) {
+                Ok(n) => n,
+                Err(errno) => (-(errno as i64)) as u64,
+            }
+        }
         3 => frame.x[0] = sys_spawn(frame.x[0], frame.x[1]),
         4 => frame.x[0] = sys_getpid(),
         _ => {
@@ -914,50 +1457,1386 @@ fn handle_a64_syscall(frame: &mut TrapFrame) {
     }
 }
 
-fn sys_write(fd: u64, buf: u64, len: u64) {
-    if fd == 1 || fd == 2 || fd == 4 {
-        let slice = unsafe { core::slice::from_raw_parts(buf as *const u8, len as usize) };
-        if let Ok(s) = core::str::from_utf8(slice) {
-            kprintln!("sys_write: {}", s);
-        }
+fn sys_write(fd: u64, buf: u64, len: u64) -> Result<u64, u64> {
+    let handle = current_file(fd as usize).ok_or(EBADF)?;
+    let slice = unsafe { core::slice::from_raw_parts(buf as *const u8, len as usize) };
+    let result = handle.write(slice).map(|n| n as u64);
+    if result == Err(EPIPE) && socket::raises_sigpipe(&handle) {
+        raise_sigpipe();
     }
+    result
 }
 
-fn sys_yield() {
-    unsafe {
-        unsafe extern "C" {
-            fn __switch_to(prev: *mut CpuContext, next: *const CpuContext);
-        }
-
-        let pointers = {
-            let mut scheduler = SCHEDULER.lock();
-            scheduler.schedule_next()
-        };
+/// Run `f` on the socket open as descriptor `fd`
+fn with_socket<R>(
+    fd: u64,
+    f: impl FnOnce(&FileHandle, &dyn Socket) -> Result<R, u64>,
+) -> Result<R, u64> {
+    let handle = current_file(fd as usize).ok_or(EBADF)?;
+    f(&handle, socket::socket_of(&handle)?)
+}
 
-        if let Some((prev, next)) = pointers {
-            __switch_to(prev.expect("No prev context in sys_yield"), next);
-        }
+/// Read a struct sockaddr from user memory, making a UNIX domain socket's
+/// relative path absolute
+fn read_sockaddr(ptr: *const u8, len: usize) -> Result<SockAddr, u64> {
+    match socket::read_sockaddr(ptr, len)? {
+        SockAddr::Unix(path) if !path.is_empty() => Ok(SockAddr::Unix(at_path(AT_FDCWD, &path)?)),
+        addr => Ok(addr),
     }
 }
 
-fn sys_exit() {
-    kprintln!("Process Exiting");
-    loop {
-        unsafe { asm!("wfe") }
+fn sys_accept(fd: u64, addr: *mut u8, lenp: *mut u32) -> Result<u64, u64> {
+    let (file, peer) = with_socket(fd, |handle, socket| {
+        socket.accept(socket::nonblocking(handle, 0))
+    })?;
+    socket::write_sockaddr(&peer, addr, lenp);
+    let handle = FileHandle::new(file, O_RDWR);
+    let fd = with_files(|files| files.insert(0, Arc::new(handle), false)).unwrap_or(Err(EBADF))?;
+    Ok(fd as u64)
+}
+
+fn sys_sendto(
+    fd: u64,
+    buf: *const u8,
+    len: usize,
+    flags: u32,
+    to: *const u8,
+    to_len: usize,
+) -> Result<u64, u64> {
+    let handle = current_file(fd as usize).ok_or(EBADF)?;
+    let socket = socket::socket_of(&handle)?;
+    let to = if to.is_null() {
+        None
+    } else {
+        Some(read_sockaddr(to, to_len)?)
+    };
+    let slice = unsafe { core::slice::from_raw_parts(buf, len) };
+    let result = socket.send_to(slice, flags, to, socket::nonblocking(&handle, flags));
+    if result == Err(EPIPE) && socket::raises_sigpipe(&handle) {
+        raise_sigpipe();
     }
+    result.map(|n| n as u64)
 }
 
-fn sys_spawn(fn_ptr: u64, arg: u64) -> u64 {
-    let mut scheduler = SCHEDULER.lock();
-    // For now, kernel-spawned threads in EL0
-    let process = crate::scheduler::Process::new(fn_ptr, 0, &[arg], 0, true);
-    let pid = process.pid;
-    scheduler.add_process(process);
-    pid
+fn sys_recvfrom(
+    fd: u64,
+    buf: *mut u8,
+    len: usize,
+    flags: u32,
+    from: *mut u8,
+    from_lenp: *mut u32,
+) -> Result<u64, u64> {
+    with_socket(fd, |handle, socket| {
+        let slice = unsafe { core::slice::from_raw_parts_mut(buf, len) };
+        let (n, sender) = socket.recv_from(slice, flags, socket::nonblocking(handle, flags))?;
+        match sender {
+            Some(sender) => socket::write_sockaddr(&sender, from, from_lenp),
+            // Stream sockets have no sender to report
+            None if !from_lenp.is_null() => unsafe { *from_lenp = 0 },
+            None => {}
+        }
+        Ok(n as u64)
+    })
 }
 
-fn sys_getpid() -> u64 {
-    1
+/// struct msghdr
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct MsgHdr {
+    name: u32,
+    name_len: u32,
+    iov: u32,
+    iov_len: u32,
+    control: u32,
+    control_len: u32,
+    flags: u32,
+}
+
+/// Most struct iovecs one call takes
+const IOV_MAX: u32 = 1024;
+
+// Ancillary data types, at level SOL_SOCKET
+const SCM_RIGHTS: u32 = 0x01;
+const SCM_CREDS: u32 = 0x03;
+/// Size of struct cmsghdr, after which its data starts
+const CMSG_HDR_LEN: usize = 12;
+/// Size of struct cmsgcred, which SCM_CREDS carries
+const CMSGCRED_LEN: usize = 84;
+
+/// The (base, len) pairs of an array of struct iovec
+fn read_iovecs(ptr: u32, count: u32) -> Result<Vec<(u32, u32)>, u64> {
+    if count > IOV_MAX {
+        return Err(EMSGSIZE);
+    }
+    let iovecs = ptr as *const [u32; 2];
+    Ok((0..count as usize)
+        .map(|i| {
+            let [base, len] = unsafe { core::ptr::read_unaligned(iovecs.add(i)) };
+            (base, len)
+        })
+        .collect())
+}
+
+/// Parse the ancillary data sendmsg was given
+fn read_control(ptr: u32, len: u32) -> Result<Control, u64> {
+    let len = if ptr == 0 { 0 } else { len };
+    let mut control = Control::default();
+    let mut offset = 0;
+    while offset + CMSG_HDR_LEN <= len as usize {
+        let cmsg = (ptr as usize + offset) as *const u32;
+        let (cmsg_len, level, ty) = unsafe {
+            (
+                core::ptr::read_unaligned(cmsg) as usize,
+                core::ptr::read_unaligned(cmsg.add(1)),
+                core::ptr::read_unaligned(cmsg.add(2)),
+            )
+        };
+        if cmsg_len < CMSG_HDR_LEN || offset + cmsg_len > len as usize || level != SOL_SOCKET {
+            return Err(EINVAL);
+        }
+        match ty {
+            SCM_RIGHTS => {
+                for i in 0..(cmsg_len - CMSG_HDR_LEN) / 4 {
+                    let fd = unsafe { core::ptr::read_unaligned(cmsg.add(3 + i)) };
+                    control.rights.push(current_file(fd as usize).ok_or(EBADF)?);
+                }
+            }
+            // The kernel fills in the credentials, whatever the caller sent
+            SCM_CREDS => {
+                let pid = scheduler::this_cpu().lock().current_pid();
+                control.creds = Some(Creds {
+                    pid: pid as u32,
+                    uid: 501,
+                    gid: 20,
+                });
+            }
+            _ => return Err(EINVAL),
+        }
+        offset += cmsg_len.next_multiple_of(4);
+    }
+    Ok(control)
+}
+
+/// Store the ancillary data recvmsg received in the caller's buffer of
+/// `len` bytes at `ptr`, installing passed files as new descriptors. What
+/// doesn't fit is dropped and sets MSG_CTRUNC in `flags`. Returns the length
+/// used.
+fn write_control(control: Control, ptr: u32, len: u32, flags: &mut u32) -> u32 {
+    let len = if ptr == 0 { 0 } else { len as usize };
+    let mut offset = 0;
+    // Data bytes that fit in a message after `offset`
+    let room = |offset: usize| len.saturating_sub(offset + CMSG_HDR_LEN);
+    let push = |offset: &mut usize, ty: u32, data: &[u32]| {
+        let cmsg = (ptr as usize + *offset) as *mut u32;
+        let cmsg_len = CMSG_HDR_LEN + data.len() * 4;
+        unsafe {
+            core::ptr::write_unaligned(cmsg, cmsg_len as u32);
+            core::ptr::write_unaligned(cmsg.add(1), SOL_SOCKET);
+            core::ptr::write_unaligned(cmsg.add(2), ty);
+            for (i, word) in data.iter().enumerate() {
+                core::ptr::write_unaligned(cmsg.add(3 + i), *word);
+            }
+        }
+        *offset += cmsg_len;
+    };
+
+    if let Some(creds) = control.creds {
+        if room(offset) >= CMSGCRED_LEN {
+            // pid, uid, euid, gid, then one group after ngroups and padding
+            let mut cred = [0u32; CMSGCRED_LEN / 4];
+            cred[..4].copy_from_slice(&[creds.pid, creds.uid, creds.uid, creds.gid]);
+            cred[4] = 1;
+            cred[5] = creds.gid;
+            push(&mut offset, SCM_CREDS, &cred);
+        } else {
+            *flags |= MSG_CTRUNC;
+        }
+    }
+    if !control.rights.is_empty() {
+        let fit = room(offset) / 4;
+        if fit < control.rights.len() {
+            *flags |= MSG_CTRUNC;
+        }
+        let mut fds = Vec::new();
+        for file in control.rights.into_iter().take(fit) {
+            match with_files(|files| files.insert(0, file, false)) {
+                Some(Ok(fd)) => fds.push(fd as u32),
+                _ => *flags |= MSG_CTRUNC,
+            }
+        }
+        if !fds.is_empty() {
+            push(&mut offset, SCM_RIGHTS, &fds);
+        }
+    }
+    offset as u32
+}
+
+fn sys_sendmsg(fd: u64, msg: *const MsgHdr, flags: u32) -> Result<u64, u64> {
+    let handle = current_file(fd as usize).ok_or(EBADF)?;
+    let socket = socket::socket_of(&handle)?;
+    let hdr = unsafe { core::ptr::read_unaligned(msg) };
+    let to = if hdr.name == 0 {
+        None
+    } else {
+        Some(read_sockaddr(hdr.name as *const u8, hdr.name_len as usize)?)
+    };
+    let mut data = Vec::new();
+    for (base, len) in read_iovecs(hdr.iov, hdr.iov_len)? {
+        data.extend_from_slice(unsafe {
+            core::slice::from_raw_parts(base as *const u8, len as usize)
+        });
+    }
+    let control = read_control(hdr.control, hdr.control_len)?;
+    let nonblock = socket::nonblocking(&handle, flags);
+    let result = socket.send_msg(&data, flags, to, control, nonblock);
+    if result == Err(EPIPE) && socket::raises_sigpipe(&handle) {
+        raise_sigpipe();
+    }
+    result.map(|n| n as u64)
+}
+
+/// kevent, kevent64 and kevent_qos, whose event arrays are in `format`. A
+/// null `timeout` timespec waits indefinitely.
+fn sys_kevent(
+    kq: u64,
+    changelist: (*const u8, usize),
+    eventlist: (*mut u8, usize),
+    flags: u32,
+    timeout: *const u8,
+    format: kqueue::Format,
+) -> Result<u64, u64> {
+    let handle = current_file(kq as usize).ok_or(EBADF)?;
+    let queue = handle.file.kqueue().ok_or(EBADF)?;
+    let deadline = if flags & KEVENT_FLAG_IMMEDIATE != 0 {
+        Some(timer::counter())
+    } else {
+        read_timeout(timeout, false, true)?
+    };
+    let changes: Vec<_> = (0..changelist.1)
+        .map(|i| format.read(changelist.0, i))
+        .collect();
+    let events = queue.kevent(
+        &changes,
+        eventlist.1,
+        deadline,
+        flags & KEVENT_FLAG_ERROR_EVENTS != 0,
+        &current_file,
+    )?;
+    for (i, event) in events.iter().enumerate() {
+        format.write(eventlist.0, i, event);
+    }
+    Ok(events.len() as u64)
+}
+
+/// Counter value at which a wait times out, from the struct timespec at
+/// `ptr`, or with `usec` the struct timeval. `a32` selects the armv7 layout,
+/// two 32-bit words, over the arm64 one, whose seconds are 64 bits. None if
+/// `ptr` is null.
+fn read_timeout(ptr: *const u8, usec: bool, a32: bool) -> Result<Option<u64>, u64> {
+    if ptr.is_null() {
+        return Ok(None);
+    }
+    let (sec, frac) = unsafe {
+        if a32 {
+            (
+                ptr.cast::<i32>().read_unaligned() as i64,
+                ptr.add(4).cast::<i32>().read_unaligned(),
+            )
+        } else {
+            (
+                ptr.cast::<i64>().read_unaligned(),
+                ptr.add(8).cast::<i32>().read_unaligned(),
+            )
+        }
+    };
+    let (unit, limit) = if usec {
+        (1_000, 1_000_000)
+    } else {
+        (1, 1_000_000_000)
+    };
+    if sec < 0 || !(0..limit).contains(&frac) {
+        return Err(EINVAL);
+    }
+    let nanos = (sec as u64).saturating_mul(1_000_000_000) + frac as u64 * unit;
+    Ok(Some(
+        timer::counter().saturating_add(timer::ticks_from_nanos(nanos)),
+    ))
+}
+
+/// Wait until `check` counts something ready, the counter reaches
+/// `deadline`, or a signal the process catches and `mask` doesn't block
+/// arrives, which fails with EINTR. Returns the last count.
+fn wait_ready(
+    deadline: Option<u64>,
+    mask: u32,
+    mut check: impl FnMut() -> Result<usize, u64>,
+) -> Result<usize, u64> {
+    loop {
+        let since = crate::waitqueue::wakeups();
+        let ready = check()?;
+        if ready > 0 || deadline.is_some_and(|d| timer::counter() >= d) {
+            return Ok(ready);
+        }
+        if take_interrupt(mask) {
+            return Err(EINTR);
+        }
+        crate::waitqueue::wait_any(since, deadline);
+    }
+}
+
+/// select and pselect. `sets` are the read, write and exception fd_sets,
+/// arrays of 32-bit words in both the armv7 and arm64 ABIs. `timeout` is a
+/// struct timeval, or for pselect (`usec` false) a struct timespec; pselect
+/// also replaces the signal mask with `sigmask` while it waits.
+fn sys_select(
+    nfds: i32,
+    sets: [*mut u32; 3],
+    timeout: *const u8,
+    usec: bool,
+    sigmask: *const u32,
+    a32: bool,
+) -> Result<u64, u64> {
+    if nfds < 0 {
+        return Err(EINVAL);
+    }
+    let deadline = read_timeout(timeout, usec, a32)?;
+    let mask = if sigmask.is_null() {
+        signal_mask()
+    } else {
+        unsafe { *sigmask }
+    };
+    // Descriptors past the table's limit can't be open
+    let limit = with_files(|files| files.limits().0).unwrap_or(0);
+    let nfds = (nfds as usize).min(limit as usize);
+    let words = nfds.div_ceil(32);
+    let wanted: Vec<Vec<u32>> = sets
+        .iter()
+        .map(|&set| {
+            if set.is_null() {
+                vec![0; words]
+            } else {
+                unsafe { core::slice::from_raw_parts(set, words) }.to_vec()
+            }
+        })
+        .collect();
+    let mut files = Vec::new();
+    for fd in 0..nfds {
+        let bit = 1 << (fd % 32);
+        if wanted.iter().any(|set| set[fd / 32] & bit != 0) {
+            files.push((fd, current_file(fd).ok_or(EBADF)?));
+        }
+    }
+
+    let mut ready = vec![vec![0u32; words]; 3];
+    let count = wait_ready(deadline, mask, || {
+        let mut count = 0;
+        for (fd, file) in &files {
+            let (word, bit) = (fd / 32, 1 << (fd % 32));
+            let readiness = file.readiness();
+            // No file has out-of-band data, so the exception set stays empty
+            let states = [readiness.read.is_some(), readiness.write.is_some()];
+            for (set, state) in states.into_iter().enumerate() {
+                if state && wanted[set][word] & bit != 0 {
+                    ready[set][word] |= bit;
+                    count += 1;
+                }
+            }
+        }
+        Ok(count)
+    })?;
+    for (set, ready) in sets.iter().zip(&ready) {
+        if !set.is_null() {
+            unsafe { core::ptr::copy_nonoverlapping(ready.as_ptr(), *set, words) };
+        }
+    }
+    Ok(count as u64)
+}
+
+/// struct pollfd
+#[repr(C)]
+struct PollFd {
+    fd: i32,
+    events: u16,
+    revents: u16,
+}
+
+// poll(2) events
+const POLLIN: u16 = 0x0001;
+const POLLOUT: u16 = 0x0004;
+const POLLHUP: u16 = 0x0010;
+const POLLNVAL: u16 = 0x0020;
+const POLLRDNORM: u16 = 0x0040;
+const POLLWRNORM: u16 = POLLOUT;
+
+/// poll. A negative `timeout` in milliseconds waits indefinitely.
+fn sys_poll(fds: *mut PollFd, nfds: usize, timeout: i32) -> Result<u64, u64> {
+    let limit = with_files(|files| files.limits().0).unwrap_or(0);
+    if nfds as u64 > limit {
+        return Err(EINVAL);
+    }
+    let deadline = u64::try_from(timeout)
+        .ok()
+        .map(|ms| timer::counter() + timer::ticks_from_nanos(ms * 1_000_000));
+    let polled = unsafe { core::slice::from_raw_parts_mut(fds, nfds) };
+    // Negative descriptors are skipped, and closed ones report POLLNVAL
+    let files: Vec<_> = polled
+        .iter()
+        .map(|pollfd| usize::try_from(pollfd.fd).ok().map(current_file))
+        .collect();
+
+    let count = wait_ready(deadline, signal_mask(), || {
+        let mut count = 0;
+        for (pollfd, file) in polled.iter_mut().zip(&files) {
+            pollfd.revents = match file {
+                None => 0,
+                Some(None) => POLLNVAL,
+                Some(Some(file)) => {
+                    let readiness = file.readiness();
+                    let mut revents = 0;
+                    if readiness.read.is_some() {
+                        revents |= pollfd.events & (POLLIN | POLLRDNORM);
+                    }
+                    if readiness.eof {
+                        // A hung up descriptor can't also be writable
+                        revents |= POLLHUP;
+                    } else if readiness.write.is_some() {
+                        revents |= pollfd.events & (POLLOUT | POLLWRNORM);
+                    }
+                    revents
+                }
+            };
+            count += (pollfd.revents != 0) as usize;
+        }
+        Ok(count)
+    })?;
+    Ok(count as u64)
+}
+
+fn sys_recvmsg(fd: u64, msg: *mut MsgHdr, flags: u32) -> Result<u64, u64> {
+    with_socket(fd, |handle, socket| {
+        let mut hdr = unsafe { core::ptr::read_unaligned(msg) };
+        let iovecs = read_iovecs(hdr.iov, hdr.iov_len)?;
+        let mut buf = vec![0; iovecs.iter().map(|&(_, len)| len as usize).sum()];
+        let received = socket.recv_msg(&mut buf, flags, socket::nonblocking(handle, flags))?;
+
+        let mut data = &buf[..received.len];
+        for (base, len) in iovecs {
+            let n = data.len().min(len as usize);
+            unsafe { core::ptr::copy_nonoverlapping(data.as_ptr(), base as *mut u8, n) };
+            data = &data[n..];
+        }
+        if hdr.name != 0 {
+            match &received.from {
+                Some(from) => socket::write_sockaddr(from, hdr.name as *mut u8, &mut hdr.name_len),
+                None => hdr.name_len = 0,
+            }
+        }
+        hdr.flags = if received.truncated { MSG_TRUNC } else { 0 };
+        hdr.control_len = write_control(
+            received.control,
+            hdr.control,
+            hdr.control_len,
+            &mut hdr.flags,
+        );
+        unsafe { core::ptr::write_unaligned(msg, hdr) };
+        Ok(received.len as u64)
+    })
+}
+
+fn sys_yield() {
+    scheduler::yield_now();
+}
+
+fn sys_exit() {
+    kprintln!("Process Exiting");
+    exit_current();
+}
+
+fn exit_current() -> ! {
+    let exited = scheduler::this_cpu()
+        .lock()
+        .current_process
+        .as_mut()
+        .map(|proc| {
+            (
+                proc.pid,
+                core::mem::replace(&mut proc.files, FdTable::new()),
+            )
+        });
+    // Closing our descriptors and telling kqueues may wake other processes,
+    // or block on a kqueue in use, so do it unlocked and before we are dead
+    if let Some((pid, files)) = exited {
+        scheduler::exiting(pid);
+        drop(files);
+        kqueue::process_exited(pid);
+        PENDING_SIGNALS.lock().remove(&pid);
+    }
+    if let Some(proc) = scheduler::this_cpu().lock().current_process.as_mut() {
+        proc.state = scheduler::ProcessState::Dead;
+    }
+    // The scheduler reaps us once we are off this kernel stack
+    scheduler::yield_now();
+    loop {
+        unsafe { asm!("wfe") }
+    }
+}
+
+fn sys_spawn(fn_ptr: u64, arg: u64) -> u64 {
+    // For now, kernel-spawned threads in EL0
+    let mut process = crate::scheduler::Process::new(fn_ptr, 0, &[arg], 0, true);
+    // The child inherits our descriptors as if by fork followed by exec
+    if let Some(mut files) = with_files(|files| files.fork()) {
+        files.close_on_exec();
+        process.files = files;
+    }
+    scheduler::spawn(process)
+}
+
+fn sys_getpid() -> u64 {
+    1
+}
+
+/// Largest request getentropy accepts
+const GETENTROPY_MAX: usize = 256;
+
+// ioctls that set and clear close-on-exec
+const FIOCLEX: u32 = 0x2000_6601;
+const FIONCLEX: u32 = 0x2000_6602;
+
+// getrlimit/setrlimit resources
+const RLIMIT_NOFILE: u32 = 8;
+const RLIM_INFINITY: u64 = 0x7FFF_FFFF_FFFF_FFFF;
+
+/// Run `f` on the current process's descriptor table
+fn with_files<R>(f: impl FnOnce(&mut FdTable) -> R) -> Option<R> {
+    let (result, released) = {
+        let mut sched = scheduler::this_cpu().lock();
+        let proc = sched.current_process.as_mut()?;
+        let result = f(&mut proc.files);
+        (result, proc.files.take_released())
+    };
+    drop(released);
+    Some(result)
+}
+
+/// Look up a descriptor of the current process. The run queue lock is
+/// released before returning so the caller can do I/O on the file.
+fn current_file(fd: usize) -> Option<Arc<FileHandle>> {
+    with_files(|files| files.get(fd)).flatten()
+}
+
+// mmap(2) protections and flags
+const PROT_WRITE: u32 = 0x2;
+const PROT_EXEC: u32 = 0x4;
+const MAP_ANON: u32 = 0x1000;
+
+/// Zeroed, page-aligned heap memory for `len` bytes of anonymous or copied
+/// mappings, evicting cached file pages if the heap is short
+/// MIB of kern.bootargs under CTL_KERN. Darwin numbers it with OID_AUTO,
+/// whose numbers start here.
+const KERN_BOOTARGS: i32 = 100;
+
+/// Copy a string sysctl value and its NUL to `oldp`. With a null `oldp`, only
+/// report the length.
+fn sysctl_string(value: &str, oldp: *mut u8, oldlenp: *mut u32) -> Result<u64, u64> {
+    let len = value.len() + 1;
+    if !oldp.is_null() {
+        let room = if oldlenp.is_null() {
+            0
+        } else {
+            unsafe { *oldlenp as usize }
+        };
+        if room < len {
+            return Err(ENOMEM);
+        }
+        unsafe {
+            core::ptr::copy_nonoverlapping(value.as_ptr(), oldp, value.len());
+            *oldp.add(value.len()) = 0;
+        }
+    }
+    if !oldlenp.is_null() {
+        unsafe { *oldlenp = len as u32 };
+    }
+    Ok(0)
+}
+
+/// Map `len` bytes of the file at `offset` to `vaddr`. Cached files share
+/// the page cache's pages; others, and unaligned offsets, get a copy.
+fn map_file(handle: &FileHandle, vaddr: u64, offset: u64, len: u64, prot: u32) -> Result<(), u64> {
+    if let Some(id) = handle.cache_id() {
+        let writable = prot & PROT_WRITE != 0;
+        let exec = prot & PROT_EXEC != 0;
+        match pagecache::map(id, &*handle.file, vaddr, offset, len, writable, exec) {
+            Err(EINVAL) => {}
+            result => return result,
+        }
+    }
+
+    pagecache::map_anon(vaddr, len)?;
+    let data = unsafe { core::slice::from_raw_parts_mut(vaddr as *mut u8, len as usize) };
+    for (i, chunk) in data.chunks_mut(4096).enumerate() {
+        if handle.read_at(offset + i as u64 * 4096, chunk) < chunk.len() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// dirfd of the *at syscalls that stands for the current directory
+const AT_FDCWD: i32 = -2;
+
+// *at syscall flags
+const AT_EACCESS: u32 = 0x10;
+const AT_SYMLINK_NOFOLLOW: u32 = 0x20;
+const AT_REMOVEDIR: u32 = 0x80;
+
+// access(2) modes
+const X_OK: u32 = 1;
+const W_OK: u32 = 2;
+const R_OK: u32 = 4;
+
+/// fcntl that copies out the path a descriptor was opened by
+const F_GETPATH: u32 = 50;
+/// Size of the buffer F_GETPATH fills
+const MAXPATHLEN: usize = 1024;
+
+/// Current directory of the running process, in canonical form
+fn current_dir() -> String {
+    scheduler::this_cpu()
+        .lock()
+        .current_process
+        .as_ref()
+        .map(|proc| proc.cwd.clone())
+        .unwrap_or_else(|| String::from("/"))
+}
+
+/// Make the existing directory at the absolute `path` the current directory
+fn set_current_dir(path: &str) -> Result<u64, u64> {
+    if crate::vfs::stat(path)?.mode & S_IFMT != S_IFDIR {
+        return Err(ENOTDIR);
+    }
+    let path = crate::vfs::realpath(path)?;
+    if let Some(proc) = scheduler::this_cpu().lock().current_process.as_mut() {
+        proc.cwd = path;
+    }
+    Ok(0)
+}
+
+/// Absolute form of a user path. Relative paths start at the directory
+/// open on `dirfd`, or at the current directory for AT_FDCWD.
+fn at_path(dirfd: i32, path: &str) -> Result<String, u64> {
+    if path.starts_with('/') {
+        return Ok(String::from(path));
+    }
+    if path.is_empty() {
+        return Err(ENOENT);
+    }
+    let mut base = if dirfd == AT_FDCWD {
+        current_dir()
+    } else {
+        let handle = current_file(dirfd as usize).ok_or(EBADF)?;
+        if handle.file.mode() & S_IFMT != S_IFDIR {
+            return Err(ENOTDIR);
+        }
+        String::from(handle.path().ok_or(ENOTDIR)?)
+    };
+    if !base.ends_with('/') {
+        base.push('/');
+    }
+    base.push_str(path);
+    Ok(base)
+}
+
+fn sys_open(dirfd: i32, path: &str, flags: u32, mode: u16) -> Result<u64, u64> {
+    let handle = Arc::new(crate::vfs::open(&at_path(dirfd, path)?, flags, mode)?);
+    with_files(|files| files.insert(0, handle, flags & O_CLOEXEC != 0))
+        .unwrap_or(Err(EMFILE))
+        .map(|fd| fd as u64)
+}
+
+/// stat64 and fstatat64
+fn sys_stat(dirfd: i32, path: &str, buf: *mut u8, flags: u32, a32: bool) -> Result<u64, u64> {
+    let path = at_path(dirfd, path)?;
+    let attr = if flags & AT_SYMLINK_NOFOLLOW != 0 {
+        crate::vfs::lstat(&path)?
+    } else {
+        crate::vfs::stat(&path)?
+    };
+    write_stat64(buf, &attr, a32);
+    Ok(0)
+}
+
+/// access and faccessat. There are no credentials yet, so permission is
+/// checked against the owner's bits, and writes against the mount.
+fn sys_access(dirfd: i32, path: &str, mode: u32, flags: u32) -> Result<u64, u64> {
+    if mode & !(R_OK | W_OK | X_OK) != 0 || flags & !(AT_EACCESS | AT_SYMLINK_NOFOLLOW) != 0 {
+        return Err(EINVAL);
+    }
+    let path = at_path(dirfd, path)?;
+    let attr = if flags & AT_SYMLINK_NOFOLLOW != 0 {
+        crate::vfs::lstat(&path)?
+    } else {
+        crate::vfs::stat(&path)?
+    };
+    if mode & W_OK != 0 && crate::vfs::statfs(&path)?.flags & MNT_RDONLY != 0 {
+        return Err(EROFS);
+    }
+    if mode & !((attr.mode as u32 >> 6) & 0o7) != 0 {
+        return Err(EACCES);
+    }
+    Ok(0)
+}
+
+/// readlink and readlinkat. The target is truncated to the buffer and not
+/// NUL-terminated.
+fn sys_readlink(dirfd: i32, path: &str, buf: *mut u8, bufsize: usize) -> Result<u64, u64> {
+    let target = crate::vfs::readlink(&at_path(dirfd, path)?)?;
+    let len = target.len().min(bufsize);
+    unsafe { core::ptr::copy_nonoverlapping(target.as_ptr(), buf, len) };
+    Ok(len as u64)
+}
+
+/// unlink, rmdir and unlinkat
+fn sys_unlink(dirfd: i32, path: &str, flags: u32) -> Result<u64, u64> {
+    if flags & !AT_REMOVEDIR != 0 {
+        return Err(EINVAL);
+    }
+    crate::vfs::remove(&at_path(dirfd, path)?, flags & AT_REMOVEDIR != 0)?;
+    Ok(0)
+}
+
+/// mkdir and mkdirat
+fn sys_mkdir(dirfd: i32, path: &str, mode: u16) -> Result<u64, u64> {
+    crate::vfs::mkdir(&at_path(dirfd, path)?, mode)?;
+    Ok(0)
+}
+
+/// symlink and symlinkat. The target is stored as given.
+fn sys_symlink(target: &str, dirfd: i32, path: &str) -> Result<u64, u64> {
+    crate::vfs::symlink(target, &at_path(dirfd, path)?)?;
+    Ok(0)
+}
+
+/// rename and renameat
+fn sys_rename(from_fd: i32, from: &str, to_fd: i32, to: &str) -> Result<u64, u64> {
+    crate::vfs::rename(&at_path(from_fd, from)?, &at_path(to_fd, to)?)?;
+    Ok(0)
+}
+
+/// chmod and fchmodat. Symbolic links have no permissions of their own to
+/// change.
+fn sys_chmod(dirfd: i32, path: &str, mode: u16, flags: u32) -> Result<u64, u64> {
+    if flags & !AT_SYMLINK_NOFOLLOW != 0 {
+        return Err(EINVAL);
+    }
+    let path = at_path(dirfd, path)?;
+    if flags & AT_SYMLINK_NOFOLLOW != 0 && crate::vfs::lstat(&path)?.mode & S_IFMT == S_IFLNK {
+        return Err(ENOTSUP);
+    }
+    crate::vfs::chmod(&path, mode)?;
+    Ok(0)
+}
+
+/// utimes and futimes. `times` holds the access and modification times as
+/// two struct timevals; if it is null, both are set to now.
+fn sys_utimes(path: &str, times: *const u8, a32: bool) -> Result<u64, u64> {
+    let (atime, mtime) = if times.is_null() {
+        let now = timer::unix_time();
+        (now, now)
+    } else {
+        // Microseconds are dropped, since times are kept in seconds
+        let size = if a32 { 8 } else { 16 };
+        let sec = |index: usize| unsafe {
+            let ptr = times.add(index * size);
+            if a32 {
+                ptr.cast::<i32>().read_unaligned() as i64
+            } else {
+                ptr.cast::<i64>().read_unaligned()
+            }
+        };
+        (sec(0), sec(1))
+    };
+    crate::vfs::utimes(path, atime, mtime)?;
+    Ok(0)
+}
+
+/// fcntl(F_GETPATH): copy the path `fd` was opened by to `buf`, which holds
+/// MAXPATHLEN bytes. Darwin's getcwd is built on this, applied to ".".
+fn sys_getpath(fd: usize, buf: *mut u8) -> Result<u64, u64> {
+    let handle = current_file(fd).ok_or(EBADF)?;
+    let path = handle.path().ok_or(EINVAL)?;
+    if path.len() >= MAXPATHLEN {
+        return Err(ENAMETOOLONG);
+    }
+    unsafe {
+        core::ptr::copy_nonoverlapping(path.as_ptr(), buf, path.len());
+        *buf.add(path.len()) = 0;
+    }
+    Ok(0)
+}
+
+const SIGPIPE: usize = 13;
+const SIG_DFL: u32 = 0;
+const SIG_IGN: u32 = 1;
+
+/// Signals sent to each process by kill or the terminal and not yet taken,
+/// by pid. Handlers can't run yet, so a pending signal only matters to a
+/// process that catches it: it interrupts the process's select or poll.
+static PENDING_SIGNALS: Mutex<BTreeMap<u64, u32>> = Mutex::new(BTreeMap::new());
+
+/// kill(pid, sig). A pid of -1 is every other process; process groups
+/// don't exist yet, so 0 and other negative pids match no process.
+fn sys_kill(pid: i32, sig: u32) -> Result<u64, u64> {
+    if sig >= 32 {
+        return Err(EINVAL);
+    }
+    let pids = match pid {
+        1.. if scheduler::exists(pid as u64) => vec![pid as u64],
+        -1 => {
+            let me = scheduler::this_cpu().lock().current_pid();
+            let mut pids = scheduler::live_pids();
+            pids.retain(|&pid| pid != me);
+            pids
+        }
+        _ => Vec::new(),
+    };
+    if pids.is_empty() {
+        return Err(ESRCH);
+    }
+    // Signal 0 only checks that the processes exist
+    if sig != 0 {
+        for pid in pids {
+            send_signal(pid, sig);
+        }
+    }
+    Ok(0)
+}
+
+/// Send `sig` to process `pid`: tell kqueues watching for it, and make it
+/// pending
+pub fn send_signal(pid: u64, sig: u32) {
+    kqueue::signal_sent(pid, sig);
+    post_signal(pid, sig);
+}
+
+/// Make `sig` pending for process `pid`, if it is alive. Checked under the
+/// lock, since `exit_current` clears the entry after the process stops
+/// being alive.
+fn post_signal(pid: u64, sig: u32) {
+    if !(1..32).contains(&sig) {
+        return;
+    }
+    let mut pending = PENDING_SIGNALS.lock();
+    if !scheduler::exists(pid) {
+        return;
+    }
+    *pending.entry(pid).or_default() |= 1 << (sig - 1);
+    drop(pending);
+    // The process may be waiting in select or poll
+    crate::waitqueue::wake_any();
+}
+
+/// Take a pending signal that interrupts a wait: one the current process
+/// catches and `mask` doesn't block. Returns whether there was one.
+fn take_interrupt(mask: u32) -> bool {
+    let (pid, caught) = {
+        let sched = scheduler::this_cpu().lock();
+        let Some(proc) = sched.current_process.as_ref() else {
+            return false;
+        };
+        let caught = (1..32)
+            .filter(|&sig| !matches!(proc.signal_handlers[sig], SIG_DFL | SIG_IGN))
+            .fold(0u32, |set, sig| set | 1 << (sig - 1));
+        (proc.pid, caught & !mask)
+    };
+    let mut pending = PENDING_SIGNALS.lock();
+    let Some(signals) = pending.get_mut(&pid) else {
+        return false;
+    };
+    let interrupting = *signals & caught;
+    if interrupting == 0 {
+        return false;
+    }
+    *signals &= !(1 << interrupting.trailing_zeros());
+    true
+}
+
+/// The current process's signal mask
+fn signal_mask() -> u32 {
+    scheduler::this_cpu()
+        .lock()
+        .current_process
+        .as_ref()
+        .map_or(0, |proc| proc.signal_mask)
+}
+
+/// Deliver SIGPIPE after a write to a pipe with no readers. Handlers can't
+/// run yet, so only the default action (terminate) has an effect; a blocked,
+/// ignored or caught SIGPIPE leaves the write failing with EPIPE.
+fn raise_sigpipe() {
+    let (pid, terminate) = {
+        let sched = scheduler::this_cpu().lock();
+        let terminate = sched.current_process.as_ref().is_some_and(|proc| {
+            proc.signal_mask & (1 << (SIGPIPE - 1)) == 0 && proc.signal_handlers[SIGPIPE] == SIG_DFL
+        });
+        (sched.current_pid(), terminate)
+    };
+    kqueue::signal_sent(pid, SIGPIPE as u32);
+    if terminate {
+        kprintln!("Process terminated by SIGPIPE");
+        exit_current();
+    }
+}
+
+/// Fill in a Darwin `struct stat64`. `a32` selects the armv7 layout, whose
+/// timespecs are two 32-bit words, over the arm64 one.
+fn write_stat64(stat_ptr: *mut u8, attr: &Attr, a32: bool) {
+    // Offsets of st_atimespec and st_size, the size of a timespec and of the
+    // whole struct
+    let (times, size_off, timespec, len) = if a32 {
+        (28, 64, 8, 112)
+    } else {
+        (32, 96, 16, 144)
+    };
+    let all_times = [attr.atime, attr.mtime, attr.ctime, attr.birthtime];
+    unsafe {
+        core::ptr::write_bytes(stat_ptr, 0, len);
+        *(stat_ptr as *mut u32) = attr.dev;
+        *(stat_ptr.add(4) as *mut u16) = attr.mode;
+        *(stat_ptr.add(6) as *mut u16) = attr.nlink.min(u16::MAX as u32) as u16;
+        *(stat_ptr.add(8) as *mut u64) = attr.ino;
+        *(stat_ptr.add(16) as *mut u32) = attr.uid;
+        *(stat_ptr.add(20) as *mut u32) = attr.gid;
+        *(stat_ptr.add(24) as *mut u32) = attr.rdev;
+        // st_atimespec, st_mtimespec, st_ctimespec, st_birthtimespec
+        for (i, time) in all_times.into_iter().enumerate() {
+            let tv_sec = stat_ptr.add(times + i * timespec);
+            if a32 {
+                *(tv_sec as *mut i32) = time as i32;
+            } else {
+                *(tv_sec as *mut i64) = time;
+            }
+        }
+        *(stat_ptr.add(size_off) as *mut u64) = attr.size;
+        *(stat_ptr.add(size_off + 8) as *mut u64) = attr.blocks;
+        *(stat_ptr.add(size_off + 16) as *mut u32) = attr.block_size;
+        *(stat_ptr.add(size_off + 20) as *mut u32) = attr.flags;
+    }
+}
+
+const ATTR_BIT_MAP_COUNT: u16 = 5;
+
+const ATTR_CMN_NAME: u32 = 0x0000_0001;
+const ATTR_CMN_DEVID: u32 = 0x0000_0002;
+const ATTR_CMN_FSID: u32 = 0x0000_0004;
+const ATTR_CMN_OBJTYPE: u32 = 0x0000_0008;
+const ATTR_CMN_OBJTAG: u32 = 0x0000_0010;
+const ATTR_CMN_OBJID: u32 = 0x0000_0020;
+const ATTR_CMN_OBJPERMANENTID: u32 = 0x0000_0040;
+const ATTR_CMN_PAROBJID: u32 = 0x0000_0080;
+const ATTR_CMN_SCRIPT: u32 = 0x0000_0100;
+const ATTR_CMN_CRTIME: u32 = 0x0000_0200;
+const ATTR_CMN_MODTIME: u32 = 0x0000_0400;
+const ATTR_CMN_CHGTIME: u32 = 0x0000_0800;
+const ATTR_CMN_ACCTIME: u32 = 0x0000_1000;
+const ATTR_CMN_BKUPTIME: u32 = 0x0000_2000;
+const ATTR_CMN_FNDRINFO: u32 = 0x0000_4000;
+const ATTR_CMN_OWNERID: u32 = 0x0000_8000;
+const ATTR_CMN_GRPID: u32 = 0x0001_0000;
+const ATTR_CMN_ACCESSMASK: u32 = 0x0002_0000;
+const ATTR_CMN_FLAGS: u32 = 0x0004_0000;
+const ATTR_CMN_USERACCESS: u32 = 0x0020_0000;
+const ATTR_CMN_FILEID: u32 = 0x0200_0000;
+const ATTR_CMN_PARENTID: u32 = 0x0400_0000;
+const ATTR_CMN_RETURNED_ATTRS: u32 = 0x8000_0000;
+const ATTR_CMN_SUPPORTED: u32 = 0x8627_ffff;
+
+const ATTR_DIR_LINKCOUNT: u32 = 0x0001;
+const ATTR_DIR_ENTRYCOUNT: u32 = 0x0002;
+const ATTR_DIR_MOUNTSTATUS: u32 = 0x0004;
+const ATTR_DIR_SUPPORTED: u32 = 0x0007;
+
+const ATTR_FILE_LINKCOUNT: u32 = 0x0001;
+const ATTR_FILE_TOTALSIZE: u32 = 0x0002;
+const ATTR_FILE_ALLOCSIZE: u32 = 0x0004;
+const ATTR_FILE_IOBLOCKSIZE: u32 = 0x0008;
+const ATTR_FILE_DEVTYPE: u32 = 0x0020;
+const ATTR_FILE_DATALENGTH: u32 = 0x0200;
+const ATTR_FILE_DATAALLOCSIZE: u32 = 0x0400;
+const ATTR_FILE_RSRCLENGTH: u32 = 0x1000;
+const ATTR_FILE_RSRCALLOCSIZE: u32 = 0x2000;
+const ATTR_FILE_SUPPORTED: u32 = 0x362f;
+
+const DIR_MNTSTATUS_MNTPOINT: u32 = 1;
+
+/// Return the attributes of a final symbolic link rather than its target
+const FSOPT_NOFOLLOW: u32 = 0x1;
+/// Report the full size of the attributes even if they were truncated
+const FSOPT_REPORT_FULLSIZE: u32 = 0x4;
+
+/// The `enum vtype` value for a file type
+fn vnode_type(mode: u16) -> u32 {
+    match mode & S_IFMT {
+        S_IFREG => 1,
+        S_IFDIR => 2,
+        S_IFBLK => 3,
+        S_IFCHR => 4,
+        S_IFLNK => 5,
+        S_IFSOCK => 6,
+        S_IFIFO => 7,
+        _ => 0,
+    }
+}
+
+/// getattrlist(2) for the common, directory and file attribute groups.
+/// Attributes follow a length word in bit order, each padded to 4 bytes,
+/// and the name is stored after all of them.
+fn sys_getattrlist(
+    path: &str,
+    alist: *const u32,
+    buf: *mut u8,
+    bufsize: usize,
+    options: u32,
+    a32: bool,
+) -> Result<u64, u64> {
+    // struct attrlist: u16 bitmapcount, u16 reserved, then one word per group
+    let count = unsafe { *(alist as *const u16) };
+    let [common, vol, dir, file, fork] = unsafe { *(alist.add(1) as *const [u32; 5]) };
+    if count != ATTR_BIT_MAP_COUNT
+        || common & !ATTR_CMN_SUPPORTED != 0
+        || vol != 0
+        || dir & !ATTR_DIR_SUPPORTED != 0
+        || file & !ATTR_FILE_SUPPORTED != 0
+        || fork != 0
+    {
+        return Err(EINVAL);
+    }
+    let attr = if options & FSOPT_NOFOLLOW != 0 {
+        crate::vfs::lstat(path)?
+    } else {
+        crate::vfs::stat(path)?
+    };
+    // Directory attributes are only returned for directories, and file
+    // attributes for everything else
+    let (dir, file) = if attr.mode & S_IFMT == S_IFDIR {
+        (dir, 0)
+    } else {
+        (0, file)
+    };
+
+    let mut out: Vec<u8> = Vec::new();
+    let push32 = |out: &mut Vec<u8>, value: u32| out.extend_from_slice(&value.to_le_bytes());
+    let push64 = |out: &mut Vec<u8>, value: u64| out.extend_from_slice(&value.to_le_bytes());
+
+    push32(&mut out, 0); // Length, filled in at the end
+    if common & ATTR_CMN_RETURNED_ATTRS != 0 {
+        for group in [common, 0, dir, file, 0] {
+            push32(&mut out, group);
+        }
+    }
+    // attrreference_t for the name, filled in once its offset is known
+    let name_ref = out.len();
+    if common & ATTR_CMN_NAME != 0 {
+        push64(&mut out, 0);
+    }
+    if common & ATTR_CMN_DEVID != 0 {
+        push32(&mut out, attr.dev);
+    }
+    if common & ATTR_CMN_FSID != 0 {
+        push64(&mut out, attr.dev as u64);
+    }
+    if common & ATTR_CMN_OBJTYPE != 0 {
+        push32(&mut out, vnode_type(attr.mode));
+    }
+    if common & ATTR_CMN_OBJTAG != 0 {
+        push32(&mut out, 0); // VT_NON
+    }
+    // fsobj_id_t is a 32-bit object number and a generation
+    if common & ATTR_CMN_OBJID != 0 {
+        push64(&mut out, attr.ino as u32 as u64);
+    }
+    if common & ATTR_CMN_OBJPERMANENTID != 0 {
+        push64(&mut out, attr.ino as u32 as u64);
+    }
+    if common & ATTR_CMN_PAROBJID != 0 {
+        push64(&mut out, attr.parent_ino as u32 as u64);
+    }
+    if common & ATTR_CMN_SCRIPT != 0 {
+        push32(&mut out, 0); // kTextEncodingMacRoman
+    }
+    let times = [
+        (ATTR_CMN_CRTIME, attr.birthtime),
+        (ATTR_CMN_MODTIME, attr.mtime),
+        (ATTR_CMN_CHGTIME, attr.ctime),
+        (ATTR_CMN_ACCTIME, attr.atime),
+        (ATTR_CMN_BKUPTIME, attr.backup_time),
+    ];
+    for (bit, time) in times {
+        if common & bit == 0 {
+            continue;
+        }
+        // struct timespec, with a zero tv_nsec
+        if a32 {
+            push32(&mut out, time as u32);
+            push32(&mut out, 0);
+        } else {
+            push64(&mut out, time as u64);
+            push64(&mut out, 0);
+        }
+    }
+    if common & ATTR_CMN_FNDRINFO != 0 {
+        out.extend_from_slice(&attr.finder_info);
+    }
+    if common & ATTR_CMN_OWNERID != 0 {
+        push32(&mut out, attr.uid);
+    }
+    if common & ATTR_CMN_GRPID != 0 {
+        push32(&mut out, attr.gid);
+    }
+    if common & ATTR_CMN_ACCESSMASK != 0 {
+        push32(&mut out, (attr.mode & !S_IFMT) as u32);
+    }
+    if common & ATTR_CMN_FLAGS != 0 {
+        push32(&mut out, attr.flags);
+    }
+    if common & ATTR_CMN_USERACCESS != 0 {
+        // There are no credentials yet, so report the owner's R_OK, W_OK
+        // and X_OK bits
+        push32(&mut out, (attr.mode as u32 >> 6) & 0o7);
+    }
+    if common & ATTR_CMN_FILEID != 0 {
+        push64(&mut out, attr.ino);
+    }
+    if common & ATTR_CMN_PARENTID != 0 {
+        push64(&mut out, attr.parent_ino);
+    }
+
+    if dir & ATTR_DIR_LINKCOUNT != 0 {
+        push32(&mut out, attr.nlink);
+    }
+    if dir & ATTR_DIR_ENTRYCOUNT != 0 {
+        push32(&mut out, attr.entries);
+    }
+    if dir & ATTR_DIR_MOUNTSTATUS != 0 {
+        let mounted = crate::vfs::is_mount_point(path);
+        push32(&mut out, if mounted { DIR_MNTSTATUS_MNTPOINT } else { 0 });
+    }
+
+    let data_alloc = attr.blocks * 512 - attr.rsrc_alloc;
+    if file & ATTR_FILE_LINKCOUNT != 0 {
+        push32(&mut out, attr.nlink);
+    }
+    if file & ATTR_FILE_TOTALSIZE != 0 {
+        push64(&mut out, attr.size + attr.rsrc_size);
+    }
+    if file & ATTR_FILE_ALLOCSIZE != 0 {
+        push64(&mut out, attr.blocks * 512);
+    }
+    if file & ATTR_FILE_IOBLOCKSIZE != 0 {
+        push32(&mut out, attr.block_size);
+    }
+    if file & ATTR_FILE_DEVTYPE != 0 {
+        push32(&mut out, attr.rdev);
+    }
+    if file & ATTR_FILE_DATALENGTH != 0 {
+        push64(&mut out, attr.size);
+    }
+    if file & ATTR_FILE_DATAALLOCSIZE != 0 {
+        push64(&mut out, data_alloc);
+    }
+    if file & ATTR_FILE_RSRCLENGTH != 0 {
+        push64(&mut out, attr.rsrc_size);
+    }
+    if file & ATTR_FILE_RSRCALLOCSIZE != 0 {
+        push64(&mut out, attr.rsrc_alloc);
+    }
+
+    if common & ATTR_CMN_NAME != 0 {
+        let name = path
+            .rsplit('/')
+            .find(|part| !part.is_empty())
+            .unwrap_or("/");
+        // The offset is relative to the attrreference_t itself
+        let offset = (out.len() - name_ref) as u32;
+        out[name_ref..name_ref + 4].copy_from_slice(&offset.to_le_bytes());
+        let len = name.len() as u32 + 1;
+        out[name_ref + 4..name_ref + 8].copy_from_slice(&len.to_le_bytes());
+        out.extend_from_slice(name.as_bytes());
+        out.push(0);
+        out.resize((out.len() + 3) & !3, 0);
+    }
+
+    let copied = out.len().min(bufsize);
+    let length = if options & FSOPT_REPORT_FULLSIZE != 0 {
+        out.len()
+    } else {
+        copied
+    };
+    out[..4].copy_from_slice(&(length as u32).to_le_bytes());
+    unsafe { core::ptr::copy_nonoverlapping(out.as_ptr(), buf, copied) };
+    Ok(0)
+}
+
+/// Longest name in a `struct dirent`
+const MAXNAMLEN: usize = 255;
+
+/// Record length of a `struct dirent` with a 32-bit d_ino
+fn dirent32_len(namlen: usize) -> usize {
+    (8 + namlen + 1 + 3) & !3
+}
+
+/// Record length of a `struct dirent` with a 64-bit d_ino, as computed by
+/// Darwin's DIRENT64_LEN
+fn dirent64_len(namlen: usize) -> usize {
+    (namlen + 32) & !7
+}
+
+/// Fill `buf` with directory entries of `fd` in the `struct dirent` layout
+/// with a 64-bit d_ino if `dirent64`, or a 32-bit one otherwise. Returns the
+/// bytes used and the seek offset of the first entry.
+fn sys_getdirentries(
+    fd: usize,
+    buf: *mut u8,
+    nbytes: usize,
+    dirent64: bool,
+) -> Result<(u64, u64), u64> {
+    let handle = current_file(fd).ok_or(EBADF)?;
+    let mut used = 0;
+    let base = handle.read_dir(|next, entry| {
+        let namlen = entry.name.len().min(MAXNAMLEN);
+        let reclen = if dirent64 {
+            dirent64_len(namlen)
+        } else {
+            dirent32_len(namlen)
+        };
+        if used + reclen > nbytes {
+            return false;
+        }
+        unsafe {
+            let rec = buf.add(used);
+            core::ptr::write_bytes(rec, 0, reclen);
+            let name = if dirent64 {
+                *(rec as *mut u64) = entry.ino;
+                *(rec.add(8) as *mut u64) = next; // d_seekoff
+                *(rec.add(16) as *mut u16) = reclen as u16;
+                *(rec.add(18) as *mut u16) = namlen as u16;
+                *rec.add(20) = entry.kind;
+                rec.add(21)
+            } else {
+                *(rec as *mut u32) = entry.ino as u32;
+                *(rec.add(4) as *mut u16) = reclen as u16;
+                *rec.add(6) = entry.kind;
+                *rec.add(7) = namlen as u8;
+                rec.add(8)
+            };
+            core::ptr::copy_nonoverlapping(entry.name.as_ptr(), name, namlen);
+        }
+        used += reclen;
+        true
+    })?;
+    Ok((used as u64, base))
+}
+
+/// Size of a Darwin `struct statfs64`, the same on both ABIs
+const STATFS64_SIZE: usize = 2168;
+
+/// Fill in a Darwin `struct statfs64`
+fn write_statfs64(buf: *mut u8, mount: &MountInfo) {
+    fn copy_str(dst: *mut u8, s: &str, max: usize) {
+        let len = s.len().min(max - 1);
+        unsafe { core::ptr::copy_nonoverlapping(s.as_ptr(), dst, len) };
+    }
+    let stat = &mount.stat;
+    unsafe {
+        core::ptr::write_bytes(buf, 0, STATFS64_SIZE);
+        *(buf as *mut u32) = stat.block_size; // f_bsize
+        *(buf.add(4) as *mut u32) = stat.block_size; // f_iosize
+        *(buf.add(8) as *mut u64) = stat.blocks;
+        *(buf.add(16) as *mut u64) = stat.free_blocks; // f_bfree
+        *(buf.add(24) as *mut u64) = stat.free_blocks; // f_bavail
+        *(buf.add(32) as *mut u64) = stat.files;
+        *(buf.add(40) as *mut u64) = stat.free_files;
+        *(buf.add(48) as *mut u32) = mount.dev; // f_fsid
+        *(buf.add(64) as *mut u32) = mount.flags;
+        copy_str(buf.add(72), mount.fs_type, 16);
+        let path = if mount.path.is_empty() {
+            "/"
+        } else {
+            &mount.path
+        };
+        copy_str(buf.add(88), path, 1024);
+        copy_str(buf.add(1112), &mount.source, 1024);
+    }
+}
+
+/// Copy a NUL-terminated string of at most 1023 bytes from user memory
+fn read_user_str(ptr: *const u8) -> String {
+    let mut bytes = Vec::new();
+    while bytes.len() < 1023 {
+        let c = unsafe { core::ptr::read(ptr.add(bytes.len())) };
+        if c == 0 {
+            break;
+        }
+        bytes.push(c);
+    }
+    String::from_utf8(bytes).unwrap_or_default()
+}
+
+/// mount(2) for the filesystem types we can create
+fn sys_mount(fs_type: &str, path: &str, flags: u32, data: *const u8) -> Result<u64, u64> {
+    if flags & MNT_UPDATE != 0 {
+        crate::vfs::update_mount(path, flags)?;
+        return Ok(0);
+    }
+    if crate::vfs::stat(path)?.mode & S_IFMT != S_IFDIR {
+        return Err(ENOTDIR);
+    }
+    let (fs, source): (Arc<dyn FileSystem>, String) = match fs_type {
+        "devfs" => (Arc::new(crate::devfs::Devfs), String::from("devfs")),
+        "tmpfs" => {
+            // Options such as "size=64m", or none for the defaults
+            let options = if data.is_null() {
+                String::new()
+            } else {
+                read_user_str(data)
+            };
+            let size = crate::tmpfs::size_option(&options, crate::tmpfs::default_size())?;
+            (
+                Arc::new(crate::tmpfs::Tmpfs::new(size)),
+                String::from("tmpfs"),
+            )
+        }
+        "hfs" => {
+            // struct hfs_mount_args starts with the device path
+            if data.is_null() {
+                return Err(EINVAL);
+            }
+            let fspec = unsafe { core::ptr::read_unaligned(data as *const u32) } as *const u8;
+            if fspec.is_null() {
+                return Err(EINVAL);
+            }
+            let source = read_user_str(fspec);
+            let device = crate::vfs::open(&source, O_RDONLY, 0)?;
+            (Arc::new(HfsFs::new(Arc::new(device), 0)?), source)
+        }
+        _ => return Err(ENOTSUP),
+    };
+    crate::vfs::mount(path, fs, &source, flags)?;
+    Ok(0)
+}
+
+/// Store a syscall result, setting the carry flag on error
+fn set_result(frame: &mut TrapFrame, result: Result<u64, u64>) {
+    match result {
+        Ok(val) => {
+            frame.x[0] = val;
+            frame.spsr &= !0x20000000;
+        }
+        Err(errno) => {
+            frame.x[0] = errno;
+            frame.spsr |= 0x20000000;
+        }
+    }
 }
 
 pub fn init_vectors() {
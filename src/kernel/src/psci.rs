@@ -0,0 +1,41 @@
+//! PSCI firmware interface used to power on secondary CPUs
+
+use core::arch::asm;
+
+// PSCI 0.2+ function IDs (SMC64 calling convention where applicable)
+const PSCI_VERSION: u32 = 0x8400_0000;
+const PSCI_CPU_ON_64: u32 = 0xC400_0003;
+
+// PSCI return codes
+pub const PSCI_SUCCESS: i64 = 0;
+pub const PSCI_ALREADY_ON: i64 = -4;
+
+/// Issue a PSCI call. QEMU virt without EL2/EL3 firmware uses the HVC conduit.
+fn call(function_id: u32, arg0: u64, arg1: u64, arg2: u64) -> i64 {
+    let ret: i64;
+    unsafe {
+        asm!(
+            "hvc #0",
+            inout("x0") function_id as u64 => ret,
+            inout("x1") arg0 => _,
+            inout("x2") arg1 => _,
+            inout("x3") arg2 => _,
+        );
+    }
+    ret
+}
+
+/// Returns the PSCI version as (major, minor)
+pub fn version() -> (u16, u16) {
+    let v = call(PSCI_VERSION, 0, 0, 0) as u32;
+    ((v >> 16) as u16, v as u16)
+}
+
+/// Power on the CPU identified by `mpidr`, starting at physical address `entry`
+/// with `context_id` in x0.
+pub fn cpu_on(mpidr: u64, entry: u64, context_id: u64) -> Result<(), i64> {
+    match call(PSCI_CPU_ON_64, mpidr, entry, context_id) {
+        PSCI_SUCCESS => Ok(()),
+        err => Err(err),
+    }
+}
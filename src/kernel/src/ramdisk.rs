@@ -0,0 +1,124 @@
+//! Memory-backed disk holding the initrd
+//!
+//! The boot loader's copy is used in place; the heap is kept clear of it.
+//! IPSW ramdisks come wrapped in an IMG3 container, whose payload is the
+//! disk image if it isn't encrypted.
+
+use crate::block::{BlockDevice, BlockReader};
+use crate::kprintln;
+
+/// "Img3", stored little-endian like every IMG3 field
+const IMG3_MAGIC: u32 = u32::from_be_bytes(*b"Img3");
+const IMG3_TAG_DATA: u32 = u32::from_be_bytes(*b"DATA");
+const IMG3_TAG_KBAG: u32 = u32::from_be_bytes(*b"KBAG");
+const IMG3_HEADER_SIZE: usize = 20;
+/// Tag magic, total length and data length
+const IMG3_TAG_HEADER_SIZE: usize = 12;
+
+pub struct RamDisk {
+    data: *mut u8,
+    size: u64,
+}
+
+// Reads and writes copy in and out of memory nothing else uses
+unsafe impl Send for RamDisk {}
+unsafe impl Sync for RamDisk {}
+
+impl RamDisk {
+    /// # Safety
+    /// `size` bytes at `data` must be memory nothing else uses, for ever.
+    pub unsafe fn new(data: *mut u8, size: u64) -> Self {
+        Self { data, size }
+    }
+
+    fn contains(&self, offset: u64, len: usize) -> bool {
+        offset
+            .checked_add(len as u64)
+            .is_some_and(|end| end <= self.size)
+    }
+}
+
+impl BlockReader for RamDisk {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> bool {
+        if !self.contains(offset, buf.len()) {
+            return false;
+        }
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                self.data.add(offset as usize),
+                buf.as_mut_ptr(),
+                buf.len(),
+            );
+        }
+        true
+    }
+}
+
+impl BlockDevice for RamDisk {
+    fn write_at(&self, offset: u64, buf: &[u8]) -> bool {
+        if !self.contains(offset, buf.len()) {
+            return false;
+        }
+        unsafe {
+            core::ptr::copy_nonoverlapping(buf.as_ptr(), self.data.add(offset as usize), buf.len());
+        }
+        true
+    }
+
+    fn flush(&self) -> bool {
+        true
+    }
+
+    fn size(&self) -> u64 {
+        self.size
+    }
+}
+
+fn u32_le(image: &[u8], offset: usize) -> Option<u32> {
+    let bytes = image.get(offset..offset + 4)?;
+    Some(u32::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+/// Offset and length of the payload of an IMG3 container. None if `image`
+/// isn't one, or its payload is encrypted.
+fn img3_payload(image: &[u8]) -> Option<(usize, usize)> {
+    if u32_le(image, 0)? != IMG3_MAGIC {
+        return None;
+    }
+    let mut offset = IMG3_HEADER_SIZE;
+    let mut payload = None;
+    while let (Some(tag), Some(total_len), Some(data_len)) = (
+        u32_le(image, offset),
+        u32_le(image, offset + 4),
+        u32_le(image, offset + 8),
+    ) {
+        match tag {
+            IMG3_TAG_DATA => payload = Some((offset + IMG3_TAG_HEADER_SIZE, data_len as usize)),
+            IMG3_TAG_KBAG => {
+                kprintln!("Ramdisk: IMG3 payload is encrypted");
+                return None;
+            }
+            _ => {}
+        }
+        if (total_len as usize) < IMG3_TAG_HEADER_SIZE {
+            break;
+        }
+        offset += total_len as usize;
+    }
+    payload.filter(|&(start, len)| start + len <= image.len())
+}
+
+/// The initrd, if the boot loader passed one
+pub fn init() -> Option<RamDisk> {
+    let initrd = crate::platform::get().initrd?;
+    let mut data = initrd.base as *mut u8;
+    let mut size = initrd.size;
+    let image = unsafe { core::slice::from_raw_parts(data, size as usize) };
+    if let Some((offset, len)) = img3_payload(image) {
+        kprintln!("Ramdisk: Using IMG3 payload of {} bytes", len);
+        data = unsafe { data.add(offset) };
+        size = len as u64;
+    }
+    kprintln!("Ramdisk: {} bytes at {:x}", size, data as usize);
+    Some(unsafe { RamDisk::new(data, size) })
+}
@@ -1,11 +1,14 @@
+use crate::fdtable::FdTable;
 use crate::ipc::IpcSpace;
 use crate::kprintln;
+use crate::percpu::{self, MAX_CPUS};
 use crate::process::CpuContext;
-use crate::vfs::FileHandle;
 use alloc::boxed::Box;
-use alloc::collections::VecDeque;
+use alloc::collections::{BTreeMap, BTreeSet, VecDeque};
+use alloc::string::String;
 use alloc::vec;
 use alloc::vec::Vec;
+use core::arch::asm;
 use core::sync::atomic::{AtomicU64, Ordering};
 use spin::Mutex;
 
@@ -15,6 +18,8 @@ static PID_COUNTER: AtomicU64 = AtomicU64::new(1);
 pub enum ProcessState {
     Ready,
     Running,
+    /// Waiting on a `WaitQueue`
+    Blocked,
     Dead,
 }
 
@@ -24,8 +29,13 @@ pub struct Process {
     pub context: CpuContext,
     pub stack: Vec<u8>,
     pub ipc_space: IpcSpace,
-    pub files: Vec<Option<FileHandle>>,
+    pub files: FdTable,
     pub signal_mask: u32,
+    /// sigaction handlers as set by user space (0 = SIG_DFL, 1 = SIG_IGN)
+    pub signal_handlers: [u32; 32],
+    pub tls_base: u64,
+    /// Current directory, as a canonical absolute path
+    pub cwd: String,
 }
 
 impl Process {
@@ -55,7 +65,7 @@ impl Process {
         context.regs[12] = kernel_thread_starter as *const () as u64; // x30/lr
 
         let mut actual_entry = entry_point;
-        let mut spsr = 0x3c0u64; // Sets bits 9,8,7,6 (DAIF masked)
+        let mut spsr = 0x340u64; // Sets bits 9,8,6 (D, A, F masked; IRQs enabled)
         if !is_64bit {
             if (entry_point & 1) != 0 {
                 spsr |= 0x20; // T bit (Thumb mode)
@@ -89,67 +99,102 @@ impl Process {
             context,
             stack,
             ipc_space: IpcSpace::new(),
-            files: {
-                let mut f = Vec::with_capacity(32);
-                f.push(crate::vfs::open("/dev/random")); // stdin
-                f.push(crate::vfs::open("/dev/random")); // stdout
-                f.push(crate::vfs::open("/dev/random")); // stderr
-                for _ in 3..32 {
-                    f.push(None);
-                }
-                f
-            },
+            files: FdTable::with_stdio(),
             signal_mask: 0,
+            signal_handlers: [0; 32],
+            tls_base,
+            cwd: String::from("/"),
         }
     }
 }
 
-pub struct Scheduler {
+/// Per-CPU run queue
+pub struct RunQueue {
     pub processes: VecDeque<Box<Process>>,
     pub current_process: Option<Box<Process>>,
+    /// Process being switched away from. It is re-queued by `finish_switch`
+    /// once its context has been saved, so no other CPU can pick it up early.
+    switching_out: Option<Box<Process>>,
+    /// Saved context of this CPU's idle loop
+    idle_context: CpuContext,
 }
 
-impl Scheduler {
+impl RunQueue {
     pub const fn new() -> Self {
         Self {
             processes: VecDeque::new(),
             current_process: None,
+            switching_out: None,
+            idle_context: CpuContext { regs: [0; 13] },
         }
     }
 
-    pub fn add_process(&mut self, process: Process) {
-        self.processes.push_back(Box::new(process));
+    pub fn add_process(&mut self, process: Box<Process>) {
+        self.processes.push_back(process);
+    }
+
+    /// Number of runnable processes on this CPU, including the running one
+    pub fn load(&self) -> usize {
+        self.processes.len() + self.current_process.is_some() as usize
     }
 
     // Returns (ptr_to_prev_ctx, ptr_to_next_ctx)
     // Box<Process> ensures memory location of Process struct is stable on heap.
-    pub fn schedule_next(&mut self) -> Option<(Option<*mut CpuContext>, *const CpuContext)> {
-        if let Some(next_proc) = self.processes.pop_front() {
-            // We have a next process.
+    pub fn schedule_next(&mut self) -> Option<(*mut CpuContext, *const CpuContext)> {
+        let current_runnable = self
+            .current_process
+            .as_ref()
+            .is_some_and(|p| p.state == ProcessState::Running);
 
-            // If there is a current process, put it back in queue.
-            if let Some(mut prev) = self.current_process.take() {
+        let next_proc = match self.processes.pop_front() {
+            Some(next) => Some(next),
+            // Keep running current if it is still runnable, or stay idle
+            None if current_runnable || self.current_process.is_none() => return None,
+            // Current can't continue and nothing else is ready: go idle
+            None => None,
+        };
+
+        let prev_ctx_ptr = if let Some(mut prev) = self.current_process.take() {
+            if prev.state == ProcessState::Running {
                 prev.state = ProcessState::Ready;
-                self.processes.push_back(prev);
             }
+            let ptr = &mut prev.context as *mut CpuContext;
+            self.switching_out = Some(prev);
+            ptr
+        } else {
+            &mut self.idle_context as *mut CpuContext
+        };
 
-            // Promote next to current
-            self.current_process = Some(next_proc);
-
-            // Now we need pointers.
-
-            let next_ctx_ptr = &self.current_process.as_ref().unwrap().context as *const CpuContext;
+        let next_ctx_ptr = if let Some(mut next) = next_proc {
+            next.state = ProcessState::Running;
+            set_user_tls(next.tls_base);
+            let ptr = &next.context as *const CpuContext;
+            self.current_process = Some(next);
+            ptr
+        } else {
+            &self.idle_context as *const CpuContext
+        };
 
-            // Prev address? It is now at the BACK of the queue.
-            let prev_ctx_ptr = self
-                .processes
-                .back_mut()
-                .map(|p| &mut p.context as *mut CpuContext);
+        Some((prev_ctx_ptr, next_ctx_ptr))
+    }
 
-            Some((prev_ctx_ptr, next_ctx_ptr))
-        } else {
-            // No ready process. Keep running current.
-            None
+    /// Called on the new stack after a context switch
+    pub fn finish_switch(&mut self) {
+        if let Some(mut prev) = self.switching_out.take() {
+            match prev.state {
+                ProcessState::Dead => kprintln!("Reaping PID {}", prev.pid),
+                ProcessState::Blocked => {
+                    let mut sleepers = SLEEPERS.lock();
+                    if sleepers.woken.remove(&prev.pid) {
+                        // Woken before it got off the CPU
+                        prev.state = ProcessState::Ready;
+                        self.processes.push_back(prev);
+                    } else {
+                        sleepers.parked.insert(prev.pid, prev);
+                    }
+                }
+                _ => self.processes.push_back(prev),
+            }
         }
     }
 
@@ -162,4 +207,182 @@ impl Scheduler {
     }
 }
 
-pub static SCHEDULER: Mutex<Scheduler> = Mutex::new(Scheduler::new());
+static RUN_QUEUES: [Mutex<RunQueue>; MAX_CPUS] = [const { Mutex::new(RunQueue::new()) }; MAX_CPUS];
+
+/// Blocked processes, off every run queue
+struct Sleepers {
+    parked: BTreeMap<u64, Box<Process>>,
+    /// Processes woken while still switching out; `finish_switch` requeues them.
+    /// A process woken while running keeps its entry until it blocks or exits.
+    woken: BTreeSet<u64>,
+}
+
+static SLEEPERS: Mutex<Sleepers> = Mutex::new(Sleepers {
+    parked: BTreeMap::new(),
+    woken: BTreeSet::new(),
+});
+
+/// Run queue of the calling CPU
+pub fn this_cpu() -> &'static Mutex<RunQueue> {
+    &RUN_QUEUES[percpu::cpu_id()]
+}
+
+fn set_user_tls(tls_base: u64) {
+    unsafe {
+        asm!("msr tpidr_el0, {0}", "msr tpidrro_el0, {0}", in(reg) tls_base);
+    }
+}
+
+/// Pids of the processes spawned that haven't started exiting
+static LIVE: Mutex<BTreeSet<u64>> = Mutex::new(BTreeSet::new());
+
+/// Enqueue a new process on the least loaded online CPU. Returns its pid.
+pub fn spawn(process: Process) -> u64 {
+    let pid = process.pid;
+    LIVE.lock().insert(pid);
+    enqueue(Box::new(process));
+    pid
+}
+
+/// Note that process `pid` is exiting, before anyone is told
+pub fn exiting(pid: u64) {
+    LIVE.lock().remove(&pid);
+    // A wakeup it never blocked for
+    SLEEPERS.lock().woken.remove(&pid);
+}
+
+/// Whether process `pid` exists and isn't exiting
+pub fn exists(pid: u64) -> bool {
+    LIVE.lock().contains(&pid)
+}
+
+/// Pids of the processes that exist and aren't exiting
+pub fn live_pids() -> Vec<u64> {
+    LIVE.lock().iter().copied().collect()
+}
+
+fn enqueue(process: Box<Process>) {
+    let me = percpu::cpu_id();
+    let target = percpu::online_cpus()
+        .min_by_key(|&c| (RUN_QUEUES[c].lock().load(), c != me))
+        .unwrap_or(me);
+
+    RUN_QUEUES[target].lock().add_process(process);
+    if target != me {
+        crate::smp::send_ipi(target, crate::smp::IPI_RESCHEDULE);
+    }
+}
+
+/// Put the current process to sleep until `wake` is called for it
+pub fn block_current() {
+    if let Some(proc) = this_cpu().lock().current_process.as_mut() {
+        proc.state = ProcessState::Blocked;
+    }
+    yield_now();
+}
+
+/// Whether the caller runs on behalf of a process, and so can block
+pub fn can_block() -> bool {
+    this_cpu().lock().current_process.is_some()
+}
+
+/// Make a blocked process runnable again
+pub fn wake(pid: u64) {
+    let mut process = {
+        let mut sleepers = SLEEPERS.lock();
+        match sleepers.parked.remove(&pid) {
+            Some(process) => process,
+            None => {
+                // Not parked yet: finish_switch will requeue it
+                sleepers.woken.insert(pid);
+                return;
+            }
+        }
+    };
+    process.state = ProcessState::Ready;
+    enqueue(process);
+}
+
+/// Pull one ready process from the busiest CPU if it has at least two more
+/// runnable processes than `cpu`.
+pub fn balance(cpu: usize) {
+    let my_load = RUN_QUEUES[cpu].lock().load();
+    let busiest = percpu::online_cpus()
+        .filter(|&c| c != cpu)
+        .map(|c| (c, RUN_QUEUES[c].lock().load()))
+        .max_by_key(|&(_, load)| load);
+
+    let Some((busiest, load)) = busiest else {
+        return;
+    };
+    if load < my_load + 2 {
+        return;
+    }
+
+    // Lock in index order to avoid deadlocking against a concurrent balance
+    let (mut src, mut dst) = if busiest < cpu {
+        let src = RUN_QUEUES[busiest].lock();
+        (src, RUN_QUEUES[cpu].lock())
+    } else {
+        let dst = RUN_QUEUES[cpu].lock();
+        (RUN_QUEUES[busiest].lock(), dst)
+    };
+    if let Some(process) = src.processes.pop_back() {
+        kprintln!(
+            "Scheduler: Migrating PID {} from CPU {} to CPU {}",
+            process.pid,
+            busiest,
+            cpu
+        );
+        dst.processes.push_back(process);
+    }
+}
+
+unsafe extern "C" {
+    fn __switch_to(prev: *mut CpuContext, next: *const CpuContext);
+}
+
+/// Switch to the next ready process on this CPU, if any
+pub fn yield_now() {
+    let pointers = this_cpu().lock().schedule_next();
+    if let Some((prev, next)) = pointers {
+        unsafe {
+            __switch_to(prev, next);
+        }
+        // We may resume on a different CPU than the one we left
+        this_cpu().lock().finish_switch();
+    }
+}
+
+/// Reschedule if the tick or an IPI asked for it. Called on IRQ return to user mode.
+pub fn preempt() {
+    if percpu::current().need_resched.swap(false, Ordering::AcqRel) {
+        yield_now();
+    }
+}
+
+/// First code a new process runs after being switched to (see switch.s)
+#[unsafe(no_mangle)]
+pub extern "C" fn schedule_tail() {
+    this_cpu().lock().finish_switch();
+}
+
+/// Idle thread of every CPU. Runs ready processes and waits for interrupts
+/// when there is nothing to do.
+pub fn idle_loop() -> ! {
+    let cpu = percpu::cpu_id();
+    loop {
+        balance(cpu);
+        percpu::current()
+            .need_resched
+            .store(false, Ordering::Release);
+        yield_now();
+
+        let idle = this_cpu().lock().processes.is_empty();
+        if idle {
+            unsafe {
+                asm!("msr daifclr, #2", "wfi", "msr daifset, #2");
+            }
+        }
+    }
+}
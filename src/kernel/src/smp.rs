@@ -0,0 +1,146 @@
+//! Secondary CPU bring-up and inter-processor interrupts
+
+use crate::percpu::{self, MAX_CPUS};
+use crate::{gic, kprintln, psci};
+use alloc::vec;
+use core::arch::asm;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+/// Software generated interrupt numbers used for IPIs
+pub const IPI_RESCHEDULE: u32 = 0;
+pub const IPI_TLB_SHOOTDOWN: u32 = 1;
+
+const SECONDARY_STACK_SIZE: usize = 64 * 1024;
+
+static NCPUS: AtomicUsize = AtomicUsize::new(1);
+static TLB_GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// Number of CPUs that are online
+pub fn ncpus() -> usize {
+    NCPUS.load(Ordering::Acquire)
+}
+
+/// Bring up secondary CPUs with PSCI CPU_ON. Must be called after the heap,
+/// the GIC distributor and the boot CPU's interface are initialized.
+pub fn init() {
+    crate::irq::register_handler(IPI_RESCHEDULE, handle_ipi);
+    crate::irq::register_handler(IPI_TLB_SHOOTDOWN, handle_ipi);
+
+    let (major, minor) = psci::version();
+    kprintln!("SMP: PSCI version {}.{}", major, minor);
+
+    unsafe extern "C" {
+        fn secondary_entry();
+    }
+
+    for cpu in 1..MAX_CPUS {
+        let stack = vec![0u8; SECONDARY_STACK_SIZE];
+        let stack_top = (stack.as_ptr() as u64 + SECONDARY_STACK_SIZE as u64) & !15;
+
+        // QEMU virt numbers CPUs in Aff0 of MPIDR
+        match psci::cpu_on(cpu as u64, secondary_entry as *const () as u64, stack_top) {
+            Ok(()) => {
+                core::mem::forget(stack);
+                let mut spins = 0;
+                while !percpu::get(cpu).online.load(Ordering::Acquire) {
+                    core::hint::spin_loop();
+                    spins += 1;
+                    if spins > 100_000_000 {
+                        kprintln!("SMP: CPU {} did not come online", cpu);
+                        break;
+                    }
+                }
+            }
+            Err(psci::PSCI_ALREADY_ON) => {
+                kprintln!("SMP: CPU {} is already on", cpu);
+            }
+            Err(_) => {
+                // No such CPU: QEMU numbers them contiguously
+                break;
+            }
+        }
+    }
+
+    let n = ncpus();
+    kprintln!("SMP: {} CPU(s) online", n);
+    crate::mmu::commpage_set_ncpus(n as u8);
+}
+
+/// Rust entry point of secondary CPUs, called from `secondary_entry` in boot.s
+#[unsafe(no_mangle)]
+pub extern "C" fn secondary_main(stack_top: u64) -> ! {
+    crate::process::init_vectors();
+    crate::mmu::init_secondary();
+    gic::init_cpu_interface();
+
+    percpu::init_cpu(stack_top);
+    NCPUS.fetch_add(1, Ordering::AcqRel);
+    crate::timer::init_cpu();
+
+    kprintln!("SMP: CPU {} online", percpu::cpu_id());
+
+    crate::scheduler::idle_loop()
+}
+
+/// Send an IPI to a single CPU
+pub fn send_ipi(cpu: usize, ipi: u32) {
+    gic::send_sgi(ipi, 1 << cpu);
+}
+
+/// Send an IPI to every online CPU except the caller
+pub fn broadcast_ipi(ipi: u32) {
+    let me = percpu::cpu_id();
+    let mask = percpu::online_cpus()
+        .filter(|&c| c != me)
+        .fold(0u8, |m, c| m | (1 << c));
+    if mask != 0 {
+        gic::send_sgi(ipi, mask);
+    }
+}
+
+fn flush_local_tlb() {
+    unsafe {
+        asm!("dsb ishst", "tlbi vmalle1", "dsb ish", "isb");
+    }
+    percpu::current()
+        .tlb_generation
+        .store(TLB_GENERATION.load(Ordering::Acquire), Ordering::Release);
+}
+
+/// Invalidate the TLBs of all online CPUs and wait until each has done so
+pub fn tlb_shootdown() {
+    if ncpus() == 1 {
+        flush_local_tlb();
+        return;
+    }
+
+    let generation = TLB_GENERATION.fetch_add(1, Ordering::AcqRel) + 1;
+    flush_local_tlb();
+    broadcast_ipi(IPI_TLB_SHOOTDOWN);
+
+    let me = percpu::cpu_id();
+    for cpu in percpu::online_cpus().filter(|&c| c != me) {
+        while percpu::get(cpu).tlb_generation.load(Ordering::Acquire) < generation {
+            // Another CPU may be shooting us down at the same time with IRQs
+            // masked, so service its request while we wait.
+            if percpu::current().tlb_generation.load(Ordering::Acquire)
+                < TLB_GENERATION.load(Ordering::Acquire)
+            {
+                flush_local_tlb();
+            }
+            core::hint::spin_loop();
+        }
+    }
+}
+
+fn handle_ipi(irq: u32) {
+    match irq {
+        IPI_RESCHEDULE => {
+            percpu::current()
+                .need_resched
+                .store(true, Ordering::Release);
+        }
+        IPI_TLB_SHOOTDOWN => flush_local_tlb(),
+        _ => {}
+    }
+}
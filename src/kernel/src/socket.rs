@@ -0,0 +1,291 @@
+//! BSD sockets
+//!
+//! A socket is a file whose `File::socket` returns it, so it lives in the
+//! descriptor table like any other and read and write work on it. The
+//! socket syscalls look it up through that. Internet sockets are in `inet`,
+//! UNIX domain sockets in `unix`.
+
+use crate::errno::{EAFNOSUPPORT, EINVAL, ENOTSOCK, EOPNOTSUPP};
+use crate::vfs::{File, FileHandle, O_NONBLOCK, O_RDWR};
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+
+// Address families
+pub const AF_UNIX: u32 = 1;
+pub const AF_INET: u32 = 2;
+pub const AF_INET6: u32 = 30;
+
+// Socket types
+pub const SOCK_STREAM: u32 = 1;
+pub const SOCK_DGRAM: u32 = 2;
+
+/// Option level of the options below
+pub const SOL_SOCKET: u32 = 0xffff;
+pub const SO_REUSEADDR: u32 = 0x0004;
+pub const SO_KEEPALIVE: u32 = 0x0008;
+pub const SO_BROADCAST: u32 = 0x0020;
+pub const SO_REUSEPORT: u32 = 0x0200;
+pub const SO_SNDBUF: u32 = 0x1001;
+pub const SO_RCVBUF: u32 = 0x1002;
+pub const SO_ERROR: u32 = 0x1007;
+pub const SO_TYPE: u32 = 0x1008;
+pub const SO_NOSIGPIPE: u32 = 0x1022;
+
+// send(2) and recv(2) flags
+pub const MSG_PEEK: u32 = 0x2;
+pub const MSG_TRUNC: u32 = 0x10;
+pub const MSG_CTRUNC: u32 = 0x20;
+pub const MSG_WAITALL: u32 = 0x40;
+pub const MSG_DONTWAIT: u32 = 0x80;
+
+// shutdown(2)
+pub const SHUT_RD: u32 = 0;
+pub const SHUT_WR: u32 = 1;
+pub const SHUT_RDWR: u32 = 2;
+
+/// Sizes of struct sockaddr_in and struct sockaddr_in6
+const SOCKADDR_IN_LEN: usize = 16;
+const SOCKADDR_IN6_LEN: usize = 28;
+/// Room for the path in struct sockaddr_un
+const SUN_PATH_LEN: usize = 104;
+/// Largest struct sockaddr accepted from user space
+const SOCKADDR_MAX: usize = 255;
+
+/// A socket address, as a struct sockaddr holds it
+#[derive(Clone, Debug, PartialEq)]
+pub enum SockAddr {
+    Inet(SocketAddr),
+    /// A UNIX domain socket's path, empty for an unbound socket
+    Unix(String),
+}
+
+impl SockAddr {
+    /// Parse a struct sockaddr
+    fn parse(buf: &[u8]) -> Result<Self, u64> {
+        let family = *buf.get(1).ok_or(EINVAL)? as u32;
+        match family {
+            AF_UNIX => {
+                let path = buf.get(2..).ok_or(EINVAL)?;
+                let path = &path[..path.iter().position(|&c| c == 0).unwrap_or(path.len())];
+                if path.len() >= SUN_PATH_LEN {
+                    return Err(EINVAL);
+                }
+                let path = core::str::from_utf8(path).map_err(|_| EINVAL)?;
+                Ok(Self::Unix(String::from(path)))
+            }
+            AF_INET => {
+                let buf: &[u8; SOCKADDR_IN_LEN] = buf
+                    .get(..SOCKADDR_IN_LEN)
+                    .ok_or(EINVAL)?
+                    .try_into()
+                    .unwrap();
+                let port = u16::from_be_bytes([buf[2], buf[3]]);
+                let ip = Ipv4Addr::new(buf[4], buf[5], buf[6], buf[7]);
+                Ok(Self::Inet(SocketAddr::V4(SocketAddrV4::new(ip, port))))
+            }
+            AF_INET6 => {
+                let buf: &[u8; SOCKADDR_IN6_LEN] = buf
+                    .get(..SOCKADDR_IN6_LEN)
+                    .ok_or(EINVAL)?
+                    .try_into()
+                    .unwrap();
+                let port = u16::from_be_bytes([buf[2], buf[3]]);
+                let flowinfo = u32::from_be_bytes(buf[4..8].try_into().unwrap());
+                let ip = Ipv6Addr::from(<[u8; 16]>::try_from(&buf[8..24]).unwrap());
+                let scope_id = u32::from_le_bytes(buf[24..28].try_into().unwrap());
+                Ok(Self::Inet(SocketAddr::V6(SocketAddrV6::new(
+                    ip, port, flowinfo, scope_id,
+                ))))
+            }
+            _ => Err(EAFNOSUPPORT),
+        }
+    }
+
+    /// The struct sockaddr for this address
+    fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            Self::Unix(path) => {
+                let mut buf = Vec::with_capacity(path.len() + 3);
+                buf.extend_from_slice(&[(path.len() + 3) as u8, AF_UNIX as u8]);
+                buf.extend_from_slice(path.as_bytes());
+                buf.push(0);
+                buf
+            }
+            Self::Inet(SocketAddr::V4(addr)) => {
+                let mut buf = Vec::with_capacity(SOCKADDR_IN_LEN);
+                buf.extend_from_slice(&[SOCKADDR_IN_LEN as u8, AF_INET as u8]);
+                buf.extend_from_slice(&addr.port().to_be_bytes());
+                buf.extend_from_slice(&addr.ip().octets());
+                buf.resize(SOCKADDR_IN_LEN, 0);
+                buf
+            }
+            Self::Inet(SocketAddr::V6(addr)) => {
+                let mut buf = Vec::with_capacity(SOCKADDR_IN6_LEN);
+                buf.extend_from_slice(&[SOCKADDR_IN6_LEN as u8, AF_INET6 as u8]);
+                buf.extend_from_slice(&addr.port().to_be_bytes());
+                buf.extend_from_slice(&addr.flowinfo().to_be_bytes());
+                buf.extend_from_slice(&addr.ip().octets());
+                buf.extend_from_slice(&addr.scope_id().to_le_bytes());
+                buf
+            }
+        }
+    }
+}
+
+/// Read the struct sockaddr of `len` bytes at `ptr`
+pub fn read_sockaddr(ptr: *const u8, len: usize) -> Result<SockAddr, u64> {
+    if ptr.is_null() || len > SOCKADDR_MAX {
+        return Err(EINVAL);
+    }
+    let buf = unsafe { core::slice::from_raw_parts(ptr, len) };
+    SockAddr::parse(buf)
+}
+
+/// Store `addr` at `ptr` for a caller with room for `*lenp` bytes, truncating
+/// it to fit, and set `*lenp` to its full length. Nothing is stored if either
+/// pointer is null.
+pub fn write_sockaddr(addr: &SockAddr, ptr: *mut u8, lenp: *mut u32) {
+    if ptr.is_null() || lenp.is_null() {
+        return;
+    }
+    let bytes = addr.to_bytes();
+    unsafe {
+        let len = bytes.len().min(*lenp as usize);
+        core::ptr::copy_nonoverlapping(bytes.as_ptr(), ptr, len);
+        *lenp = bytes.len() as u32;
+    }
+}
+
+/// Credentials of the sender of a message, for SCM_CREDS
+#[derive(Clone, Copy)]
+pub struct Creds {
+    pub pid: u32,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+/// Ancillary data of a message
+#[derive(Default)]
+pub struct Control {
+    /// Files passed with SCM_RIGHTS
+    pub rights: Vec<Arc<FileHandle>>,
+    /// Sender credentials, if SCM_CREDS asked for them
+    pub creds: Option<Creds>,
+}
+
+impl Control {
+    pub fn is_empty(&self) -> bool {
+        self.rights.is_empty() && self.creds.is_none()
+    }
+}
+
+/// What recvmsg receives
+pub struct Received {
+    pub len: usize,
+    /// The sender, for datagram sockets
+    pub from: Option<SockAddr>,
+    pub control: Control,
+    /// Whether part of a datagram didn't fit and was dropped
+    pub truncated: bool,
+}
+
+/// The operations of a socket beyond read and write. Calls that may block
+/// fail with EAGAIN instead if `nonblock`.
+pub trait Socket: Send + Sync {
+    fn bind(&self, addr: SockAddr) -> Result<(), u64>;
+    /// Connect to `addr`, or for datagram sockets set the default peer
+    fn connect(&self, addr: SockAddr, nonblock: bool) -> Result<(), u64>;
+    fn listen(&self, backlog: u32) -> Result<(), u64>;
+    /// Wait for a connection. Returns the connected socket and its peer.
+    fn accept(&self, nonblock: bool) -> Result<(Box<dyn File>, SockAddr), u64>;
+    /// Send `buf` to `to`, or to the peer if None
+    fn send_to(
+        &self,
+        buf: &[u8],
+        flags: u32,
+        to: Option<SockAddr>,
+        nonblock: bool,
+    ) -> Result<usize, u64>;
+    /// Receive into `buf`. Returns the length and, for datagram sockets, the
+    /// sender.
+    fn recv_from(
+        &self,
+        buf: &mut [u8],
+        flags: u32,
+        nonblock: bool,
+    ) -> Result<(usize, Option<SockAddr>), u64>;
+    /// Send with ancillary data. Only UNIX domain sockets carry files.
+    fn send_msg(
+        &self,
+        buf: &[u8],
+        flags: u32,
+        to: Option<SockAddr>,
+        control: Control,
+        nonblock: bool,
+    ) -> Result<usize, u64> {
+        if !control.rights.is_empty() {
+            return Err(EOPNOTSUPP);
+        }
+        self.send_to(buf, flags, to, nonblock)
+    }
+    /// Receive with ancillary data
+    fn recv_msg(&self, buf: &mut [u8], flags: u32, nonblock: bool) -> Result<Received, u64> {
+        let (len, from) = self.recv_from(buf, flags, nonblock)?;
+        Ok(Received {
+            len,
+            from,
+            control: Control::default(),
+            truncated: false,
+        })
+    }
+    fn shutdown(&self, how: u32) -> Result<(), u64>;
+    fn local_addr(&self) -> Result<SockAddr, u64>;
+    fn peer_addr(&self) -> Result<SockAddr, u64>;
+    /// Set an int-valued option
+    fn set_option(&self, level: u32, name: u32, value: i32) -> Result<(), u64>;
+    fn get_option(&self, level: u32, name: u32) -> Result<i32, u64>;
+}
+
+/// socket(2)
+pub fn create(domain: u32, ty: u32, protocol: u32) -> Result<FileHandle, u64> {
+    let file: Box<dyn File> = match domain {
+        AF_UNIX => Box::new(crate::unix::UnixSocket::new(ty, protocol)?),
+        AF_INET | AF_INET6 => Box::new(crate::inet::InetSocket::new(domain, ty, protocol)?),
+        _ => return Err(EAFNOSUPPORT),
+    };
+    Ok(FileHandle::new(file, O_RDWR))
+}
+
+/// socketpair(2): two sockets connected to each other
+pub fn pair(domain: u32, ty: u32, protocol: u32) -> Result<(FileHandle, FileHandle), u64> {
+    if domain != AF_UNIX {
+        return Err(EOPNOTSUPP);
+    }
+    let (a, b) = crate::unix::UnixSocket::pair(ty, protocol)?;
+    Ok((
+        FileHandle::new(Box::new(a), O_RDWR),
+        FileHandle::new(Box::new(b), O_RDWR),
+    ))
+}
+
+/// The socket open as `handle`
+pub fn socket_of(handle: &FileHandle) -> Result<&dyn Socket, u64> {
+    handle.file.socket().ok_or(ENOTSOCK)
+}
+
+/// Whether calls on `handle` with send or recv `flags` should fail rather
+/// than block
+pub fn nonblocking(handle: &FileHandle, flags: u32) -> bool {
+    handle.status_flags() & O_NONBLOCK != 0 || flags & MSG_DONTWAIT != 0
+}
+
+/// Whether a send on `handle` failing with EPIPE should raise SIGPIPE
+pub fn raises_sigpipe(handle: &FileHandle) -> bool {
+    handle
+        .file
+        .socket()
+        .is_none_or(|socket| socket.get_option(SOL_SOCKET, SO_NOSIGPIPE) != Ok(1))
+}
@@ -46,6 +46,9 @@ __switch_to:
  * x28 = tls_base
  */
 kernel_thread_starter:
+    /* Let the scheduler re-queue the process we switched away from */
+    bl  schedule_tail
+
     msr elr_el1, x19
     msr sp_el0, x20
     /* AArch32 uses X13 as SP (R13) and X14 as LR (R14) */
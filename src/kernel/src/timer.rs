@@ -0,0 +1,65 @@
+//! ARM generic timer driving the per-CPU scheduler tick
+
+use crate::percpu;
+use core::arch::asm;
+use core::sync::atomic::Ordering;
+
+pub const TICK_HZ: u64 = 100;
+
+/// Wall clock time at boot, in seconds since the Unix epoch. There is no
+/// RTC yet, so every boot starts on Jan 28 2022.
+pub const BOOT_TIME: i64 = 1_643_328_000;
+
+pub fn frequency() -> u64 {
+    let freq: u64;
+    unsafe {
+        asm!("mrs {}, cntfrq_el0", out(reg) freq);
+    }
+    freq
+}
+
+/// Current value of the virtual counter
+pub fn counter() -> u64 {
+    let count: u64;
+    unsafe {
+        asm!("mrs {}, cntvct_el0", out(reg) count);
+    }
+    count
+}
+
+/// Seconds since the Unix epoch, counted from BOOT_TIME
+pub fn unix_time() -> i64 {
+    BOOT_TIME + (counter() / frequency()) as i64
+}
+
+/// Counter ticks in `nanos` nanoseconds, rounded up
+pub fn ticks_from_nanos(nanos: u64) -> u64 {
+    let ticks = (nanos as u128 * frequency() as u128).div_ceil(1_000_000_000);
+    ticks.min(u64::MAX as u128) as u64
+}
+
+fn arm() {
+    let interval = frequency() / TICK_HZ;
+    unsafe {
+        asm!("msr cntv_tval_el0, {}", in(reg) interval);
+        asm!("msr cntv_ctl_el0, {}", in(reg) 1u64);
+    }
+}
+
+fn handle_tick(_irq: u32) {
+    arm();
+    let cpu = percpu::current();
+    cpu.ticks.fetch_add(1, Ordering::Relaxed);
+    cpu.need_resched.store(true, Ordering::Release);
+    crate::klog::wake_readers();
+    crate::entropy::add_timer_sample(counter());
+    crate::net::tick();
+    crate::waitqueue::tick();
+}
+
+/// Start the tick on the calling CPU. The timer PPI is banked, so every CPU
+/// calls this after its GIC CPU interface is up.
+pub fn init_cpu() {
+    crate::irq::register_handler(crate::platform::get().timer_irq, handle_tick);
+    arm();
+}
@@ -0,0 +1,284 @@
+//! Memory-backed filesystem for /tmp, /var/tmp and /var/run
+//!
+//! Files live in the kernel heap until they are removed, or until the
+//! filesystem is unmounted and no file on it is open. Each mount has a size
+//! limit counted in 4 KiB blocks: a file takes one block for its inode plus
+//! its contents rounded up to blocks. Growing past the limit, or while the
+//! heap is short even after the page cache gives memory back, fails with
+//! ENOSPC.
+
+use crate::errno::{
+    EEXIST, EINVAL, EISDIR, ELOOP, ENAMETOOLONG, ENOENT, ENOSPC, ENOTDIR, ENOTEMPTY, EOPNOTSUPP,
+    EPERM,
+};
+use crate::timer;
+use crate::vfs::{
+    Attr, DT_DIR, DT_FIFO, DT_LNK, DT_REG, DT_SOCK, DirEntry, DirFile, File, FileSystem, FsStat,
+    S_IFDIR, S_IFIFO, S_IFLNK, S_IFMT, S_IFREG,
+};
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use memfs::{BLOCK_SIZE, Tree};
+use spin::Mutex;
+
+/// Heap left for the rest of the kernel when files grow
+const HEAP_RESERVE: u64 = 16 * 1024 * 1024;
+
+/// Gives the tree the time of day and the kernel heap
+struct KernelHost;
+
+impl memfs::Host for KernelHost {
+    fn now(&self) -> i64 {
+        timer::unix_time()
+    }
+
+    /// Whether the heap can spare `bytes` and still keep HEAP_RESERVE free,
+    /// after evicting cached pages if it can't at first
+    fn has_room(&self, bytes: u64) -> bool {
+        let needed = (bytes + HEAP_RESERVE) as usize;
+        if crate::heap::free_bytes() < needed {
+            crate::pagecache::reclaim(bytes as usize);
+        }
+        crate::heap::free_bytes() >= needed
+    }
+}
+
+static HOST: KernelHost = KernelHost;
+
+fn errno(error: memfs::Error) -> u64 {
+    match error {
+        memfs::Error::NotFound => ENOENT,
+        memfs::Error::Exists => EEXIST,
+        memfs::Error::NotDir => ENOTDIR,
+        memfs::Error::IsDir => EISDIR,
+        memfs::Error::NotEmpty => ENOTEMPTY,
+        memfs::Error::NameTooLong => ENAMETOOLONG,
+        memfs::Error::Invalid => EINVAL,
+        memfs::Error::Permission => EPERM,
+        memfs::Error::NoSpace => ENOSPC,
+    }
+}
+
+fn attr(stat: memfs::Stat) -> Attr {
+    Attr {
+        ino: stat.ino,
+        parent_ino: stat.parent_ino,
+        mode: stat.mode,
+        nlink: stat.nlink,
+        size: stat.size,
+        blocks: stat.blocks,
+        block_size: BLOCK_SIZE as u32,
+        atime: stat.atime,
+        mtime: stat.mtime,
+        ctime: stat.ctime,
+        birthtime: stat.birthtime,
+        entries: stat.entries,
+        ..Attr::default()
+    }
+}
+
+/// A tmpfs instance. Open files share its nodes, so they outlive an
+/// unmount.
+pub struct Tmpfs {
+    tree: Arc<Mutex<Tree>>,
+}
+
+impl Tmpfs {
+    /// An empty filesystem that may use up to `size` bytes
+    pub fn new(size: u64) -> Self {
+        Self {
+            tree: Arc::new(Mutex::new(Tree::new(size, &HOST))),
+        }
+    }
+}
+
+/// Parse a size in bytes, or in KiB, MiB or GiB with a k, m or g suffix
+fn parse_size(size: &str) -> Option<u64> {
+    let (digits, shift) = match size.as_bytes().last()? {
+        b'k' | b'K' => (&size[..size.len() - 1], 10),
+        b'm' | b'M' => (&size[..size.len() - 1], 20),
+        b'g' | b'G' => (&size[..size.len() - 1], 30),
+        _ => (size, 0),
+    };
+    digits.parse::<u64>().ok()?.checked_mul(1 << shift)
+}
+
+/// Default size limit for a mount: half of the free heap at mount time
+pub fn default_size() -> u64 {
+    crate::heap::free_bytes() as u64 / 2
+}
+
+/// Size limit from mount options, which are separated by commas. Only
+/// `size=` is known, e.g. `size=64m`. Without it the limit is `default`.
+pub fn size_option(options: &str, default: u64) -> Result<u64, u64> {
+    let mut size = default;
+    for option in options.split(',').filter(|option| !option.is_empty()) {
+        match option.split_once('=') {
+            Some(("size", value)) => size = parse_size(value).ok_or(EINVAL)?,
+            _ => return Err(EINVAL),
+        }
+    }
+    Ok(size)
+}
+
+impl FileSystem for Tmpfs {
+    fn fs_type(&self) -> &'static str {
+        "tmpfs"
+    }
+
+    fn getattr(&self, path: &str) -> Result<Attr, u64> {
+        let tree = self.tree.lock();
+        Ok(attr(tree.stat(tree.find(path).map_err(errno)?)))
+    }
+
+    fn mode(&self, path: &str) -> Result<u16, u64> {
+        let tree = self.tree.lock();
+        Ok(tree.mode(tree.find(path).map_err(errno)?))
+    }
+
+    fn readlink(&self, path: &str) -> Result<String, u64> {
+        let tree = self.tree.lock();
+        tree.readlink(tree.find(path).map_err(errno)?)
+            .map_err(errno)
+    }
+
+    fn remove(&self, path: &str, dir: bool) -> Result<(), u64> {
+        self.tree.lock().remove(path, dir).map_err(errno)
+    }
+
+    fn create(&self, path: &str, mode: u16) -> Result<(), u64> {
+        self.tree.lock().create(path, mode).map_err(errno)
+    }
+
+    fn mkdir(&self, path: &str, mode: u16) -> Result<(), u64> {
+        self.tree.lock().mkdir(path, mode).map_err(errno)
+    }
+
+    fn mknod(&self, path: &str, mode: u16) -> Result<(), u64> {
+        self.tree.lock().mknod(path, mode).map_err(errno)
+    }
+
+    fn symlink(&self, path: &str, target: &str) -> Result<(), u64> {
+        self.tree.lock().symlink(path, target).map_err(errno)
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<(), u64> {
+        self.tree.lock().rename(from, to).map_err(errno)
+    }
+
+    fn truncate(&self, path: &str, size: u64) -> Result<(), u64> {
+        let mut tree = self.tree.lock();
+        let ino = tree.find(path).map_err(errno)?;
+        tree.resize(ino, size).map_err(errno)
+    }
+
+    fn chmod(&self, path: &str, mode: u16) -> Result<(), u64> {
+        let mut tree = self.tree.lock();
+        let ino = tree.find(path).map_err(errno)?;
+        tree.chmod(ino, mode);
+        Ok(())
+    }
+
+    fn utimes(&self, path: &str, atime: i64, mtime: i64) -> Result<(), u64> {
+        let mut tree = self.tree.lock();
+        let ino = tree.find(path).map_err(errno)?;
+        tree.utimes(ino, atime, mtime);
+        Ok(())
+    }
+
+    fn readdir(&self, path: &str) -> Result<Vec<DirEntry>, u64> {
+        let tree = self.tree.lock();
+        let entries = tree.readdir(tree.find(path).map_err(errno)?);
+        Ok(entries
+            .map_err(errno)?
+            .into_iter()
+            .map(|entry| DirEntry {
+                ino: entry.ino,
+                kind: match entry.mode & S_IFMT {
+                    S_IFREG => DT_REG,
+                    S_IFDIR => DT_DIR,
+                    S_IFLNK => DT_LNK,
+                    S_IFIFO => DT_FIFO,
+                    _ => DT_SOCK,
+                },
+                name: entry.name,
+            })
+            .collect())
+    }
+
+    fn open(&self, path: &str, _flags: u32) -> Result<Box<dyn File>, u64> {
+        let mut tree = self.tree.lock();
+        let ino = tree.find(path).map_err(errno)?;
+        match tree.mode(ino) & S_IFMT {
+            S_IFREG => {}
+            S_IFDIR => {
+                let attr = attr(tree.stat(ino));
+                drop(tree);
+                return Ok(Box::new(DirFile::new(attr, self.readdir(path)?)));
+            }
+            S_IFLNK => return Err(ELOOP),
+            // Opened by the VFS
+            _ => return Err(EOPNOTSUPP),
+        }
+        tree.open(ino).map_err(errno)?;
+        Ok(Box::new(TmpFile {
+            tree: Arc::clone(&self.tree),
+            ino,
+        }))
+    }
+
+    fn statfs(&self) -> FsStat {
+        let (limit, free) = self.tree.lock().usage();
+        FsStat {
+            block_size: BLOCK_SIZE as u32,
+            blocks: limit,
+            free_blocks: free,
+            files: limit,
+            free_files: free,
+        }
+    }
+
+    fn cacheable(&self) -> bool {
+        false
+    }
+}
+
+/// An open regular file. It keeps its contents after it is removed.
+struct TmpFile {
+    tree: Arc<Mutex<Tree>>,
+    ino: u64,
+}
+
+impl Drop for TmpFile {
+    fn drop(&mut self) {
+        self.tree.lock().close(self.ino);
+    }
+}
+
+impl File for TmpFile {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> usize {
+        self.tree.lock().read(self.ino, offset, buf)
+    }
+
+    fn size(&self) -> u64 {
+        self.tree.lock().size(self.ino)
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<usize, u64> {
+        self.tree.lock().write(self.ino, offset, buf).map_err(errno)
+    }
+
+    fn truncate(&self, size: u64) -> Result<(), u64> {
+        self.tree.lock().resize(self.ino, size).map_err(errno)
+    }
+
+    fn mode(&self) -> u16 {
+        self.tree.lock().mode(self.ino)
+    }
+
+    fn getattr(&self) -> Attr {
+        attr(self.tree.lock().stat(self.ino))
+    }
+}
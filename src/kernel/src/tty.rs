@@ -0,0 +1,605 @@
+//! Terminal layer with a line discipline, backing /dev/console and /dev/tty
+
+use crate::devfs::{self, NodeKind};
+use crate::errno::{EAGAIN, ENOTTY};
+use crate::vfs::{File, Readiness};
+use crate::waitqueue::WaitQueue;
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+// termios c_iflag
+const ISTRIP: u32 = 0x0000_0020;
+const INLCR: u32 = 0x0000_0040;
+const IGNCR: u32 = 0x0000_0080;
+const ICRNL: u32 = 0x0000_0100;
+const IXON: u32 = 0x0000_0200;
+const IXANY: u32 = 0x0000_0800;
+const IMAXBEL: u32 = 0x0000_2000;
+const BRKINT: u32 = 0x0000_0002;
+
+// termios c_oflag
+const OPOST: u32 = 0x0000_0001;
+const ONLCR: u32 = 0x0000_0002;
+const OCRNL: u32 = 0x0000_0010;
+
+// termios c_cflag
+const CS8: u32 = 0x0000_0300;
+const CREAD: u32 = 0x0000_0800;
+const HUPCL: u32 = 0x0000_4000;
+
+// termios c_lflag
+const ECHOKE: u32 = 0x0000_0001;
+const ECHOE: u32 = 0x0000_0002;
+const ECHOK: u32 = 0x0000_0004;
+const ECHO: u32 = 0x0000_0008;
+const ECHONL: u32 = 0x0000_0010;
+const ECHOCTL: u32 = 0x0000_0040;
+const ISIG: u32 = 0x0000_0080;
+const ICANON: u32 = 0x0000_0100;
+const IEXTEN: u32 = 0x0000_0400;
+const NOFLSH: u32 = 0x8000_0000;
+
+// c_cc indices
+const VEOF: usize = 0;
+const VEOL: usize = 1;
+const VEOL2: usize = 2;
+const VERASE: usize = 3;
+const VWERASE: usize = 4;
+const VKILL: usize = 5;
+const VREPRINT: usize = 6;
+const VINTR: usize = 8;
+const VQUIT: usize = 9;
+const VSUSP: usize = 10;
+const VSTART: usize = 12;
+const VSTOP: usize = 13;
+const VLNEXT: usize = 14;
+const VMIN: usize = 16;
+const VTIME: usize = 17;
+const NCCS: usize = 20;
+
+// Signals generated by VINTR, VQUIT and VSUSP
+const SIGINT: u32 = 2;
+const SIGQUIT: u32 = 3;
+const SIGTSTP: u32 = 18;
+
+/// c_cc value that disables a control character
+const VDISABLE: u8 = 0xFF;
+
+// ioctl commands, as encoded by <sys/ioccom.h> for 32-bit processes
+const TIOCGETA: u32 = 0x402C_7413;
+const TIOCSETA: u32 = 0x802C_7414;
+const TIOCSETAW: u32 = 0x802C_7415;
+const TIOCSETAF: u32 = 0x802C_7416;
+const TIOCGWINSZ: u32 = 0x4008_7468;
+const TIOCSWINSZ: u32 = 0x8008_7467;
+const TIOCSCTTY: u32 = 0x2000_7461;
+const FIONREAD: u32 = 0x4004_667F;
+
+// Character majors of /dev/console and /dev/tty
+const CONSOLE_MAJOR: u32 = 0;
+const CTTY_MAJOR: u32 = 2;
+
+/// Longest line canonical mode will buffer
+const MAX_CANON: usize = 1024;
+
+#[derive(Clone, Copy)]
+struct Termios {
+    iflag: u32,
+    oflag: u32,
+    cflag: u32,
+    lflag: u32,
+    cc: [u8; NCCS],
+    ispeed: u32,
+    ospeed: u32,
+}
+
+impl Termios {
+    /// Defaults from <sys/ttydefaults.h>
+    const fn new() -> Self {
+        let mut cc = [VDISABLE; NCCS];
+        cc[VEOF] = 0x04; // ^D
+        cc[VERASE] = 0x7F; // DEL
+        cc[VWERASE] = 0x17; // ^W
+        cc[VKILL] = 0x15; // ^U
+        cc[VREPRINT] = 0x12; // ^R
+        cc[VINTR] = 0x03; // ^C
+        cc[VQUIT] = 0x1C; // ^\
+        cc[VSUSP] = 0x1A; // ^Z
+        cc[VSTART] = 0x11; // ^Q
+        cc[VSTOP] = 0x13; // ^S
+        cc[VLNEXT] = 0x16; // ^V
+        cc[VMIN] = 1;
+        cc[VTIME] = 0;
+        Self {
+            iflag: BRKINT | ICRNL | IMAXBEL | IXON | IXANY,
+            oflag: OPOST | ONLCR,
+            cflag: CREAD | CS8 | HUPCL,
+            lflag: ECHO | ICANON | ISIG | IEXTEN | ECHOE | ECHOKE | ECHOCTL,
+            cc,
+            ispeed: 115200,
+            ospeed: 115200,
+        }
+    }
+
+    /// Read a 32-bit `struct termios` from user memory
+    unsafe fn read_user(ptr: *const u8) -> Self {
+        unsafe {
+            let words = ptr as *const u32;
+            let mut cc = [0u8; NCCS];
+            core::ptr::copy_nonoverlapping(ptr.add(16), cc.as_mut_ptr(), NCCS);
+            Self {
+                iflag: *words,
+                oflag: *words.add(1),
+                cflag: *words.add(2),
+                lflag: *words.add(3),
+                cc,
+                ispeed: *words.add(9),
+                ospeed: *words.add(10),
+            }
+        }
+    }
+
+    unsafe fn write_user(&self, ptr: *mut u8) {
+        unsafe {
+            let words = ptr as *mut u32;
+            *words = self.iflag;
+            *words.add(1) = self.oflag;
+            *words.add(2) = self.cflag;
+            *words.add(3) = self.lflag;
+            core::ptr::copy_nonoverlapping(self.cc.as_ptr(), ptr.add(16), NCCS);
+            *words.add(9) = self.ispeed;
+            *words.add(10) = self.ospeed;
+        }
+    }
+
+    /// True if `c` is enabled as the control character at `index`
+    fn is(&self, index: usize, c: u8) -> bool {
+        self.cc[index] != VDISABLE && self.cc[index] == c
+    }
+}
+
+struct TtyState {
+    termios: Termios,
+    /// struct winsize: rows, cols, xpixel, ypixel
+    winsize: [u16; 4],
+    /// Completed lines in canonical mode. An empty line is end-of-file.
+    lines: VecDeque<Vec<u8>>,
+    /// Line being edited in canonical mode
+    line: Vec<u8>,
+    /// Input available to read in non-canonical mode
+    raw: VecDeque<u8>,
+    /// The next character is taken literally (VLNEXT)
+    literal_next: bool,
+    /// Output stopped by VSTOP
+    stopped: bool,
+    /// Signal generated by the last input character, for `Tty::input` to
+    /// send once the state is unlocked
+    signal: Option<u32>,
+}
+
+pub struct Tty {
+    state: Mutex<TtyState>,
+    readable: WaitQueue,
+    writable: WaitQueue,
+}
+
+/// The serial console
+pub static CONSOLE: Tty = Tty::new();
+
+impl Tty {
+    const fn new() -> Self {
+        Self {
+            state: Mutex::new(TtyState {
+                termios: Termios::new(),
+                winsize: [24, 80, 0, 0],
+                lines: VecDeque::new(),
+                line: Vec::new(),
+                raw: VecDeque::new(),
+                literal_next: false,
+                stopped: false,
+                signal: None,
+            }),
+            readable: WaitQueue::new(),
+            writable: WaitQueue::new(),
+        }
+    }
+
+    /// Feed a received byte through the line discipline. Called from the
+    /// UART interrupt handler.
+    pub fn input(&self, byte: u8) {
+        let (readable, restarted, signal) = {
+            let mut state = self.state.lock();
+            let was_stopped = state.stopped;
+            let readable = state.input(byte);
+            (readable, was_stopped && !state.stopped, state.signal.take())
+        };
+        if let Some(sig) = signal {
+            // There is a single terminal, so it is every process's
+            // controlling terminal
+            for pid in crate::scheduler::live_pids() {
+                crate::process::send_signal(pid, sig);
+            }
+        }
+        if readable {
+            self.readable.wake_all();
+        }
+        if restarted {
+            self.writable.wake_all();
+        }
+    }
+
+    fn read(&self, buf: &mut [u8], nonblock: bool) -> Result<usize, u64> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        self.readable.wait_until(|| {
+            let mut state = self.state.lock();
+            if let Some(n) = state.read(buf) {
+                Some(Ok(n))
+            } else if nonblock {
+                Some(Err(EAGAIN))
+            } else {
+                None
+            }
+        })
+    }
+
+    fn write(&self, buf: &[u8], nonblock: bool) -> Result<usize, u64> {
+        let oflag = self.writable.wait_until(|| {
+            let state = self.state.lock();
+            if !state.stopped {
+                Some(Ok(state.termios.oflag))
+            } else if nonblock {
+                Some(Err(EAGAIN))
+            } else {
+                None
+            }
+        })?;
+        output(oflag, buf);
+        Ok(buf.len())
+    }
+
+    fn readiness(&self) -> Readiness {
+        let state = self.state.lock();
+        let readable = if state.canonical() {
+            !state.lines.is_empty()
+        } else {
+            !state.raw.is_empty() || state.termios.cc[VMIN] == 0
+        };
+        Readiness {
+            read: readable.then(|| state.available()),
+            write: (!state.stopped).then_some(0),
+            eof: false,
+        }
+    }
+
+    fn ioctl(&self, cmd: u32, arg: u64) -> Result<u64, u64> {
+        let ptr = arg as *mut u8;
+        let mut state = self.state.lock();
+        match cmd {
+            TIOCGETA => unsafe { state.termios.write_user(ptr) },
+            TIOCSETA | TIOCSETAW | TIOCSETAF => {
+                // Output is synchronous, so there is never anything to drain
+                if cmd == TIOCSETAF {
+                    state.flush_input();
+                }
+                let termios = unsafe { Termios::read_user(ptr) };
+                state.set_termios(termios);
+                drop(state);
+                self.readable.wake_all();
+                self.writable.wake_all();
+            }
+            TIOCGWINSZ => unsafe {
+                core::ptr::copy_nonoverlapping(state.winsize.as_ptr(), ptr as *mut u16, 4);
+            },
+            TIOCSWINSZ => unsafe {
+                core::ptr::copy_nonoverlapping(ptr as *const u16, state.winsize.as_mut_ptr(), 4);
+            },
+            FIONREAD => unsafe {
+                *(ptr as *mut u32) = state.available() as u32;
+            },
+            // There is only one terminal, so it is everyone's controlling tty
+            TIOCSCTTY => {}
+            _ => return Err(ENOTTY),
+        }
+        Ok(0)
+    }
+}
+
+impl TtyState {
+    fn canonical(&self) -> bool {
+        self.termios.lflag & ICANON != 0
+    }
+
+    fn flush_input(&mut self) {
+        self.lines.clear();
+        self.line.clear();
+        self.raw.clear();
+    }
+
+    fn available(&self) -> usize {
+        if self.canonical() {
+            self.lines.iter().map(|l| l.len()).sum()
+        } else {
+            self.raw.len()
+        }
+    }
+
+    fn set_termios(&mut self, termios: Termios) {
+        let was_canonical = self.canonical();
+        self.termios = termios;
+        if was_canonical && !self.canonical() {
+            // Pending input, including the partial line, becomes readable
+            for line in self.lines.drain(..) {
+                self.raw.extend(line);
+            }
+            self.raw.extend(self.line.drain(..));
+        }
+        if self.termios.iflag & IXON == 0 {
+            self.stopped = false;
+        }
+    }
+
+    /// Copy available input into `buf`, or None if a read must wait
+    fn read(&mut self, buf: &mut [u8]) -> Option<usize> {
+        if self.canonical() {
+            let line = self.lines.front_mut()?;
+            let n = buf.len().min(line.len());
+            buf[..n].copy_from_slice(&line[..n]);
+            if n == line.len() {
+                self.lines.pop_front();
+            } else {
+                line.drain(..n);
+            }
+            Some(n)
+        } else {
+            // VTIME is not supported: reads wait for VMIN bytes, and return
+            // immediately when VMIN is 0
+            let min = (self.termios.cc[VMIN] as usize).min(buf.len());
+            if min > 0 && self.raw.len() < min {
+                return None;
+            }
+            let n = buf.len().min(self.raw.len());
+            for (dst, src) in buf.iter_mut().zip(self.raw.drain(..n)) {
+                *dst = src;
+            }
+            Some(n)
+        }
+    }
+
+    /// Process one input character. Returns true if a read can now complete.
+    fn input(&mut self, mut c: u8) -> bool {
+        let t = self.termios;
+        if t.cflag & CREAD == 0 {
+            return false;
+        }
+        if t.iflag & ISTRIP != 0 {
+            c &= 0x7F;
+        }
+
+        if self.literal_next {
+            self.literal_next = false;
+            return self.insert(c);
+        }
+
+        if t.iflag & IXON != 0 {
+            if t.is(VSTOP, c) {
+                self.stopped = true;
+                return false;
+            }
+            if t.is(VSTART, c) {
+                self.stopped = false;
+                return false;
+            }
+            if t.iflag & IXANY != 0 {
+                self.stopped = false;
+            }
+        }
+
+        if c == b'\r' {
+            if t.iflag & IGNCR != 0 {
+                return false;
+            }
+            if t.iflag & ICRNL != 0 {
+                c = b'\n';
+            }
+        } else if c == b'\n' && t.iflag & INLCR != 0 {
+            c = b'\r';
+        }
+
+        if t.lflag & IEXTEN != 0 && t.is(VLNEXT, c) {
+            self.literal_next = true;
+            if t.lflag & ECHO != 0 && t.lflag & ECHOCTL != 0 {
+                output(t.oflag, b"^\x08");
+            }
+            return false;
+        }
+
+        if t.lflag & ISIG != 0 && (t.is(VINTR, c) || t.is(VQUIT, c) || t.is(VSUSP, c)) {
+            self.signal = Some(if t.is(VINTR, c) {
+                SIGINT
+            } else if t.is(VQUIT, c) {
+                SIGQUIT
+            } else {
+                SIGTSTP
+            });
+            if t.lflag & NOFLSH == 0 {
+                self.flush_input();
+            }
+            self.echo(c);
+            return false;
+        }
+
+        if self.canonical() {
+            if t.is(VERASE, c) {
+                self.erase_char();
+                return false;
+            }
+            if t.lflag & IEXTEN != 0 && t.is(VWERASE, c) {
+                while self.line.last().is_some_and(|b| b.is_ascii_whitespace()) {
+                    self.erase_char();
+                }
+                while self.line.last().is_some_and(|b| !b.is_ascii_whitespace()) {
+                    self.erase_char();
+                }
+                return false;
+            }
+            if t.is(VKILL, c) {
+                if t.lflag & ECHOKE != 0 && t.lflag & ECHOE != 0 {
+                    while !self.line.is_empty() {
+                        self.erase_char();
+                    }
+                } else {
+                    self.line.clear();
+                    self.echo(c);
+                    if t.lflag & ECHOK != 0 {
+                        self.echo(b'\n');
+                    }
+                }
+                return false;
+            }
+            if t.lflag & IEXTEN != 0 && t.is(VREPRINT, c) {
+                self.echo(c);
+                output(t.oflag, b"\n");
+                let line = core::mem::take(&mut self.line);
+                for &b in &line {
+                    self.echo(b);
+                }
+                self.line = line;
+                return false;
+            }
+            if t.is(VEOF, c) {
+                // Complete the line without a newline; empty means EOF
+                let line = core::mem::take(&mut self.line);
+                self.lines.push_back(line);
+                return true;
+            }
+        }
+
+        self.insert(c)
+    }
+
+    /// Add a character to the input. Returns true if it completed a read.
+    fn insert(&mut self, c: u8) -> bool {
+        let t = self.termios;
+        if !self.canonical() {
+            self.raw.push_back(c);
+            self.echo(c);
+            return true;
+        }
+
+        if self.line.len() >= MAX_CANON {
+            if t.iflag & IMAXBEL != 0 {
+                output(t.oflag, b"\x07");
+            }
+            return false;
+        }
+
+        self.line.push(c);
+        if c == b'\n' && t.lflag & ECHONL != 0 && t.lflag & ECHO == 0 {
+            output(t.oflag, b"\n");
+        } else {
+            self.echo(c);
+        }
+        if c == b'\n' || t.is(VEOL, c) || t.is(VEOL2, c) {
+            let line = core::mem::take(&mut self.line);
+            self.lines.push_back(line);
+            return true;
+        }
+        false
+    }
+
+    fn erase_char(&mut self) {
+        let t = self.termios;
+        let Some(c) = self.line.pop() else {
+            return;
+        };
+        if t.lflag & ECHO == 0 {
+            return;
+        }
+        if t.lflag & ECHOE != 0 {
+            // Control characters were echoed as two columns
+            let width = if c.is_ascii_control() && t.lflag & ECHOCTL != 0 {
+                2
+            } else {
+                1
+            };
+            for _ in 0..width {
+                output(t.oflag, b"\x08 \x08");
+            }
+        } else {
+            self.echo(t.cc[VERASE]);
+        }
+    }
+
+    fn echo(&self, c: u8) {
+        let t = &self.termios;
+        if t.lflag & ECHO == 0 {
+            return;
+        }
+        if t.lflag & ECHOCTL != 0 && c.is_ascii_control() && c != b'\n' && c != b'\t' {
+            output(t.oflag, &[b'^', c ^ 0x40]);
+        } else {
+            output(t.oflag, &[c]);
+        }
+    }
+}
+
+/// Write bytes to the UART, applying output processing
+fn output(oflag: u32, buf: &[u8]) {
+    if oflag & OPOST == 0 {
+        crate::uart::write_bytes(buf);
+        return;
+    }
+    for &c in buf {
+        match c {
+            b'\n' if oflag & ONLCR != 0 => crate::uart::write_bytes(b"\r\n"),
+            b'\r' if oflag & OCRNL != 0 => crate::uart::write_bytes(b"\n"),
+            _ => crate::uart::write_bytes(&[c]),
+        }
+    }
+}
+
+/// A descriptor open on a terminal
+struct TtyFile {
+    tty: &'static Tty,
+}
+
+impl File for TtyFile {
+    fn read_at(&self, _offset: u64, _buf: &mut [u8]) -> usize {
+        0
+    }
+    fn size(&self) -> u64 {
+        0
+    }
+    fn is_stream(&self) -> bool {
+        true
+    }
+    fn read(&self, buf: &mut [u8], nonblock: bool) -> Result<usize, u64> {
+        self.tty.read(buf, nonblock)
+    }
+    fn write(&self, buf: &[u8], nonblock: bool) -> Result<usize, u64> {
+        self.tty.write(buf, nonblock)
+    }
+    fn ioctl(&self, cmd: u32, arg: u64) -> Result<u64, u64> {
+        self.tty.ioctl(cmd, arg)
+    }
+    fn readiness(&self) -> Readiness {
+        self.tty.readiness()
+    }
+}
+
+fn open_console(_minor: u32) -> Result<Box<dyn File>, u64> {
+    Ok(Box::new(TtyFile { tty: &CONSOLE }))
+}
+
+/// Register /dev/console and /dev/tty. There is a single terminal, so the
+/// controlling terminal is always the console.
+pub fn init() {
+    devfs::register_driver(NodeKind::Char, CONSOLE_MAJOR, open_console);
+    devfs::register_driver(NodeKind::Char, CTTY_MAJOR, open_console);
+    devfs::make_node("console", NodeKind::Char, CONSOLE_MAJOR, 0, 0o600);
+    devfs::make_node("tty", NodeKind::Char, CTTY_MAJOR, 0, 0o666);
+}
@@ -1,20 +1,92 @@
+//! PL011 UART for QEMU virt machine
+
 use core::fmt;
 use core::fmt::Write;
+use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+// Register offsets
+const UARTDR: usize = 0x00;
+const UARTFR: usize = 0x18;
+const UARTCR: usize = 0x30;
+const UARTIFLS: usize = 0x34;
+const UARTIMSC: usize = 0x38;
+const UARTICR: usize = 0x44;
+
+// Flag register bits
+const FR_RXFE: u32 = 1 << 4;
+const FR_TXFF: u32 = 1 << 5;
+
+// Control register bits
+const CR_UARTEN: u32 = 1 << 0;
+const CR_TXE: u32 = 1 << 8;
+const CR_RXE: u32 = 1 << 9;
+
+// Interrupt bits: receive and receive timeout
+const INT_RX: u32 = 1 << 4;
+const INT_RT: u32 = 1 << 6;
+
+fn base() -> usize {
+    crate::platform::get().uart.region.base as usize
+}
+
+fn read_reg(offset: usize) -> u32 {
+    unsafe { read_volatile((base() + offset) as *const u32) }
+}
+
+fn write_reg(offset: usize, val: u32) {
+    unsafe { write_volatile((base() + offset) as *mut u32, val) }
+}
 
 pub struct Uart;
 
+/// Whether kernel messages go to the UART as well as the log buffer
+static KERNEL_OUTPUT: AtomicBool = AtomicBool::new(true);
+
+/// Send kernel messages to the UART, or only to the log buffer
+pub fn set_kernel_output(enabled: bool) {
+    KERNEL_OUTPUT.store(enabled, Ordering::Relaxed);
+}
+
+/// Write raw bytes to the UART
+pub fn write_bytes(bytes: &[u8]) {
+    for &byte in bytes {
+        while read_reg(UARTFR) & FR_TXFF != 0 {
+            core::hint::spin_loop();
+        }
+        write_reg(UARTDR, byte as u32);
+    }
+}
+
 impl Write for Uart {
     fn write_str(&mut self, s: &str) -> fmt::Result {
-        let uart_ptr = 0x0900_0000 as *mut u8;
-        for byte in s.bytes() {
-            unsafe {
-                uart_ptr.add(0).write_volatile(byte);
-            }
+        crate::klog::append(s.as_bytes());
+        if KERNEL_OUTPUT.load(Ordering::Relaxed) {
+            write_bytes(s.as_bytes());
         }
         Ok(())
     }
 }
 
+/// Enable the receiver and deliver received bytes to the console tty.
+/// Must be called after the GIC is initialized.
+pub fn init() {
+    write_reg(UARTCR, CR_UARTEN | CR_TXE | CR_RXE);
+    // Interrupt when the RX FIFO is 1/8 full, or on timeout for less
+    write_reg(UARTIFLS, 0);
+    write_reg(UARTICR, INT_RX | INT_RT);
+    write_reg(UARTIMSC, INT_RX | INT_RT);
+    crate::irq::register_handler(crate::platform::get().uart.irq, handle_irq);
+}
+
+fn handle_irq(_irq: u32) {
+    while read_reg(UARTFR) & FR_RXFE == 0 {
+        let byte = (read_reg(UARTDR) & 0xFF) as u8;
+        crate::tty::CONSOLE.input(byte);
+    }
+    write_reg(UARTICR, INT_RX | INT_RT);
+}
+
 #[macro_export]
 macro_rules! kprint {
     ($($arg:tt)*) => ({
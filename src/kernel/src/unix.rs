@@ -0,0 +1,837 @@
+//! UNIX domain sockets
+//!
+//! Data moves through channels, each carrying messages one way into a
+//! socket's receive buffer. A connected stream pair shares two, one per
+//! direction. A datagram socket owns the channel it receives on and sends
+//! into its peers'. A listener holds the connections made to it until they
+//! are accepted, so connect completes without waiting for accept.
+//!
+//! Binding makes a socket node on the filesystem, and `BOUND` leads from
+//! the node to the socket while it is open. The node stays until it is
+//! unlinked, even after the socket closes.
+//!
+//! Files passed with SCM_RIGHTS are held by the message carrying them until
+//! it is received. Nothing collects sockets sent over themselves, so those
+//! are never freed.
+
+use crate::errno::{
+    EAFNOSUPPORT, EAGAIN, ECONNREFUSED, EINVAL, EISCONN, EMSGSIZE, ENOPROTOOPT, ENOTCONN,
+    EOPNOTSUPP, EPIPE, EPROTONOSUPPORT, EPROTOTYPE,
+};
+use crate::pagecache::FileId;
+use crate::socket::{
+    Control, MSG_PEEK, MSG_WAITALL, Received, SHUT_RD, SHUT_RDWR, SHUT_WR, SO_ERROR, SO_NOSIGPIPE,
+    SO_RCVBUF, SO_SNDBUF, SO_TYPE, SOCK_DGRAM, SOCK_STREAM, SOL_SOCKET, SockAddr, Socket,
+};
+use crate::vfs::{self, File, Readiness, S_IFSOCK};
+use crate::waitqueue::WaitQueue;
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::string::String;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use spin::Mutex;
+
+/// Bytes of data a socket's receive buffer holds
+const BUFFER_SIZE: usize = 16384;
+/// Most connections a listener holds waiting to be accepted
+const BACKLOG_MAX: u32 = 128;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Kind {
+    Stream,
+    Datagram,
+}
+
+/// What one send puts in a channel
+struct Message {
+    data: Vec<u8>,
+    control: Control,
+    /// Name of the sender, for datagrams
+    from: Option<String>,
+}
+
+struct Queue {
+    messages: VecDeque<Message>,
+    /// Bytes of data in `messages`
+    len: usize,
+    /// The receiver closed or shut down reading, so sends fail
+    reader_closed: bool,
+    /// The stream sender closed or shut down writing, so reads see EOF
+    writer_closed: bool,
+}
+
+/// Messages on their way to one socket
+pub struct Channel {
+    queue: Mutex<Queue>,
+    readable: WaitQueue,
+    writable: WaitQueue,
+}
+
+impl Channel {
+    fn new() -> Arc<Self> {
+        Arc::new(Self {
+            queue: Mutex::new(Queue {
+                messages: VecDeque::new(),
+                len: 0,
+                reader_closed: false,
+                writer_closed: false,
+            }),
+            readable: WaitQueue::new(),
+            writable: WaitQueue::new(),
+        })
+    }
+
+    /// Queue stream data, as much as fits at a time. `control` goes with
+    /// the first byte.
+    fn send_stream(&self, buf: &[u8], mut control: Control, nonblock: bool) -> Result<usize, u64> {
+        if buf.is_empty() && control.is_empty() {
+            return Ok(0);
+        }
+        let mut sent = 0;
+        loop {
+            let remaining = &buf[sent..];
+            let result = self.writable.wait_until(|| {
+                let mut queue = self.queue.lock();
+                if queue.reader_closed {
+                    return Some(Err(EPIPE));
+                }
+                let space = BUFFER_SIZE - queue.len;
+                if space > 0 || remaining.is_empty() {
+                    let n = space.min(remaining.len());
+                    queue.len += n;
+                    queue.messages.push_back(Message {
+                        data: remaining[..n].to_vec(),
+                        control: core::mem::take(&mut control),
+                        from: None,
+                    });
+                    Some(Ok(n))
+                } else if nonblock {
+                    Some(Err(EAGAIN))
+                } else {
+                    None
+                }
+            });
+            match result {
+                Ok(n) => {
+                    sent += n;
+                    self.readable.wake_all();
+                }
+                Err(_) if sent > 0 => break,
+                Err(errno) => return Err(errno),
+            }
+            if sent == buf.len() {
+                break;
+            }
+        }
+        Ok(sent)
+    }
+
+    /// Queue a datagram whole
+    fn send_datagram(&self, message: Message, nonblock: bool) -> Result<usize, u64> {
+        let len = message.data.len();
+        if len > BUFFER_SIZE {
+            return Err(EMSGSIZE);
+        }
+        let mut message = Some(message);
+        self.writable.wait_until(|| {
+            let mut queue = self.queue.lock();
+            if queue.reader_closed {
+                Some(Err(ECONNREFUSED))
+            } else if BUFFER_SIZE - queue.len >= len {
+                queue.len += len;
+                queue.messages.extend(message.take());
+                Some(Ok(()))
+            } else if nonblock {
+                Some(Err(EAGAIN))
+            } else {
+                None
+            }
+        })?;
+        self.readable.wake_all();
+        Ok(len)
+    }
+
+    /// Take stream data into `buf`. The ancillary data of the first message
+    /// read comes with it, and reading stops short of the next message that
+    /// has any.
+    fn recv_stream(
+        &self,
+        buf: &mut [u8],
+        peek: bool,
+        nonblock: bool,
+    ) -> Result<(usize, Control), u64> {
+        let result = self.readable.wait_until(|| {
+            let mut queue = self.queue.lock();
+            if queue.messages.is_empty() {
+                return if queue.writer_closed || queue.reader_closed {
+                    Some(Ok((0, Control::default())))
+                } else if nonblock {
+                    Some(Err(EAGAIN))
+                } else {
+                    None
+                };
+            }
+            let mut read = 0;
+            let mut control = Control::default();
+            if peek {
+                for message in &queue.messages {
+                    if read == buf.len() || (read > 0 && !message.control.is_empty()) {
+                        break;
+                    }
+                    let n = (buf.len() - read).min(message.data.len());
+                    buf[read..read + n].copy_from_slice(&message.data[..n]);
+                    read += n;
+                }
+                return Some(Ok((read, control)));
+            }
+            while read < buf.len()
+                && let Some(message) = queue.messages.front_mut()
+            {
+                if !message.control.is_empty() {
+                    if read > 0 || !control.is_empty() {
+                        break;
+                    }
+                    control = core::mem::take(&mut message.control);
+                }
+                let n = (buf.len() - read).min(message.data.len());
+                buf[read..read + n].copy_from_slice(&message.data[..n]);
+                message.data.drain(..n);
+                read += n;
+                if message.data.is_empty() {
+                    queue.messages.pop_front();
+                }
+            }
+            queue.len -= read;
+            Some(Ok((read, control)))
+        });
+        if matches!(result, Ok((n, _)) if n > 0) {
+            self.writable.wake_all();
+        }
+        result
+    }
+
+    /// Take the next datagram into `buf`, dropping what doesn't fit
+    fn recv_datagram(&self, buf: &mut [u8], peek: bool, nonblock: bool) -> Result<Received, u64> {
+        let result = self.readable.wait_until(|| {
+            let mut queue = self.queue.lock();
+            if queue.messages.is_empty() {
+                return if queue.reader_closed {
+                    Some(Ok((0, None, Control::default(), false)))
+                } else if nonblock {
+                    Some(Err(EAGAIN))
+                } else {
+                    None
+                };
+            }
+            let message = if peek {
+                let front = &queue.messages[0];
+                Message {
+                    data: front.data.clone(),
+                    control: Control::default(),
+                    from: front.from.clone(),
+                }
+            } else {
+                let message = queue.messages.pop_front().unwrap();
+                queue.len -= message.data.len();
+                message
+            };
+            let n = buf.len().min(message.data.len());
+            buf[..n].copy_from_slice(&message.data[..n]);
+            let truncated = n < message.data.len();
+            Some(Ok((n, message.from, message.control, truncated)))
+        });
+        let (len, from, control, truncated) = result?;
+        if !peek {
+            self.writable.wake_all();
+        }
+        Ok(Received {
+            len,
+            from: Some(SockAddr::Unix(from.unwrap_or_default())),
+            control,
+            truncated,
+        })
+    }
+
+    /// Bytes queued, or None if a read would wait
+    fn readable(&self) -> Option<usize> {
+        let queue = self.queue.lock();
+        let ready = !queue.messages.is_empty() || queue.writer_closed || queue.reader_closed;
+        ready.then_some(queue.len)
+    }
+
+    /// Room for a send, or None if it would wait. Sends fail at once, with
+    /// no room, once the receiver has closed.
+    fn writable(&self) -> Option<usize> {
+        let queue = self.queue.lock();
+        if queue.reader_closed {
+            Some(0)
+        } else {
+            (queue.len < BUFFER_SIZE).then_some(BUFFER_SIZE - queue.len)
+        }
+    }
+
+    /// The receiving socket is gone: drop what is queued and fail sends
+    fn close_reader(&self) {
+        let dropped = {
+            let mut queue = self.queue.lock();
+            queue.reader_closed = true;
+            queue.len = 0;
+            core::mem::take(&mut queue.messages)
+        };
+        // Dropping passed files may close sockets, which lock their channels
+        drop(dropped);
+        self.readable.wake_all();
+        self.writable.wake_all();
+    }
+
+    /// The sending stream socket is done: reads see EOF once it is drained
+    fn close_writer(&self) {
+        self.queue.lock().writer_closed = true;
+        self.readable.wake_all();
+    }
+}
+
+struct Backlog {
+    sockets: VecDeque<UnixSocket>,
+    /// Connections it may hold, 0 until listen
+    limit: usize,
+}
+
+/// Where connections to a bound stream socket wait to be accepted
+pub struct Listener {
+    backlog: Mutex<Backlog>,
+    /// Processes waiting in accept
+    pending: WaitQueue,
+}
+
+impl Listener {
+    fn new() -> Arc<Self> {
+        Arc::new(Self {
+            backlog: Mutex::new(Backlog {
+                sockets: VecDeque::new(),
+                limit: 0,
+            }),
+            pending: WaitQueue::new(),
+        })
+    }
+
+    /// Queue the server end of a new connection. Fails with ECONNREFUSED,
+    /// handing it back, if the backlog is full or the socket isn't listening.
+    fn push(&self, socket: UnixSocket) -> Result<(), (UnixSocket, u64)> {
+        {
+            let mut backlog = self.backlog.lock();
+            if backlog.sockets.len() >= backlog.limit {
+                return Err((socket, ECONNREFUSED));
+            }
+            backlog.sockets.push_back(socket);
+        }
+        self.pending.wake_all();
+        Ok(())
+    }
+
+    /// The listening socket closed: refuse connections and drop the ones
+    /// not yet accepted, which their clients see as EOF
+    fn close(&self) {
+        let dropped = {
+            let mut backlog = self.backlog.lock();
+            backlog.limit = 0;
+            core::mem::take(&mut backlog.sockets)
+        };
+        drop(dropped);
+    }
+}
+
+/// What a bound name leads to
+#[derive(Clone)]
+pub enum Endpoint {
+    Stream(Weak<Listener>),
+    Datagram(Weak<Channel>),
+}
+
+impl Endpoint {
+    /// Whether the socket is still open
+    fn is_open(&self) -> bool {
+        match self {
+            Endpoint::Stream(listener) => listener.strong_count() > 0,
+            Endpoint::Datagram(rx) => rx.strong_count() > 0,
+        }
+    }
+}
+
+/// Endpoints of bound sockets, by the file ID of their socket node
+static BOUND: Mutex<BTreeMap<FileId, Endpoint>> = Mutex::new(BTreeMap::new());
+
+/// Lead the new socket node `id` to `endpoint`
+pub fn bind(id: FileId, endpoint: Endpoint) {
+    let mut bound = BOUND.lock();
+    bound.retain(|_, endpoint| endpoint.is_open());
+    bound.insert(id, endpoint);
+}
+
+/// The endpoint of socket node `id`, if its socket is still open
+pub fn lookup(id: FileId) -> Option<Endpoint> {
+    BOUND
+        .lock()
+        .get(&id)
+        .filter(|endpoint| endpoint.is_open())
+        .cloned()
+}
+
+enum Conn {
+    /// A stream socket neither connected nor listening
+    Idle,
+    /// A stream socket accepting connections through its listener
+    Listening,
+    Stream {
+        rx: Arc<Channel>,
+        tx: Arc<Channel>,
+    },
+    Datagram {
+        rx: Arc<Channel>,
+    },
+}
+
+struct State {
+    conn: Conn,
+    /// Canonical path the socket is bound to
+    name: Option<String>,
+    /// A bound stream socket's listener
+    listener: Option<Arc<Listener>>,
+    /// Name of the peer of a connected socket, if it is bound
+    peer_name: Option<String>,
+    /// Where a connected datagram socket sends
+    peer: Option<Arc<Channel>>,
+    read_shut: bool,
+    write_shut: bool,
+    no_sigpipe: bool,
+}
+
+pub struct UnixSocket {
+    kind: Kind,
+    state: Mutex<State>,
+}
+
+/// The path in a UNIX domain socket address
+fn unix_path(addr: SockAddr) -> Result<String, u64> {
+    match addr {
+        SockAddr::Unix(path) if !path.is_empty() => Ok(path),
+        SockAddr::Unix(_) => Err(EINVAL),
+        SockAddr::Inet(_) => Err(EAFNOSUPPORT),
+    }
+}
+
+impl UnixSocket {
+    pub fn new(ty: u32, protocol: u32) -> Result<Self, u64> {
+        if protocol != 0 {
+            return Err(EPROTONOSUPPORT);
+        }
+        match ty {
+            SOCK_STREAM => Ok(Self::with_conn(Kind::Stream, Conn::Idle)),
+            SOCK_DGRAM => Ok(Self::with_conn(
+                Kind::Datagram,
+                Conn::Datagram { rx: Channel::new() },
+            )),
+            _ => Err(EPROTONOSUPPORT),
+        }
+    }
+
+    fn with_conn(kind: Kind, conn: Conn) -> Self {
+        Self {
+            kind,
+            state: Mutex::new(State {
+                conn,
+                name: None,
+                listener: None,
+                peer_name: None,
+                peer: None,
+                read_shut: false,
+                write_shut: false,
+                no_sigpipe: false,
+            }),
+        }
+    }
+
+    /// Two sockets connected to each other, for socketpair
+    pub fn pair(ty: u32, protocol: u32) -> Result<(Self, Self), u64> {
+        let a = Self::new(ty, protocol)?;
+        let b = Self::new(ty, protocol)?;
+        if a.kind == Kind::Stream {
+            let (a_to_b, b_to_a) = (Channel::new(), Channel::new());
+            a.state.lock().conn = Conn::Stream {
+                rx: Arc::clone(&b_to_a),
+                tx: Arc::clone(&a_to_b),
+            };
+            b.state.lock().conn = Conn::Stream {
+                rx: a_to_b,
+                tx: b_to_a,
+            };
+        } else {
+            a.state.lock().peer = b.datagram_rx();
+            b.state.lock().peer = a.datagram_rx();
+        }
+        Ok((a, b))
+    }
+
+    fn datagram_rx(&self) -> Option<Arc<Channel>> {
+        match &self.state.lock().conn {
+            Conn::Datagram { rx } => Some(Arc::clone(rx)),
+            _ => None,
+        }
+    }
+
+    /// The channels of a connected stream socket
+    fn stream(&self) -> Result<(Arc<Channel>, Arc<Channel>), u64> {
+        match &self.state.lock().conn {
+            Conn::Stream { rx, tx } => Ok((Arc::clone(rx), Arc::clone(tx))),
+            _ => Err(ENOTCONN),
+        }
+    }
+
+    fn connect_stream(&self, path: String, listener: Arc<Listener>) -> Result<(), u64> {
+        let mut state = self.state.lock();
+        match state.conn {
+            Conn::Idle => {}
+            Conn::Stream { .. } => return Err(EISCONN),
+            Conn::Listening | Conn::Datagram { .. } => return Err(EINVAL),
+        }
+        let (to_server, to_client) = (Channel::new(), Channel::new());
+        let server = Self::with_conn(
+            Kind::Stream,
+            Conn::Stream {
+                rx: Arc::clone(&to_server),
+                tx: Arc::clone(&to_client),
+            },
+        );
+        {
+            let server_state = &mut *server.state.lock();
+            server_state.name = Some(path.clone());
+            server_state.peer_name = state.name.clone();
+        }
+        if let Err((server, errno)) = listener.push(server) {
+            drop(state);
+            drop(server);
+            return Err(errno);
+        }
+        state.conn = Conn::Stream {
+            rx: to_client,
+            tx: to_server,
+        };
+        state.peer_name = Some(path);
+        Ok(())
+    }
+}
+
+impl Socket for UnixSocket {
+    fn bind(&self, addr: SockAddr) -> Result<(), u64> {
+        let path = unix_path(addr)?;
+        if self.state.lock().name.is_some() {
+            return Err(EINVAL);
+        }
+        let listener = (self.kind == Kind::Stream).then(Listener::new);
+        let endpoint = match &listener {
+            Some(listener) => Endpoint::Stream(Arc::downgrade(listener)),
+            None => Endpoint::Datagram(Arc::downgrade(&self.datagram_rx().unwrap())),
+        };
+        // Naming it may do filesystem I/O, so the state isn't locked
+        let path = vfs::bind_socket(&path, endpoint)?;
+        let mut state = self.state.lock();
+        if state.name.is_some() {
+            drop(state);
+            let _ = vfs::remove(&path, false);
+            return Err(EINVAL);
+        }
+        state.name = Some(path);
+        state.listener = listener;
+        Ok(())
+    }
+
+    fn connect(&self, addr: SockAddr, _nonblock: bool) -> Result<(), u64> {
+        let path = unix_path(addr)?;
+        let endpoint = vfs::lookup_socket(&path)?;
+        match (self.kind, endpoint) {
+            (Kind::Stream, Endpoint::Stream(listener)) => {
+                let listener = listener.upgrade().ok_or(ECONNREFUSED)?;
+                self.connect_stream(path, listener)
+            }
+            (Kind::Datagram, Endpoint::Datagram(rx)) => {
+                let rx = rx.upgrade().ok_or(ECONNREFUSED)?;
+                let mut state = self.state.lock();
+                state.peer = Some(rx);
+                state.peer_name = Some(path);
+                Ok(())
+            }
+            _ => Err(EPROTOTYPE),
+        }
+    }
+
+    fn listen(&self, backlog: u32) -> Result<(), u64> {
+        let mut state = self.state.lock();
+        match state.conn {
+            Conn::Idle | Conn::Listening => {}
+            Conn::Stream { .. } => return Err(EINVAL),
+            Conn::Datagram { .. } => return Err(EOPNOTSUPP),
+        }
+        let listener = state.listener.as_ref().ok_or(EINVAL)?;
+        listener.backlog.lock().limit = backlog.clamp(1, BACKLOG_MAX) as usize;
+        state.conn = Conn::Listening;
+        Ok(())
+    }
+
+    fn accept(&self, nonblock: bool) -> Result<(Box<dyn File>, SockAddr), u64> {
+        let listener = {
+            let state = self.state.lock();
+            match (&state.conn, &state.listener) {
+                (Conn::Listening, Some(listener)) => Arc::clone(listener),
+                _ => return Err(EINVAL),
+            }
+        };
+        let mut socket =
+            listener
+                .pending
+                .wait_until(|| match listener.backlog.lock().sockets.pop_front() {
+                    Some(socket) => Some(Ok(socket)),
+                    None if nonblock => Some(Err(EAGAIN)),
+                    None => None,
+                })?;
+        let peer = SockAddr::Unix(socket.state.get_mut().peer_name.clone().unwrap_or_default());
+        Ok((Box::new(socket), peer))
+    }
+
+    fn send_to(
+        &self,
+        buf: &[u8],
+        flags: u32,
+        to: Option<SockAddr>,
+        nonblock: bool,
+    ) -> Result<usize, u64> {
+        self.send_msg(buf, flags, to, Control::default(), nonblock)
+    }
+
+    fn recv_from(
+        &self,
+        buf: &mut [u8],
+        flags: u32,
+        nonblock: bool,
+    ) -> Result<(usize, Option<SockAddr>), u64> {
+        // Files passed to a caller that can't take them are closed
+        let received = self.recv_msg(buf, flags, nonblock)?;
+        Ok((received.len, received.from))
+    }
+
+    fn send_msg(
+        &self,
+        buf: &[u8],
+        _flags: u32,
+        to: Option<SockAddr>,
+        control: Control,
+        nonblock: bool,
+    ) -> Result<usize, u64> {
+        if self.state.lock().write_shut {
+            return Err(EPIPE);
+        }
+        if self.kind == Kind::Stream {
+            if to.is_some() {
+                return Err(EISCONN);
+            }
+            let (_, tx) = self.stream()?;
+            return tx.send_stream(buf, control, nonblock);
+        }
+
+        let (peer, from) = {
+            let state = self.state.lock();
+            (state.peer.clone(), state.name.clone())
+        };
+        let channel = match (to, peer) {
+            (Some(_), Some(_)) => return Err(EISCONN),
+            (Some(to), None) => match vfs::lookup_socket(&unix_path(to)?)? {
+                Endpoint::Datagram(rx) => rx.upgrade().ok_or(ECONNREFUSED)?,
+                Endpoint::Stream(_) => return Err(EPROTOTYPE),
+            },
+            (None, Some(peer)) => peer,
+            (None, None) => return Err(ENOTCONN),
+        };
+        let message = Message {
+            data: buf.to_vec(),
+            control,
+            from,
+        };
+        channel.send_datagram(message, nonblock)
+    }
+
+    fn recv_msg(&self, buf: &mut [u8], flags: u32, nonblock: bool) -> Result<Received, u64> {
+        let peek = flags & MSG_PEEK != 0;
+        if self.kind == Kind::Datagram {
+            let rx = self.datagram_rx().unwrap();
+            return rx.recv_datagram(buf, peek, nonblock);
+        }
+
+        let (rx, _) = self.stream()?;
+        let (mut len, mut control) = rx.recv_stream(buf, peek, nonblock)?;
+        let waitall = flags & (MSG_WAITALL | MSG_PEEK) == MSG_WAITALL;
+        while waitall && len > 0 && len < buf.len() && control.is_empty() {
+            match rx.recv_stream(&mut buf[len..], false, nonblock) {
+                Ok((0, _)) | Err(_) => break,
+                Ok((n, more)) => {
+                    len += n;
+                    control = more;
+                }
+            }
+        }
+        Ok(Received {
+            len,
+            from: None,
+            control,
+            truncated: false,
+        })
+    }
+
+    fn shutdown(&self, how: u32) -> Result<(), u64> {
+        if how > SHUT_RDWR {
+            return Err(EINVAL);
+        }
+        let mut state = self.state.lock();
+        match &state.conn {
+            Conn::Stream { rx, tx } => {
+                if how != SHUT_WR {
+                    rx.close_reader();
+                }
+                if how != SHUT_RD {
+                    tx.close_writer();
+                }
+            }
+            Conn::Datagram { rx } if state.peer.is_some() => {
+                if how != SHUT_WR {
+                    rx.close_reader();
+                }
+            }
+            _ => return Err(ENOTCONN),
+        }
+        state.read_shut |= how != SHUT_WR;
+        state.write_shut |= how != SHUT_RD;
+        Ok(())
+    }
+
+    fn local_addr(&self) -> Result<SockAddr, u64> {
+        Ok(SockAddr::Unix(
+            self.state.lock().name.clone().unwrap_or_default(),
+        ))
+    }
+
+    fn peer_addr(&self) -> Result<SockAddr, u64> {
+        let state = self.state.lock();
+        match state.conn {
+            Conn::Stream { .. } => {}
+            Conn::Datagram { .. } if state.peer.is_some() => {}
+            _ => return Err(ENOTCONN),
+        }
+        Ok(SockAddr::Unix(state.peer_name.clone().unwrap_or_default()))
+    }
+
+    fn set_option(&self, level: u32, name: u32, value: i32) -> Result<(), u64> {
+        match (level, name) {
+            (SOL_SOCKET, SO_NOSIGPIPE) => self.state.lock().no_sigpipe = value != 0,
+            // Buffers have a fixed size
+            (SOL_SOCKET, SO_SNDBUF | SO_RCVBUF) => {}
+            _ => return Err(ENOPROTOOPT),
+        }
+        Ok(())
+    }
+
+    fn get_option(&self, level: u32, name: u32) -> Result<i32, u64> {
+        match (level, name) {
+            (SOL_SOCKET, SO_NOSIGPIPE) => Ok(self.state.lock().no_sigpipe as i32),
+            (SOL_SOCKET, SO_TYPE) => Ok(match self.kind {
+                Kind::Stream => SOCK_STREAM as i32,
+                Kind::Datagram => SOCK_DGRAM as i32,
+            }),
+            (SOL_SOCKET, SO_SNDBUF | SO_RCVBUF) => Ok(BUFFER_SIZE as i32),
+            (SOL_SOCKET, SO_ERROR) => Ok(0),
+            _ => Err(ENOPROTOOPT),
+        }
+    }
+}
+
+impl File for UnixSocket {
+    fn read_at(&self, _offset: u64, _buf: &mut [u8]) -> usize {
+        0
+    }
+
+    fn size(&self) -> u64 {
+        0
+    }
+
+    fn is_stream(&self) -> bool {
+        true
+    }
+
+    fn read(&self, buf: &mut [u8], nonblock: bool) -> Result<usize, u64> {
+        self.recv_from(buf, 0, nonblock).map(|(len, _)| len)
+    }
+
+    fn write(&self, buf: &[u8], nonblock: bool) -> Result<usize, u64> {
+        self.send_to(buf, 0, None, nonblock)
+    }
+
+    fn mode(&self) -> u16 {
+        S_IFSOCK | 0o777
+    }
+
+    fn readiness(&self) -> Readiness {
+        let state = self.state.lock();
+        let mut readiness = match &state.conn {
+            Conn::Idle => Readiness::default(),
+            Conn::Listening => {
+                let pending = state
+                    .listener
+                    .as_ref()
+                    .map_or(0, |listener| listener.backlog.lock().sockets.len());
+                Readiness {
+                    read: (pending > 0).then_some(pending),
+                    ..Readiness::default()
+                }
+            }
+            Conn::Stream { rx, tx } => Readiness {
+                read: rx.readable(),
+                write: tx.writable(),
+                eof: rx.queue.lock().writer_closed || tx.queue.lock().reader_closed,
+            },
+            Conn::Datagram { rx } => Readiness {
+                read: rx.readable(),
+                write: match &state.peer {
+                    Some(peer) => peer.writable(),
+                    None => Some(BUFFER_SIZE),
+                },
+                eof: false,
+            },
+        };
+        if state.read_shut {
+            readiness.read = Some(readiness.read.unwrap_or(0));
+        }
+        if state.write_shut {
+            readiness.write = Some(0);
+        }
+        readiness
+    }
+
+    fn socket(&self) -> Option<&dyn Socket> {
+        Some(self)
+    }
+}
+
+impl Drop for UnixSocket {
+    fn drop(&mut self) {
+        let state = self.state.get_mut();
+        match &state.conn {
+            Conn::Stream { rx, tx } => {
+                rx.close_reader();
+                tx.close_writer();
+            }
+            Conn::Datagram { rx } => rx.close_reader(),
+            Conn::Idle | Conn::Listening => {}
+        }
+        if let Some(listener) = &state.listener {
+            listener.close();
+        }
+    }
+}
@@ -44,9 +44,8 @@ vectors:
     .balign 128
     b .
 
-sync_handler:
-    /* Save context */
-    /* TrapFrame size = 32 * 8 + 2 * 8 = 272 bytes. We use 288 for alignment. */
+/* TrapFrame size = 32 * 8 + 2 * 8 = 272 bytes. We use 288 for alignment. */
+.macro SAVE_FRAME
     sub sp, sp, #288
     stp x0, x1, [sp, #16 * 0]
     stp x2, x3, [sp, #16 * 1]
@@ -70,12 +69,9 @@ sync_handler:
     mrs x12, sp_el0
     stp x10, x11, [sp, #16 * 16]
     str x12, [sp, #272]
+.endm
 
-    /* Pass trap frame to Rust */
-    mov x0, sp
-    bl handle_sync_exception
-
-    /* Restore context */
+.macro RESTORE_FRAME
     ldr x12, [sp, #272]
     msr sp_el0, x12
     ldp x10, x11, [sp, #16 * 16]
@@ -99,7 +95,25 @@ sync_handler:
     ldp x28, x29, [sp, #16 * 14]
     ldr x30, [sp, #16 * 15]
     add sp, sp, #288
+.endm
+
+sync_handler:
+    /* Save context */
+    SAVE_FRAME
+
+    /* Pass trap frame to Rust */
+    mov x0, sp
+    bl handle_sync_exception
+
+    /* Restore context */
+    RESTORE_FRAME
     eret
 
 irq_handler:
-    b .
+    SAVE_FRAME
+
+    mov x0, sp
+    bl handle_irq_exception
+
+    RESTORE_FRAME
+    eret
@@ -1,126 +1,968 @@
-//! Simple Virtual Filesystem abstraction
+//! Virtual filesystem: a mount table of filesystems keyed by path prefix
+//!
+//! Paths are resolved to the mount with the longest matching prefix, and
+//! the rest of the path is looked up by that filesystem. Symbolic links are
+//! resolved a component at a time, so they may cross mounts.
 
-use crate::hfsfs::HfsFs;
+use crate::block::BlockReader;
+use crate::errno::{
+    EADDRINUSE, EBADF, EBUSY, ECONNREFUSED, EEXIST, EINVAL, EISDIR, ELOOP, ENOENT, ENOTDIR, ENOTTY,
+    EOPNOTSUPP, EPERM, EROFS, ESPIPE, EXDEV,
+};
+use crate::kqueue::{self, KQueue, NOTE_ATTRIB, NOTE_DELETE, NOTE_EXTEND, NOTE_RENAME, NOTE_WRITE};
+use crate::pagecache::{self, FileId};
+use crate::socket::Socket;
+use crate::unix::Endpoint;
+use crate::waitqueue::SleepLock;
 use alloc::boxed::Box;
+use alloc::string::String;
 use alloc::sync::Arc;
 use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
 use spin::Mutex;
 
-static VFS: Mutex<Option<Vfs>> = Mutex::new(None);
+// open(2) flags
+pub const O_RDONLY: u32 = 0x0000;
+pub const O_WRONLY: u32 = 0x0001;
+pub const O_RDWR: u32 = 0x0002;
+pub const O_ACCMODE: u32 = 0x0003;
+pub const O_NONBLOCK: u32 = 0x0004;
+pub const O_APPEND: u32 = 0x0008;
+pub const O_NOFOLLOW: u32 = 0x0100;
+pub const O_CREAT: u32 = 0x0200;
+pub const O_TRUNC: u32 = 0x0400;
+pub const O_EXCL: u32 = 0x0800;
+pub const O_CLOEXEC: u32 = 0x0100_0000;
 
+// lseek(2) whence
+pub const SEEK_SET: u32 = 0;
+pub const SEEK_CUR: u32 = 1;
+pub const SEEK_END: u32 = 2;
+
+// st_mode file types
+pub const S_IFMT: u16 = 0o170000;
+pub const S_IFIFO: u16 = 0o010000;
+pub const S_IFCHR: u16 = 0o020000;
+pub const S_IFDIR: u16 = 0o040000;
+pub const S_IFBLK: u16 = 0o060000;
+pub const S_IFREG: u16 = 0o100000;
+pub const S_IFLNK: u16 = 0o120000;
+pub const S_IFSOCK: u16 = 0o140000;
+
+// dirent d_type
+pub const DT_FIFO: u8 = 1;
+pub const DT_CHR: u8 = 2;
+pub const DT_DIR: u8 = 4;
+pub const DT_BLK: u8 = 6;
+pub const DT_REG: u8 = 8;
+pub const DT_LNK: u8 = 10;
+pub const DT_SOCK: u8 = 12;
+
+// mount(2) flags
+pub const MNT_RDONLY: u32 = 0x0000_0001;
+pub const MNT_LOCAL: u32 = 0x0000_1000;
+pub const MNT_ROOTFS: u32 = 0x0000_4000;
+pub const MNT_UPDATE: u32 = 0x0001_0000;
+/// Flags a mount or update may set
+const MNT_SETTABLE: u32 = MNT_RDONLY;
+
+/// ioctl that sets or clears O_NONBLOCK
+const FIONBIO: u32 = 0x8004_667E;
+
+/// Status flags that fcntl(F_SETFL) may change
+const O_SETTABLE: u32 = O_NONBLOCK | O_APPEND;
+
+/// Mounted filesystems, sorted by path so that nested mounts follow the
+/// mount they are nested in
+static MOUNTS: Mutex<Vec<Mount>> = Mutex::new(Vec::new());
+
+/// File I/O. The file offset lives in `FileHandle`; regular files implement
+/// the positional methods, streams (pipes, terminals) override `is_stream`
+/// and implement `read`/`write`, which may block.
 pub trait File: Send + Sync {
-    fn read(&mut self, buf: &mut [u8]) -> usize;
     fn read_at(&self, offset: u64, buf: &mut [u8]) -> usize;
-    fn seek(&mut self, pos: u64);
     fn size(&self) -> u64;
 
-    fn read_to_end(&mut self) -> Vec<u8> {
-        let mut chunk = [0u8; 4096];
-        let mut buf = Vec::new();
+    fn write_at(&self, _offset: u64, _buf: &[u8]) -> Result<usize, u64> {
+        Err(EBADF)
+    }
 
-        loop {
-            let read = self.read(&mut chunk);
-            if read == 0 {
-                break;
-            }
-            buf.extend_from_slice(&chunk[..read]);
+    /// Cut the file to `size` bytes, or extend it with zeros, for ftruncate
+    fn truncate(&self, _size: u64) -> Result<(), u64> {
+        Err(EINVAL)
+    }
+
+    fn is_stream(&self) -> bool {
+        false
+    }
+
+    fn read(&self, buf: &mut [u8], _nonblock: bool) -> Result<usize, u64> {
+        Ok(self.read_at(0, buf))
+    }
+
+    fn write(&self, buf: &[u8], _nonblock: bool) -> Result<usize, u64> {
+        self.write_at(0, buf)
+    }
+
+    fn ioctl(&self, _cmd: u32, _arg: u64) -> Result<u64, u64> {
+        Err(ENOTTY)
+    }
+
+    /// st_mode reported by stat
+    fn mode(&self) -> u16 {
+        S_IFREG | 0o644
+    }
+
+    /// st_rdev reported by stat, for device nodes
+    fn rdev(&self) -> u32 {
+        0
+    }
+
+    /// Attributes reported by fstat. Files without an inode on a filesystem
+    /// report only their type and size.
+    fn getattr(&self) -> Attr {
+        Attr {
+            mode: self.mode(),
+            nlink: 1,
+            rdev: self.rdev(),
+            size: self.size(),
+            block_size: 4096,
+            ..Attr::default()
+        }
+    }
+
+    /// Entry `index` of a directory, or None past the last one
+    fn dir_entry(&self, _index: u64) -> Result<Option<DirEntry>, u64> {
+        Err(ENOTDIR)
+    }
+
+    /// The socket this file is, for the socket syscalls
+    fn socket(&self) -> Option<&dyn Socket> {
+        None
+    }
+
+    /// The kqueue this file is, for kevent
+    fn kqueue(&self) -> Option<&KQueue> {
+        None
+    }
+
+    /// Whether reads and writes would block, for kevent and select. Files
+    /// that may block must wake a `WaitQueue` when this changes.
+    fn readiness(&self) -> Readiness {
+        Readiness {
+            read: Some(self.size() as usize),
+            write: Some(0),
+            eof: false,
         }
+    }
+}
 
-        buf
+/// What reads and writes on a file can do without blocking
+#[derive(Clone, Copy, Default)]
+pub struct Readiness {
+    /// Bytes a read can return, or None if it would block
+    pub read: Option<usize>,
+    /// Room for a write, or None if it would block
+    pub write: Option<usize>,
+    /// The other end is gone: reads see end-of-file, writes fail
+    pub eof: bool,
+}
+
+#[derive(Clone)]
+pub struct DirEntry {
+    pub ino: u64,
+    /// DT_* type
+    pub kind: u8,
+    pub name: String,
+}
+
+/// A directory opened for reading, with its entries as of the open. Entry
+/// indexes serve as seek cookies; `.` and `..` come first.
+pub struct DirFile {
+    attr: Attr,
+    entries: Vec<DirEntry>,
+}
+
+impl DirFile {
+    pub fn new(attr: Attr, entries: Vec<DirEntry>) -> Self {
+        Self { attr, entries }
+    }
+}
+
+impl File for DirFile {
+    fn read_at(&self, _offset: u64, _buf: &mut [u8]) -> usize {
+        0
+    }
+    fn size(&self) -> u64 {
+        0
+    }
+    fn mode(&self) -> u16 {
+        self.attr.mode
+    }
+    fn getattr(&self) -> Attr {
+        self.attr.clone()
+    }
+    fn dir_entry(&self, index: u64) -> Result<Option<DirEntry>, u64> {
+        let dot = |ino, name| DirEntry {
+            ino,
+            kind: DT_DIR,
+            name: String::from(name),
+        };
+        Ok(match index {
+            0 => Some(dot(self.attr.ino, ".")),
+            1 => Some(dot(self.attr.parent_ino, "..")),
+            _ => self.entries.get(index as usize - 2).cloned(),
+        })
+    }
+}
+
+/// Attributes reported by stat and getattrlist
+#[derive(Clone, Default)]
+pub struct Attr {
+    /// Device number of the filesystem, filled in by the VFS
+    pub dev: u32,
+    pub ino: u64,
+    pub parent_ino: u64,
+    pub mode: u16,
+    pub nlink: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub rdev: u32,
+    pub size: u64,
+    /// Space allocated, in 512-byte blocks
+    pub blocks: u64,
+    /// Preferred I/O size
+    pub block_size: u32,
+    /// st_flags: UF_* in the low 16 bits, SF_* in the high 16
+    pub flags: u32,
+    // Times in seconds since the Unix epoch
+    pub atime: i64,
+    pub mtime: i64,
+    pub ctime: i64,
+    pub birthtime: i64,
+    pub backup_time: i64,
+    /// Resource fork size and allocation in bytes, for getattrlist
+    pub rsrc_size: u64,
+    pub rsrc_alloc: u64,
+    /// Number of entries in a directory
+    pub entries: u32,
+    /// Finder info, big-endian as stored on disk
+    pub finder_info: [u8; 32],
+}
+
+/// Usage reported by statfs
+#[derive(Clone, Copy, Default)]
+pub struct FsStat {
+    pub block_size: u32,
+    pub blocks: u64,
+    pub free_blocks: u64,
+    pub files: u64,
+    pub free_files: u64,
+}
+
+/// A filesystem that can be mounted. Paths are relative to its root and
+/// have no leading slash; the empty path is the root itself. Read-only
+/// filesystems leave the methods that create and change files to fail with
+/// EROFS.
+pub trait FileSystem: Send + Sync {
+    /// Type name reported by statfs, e.g. "hfs"
+    fn fs_type(&self) -> &'static str;
+
+    /// Inode number of the file at `path`
+    fn lookup(&self, path: &str) -> Result<u64, u64> {
+        self.getattr(path).map(|attr| attr.ino)
+    }
+
+    fn getattr(&self, path: &str) -> Result<Attr, u64>;
+
+    /// st_mode of the file at `path`, for filesystems that can find it
+    /// more cheaply than the other attributes
+    fn mode(&self, path: &str) -> Result<u16, u64> {
+        self.getattr(path).map(|attr| attr.mode)
+    }
+
+    /// Target of the symbolic link at `path`
+    fn readlink(&self, _path: &str) -> Result<String, u64> {
+        Err(EINVAL)
+    }
+
+    /// Remove the file at `path`, or the empty directory if `dir`
+    fn remove(&self, _path: &str, _dir: bool) -> Result<(), u64> {
+        Err(EPERM)
+    }
+
+    /// Create an empty regular file at `path` with permission bits `mode`.
+    /// Fails with EEXIST if there is already a file there.
+    fn create(&self, _path: &str, _mode: u16) -> Result<(), u64> {
+        Err(EROFS)
+    }
+
+    /// Create an empty directory at `path`
+    fn mkdir(&self, _path: &str, _mode: u16) -> Result<(), u64> {
+        Err(EROFS)
+    }
+
+    /// Create a FIFO or socket node at `path`, with the type and permission
+    /// bits in `mode`. The VFS opens it; the filesystem only names it.
+    fn mknod(&self, _path: &str, _mode: u16) -> Result<(), u64> {
+        Err(EROFS)
+    }
+
+    /// Create a symbolic link at `path` pointing to `target`
+    fn symlink(&self, _path: &str, _target: &str) -> Result<(), u64> {
+        Err(EROFS)
+    }
+
+    /// Move the file at `from` to `to`, replacing what is there: a file
+    /// if `from` is one, or an empty directory if `from` is a directory
+    fn rename(&self, _from: &str, _to: &str) -> Result<(), u64> {
+        Err(EROFS)
+    }
+
+    /// Cut the regular file at `path` to `size` bytes, or extend it with
+    /// zeros
+    fn truncate(&self, _path: &str, _size: u64) -> Result<(), u64> {
+        Err(EROFS)
+    }
+
+    /// Set the permission bits of the file at `path`
+    fn chmod(&self, _path: &str, _mode: u16) -> Result<(), u64> {
+        Err(EROFS)
+    }
+
+    /// Set the access and modification times of the file at `path`
+    fn utimes(&self, _path: &str, _atime: i64, _mtime: i64) -> Result<(), u64> {
+        Err(EROFS)
     }
+
+    /// Entries of the directory at `path`, excluding `.` and `..`
+    fn readdir(&self, path: &str) -> Result<Vec<DirEntry>, u64>;
+
+    /// Open the file or directory at `path` with open(2) `flags`
+    fn open(&self, path: &str, flags: u32) -> Result<Box<dyn File>, u64>;
+
+    fn statfs(&self) -> FsStat;
+
+    /// Whether reads of its regular files go through the page cache.
+    /// Filesystems kept in memory opt out rather than hold a second copy.
+    fn cacheable(&self) -> bool {
+        true
+    }
+}
+
+struct Mount {
+    /// Absolute path of the mount point, without a trailing slash
+    path: String,
+    /// What is mounted, e.g. the device node
+    source: String,
+    fs: Arc<dyn FileSystem>,
+    flags: u32,
+    /// st_dev of its files
+    dev: u32,
 }
 
-pub struct Vfs {
-    hfsfs: Arc<HfsFs>,
+/// A mount table entry, as reported by statfs and getfsstat
+pub struct MountInfo {
+    pub dev: u32,
+    pub path: String,
+    pub source: String,
+    pub fs_type: &'static str,
+    pub flags: u32,
+    pub stat: FsStat,
+}
+
+impl Mount {
+    fn info(&self) -> MountInfo {
+        MountInfo {
+            dev: self.dev,
+            path: self.path.clone(),
+            source: self.source.clone(),
+            fs_type: self.fs.fs_type(),
+            flags: self.flags,
+            stat: self.fs.statfs(),
+        }
+    }
+
+    /// Path of `path` relative to this mount, if it is at or below it
+    fn relative<'a>(&self, path: &'a str) -> Option<&'a str> {
+        if self.path.is_empty() {
+            return Some(path.trim_start_matches('/'));
+        }
+        let rest = path.strip_prefix(self.path.as_str())?;
+        if rest.is_empty() {
+            Some(rest)
+        } else {
+            rest.strip_prefix('/')
+        }
+    }
 }
 
+/// An open-file description: the file plus the offset and status flags
+/// shared by every descriptor that refers to it.
 pub struct FileHandle {
     pub file: Box<dyn File>,
+    /// Held across reads and writes, which may block
+    offset: SleepLock<u64>,
+    flags: AtomicU32,
+    /// Device number of the filesystem the file was opened from
+    dev: u32,
+    /// Canonical path it was opened by, for files opened from a filesystem
+    path: Option<String>,
+    /// Page cache key, for regular files with an inode number
+    cache_id: Option<FileId>,
+    /// End of the last read, to spot sequential readers
+    last_end: AtomicU64,
 }
 
-impl Vfs {
-    pub fn new(hfsfs: HfsFs) -> Self {
-        Self {
-            hfsfs: Arc::new(hfsfs),
+/// Longest chain of symbolic links followed in one lookup
+const MAXSYMLINKS: usize = 32;
+
+/// Canonical form of an absolute path: symbolic links resolved, no empty or
+/// `.` components, `..` applied and no trailing slash. The root is the empty
+/// string. The last component is only resolved if `follow`, or if it is
+/// followed by a slash; if it doesn't exist, the path it would have is
+/// returned for the caller to report.
+fn walk(path: &str, follow: bool) -> Result<String, u64> {
+    if !path.starts_with('/') {
+        return Err(ENOENT);
+    }
+    let mut resolved = String::new();
+    let mut links = 0;
+    // Components left to walk, next one last
+    let mut pending: Vec<String> = path.split('/').rev().map(String::from).collect();
+    while let Some(part) = pending.pop() {
+        match part.as_str() {
+            "" | "." => continue,
+            // The parent has no links left in it, so this leaves the target
+            // of a link rather than the link
+            ".." => {
+                let parent = resolved.rfind('/').unwrap_or(0);
+                resolved.truncate(parent);
+                continue;
+            }
+            _ => {}
+        }
+        let parent = resolved.len();
+        resolved.push('/');
+        resolved.push_str(&part);
+        let last = pending.is_empty();
+        if last && !follow {
+            break;
+        }
+
+        let (fs, rel, _) = resolve(&resolved)?;
+        let mode = match fs.mode(&rel) {
+            Ok(mode) => mode,
+            Err(ENOENT) if last => break,
+            Err(errno) => return Err(errno),
+        };
+        match mode & S_IFMT {
+            S_IFLNK => {
+                links += 1;
+                if links > MAXSYMLINKS {
+                    return Err(ELOOP);
+                }
+                let target = fs.readlink(&rel)?;
+                if target.is_empty() {
+                    return Err(ENOENT);
+                }
+                if target.starts_with('/') {
+                    resolved.clear();
+                } else {
+                    resolved.truncate(parent);
+                }
+                pending.extend(target.split('/').rev().map(String::from));
+            }
+            S_IFDIR => {}
+            _ if !last => return Err(ENOTDIR),
+            _ => {}
         }
     }
+    Ok(resolved)
+}
+
+/// The mount holding the canonical path `path` and the path relative to
+/// its root. Mount points are crossed by picking the deepest mount the path
+/// is under.
+fn find_mount<'a, 'p>(mounts: &'a [Mount], path: &'p str) -> Result<(&'a Mount, &'p str), u64> {
+    mounts
+        .iter()
+        .rev()
+        .find_map(|m| m.relative(path).map(|rel| (m, rel)))
+        .ok_or(ENOENT)
+}
+
+/// The filesystem holding the canonical path `path`, the path relative to
+/// its root and its device number
+fn resolve(path: &str) -> Result<(Arc<dyn FileSystem>, String, u32), u64> {
+    let mounts = MOUNTS.lock();
+    let (mount, rel) = find_mount(&mounts, path)?;
+    Ok((Arc::clone(&mount.fs), String::from(rel), mount.dev))
+}
+
+/// Fail with EROFS if the canonical path `path` is on a read-only mount
+fn check_writable(path: &str) -> Result<(), u64> {
+    let mounts = MOUNTS.lock();
+    if find_mount(&mounts, path)?.0.flags & MNT_RDONLY != 0 {
+        return Err(EROFS);
+    }
+    Ok(())
+}
+
+/// Whether a filesystem is mounted at the canonical path `path`
+fn mounted_at(path: &str) -> bool {
+    MOUNTS.lock().iter().any(|m| m.path == path)
 }
 
-/// Initialize the VFS with an HFS+ filesystem
-pub fn init(hfsfs: HfsFs) {
-    let mut vfs = VFS.lock();
-    *vfs = Some(Vfs::new(hfsfs));
+/// Whether a filesystem is mounted at `path`
+pub fn is_mount_point(path: &str) -> bool {
+    walk(path, true).is_ok_and(|path| mounted_at(&path))
 }
 
-/// Open a file by path
-pub fn open(path: &str) -> Option<FileHandle> {
-    if path == "/dev/random" || path == "/dev/urandom" {
-        return Some(FileHandle {
-            file: Box::new(RandomFile { pos: 0 }),
-        });
+/// Device numbers for mounts whose source isn't a device node
+static NEXT_ANON_DEV: AtomicU32 = AtomicU32::new(1);
+
+/// Mount `fs` at the absolute path `path`. Fails with EBUSY if something is
+/// already mounted there. The mount point itself is not checked.
+pub fn mount(path: &str, fs: Arc<dyn FileSystem>, source: &str, flags: u32) -> Result<(), u64> {
+    let path = walk(path, true)?;
+    // Files report the device they are mounted from, like on Darwin
+    let dev = match stat(source) {
+        Ok(attr) if attr.rdev != 0 => attr.rdev,
+        _ => 0xFF00_0000 | NEXT_ANON_DEV.fetch_add(1, Ordering::Relaxed),
+    };
+    let mut mounts = MOUNTS.lock();
+    if mounts.iter().any(|m| m.path == path) {
+        return Err(EBUSY);
     }
+    let flags = (flags & MNT_SETTABLE) | MNT_LOCAL | if path.is_empty() { MNT_ROOTFS } else { 0 };
+    let index = mounts.partition_point(|m| m.path < path);
+    mounts.insert(
+        index,
+        Mount {
+            path,
+            source: String::from(source),
+            fs,
+            flags,
+            dev,
+        },
+    );
+    Ok(())
+}
 
-    let vfs = VFS.lock();
-    let vfs = vfs.as_ref()?;
+/// Change the flags of the filesystem mounted at `path`
+pub fn update_mount(path: &str, flags: u32) -> Result<(), u64> {
+    let path = walk(path, true)?;
+    let mut mounts = MOUNTS.lock();
+    let mount = mounts.iter_mut().find(|m| m.path == path).ok_or(EINVAL)?;
+    mount.flags = (mount.flags & !MNT_SETTABLE) | (flags & MNT_SETTABLE);
+    Ok(())
+}
 
-    if let Some(file) = vfs.hfsfs.open(path) {
-        Some(FileHandle { file })
+/// Remove the filesystem mounted at `path`. The root, and filesystems with
+/// others mounted inside them, are busy.
+pub fn unmount(path: &str) -> Result<(), u64> {
+    let path = walk(path, true)?;
+    if path.is_empty() {
+        return Err(EBUSY);
+    }
+    let mut mounts = MOUNTS.lock();
+    let index = mounts.iter().position(|m| m.path == path).ok_or(EINVAL)?;
+    let prefix = path + "/";
+    if mounts.iter().any(|m| m.path.starts_with(&prefix)) {
+        return Err(EBUSY);
+    }
+    let mount = mounts.remove(index);
+    drop(mounts);
+    pagecache::purge(mount.dev);
+    Ok(())
+}
+
+/// The mount holding the existing file at `path`
+pub fn statfs(path: &str) -> Result<MountInfo, u64> {
+    let path = walk(path, true)?;
+    let (fs, rel, info) = {
+        let mounts = MOUNTS.lock();
+        let (mount, rel) = find_mount(&mounts, &path)?;
+        (Arc::clone(&mount.fs), String::from(rel), mount.info())
+    };
+    fs.lookup(&rel)?;
+    Ok(info)
+}
+
+/// Every mount, in mount table order
+pub fn mounts() -> Vec<MountInfo> {
+    MOUNTS.lock().iter().map(Mount::info).collect()
+}
+
+/// Attributes of the file at `path`, following a final symbolic link.
+/// FIFOs are not opened, since that would block.
+pub fn stat(path: &str) -> Result<Attr, u64> {
+    getattr(path, true)
+}
+
+/// Attributes of the file at `path`, or of the symbolic link if it is one
+pub fn lstat(path: &str) -> Result<Attr, u64> {
+    getattr(path, false)
+}
+
+fn getattr(path: &str, follow: bool) -> Result<Attr, u64> {
+    let path = walk(path, follow)?;
+    let (fs, rel, dev) = resolve(&path)?;
+    let mut attr = fs.getattr(&rel)?;
+    attr.dev = dev;
+    Ok(attr)
+}
+
+/// Canonical absolute path of the existing file at `path`, with `/` for
+/// the root
+pub fn realpath(path: &str) -> Result<String, u64> {
+    stat(path)?;
+    let path = walk(path, true)?;
+    Ok(if path.is_empty() {
+        String::from("/")
     } else {
-        None
+        path
+    })
+}
+
+/// Target of the symbolic link at `path`
+pub fn readlink(path: &str) -> Result<String, u64> {
+    let (fs, rel, _) = resolve(&walk(path, false)?)?;
+    fs.readlink(&rel)
+}
+
+/// Create a FIFO at `path` with permission bits `mode`
+pub fn mkfifo(path: &str, mode: u16) -> Result<(), u64> {
+    let path = new_path(path)?;
+    let (fs, rel, _) = resolve(&path)?;
+    check_writable(&path)?;
+    fs.mknod(&rel, S_IFIFO | (mode & !S_IFMT))?;
+    dir_changed(&path);
+    Ok(())
+}
+
+/// Give a UNIX domain socket the name `path` by making a socket node
+/// there. Returns the canonical path.
+pub fn bind_socket(path: &str, endpoint: Endpoint) -> Result<String, u64> {
+    let path = new_path(path).map_err(|errno| match errno {
+        EEXIST => EADDRINUSE,
+        errno => errno,
+    })?;
+    let (fs, rel, dev) = resolve(&path)?;
+    check_writable(&path)?;
+    fs.mknod(&rel, S_IFSOCK | 0o755)?;
+    let id = FileId {
+        dev,
+        ino: fs.lookup(&rel)?,
+    };
+    crate::unix::bind(id, endpoint);
+    dir_changed(&path);
+    Ok(path)
+}
+
+/// The UNIX domain socket named `path`. Fails with ECONNREFUSED if the
+/// file there isn't a socket node, or its socket has closed.
+pub fn lookup_socket(path: &str) -> Result<Endpoint, u64> {
+    let path = walk(path, true)?;
+    let (fs, rel, dev) = resolve(&path)?;
+    let attr = fs.getattr(&rel)?;
+    if attr.mode & S_IFMT != S_IFSOCK {
+        return Err(ECONNREFUSED);
     }
+    crate::unix::lookup(FileId { dev, ino: attr.ino }).ok_or(ECONNREFUSED)
 }
 
-use rand_chacha::ChaCha20Rng;
-use rand_core::{RngCore, SeedableRng};
+/// Remove the file at `path`, or the empty directory if `dir`
+pub fn remove(path: &str, dir: bool) -> Result<(), u64> {
+    let is_dir = lstat(path)?.mode & S_IFMT == S_IFDIR;
+    let path = walk(path, false)?;
+    match (dir, is_dir) {
+        (true, false) => return Err(ENOTDIR),
+        (false, true) => return Err(EPERM),
+        _ => {}
+    }
+    if path.is_empty() || mounted_at(&path) {
+        return Err(EBUSY);
+    }
+    check_writable(&path)?;
+    let (fs, rel, _) = resolve(&path)?;
+    fs.remove(&rel, dir)?;
+    kqueue::vnode_changed(&path, NOTE_DELETE);
+    dir_changed(&path);
+    Ok(())
+}
 
-static mut RANDOM_RNG: Option<ChaCha20Rng> = None;
+/// Tell kqueues watching the file at canonical path `path` that it changed
+fn changed(path: &str, fflags: u32) {
+    kqueue::vnode_changed(if path.is_empty() { "/" } else { path }, fflags);
+}
 
-struct RandomFile {
-    pos: u64,
+/// Tell kqueues watching the directory holding `path` that its entries
+/// changed
+fn dir_changed(path: &str) {
+    changed(&path[..path.rfind('/').unwrap_or(0)], NOTE_WRITE);
 }
 
-impl File for RandomFile {
-    fn read(&mut self, buf: &mut [u8]) -> usize {
-        unsafe {
-            let rng_ptr = core::ptr::addr_of_mut!(RANDOM_RNG);
-            if (*rng_ptr).is_none() {
-                *rng_ptr = Some(ChaCha20Rng::from_seed([0x42; 32]));
-            }
-            if let Some(ref mut rng) = *rng_ptr {
-                rng.fill_bytes(buf);
+/// Drop cached pages past the old or new end of a file that changed size,
+/// and tell kqueues watching it
+fn resized(id: Option<FileId>, path: Option<&str>, old: u64, new: u64) {
+    if let Some(id) = id {
+        pagecache::invalidate(id, old.min(new), old.abs_diff(new));
+    }
+    if let Some(path) = path {
+        let extend = if new > old { NOTE_EXTEND } else { 0 };
+        changed(path, NOTE_ATTRIB | extend);
+    }
+}
+
+/// Canonical path for a file about to be made at `path`, which must not
+/// exist yet
+fn new_path(path: &str) -> Result<String, u64> {
+    if lstat(path).is_ok() {
+        return Err(EEXIST);
+    }
+    walk(path, false)
+}
+
+/// Create a directory at `path` with permission bits `mode`
+pub fn mkdir(path: &str, mode: u16) -> Result<(), u64> {
+    let path = new_path(path)?;
+    let (fs, rel, _) = resolve(&path)?;
+    check_writable(&path)?;
+    fs.mkdir(&rel, mode & !S_IFMT)?;
+    dir_changed(&path);
+    Ok(())
+}
+
+/// Create a symbolic link at `path` pointing to `target`
+pub fn symlink(target: &str, path: &str) -> Result<(), u64> {
+    if target.is_empty() {
+        return Err(ENOENT);
+    }
+    let path = new_path(path)?;
+    let (fs, rel, _) = resolve(&path)?;
+    check_writable(&path)?;
+    fs.symlink(&rel, target)?;
+    dir_changed(&path);
+    Ok(())
+}
+
+/// Move the file at `from` to `to`, which must be on the same mount. A
+/// final symbolic link is moved rather than followed.
+pub fn rename(from: &str, to: &str) -> Result<(), u64> {
+    lstat(from)?;
+    let from = walk(from, false)?;
+    let to = walk(to, false)?;
+    if from.is_empty() || to.is_empty() || mounted_at(&from) || mounted_at(&to) {
+        return Err(EBUSY);
+    }
+    if from == to {
+        return Ok(());
+    }
+    // A directory can't be moved inside itself
+    if to
+        .strip_prefix(from.as_str())
+        .is_some_and(|rest| rest.starts_with('/'))
+    {
+        return Err(EINVAL);
+    }
+    let (fs, from_rel, dev) = resolve(&from)?;
+    let (_, to_rel, to_dev) = resolve(&to)?;
+    if dev != to_dev {
+        return Err(EXDEV);
+    }
+    check_writable(&from)?;
+    let replaced = lstat(&to).is_ok();
+    fs.rename(&from_rel, &to_rel)?;
+    if replaced {
+        changed(&to, NOTE_DELETE);
+    }
+    changed(&from, NOTE_RENAME);
+    dir_changed(&from);
+    dir_changed(&to);
+    Ok(())
+}
+
+/// Cut the regular file at `path` to `size` bytes, or extend it with zeros
+pub fn truncate(path: &str, size: u64) -> Result<(), u64> {
+    let path = walk(path, true)?;
+    let (fs, rel, dev) = resolve(&path)?;
+    let attr = fs.getattr(&rel)?;
+    match attr.mode & S_IFMT {
+        S_IFREG => {}
+        S_IFDIR => return Err(EISDIR),
+        _ => return Err(EINVAL),
+    }
+    check_writable(&path)?;
+    fs.truncate(&rel, size)?;
+    let id = FileId { dev, ino: attr.ino };
+    resized(Some(id), Some(&path), attr.size, size);
+    Ok(())
+}
+
+/// Set the permission bits of the file at `path`, following a final
+/// symbolic link
+pub fn chmod(path: &str, mode: u16) -> Result<(), u64> {
+    let path = walk(path, true)?;
+    let (fs, rel, _) = resolve(&path)?;
+    fs.lookup(&rel)?;
+    check_writable(&path)?;
+    fs.chmod(&rel, mode & !S_IFMT)?;
+    changed(&path, NOTE_ATTRIB);
+    Ok(())
+}
+
+/// Set the access and modification times of the file at `path`, in
+/// seconds since the Unix epoch, following a final symbolic link
+pub fn utimes(path: &str, atime: i64, mtime: i64) -> Result<(), u64> {
+    let path = walk(path, true)?;
+    let (fs, rel, _) = resolve(&path)?;
+    fs.lookup(&rel)?;
+    check_writable(&path)?;
+    fs.utimes(&rel, atime, mtime)?;
+    changed(&path, NOTE_ATTRIB);
+    Ok(())
+}
+
+/// Open a file by path with open(2) `flags`. With O_NOFOLLOW, a symbolic
+/// link fails with ELOOP instead of being followed. O_CREAT makes a missing
+/// regular file with permission bits `mode`.
+pub fn open(path: &str, flags: u32, mode: u16) -> Result<FileHandle, u64> {
+    let exclusive = flags & (O_CREAT | O_EXCL) == O_CREAT | O_EXCL;
+    // O_EXCL doesn't create through a final symbolic link
+    let path = walk(path, flags & O_NOFOLLOW == 0 && !exclusive)?;
+    if flags & O_CREAT != 0 {
+        if path.is_empty() || lstat(&path).is_ok() {
+            if exclusive {
+                return Err(EEXIST);
             }
+        } else {
+            let (fs, rel, _) = resolve(&path)?;
+            check_writable(&path)?;
+            fs.create(&rel, mode & !S_IFMT)?;
+            dir_changed(&path);
         }
-        buf.len()
     }
-    fn read_at(&self, _offset: u64, buf: &mut [u8]) -> usize {
-        unsafe {
-            let rng_ptr = core::ptr::addr_of_mut!(RANDOM_RNG);
-            if (*rng_ptr).is_none() {
-                *rng_ptr = Some(ChaCha20Rng::from_seed([0x42; 32]));
-            }
-            if let Some(ref mut rng) = *rng_ptr {
-                rng.fill_bytes(buf);
-            }
+    let (fs, rel, dev) = resolve(&path)?;
+    match fs.mode(&rel)? & S_IFMT {
+        S_IFLNK if flags & O_NOFOLLOW != 0 => return Err(ELOOP),
+        S_IFIFO => {
+            let id = FileId {
+                dev,
+                ino: fs.lookup(&rel)?,
+            };
+            return crate::pipe::open_fifo(crate::pipe::fifo(id), flags);
         }
-        buf.len()
+        S_IFSOCK => return Err(EOPNOTSUPP),
+        _ => {}
     }
-    fn seek(&mut self, pos: u64) {
-        self.pos = pos;
+    let file = fs.open(&rel, flags)?;
+    if file.mode() & S_IFMT == S_IFDIR && flags & O_ACCMODE != O_RDONLY {
+        return Err(EISDIR);
     }
-    fn size(&self) -> u64 {
-        u64::MAX
+    let attr = file.getattr();
+    let regular = attr.mode & S_IFMT == S_IFREG;
+    let cacheable = regular && attr.ino != 0 && !file.is_stream() && fs.cacheable();
+    let cache_id = cacheable.then_some(FileId { dev, ino: attr.ino });
+    if flags & O_TRUNC != 0 && flags & O_ACCMODE != O_RDONLY && regular && attr.size != 0 {
+        check_writable(&path)?;
+        file.truncate(0)?;
+        resized(cache_id, Some(&path), attr.size, 0);
     }
+    let mut handle = FileHandle::new(file, flags);
+    handle.dev = dev;
+    handle.path = Some(path);
+    handle.cache_id = cache_id;
+    Ok(handle)
 }
 
 impl FileHandle {
-    /// Read bytes from file
-    pub fn read(&mut self, buf: &mut [u8]) -> usize {
-        self.file.read(buf)
+    pub fn new(file: Box<dyn File>, flags: u32) -> Self {
+        Self {
+            file,
+            offset: SleepLock::new(0),
+            flags: AtomicU32::new(flags & (O_ACCMODE | O_SETTABLE)),
+            dev: 0,
+            path: None,
+            cache_id: None,
+            last_end: AtomicU64::new(0),
+        }
     }
 
-    /// Read at offset
+    /// Read bytes from file at the shared offset
+    pub fn read(&self, buf: &mut [u8]) -> Result<usize, u64> {
+        let flags = self.status_flags();
+        if flags & O_ACCMODE == O_WRONLY {
+            return Err(EBADF);
+        }
+        if self.file.is_stream() {
+            return self.file.read(buf, flags & O_NONBLOCK != 0);
+        }
+
+        let mut offset = self.offset.lock();
+        let read = self.read_at(*offset, buf);
+        *offset += read as u64;
+        Ok(read)
+    }
+
+    /// Write bytes at the shared offset, or at the end with O_APPEND
+    pub fn write(&self, buf: &[u8]) -> Result<usize, u64> {
+        let flags = self.status_flags();
+        if flags & O_ACCMODE == O_RDONLY {
+            return Err(EBADF);
+        }
+        if self.file.is_stream() {
+            return self.file.write(buf, flags & O_NONBLOCK != 0);
+        }
+
+        let mut offset = self.offset.lock();
+        if flags & O_APPEND != 0 {
+            *offset = self.file.size();
+        }
+        let size = self.file.size();
+        let written = self.file.write_at(*offset, buf)?;
+        if let Some(id) = self.cache_id {
+            pagecache::invalidate(id, *offset, written as u64);
+        }
+        *offset += written as u64;
+        let extended = *offset > size;
+        // kevent may be reading the offset while it holds the kqueue
+        drop(offset);
+        if written > 0
+            && let Some(path) = self.path()
+        {
+            let extend = if extended { NOTE_EXTEND } else { 0 };
+            kqueue::vnode_changed(path, NOTE_WRITE | extend);
+        }
+        Ok(written)
+    }
+
+    /// Cut the file to `size` bytes or extend it with zeros, for ftruncate
+    pub fn truncate(&self, size: u64) -> Result<(), u64> {
+        if self.status_flags() & O_ACCMODE == O_RDONLY || self.file.is_stream() {
+            return Err(EINVAL);
+        }
+        let old = self.file.size();
+        self.file.truncate(size)?;
+        resized(self.cache_id, self.path(), old, size);
+        Ok(())
+    }
+
+    /// Read at offset, through the page cache for regular files
     pub fn read_at(&self, offset: u64, buf: &mut [u8]) -> usize {
-        self.file.read_at(offset, buf)
+        let Some(id) = self.cache_id else {
+            return self.file.read_at(offset, buf);
+        };
+        let sequential = self.last_end.load(Ordering::Relaxed) == offset;
+        let read = pagecache::read(id, &*self.file, offset, buf, sequential);
+        self.last_end.store(offset + read as u64, Ordering::Relaxed);
+        read
+    }
+
+    /// Page cache key of the file, if it is cached
+    pub fn cache_id(&self) -> Option<FileId> {
+        self.cache_id
     }
 
     /// Get file size
@@ -128,13 +970,117 @@ impl FileHandle {
         self.file.size()
     }
 
-    /// Seek to position
-    pub fn seek(&mut self, pos: u64) {
-        self.file.seek(pos);
+    /// Attributes reported by fstat
+    pub fn getattr(&self) -> Attr {
+        let mut attr = self.file.getattr();
+        attr.dev = self.dev;
+        attr
+    }
+
+    /// Whether reads and writes would block. Regular files count what is
+    /// left to read from the shared offset.
+    pub fn readiness(&self) -> Readiness {
+        let mut readiness = self.file.readiness();
+        if !self.file.is_stream()
+            && let Some(read) = &mut readiness.read
+        {
+            *read = read.saturating_sub(*self.offset.lock() as usize);
+        }
+        readiness
+    }
+
+    /// Canonical absolute path the file was opened by, with `/` for the root
+    pub fn path(&self) -> Option<&str> {
+        self.path
+            .as_deref()
+            .map(|path| if path.is_empty() { "/" } else { path })
+    }
+
+    /// Reposition the shared offset relative to `whence`. Returns the new offset.
+    pub fn seek(&self, offset: i64, whence: u32) -> Result<u64, u64> {
+        if self.file.is_stream() {
+            return Err(ESPIPE);
+        }
+        let mut pos = self.offset.lock();
+        let base = match whence {
+            SEEK_SET => 0,
+            SEEK_CUR => *pos,
+            SEEK_END => self.size(),
+            _ => return Err(EINVAL),
+        };
+        let new_pos = base.checked_add_signed(offset).ok_or(EINVAL)?;
+        *pos = new_pos;
+        Ok(new_pos)
     }
 
     /// Read bytes from file to end
-    pub fn read_to_end(&mut self) -> Vec<u8> {
-        self.file.read_to_end()
+    pub fn read_to_end(&self) -> Vec<u8> {
+        let mut chunk = [0u8; 4096];
+        let mut buf = Vec::new();
+
+        while let Ok(read) = self.read(&mut chunk) {
+            if read == 0 {
+                break;
+            }
+            buf.extend_from_slice(&chunk[..read]);
+        }
+
+        buf
+    }
+
+    /// Pass directory entries from the shared offset to `fill` until it
+    /// returns false, advancing the offset past those it accepted. `fill`
+    /// also gets the offset of the entry after each one, which can be passed
+    /// to `seek` to resume there. Returns the offset of the first entry, or
+    /// EINVAL if `fill` rejected it.
+    pub fn read_dir(&self, mut fill: impl FnMut(u64, &DirEntry) -> bool) -> Result<u64, u64> {
+        let mut offset = self.offset.lock();
+        let base = *offset;
+        while let Some(entry) = self.file.dir_entry(*offset)? {
+            if !fill(*offset + 1, &entry) {
+                if *offset == base {
+                    return Err(EINVAL);
+                }
+                break;
+            }
+            *offset += 1;
+        }
+        Ok(base)
+    }
+
+    /// Device control. FIONBIO applies to the description itself.
+    pub fn ioctl(&self, cmd: u32, arg: u64) -> Result<u64, u64> {
+        if cmd == FIONBIO {
+            let on = unsafe { *(arg as *const u32) } != 0;
+            let flags = self.status_flags();
+            self.set_status_flags(if on {
+                flags | O_NONBLOCK
+            } else {
+                flags & !O_NONBLOCK
+            });
+            return Ok(0);
+        }
+        self.file.ioctl(cmd, arg)
+    }
+
+    /// File status flags (access mode, O_NONBLOCK, O_APPEND)
+    pub fn status_flags(&self) -> u32 {
+        self.flags.load(Ordering::Relaxed)
+    }
+
+    pub fn set_status_flags(&self, flags: u32) {
+        let old = self.flags.load(Ordering::Relaxed);
+        self.flags.store(
+            (old & !O_SETTABLE) | (flags & O_SETTABLE),
+            Ordering::Relaxed,
+        );
+    }
+}
+
+/// A file used as a disk, so that filesystems can be mounted from device
+/// nodes
+impl BlockReader for FileHandle {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> bool {
+        self.file.read_at(offset, buf) == buf.len()
     }
 }
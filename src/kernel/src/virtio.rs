@@ -1,45 +1,28 @@
-//! Virtio-blk driver using PCI transport for QEMU virt machine
+//! Virtio-blk, virtio-net and virtio-rng drivers for the QEMU virt machine
+//!
+//! Devices are found on PCI first, then among the virtio-mmio slots the
+//! devicetree lists. The drivers talk to either through a `Transport`.
+//!
+//! PCI devices interrupt through their legacy INTx pin, which QEMU routes to
+//! one of four GIC lines shared by all PCI devices. Each virtio-mmio slot
+//! has a line of its own. QEMU's virtio-mmio devices are legacy ones unless
+//! told otherwise, so both versions of that interface are supported.
 
-use crate::block::BlockReader;
-use crate::kprintln;
+use crate::block::{BlockDevice, BlockReader};
+use crate::platform::{self, PciHost};
+use crate::virtqueue::{
+    Buffer, LEGACY_QUEUE_ALIGN, Notifier, QUEUE_SIZE, Virtqueue, cache_clean_range,
+    cache_invalidate_range,
+};
+use crate::{kprintln, mmu};
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
 use alloc::vec;
 use alloc::vec::Vec;
-use core::arch::asm;
 use core::ptr::{read_volatile, write_volatile};
 use spin::Mutex;
 
-/// Clean cache range for DMA
-fn cache_clean_range(addr: usize, len: usize) {
-    let mut curr = addr & !63;
-    let end = addr + len;
-    while curr < end {
-        unsafe {
-            asm!("dc cvac, {0}", in(reg) curr);
-        }
-        curr += 64;
-    }
-    unsafe {
-        asm!("dsb sy");
-    }
-}
-
-fn cache_invalidate_range(addr: usize, len: usize) {
-    let mut curr = addr & !63;
-    let end = addr + len;
-    while curr < end {
-        unsafe {
-            asm!("dc ivac, {0}", in(reg) curr);
-        }
-        curr += 64;
-    }
-    unsafe {
-        asm!("dsb sy");
-    }
-}
-
-// QEMU virt PCI ECAM base address
-const PCI_ECAM_BASE: usize = 0x3f00_0000;
-
 // PCI config space offsets
 const PCI_VENDOR_ID: usize = 0x00;
 const PCI_DEVICE_ID: usize = 0x02;
@@ -47,14 +30,23 @@ const PCI_COMMAND: usize = 0x04;
 const PCI_STATUS: usize = 0x06;
 const PCI_BAR0: usize = 0x10;
 const PCI_CAP_PTR: usize = 0x34;
+const PCI_INTERRUPT_PIN: usize = 0x3d;
+
+/// PCI_COMMAND bit that masks INTx
+const PCI_COMMAND_INTX_DISABLE: u16 = 1 << 10;
 
 // Virtio PCI capability types
 const VIRTIO_PCI_CAP_COMMON_CFG: u8 = 1;
 const VIRTIO_PCI_CAP_NOTIFY_CFG: u8 = 2;
+const VIRTIO_PCI_CAP_ISR_CFG: u8 = 3;
+const VIRTIO_PCI_CAP_DEVICE_CFG: u8 = 4;
 
 // Virtio PCI common configuration offsets
+const VIRTIO_PCI_COMMON_DFSELECT: usize = 0x00;
+const VIRTIO_PCI_COMMON_DF: usize = 0x04;
 const VIRTIO_PCI_COMMON_GFSELECT: usize = 0x08;
 const VIRTIO_PCI_COMMON_GF: usize = 0x0c;
+const VIRTIO_PCI_COMMON_NUM_QUEUES: usize = 0x12;
 const VIRTIO_PCI_COMMON_STATUS: usize = 0x14;
 const VIRTIO_PCI_COMMON_Q_SELECT: usize = 0x16;
 const VIRTIO_PCI_COMMON_Q_SIZE: usize = 0x18;
@@ -64,8 +56,48 @@ const VIRTIO_PCI_COMMON_Q_DESCLO: usize = 0x20;
 const VIRTIO_PCI_COMMON_Q_AVAILLO: usize = 0x28;
 const VIRTIO_PCI_COMMON_Q_USEDLO: usize = 0x30;
 
-// Virtio feature bits
+// Virtio-mmio register offsets. The legacy interface has GUEST_PAGE_SIZE,
+// QUEUE_ALIGN and QUEUE_PFN in place of QUEUE_READY and the ring addresses.
+const VIRTIO_MMIO_MAGIC: usize = 0x000;
+const VIRTIO_MMIO_VERSION: usize = 0x004;
+const VIRTIO_MMIO_DEVICE_ID: usize = 0x008;
+const VIRTIO_MMIO_DEVICE_FEATURES: usize = 0x010;
+const VIRTIO_MMIO_DEVICE_FEATURES_SEL: usize = 0x014;
+const VIRTIO_MMIO_DRIVER_FEATURES: usize = 0x020;
+const VIRTIO_MMIO_DRIVER_FEATURES_SEL: usize = 0x024;
+const VIRTIO_MMIO_GUEST_PAGE_SIZE: usize = 0x028;
+const VIRTIO_MMIO_QUEUE_SEL: usize = 0x030;
+const VIRTIO_MMIO_QUEUE_NUM_MAX: usize = 0x034;
+const VIRTIO_MMIO_QUEUE_NUM: usize = 0x038;
+const VIRTIO_MMIO_QUEUE_ALIGN: usize = 0x03c;
+const VIRTIO_MMIO_QUEUE_PFN: usize = 0x040;
+const VIRTIO_MMIO_QUEUE_READY: usize = 0x044;
+const VIRTIO_MMIO_QUEUE_NOTIFY: usize = 0x050;
+const VIRTIO_MMIO_INTERRUPT_STATUS: usize = 0x060;
+const VIRTIO_MMIO_INTERRUPT_ACK: usize = 0x064;
+const VIRTIO_MMIO_STATUS: usize = 0x070;
+const VIRTIO_MMIO_QUEUE_DESC_LOW: usize = 0x080;
+const VIRTIO_MMIO_QUEUE_DESC_HIGH: usize = 0x084;
+const VIRTIO_MMIO_QUEUE_DRIVER_LOW: usize = 0x090;
+const VIRTIO_MMIO_QUEUE_DRIVER_HIGH: usize = 0x094;
+const VIRTIO_MMIO_QUEUE_DEVICE_LOW: usize = 0x0a0;
+const VIRTIO_MMIO_QUEUE_DEVICE_HIGH: usize = 0x0a4;
+const VIRTIO_MMIO_CONFIG: usize = 0x100;
+
+/// "virt", little-endian
+const VIRTIO_MMIO_MAGIC_VALUE: u32 = 0x7472_6976;
+const VIRTIO_MMIO_VERSION_LEGACY: u32 = 1;
+const VIRTIO_MMIO_VERSION_MODERN: u32 = 2;
+/// Page size legacy devices compute ring addresses with
+const VIRTIO_MMIO_PAGE_SIZE: u32 = 4096;
+
+// Virtio feature bits. VERSION_1 is in the second word of features.
 const VIRTIO_F_VERSION_1: u32 = 1 << 0;
+const VIRTIO_BLK_F_SIZE_MAX: u32 = 1 << 1;
+const VIRTIO_BLK_F_RO: u32 = 1 << 5;
+const VIRTIO_BLK_F_BLK_SIZE: u32 = 1 << 6;
+const VIRTIO_BLK_F_FLUSH: u32 = 1 << 9;
+const VIRTIO_NET_F_MAC: u32 = 1 << 5;
 
 // Virtio status bits
 const VIRTIO_STATUS_ACKNOWLEDGE: u8 = 1;
@@ -73,66 +105,114 @@ const VIRTIO_STATUS_DRIVER: u8 = 2;
 const VIRTIO_STATUS_FEATURES_OK: u8 = 8;
 const VIRTIO_STATUS_DRIVER_OK: u8 = 4;
 
+/// ISR status bit for a used buffer notification
+const VIRTIO_ISR_QUEUE: u8 = 1;
+
 const VIRTIO_BLK_T_IN: u32 = 0;
-const VIRTQ_DESC_F_NEXT: u16 = 1;
-const VIRTQ_DESC_F_WRITE: u16 = 2;
+const VIRTIO_BLK_T_OUT: u32 = 1;
+const VIRTIO_BLK_T_FLUSH: u32 = 4;
+const VIRTIO_BLK_S_OK: u8 = 0;
+
+// struct virtio_blk_config offsets
+const VIRTIO_BLK_CFG_CAPACITY: usize = 0x00;
+const VIRTIO_BLK_CFG_SIZE_MAX: usize = 0x08;
+const VIRTIO_BLK_CFG_BLK_SIZE: usize = 0x14;
+
+const SECTOR_SIZE: u64 = 512;
 
-const QUEUE_SIZE: usize = 16;
+// struct virtio_net_config offsets
+const VIRTIO_NET_CFG_MAC: usize = 0x00;
+
+/// struct virtio_net_hdr, which precedes every frame. Its num_buffers field
+/// is only there with VERSION_1 or VIRTIO_NET_F_MRG_RXBUF.
+const VIRTIO_NET_HDR_LEN: usize = 12;
+const VIRTIO_NET_HDR_LEN_LEGACY: usize = 10;
+
+const VIRTIO_NET_RX_QUEUE: usize = 0;
+const VIRTIO_NET_TX_QUEUE: usize = 1;
+
+/// Largest Ethernet frame the device passes, without its FCS
+pub const ETHERNET_FRAME_MAX: usize = 1514;
+const NET_BUFFER_SIZE: usize = 2048;
 
 const VIRTIO_VENDOR_ID: u16 = 0x1af4;
 const VIRTIO_BLK_DEVICE_ID_LEGACY: u16 = 0x1001;
 const VIRTIO_BLK_DEVICE_ID_MODERN: u16 = 0x1042;
+const VIRTIO_RNG_DEVICE_ID_LEGACY: u16 = 0x1005;
+const VIRTIO_RNG_DEVICE_ID_MODERN: u16 = 0x1044;
+const VIRTIO_NET_DEVICE_ID_LEGACY: u16 = 0x1000;
+const VIRTIO_NET_DEVICE_ID_MODERN: u16 = 0x1041;
+
+// Virtio device IDs, which virtio-pci offsets by 0x1040
+const VIRTIO_ID_NET: u32 = 1;
+const VIRTIO_ID_BLOCK: u32 = 2;
+const VIRTIO_ID_RNG: u32 = 4;
 
 #[repr(C)]
-struct VirtqDesc {
-    addr: u64,
-    len: u32,
-    flags: u16,
-    next: u16,
+struct VirtioBlkReq {
+    req_type: u32,
+    reserved: u32,
+    sector: u64,
 }
 
-#[repr(C)]
-struct VirtqAvail {
-    flags: u16,
-    idx: u16,
-    ring: [u16; QUEUE_SIZE],
+/// A request's header and the status byte the device writes, in cache
+/// lines of their own so that cleaning them can't overwrite the status of
+/// another request in flight
+#[repr(C, align(64))]
+struct BlkRequest {
+    header: VirtioBlkReq,
+    status: u8,
 }
 
-#[repr(C)]
-struct VirtqUsedElem {
-    id: u32,
-    len: u32,
+pub struct VirtioBlk {
+    queue: Arc<Virtqueue>,
+    /// Size of the disk in 512-byte sectors
+    capacity: u64,
+    /// Largest data buffer of a single request
+    size_max: u32,
+    block_size: u32,
+    read_only: bool,
+    can_flush: bool,
 }
 
-#[repr(C)]
-struct VirtqUsed {
-    flags: u16,
-    idx: u16,
-    ring: [VirtqUsedElem; QUEUE_SIZE],
+pub struct VirtioRng {
+    queue: Arc<Virtqueue>,
 }
 
-#[repr(C)]
-struct VirtioBlkReq {
-    req_type: u32,
-    reserved: u32,
-    sector: u64,
+/// A frame buffer, in cache lines of its own
+#[repr(C, align(64))]
+struct NetBuffer([u8; NET_BUFFER_SIZE]);
+
+impl NetBuffer {
+    fn new() -> Box<Self> {
+        Box::new(Self([0; NET_BUFFER_SIZE]))
+    }
+
+    fn addr(&self) -> u64 {
+        self.0.as_ptr() as u64
+    }
 }
 
-pub struct VirtioBlk {
-    notify_addr: usize,
-    desc: *mut VirtqDesc,
-    avail: *mut VirtqAvail,
-    used: *mut VirtqUsed,
-    last_used_idx: u16,
-    req_buf: *mut VirtioBlkReq,
-    status_buf: *mut u8,
-    next_desc_idx: u16,
+/// A network card. Never blocks, so the network stack can drive it with
+/// spin locks held and from interrupt handlers.
+pub struct VirtioNet {
+    rx: Arc<Virtqueue>,
+    tx: Arc<Virtqueue>,
+    mac: [u8; 6],
+    hdr_len: usize,
+    /// Receive buffers the device holds, by head, oldest first
+    rx_posted: VecDeque<(u16, Box<NetBuffer>)>,
+    /// Frames the device may not have sent yet, by head
+    tx_pending: VecDeque<(u16, Box<NetBuffer>)>,
 }
 
-unsafe impl Send for VirtioBlk {}
+/// The host bridge, which `scan_pci` checks for
+fn pci_host() -> &'static PciHost {
+    platform::get().pci.as_ref().expect("No PCI host bridge")
+}
 
 fn pci_config_addr(bus: u8, dev: u8, func: u8, offset: usize) -> usize {
-    PCI_ECAM_BASE
+    pci_host().ecam.base as usize
         + ((bus as usize) << 20)
         + ((dev as usize) << 15)
         + ((func as usize) << 12)
@@ -165,6 +245,10 @@ struct VirtioCaps {
     notify_bar: u8,
     notify_offset: u32,
     notify_off_mult: u32,
+    isr_bar: u8,
+    isr_offset: u32,
+    device_cfg_bar: u8,
+    device_cfg_offset: u32,
 }
 
 fn find_virtio_caps(bus: u8, dev: u8, func: u8) -> Option<VirtioCaps> {
@@ -180,6 +264,10 @@ fn find_virtio_caps(bus: u8, dev: u8, func: u8) -> Option<VirtioCaps> {
         notify_bar: 0,
         notify_offset: 0,
         notify_off_mult: 0,
+        isr_bar: 0,
+        isr_offset: 0,
+        device_cfg_bar: 0,
+        device_cfg_offset: 0,
     };
 
     while cap_ptr != 0 {
@@ -198,6 +286,14 @@ fn find_virtio_caps(bus: u8, dev: u8, func: u8) -> Option<VirtioCaps> {
                     caps.notify_offset = offset;
                     caps.notify_off_mult = pci_read32(bus, dev, func, cap_ptr + 16);
                 }
+                VIRTIO_PCI_CAP_ISR_CFG => {
+                    caps.isr_bar = bar;
+                    caps.isr_offset = offset;
+                }
+                VIRTIO_PCI_CAP_DEVICE_CFG => {
+                    caps.device_cfg_bar = bar;
+                    caps.device_cfg_offset = offset;
+                }
                 _ => {}
             }
         }
@@ -206,7 +302,8 @@ fn find_virtio_caps(bus: u8, dev: u8, func: u8) -> Option<VirtioCaps> {
     Some(caps)
 }
 
-static mut PCI_MMIO_ALLOC_NEXT: u32 = 0x1000_0000;
+/// Next free address in the PCI memory window, 0 before the first BAR
+static mut PCI_MMIO_ALLOC_NEXT: u32 = 0;
 
 fn pci_assign_bar(bus: u8, dev: u8, func: u8, bar_num: u8) -> u32 {
     let bar_offset = PCI_BAR0 + (bar_num as usize) * 4;
@@ -220,220 +317,775 @@ fn pci_assign_bar(bus: u8, dev: u8, func: u8, bar_num: u8) -> u32 {
         return 0;
     }
     let size = !(size_mask & !0xF) + 1;
+    let window = &pci_host().mmio;
+    let window_end = window.base + window.size.min(mmu::PCI_MMIO_MAPPED);
     unsafe {
+        if PCI_MMIO_ALLOC_NEXT == 0 {
+            PCI_MMIO_ALLOC_NEXT = window.base as u32;
+        }
         let aligned = (PCI_MMIO_ALLOC_NEXT + size - 1) & !(size - 1);
+        if aligned as u64 + size as u64 > window_end {
+            kprintln!(
+                "PCI: No room for BAR {} of {}:{}:{}",
+                bar_num,
+                bus,
+                dev,
+                func
+            );
+            return 0;
+        }
         pci_write32(bus, dev, func, bar_offset, aligned);
         PCI_MMIO_ALLOC_NEXT = aligned + size;
         aligned
     }
 }
 
-fn scan_pci() -> Option<(u8, u8, u8, VirtioCaps)> {
-    kprintln!("Virtio: Scanning PCI bus...");
+fn pci_bar(bus: u8, dev: u8, func: u8, bar_num: u8) -> usize {
+    (pci_read32(bus, dev, func, PCI_BAR0 + (bar_num as usize) * 4) & !0xF) as usize
+}
+
+/// Find and enable the first virtio device with one of the given device IDs
+fn scan_pci(name: &str, device_ids: [u16; 2]) -> Option<PciTransport> {
+    let bus = platform::get().pci.as_ref()?.first_bus;
+    kprintln!("Virtio: Scanning PCI bus {}...", bus);
     for dev in 0..32 {
-        let vendor = pci_read16(0, dev, 0, PCI_VENDOR_ID);
+        let vendor = pci_read16(bus, dev, 0, PCI_VENDOR_ID);
         if vendor == 0xFFFF {
             continue;
         }
-        let device = pci_read16(0, dev, 0, PCI_DEVICE_ID);
+        let device = pci_read16(bus, dev, 0, PCI_DEVICE_ID);
         kprintln!(
-            "PCI: Found device {:04x}:{:04x} at 0:{}:0",
+            "PCI: Found device {:04x}:{:04x} at {}:{}:0",
             vendor,
             device,
+            bus,
             dev
         );
-        if vendor == VIRTIO_VENDOR_ID
-            && (device == VIRTIO_BLK_DEVICE_ID_LEGACY || device == VIRTIO_BLK_DEVICE_ID_MODERN)
-        {
-            kprintln!("Virtio: Found blk device, enabling...");
-            let cmd = pci_read16(0, dev, 0, PCI_COMMAND);
-            pci_write16(0, dev, 0, PCI_COMMAND, cmd | 0x06);
-            if let Some(caps) = find_virtio_caps(0, dev, 0) {
-                pci_assign_bar(0, dev, 0, caps.common_cfg_bar);
-                if caps.notify_bar != caps.common_cfg_bar {
-                    pci_assign_bar(0, dev, 0, caps.notify_bar);
+        if vendor == VIRTIO_VENDOR_ID && device_ids.contains(&device) {
+            kprintln!("Virtio: Found {} device, enabling...", name);
+            let cmd = pci_read16(bus, dev, 0, PCI_COMMAND);
+            pci_write16(
+                bus,
+                dev,
+                0,
+                PCI_COMMAND,
+                (cmd | 0x06) & !PCI_COMMAND_INTX_DISABLE,
+            );
+            if let Some(caps) = find_virtio_caps(bus, dev, 0) {
+                let mut assigned = Vec::new();
+                for bar in [
+                    caps.common_cfg_bar,
+                    caps.notify_bar,
+                    caps.isr_bar,
+                    caps.device_cfg_bar,
+                ] {
+                    if !assigned.contains(&bar) {
+                        pci_assign_bar(bus, dev, 0, bar);
+                        assigned.push(bar);
+                    }
                 }
-                return Some((0, dev, 0, caps));
+                return PciTransport::new(bus, dev, 0, &caps);
             }
         }
     }
     None
 }
 
-impl VirtioBlk {
-    pub fn new() -> Option<Self> {
-        let (bus, dev, func, caps) = scan_pci()?;
-        kprintln!("Virtio: Blk device configured, BARs assigned");
-        let bar_offset = PCI_BAR0 + (caps.common_cfg_bar as usize) * 4;
-        let bar = pci_read32(bus, dev, func, bar_offset) & !0xF;
+/// How a driver reaches its device's registers
+trait Transport: Send + Sync {
+    fn status(&self) -> u8;
+    fn set_status(&self, status: u8);
+    /// Word `word` of the features the device offers
+    fn device_features(&self, word: u32) -> u32;
+    /// Accept `features` as word `word` of the features in use
+    fn set_driver_features(&self, word: u32, features: u32);
+    /// Set up and enable queue `index`. None if the device doesn't have it.
+    fn setup_queue(&self, index: u16) -> Option<Arc<Virtqueue>>;
+    /// Address of the device-specific configuration
+    fn device_cfg(&self) -> usize;
+    /// The device's interrupt line, if it has one
+    fn irq(&self) -> Option<u32>;
+    /// Read and acknowledge the reasons for an interrupt, VIRTIO_ISR_* bits
+    fn interrupt_status(&self) -> u8;
+}
+
+/// A modern virtio-pci device, found by `scan_pci`
+struct PciTransport {
+    common_cfg: usize,
+    device_cfg: usize,
+    notify_base: usize,
+    notify_off_mult: usize,
+    /// ISR status register. Reading it acknowledges the interrupt.
+    isr_addr: usize,
+    irq: Option<u32>,
+}
+
+impl PciTransport {
+    fn new(bus: u8, dev: u8, func: u8, caps: &VirtioCaps) -> Option<Self> {
+        let bar = pci_bar(bus, dev, func, caps.common_cfg_bar);
         if bar == 0 {
             return None;
         }
         kprintln!("Virtio: BAR at {:x}", bar);
-        let common_cfg = bar as usize + caps.common_cfg_offset as usize;
-        let notify_cap_base = bar as usize + caps.notify_offset as usize;
-
-        unsafe {
-            write_volatile((common_cfg + VIRTIO_PCI_COMMON_STATUS) as *mut u8, 0);
-            let mut status = VIRTIO_STATUS_ACKNOWLEDGE;
-            write_volatile((common_cfg + VIRTIO_PCI_COMMON_STATUS) as *mut u8, status);
-            status |= VIRTIO_STATUS_DRIVER;
-            write_volatile((common_cfg + VIRTIO_PCI_COMMON_STATUS) as *mut u8, status);
-            kprintln!("Virtio: Status set to DRIVER");
-            write_volatile((common_cfg + VIRTIO_PCI_COMMON_GFSELECT) as *mut u32, 1);
-            write_volatile(
-                (common_cfg + VIRTIO_PCI_COMMON_GF) as *mut u32,
-                VIRTIO_F_VERSION_1,
-            );
-            status |= VIRTIO_STATUS_FEATURES_OK;
-            write_volatile((common_cfg + VIRTIO_PCI_COMMON_STATUS) as *mut u8, status);
-
-            // Check status
-            let check = read_volatile((common_cfg + VIRTIO_PCI_COMMON_STATUS) as *const u8);
-            if (check & VIRTIO_STATUS_FEATURES_OK) == 0 {
-                kprintln!("Virtio: Features NOT OK ({:x})", check);
-                return None;
-            }
-            kprintln!("Virtio: Features OK");
+        let pin = pci_read8(bus, dev, func, PCI_INTERRUPT_PIN);
+        Some(Self {
+            common_cfg: bar + caps.common_cfg_offset as usize,
+            device_cfg: pci_bar(bus, dev, func, caps.device_cfg_bar)
+                + caps.device_cfg_offset as usize,
+            notify_base: pci_bar(bus, dev, func, caps.notify_bar) + caps.notify_offset as usize,
+            notify_off_mult: caps.notify_off_mult as usize,
+            isr_addr: pci_bar(bus, dev, func, caps.isr_bar) + caps.isr_offset as usize,
+            irq: match pin {
+                0 => None,
+                pin => pci_host().intx_irq(bus, dev, func, pin),
+            },
+        })
+    }
+
+    fn read<T>(&self, offset: usize) -> T {
+        unsafe { read_volatile((self.common_cfg + offset) as *const T) }
+    }
+
+    fn write<T>(&self, offset: usize, value: T) {
+        unsafe { write_volatile((self.common_cfg + offset) as *mut T, value) }
+    }
+}
+
+impl Transport for PciTransport {
+    fn status(&self) -> u8 {
+        self.read(VIRTIO_PCI_COMMON_STATUS)
+    }
+
+    fn set_status(&self, status: u8) {
+        self.write(VIRTIO_PCI_COMMON_STATUS, status);
+    }
+
+    fn device_features(&self, word: u32) -> u32 {
+        self.write(VIRTIO_PCI_COMMON_DFSELECT, word);
+        self.read(VIRTIO_PCI_COMMON_DF)
+    }
+
+    fn set_driver_features(&self, word: u32, features: u32) {
+        self.write(VIRTIO_PCI_COMMON_GFSELECT, word);
+        self.write(VIRTIO_PCI_COMMON_GF, features);
+    }
+
+    fn setup_queue(&self, index: u16) -> Option<Arc<Virtqueue>> {
+        if index >= self.read::<u16>(VIRTIO_PCI_COMMON_NUM_QUEUES) {
+            return None;
+        }
+        self.write(VIRTIO_PCI_COMMON_Q_SELECT, index);
+        self.write(VIRTIO_PCI_COMMON_Q_SIZE, QUEUE_SIZE as u16);
+        let notify_off = self.read::<u16>(VIRTIO_PCI_COMMON_Q_NOTIFY_OFF) as usize;
+        let notify_addr = self.notify_base + notify_off * self.notify_off_mult;
+
+        let queue = Arc::new(Virtqueue::new(index, Notifier::Register16(notify_addr)));
+        let (desc, avail, used) = queue.ring_addrs();
+        self.write(VIRTIO_PCI_COMMON_Q_DESCLO, desc as u32);
+        self.write(VIRTIO_PCI_COMMON_Q_AVAILLO, avail as u32);
+        self.write(VIRTIO_PCI_COMMON_Q_USEDLO, used as u32);
+        self.write(VIRTIO_PCI_COMMON_Q_ENABLE, 1u16);
+        Some(queue)
+    }
+
+    fn device_cfg(&self) -> usize {
+        self.device_cfg
+    }
+
+    fn irq(&self) -> Option<u32> {
+        self.irq
+    }
+
+    fn interrupt_status(&self) -> u8 {
+        unsafe { read_volatile(self.isr_addr as *const u8) }
+    }
+}
+
+/// A device in a virtio-mmio slot, found by `scan_mmio`
+struct MmioTransport {
+    base: usize,
+    /// Version 1 of the interface, from before virtio 1.0
+    legacy: bool,
+    irq: Option<u32>,
+}
+
+impl MmioTransport {
+    fn read(&self, offset: usize) -> u32 {
+        unsafe { read_volatile((self.base + offset) as *const u32) }
+    }
 
-            write_volatile((common_cfg + VIRTIO_PCI_COMMON_Q_SELECT) as *mut u16, 0);
-            write_volatile(
-                (common_cfg + VIRTIO_PCI_COMMON_Q_SIZE) as *mut u16,
-                QUEUE_SIZE as u16,
+    fn write(&self, offset: usize, value: u32) {
+        unsafe { write_volatile((self.base + offset) as *mut u32, value) }
+    }
+
+    /// Write a 64-bit address to a pair of registers
+    fn write_addr(&self, low: usize, high: usize, addr: u64) {
+        self.write(low, addr as u32);
+        self.write(high, (addr >> 32) as u32);
+    }
+}
+
+impl Transport for MmioTransport {
+    fn status(&self) -> u8 {
+        self.read(VIRTIO_MMIO_STATUS) as u8
+    }
+
+    fn set_status(&self, status: u8) {
+        self.write(VIRTIO_MMIO_STATUS, status as u32);
+    }
+
+    fn device_features(&self, word: u32) -> u32 {
+        self.write(VIRTIO_MMIO_DEVICE_FEATURES_SEL, word);
+        self.read(VIRTIO_MMIO_DEVICE_FEATURES)
+    }
+
+    fn set_driver_features(&self, word: u32, features: u32) {
+        // Legacy devices only have the first word
+        if self.legacy && word != 0 {
+            return;
+        }
+        self.write(VIRTIO_MMIO_DRIVER_FEATURES_SEL, word);
+        self.write(VIRTIO_MMIO_DRIVER_FEATURES, features);
+    }
+
+    fn setup_queue(&self, index: u16) -> Option<Arc<Virtqueue>> {
+        self.write(VIRTIO_MMIO_QUEUE_SEL, index as u32);
+        let max = self.read(VIRTIO_MMIO_QUEUE_NUM_MAX);
+        if max < QUEUE_SIZE as u32 {
+            return None;
+        }
+        self.write(VIRTIO_MMIO_QUEUE_NUM, QUEUE_SIZE as u32);
+
+        let notify_addr = self.base + VIRTIO_MMIO_QUEUE_NOTIFY;
+        let queue = Arc::new(Virtqueue::new(index, Notifier::Register32(notify_addr)));
+        let (desc, avail, used) = queue.ring_addrs();
+        if self.legacy {
+            // The rest of the rings follow the descriptor table
+            self.write(VIRTIO_MMIO_GUEST_PAGE_SIZE, VIRTIO_MMIO_PAGE_SIZE);
+            self.write(VIRTIO_MMIO_QUEUE_ALIGN, LEGACY_QUEUE_ALIGN as u32);
+            self.write(
+                VIRTIO_MMIO_QUEUE_PFN,
+                (desc / VIRTIO_MMIO_PAGE_SIZE as u64) as u32,
             );
-            let queue_notify_off =
-                read_volatile((common_cfg + VIRTIO_PCI_COMMON_Q_NOTIFY_OFF) as *const u16);
-            let notify_addr =
-                notify_cap_base + (queue_notify_off as usize) * (caps.notify_off_mult as usize);
-
-            let desc_size = core::mem::size_of::<VirtqDesc>() * QUEUE_SIZE;
-            let avail_size = core::mem::size_of::<VirtqAvail>();
-            let used_size = core::mem::size_of::<VirtqUsed>();
-            let total = desc_size + avail_size + used_size + 4096;
-            let queue_mem: Vec<u8> = alloc::vec![0u8; total + 4096];
-            let ptr = queue_mem.as_ptr() as usize;
-            let aligned = (ptr + 4095) & !4095;
-            core::mem::forget(queue_mem);
-
-            let desc = aligned as *mut VirtqDesc;
-            let avail = (aligned + desc_size) as *mut VirtqAvail;
-            let used = (aligned + desc_size + avail_size) as *mut VirtqUsed;
-
-            write_volatile(
-                (common_cfg + VIRTIO_PCI_COMMON_Q_DESCLO) as *mut u32,
-                aligned as u32,
+        } else {
+            self.write_addr(
+                VIRTIO_MMIO_QUEUE_DESC_LOW,
+                VIRTIO_MMIO_QUEUE_DESC_HIGH,
+                desc,
             );
-            write_volatile(
-                (common_cfg + VIRTIO_PCI_COMMON_Q_AVAILLO) as *mut u32,
-                (aligned + desc_size) as u32,
+            self.write_addr(
+                VIRTIO_MMIO_QUEUE_DRIVER_LOW,
+                VIRTIO_MMIO_QUEUE_DRIVER_HIGH,
+                avail,
             );
-            write_volatile(
-                (common_cfg + VIRTIO_PCI_COMMON_Q_USEDLO) as *mut u32,
-                (aligned + desc_size + avail_size) as u32,
+            self.write_addr(
+                VIRTIO_MMIO_QUEUE_DEVICE_LOW,
+                VIRTIO_MMIO_QUEUE_DEVICE_HIGH,
+                used,
             );
-            write_volatile((common_cfg + VIRTIO_PCI_COMMON_Q_ENABLE) as *mut u16, 1);
-            status |= VIRTIO_STATUS_DRIVER_OK;
-            write_volatile((common_cfg + VIRTIO_PCI_COMMON_STATUS) as *mut u8, status);
+            self.write(VIRTIO_MMIO_QUEUE_READY, 1);
+        }
+        Some(queue)
+    }
 
-            let req_layout =
-                alloc::alloc::Layout::from_size_align(core::mem::size_of::<VirtioBlkReq>(), 16)
-                    .unwrap();
-            let req_buf = alloc::alloc::alloc(req_layout) as *mut VirtioBlkReq;
-            let status_layout = alloc::alloc::Layout::from_size_align(4, 4).unwrap();
-            let status_buf = alloc::alloc::alloc(status_layout);
+    fn device_cfg(&self) -> usize {
+        self.base + VIRTIO_MMIO_CONFIG
+    }
 
-            kprintln!("Virtio: Blk device ready");
+    fn irq(&self) -> Option<u32> {
+        self.irq
+    }
 
-            Some(Self {
-                notify_addr,
-                desc,
-                avail,
-                used,
-                last_used_idx: 0,
-                req_buf,
-                status_buf,
-                next_desc_idx: 0,
-            })
+    fn interrupt_status(&self) -> u8 {
+        let status = self.read(VIRTIO_MMIO_INTERRUPT_STATUS);
+        self.write(VIRTIO_MMIO_INTERRUPT_ACK, status);
+        status as u8
+    }
+}
+
+/// Find the first device with ID `device_id` in the virtio-mmio slots
+fn scan_mmio(name: &str, device_id: u32) -> Option<MmioTransport> {
+    let slots = platform::get().virtio_mmio();
+    if slots.is_empty() {
+        return None;
+    }
+    kprintln!("Virtio: Scanning {} MMIO slots...", slots.len());
+    for slot in slots {
+        let transport = MmioTransport {
+            base: slot.region.base as usize,
+            legacy: false,
+            irq: (slot.irq != 0).then_some(slot.irq),
+        };
+        if transport.read(VIRTIO_MMIO_MAGIC) != VIRTIO_MMIO_MAGIC_VALUE
+            || transport.read(VIRTIO_MMIO_DEVICE_ID) != device_id
+        {
+            continue;
         }
+        let version = transport.read(VIRTIO_MMIO_VERSION);
+        if version != VIRTIO_MMIO_VERSION_LEGACY && version != VIRTIO_MMIO_VERSION_MODERN {
+            kprintln!(
+                "Virtio: Unknown MMIO version {} at {:x}",
+                version,
+                transport.base
+            );
+            continue;
+        }
+        kprintln!(
+            "Virtio: Found {} device at {:x}, version {}",
+            name,
+            transport.base,
+            version
+        );
+        return Some(MmioTransport {
+            legacy: version == VIRTIO_MMIO_VERSION_LEGACY,
+            ..transport
+        });
     }
+    None
+}
 
-    fn allocate_desc(&mut self) -> u16 {
-        let idx = self.next_desc_idx;
-        self.next_desc_idx = (self.next_desc_idx + 1) % QUEUE_SIZE as u16;
-        idx
+/// The first device of a kind, on PCI or else virtio-mmio
+fn find_device(name: &str, pci_ids: [u16; 2], device_id: u32) -> Option<Box<dyn Transport>> {
+    if let Some(transport) = scan_pci(name, pci_ids) {
+        return Some(Box::new(transport));
     }
+    scan_mmio(name, device_id).map(|transport| Box::new(transport) as Box<dyn Transport>)
+}
 
-    pub fn read_sectors(&mut self, sector: u64, buf: &mut [u8]) -> bool {
-        // kprintln!("Virtio: read_sectors sector={} len={}", sector, buf.len());
-        if !buf.len().is_multiple_of(512) {
-            return false;
+/// A device's interrupt line and the queues to service on it
+struct DeviceIrq {
+    irq: u32,
+    transport: Box<dyn Transport>,
+    queues: Vec<Arc<Virtqueue>>,
+    /// Called once the queues' used buffers are collected
+    on_interrupt: Option<fn()>,
+}
+
+static DEVICE_IRQS: Mutex<Vec<DeviceIrq>> = Mutex::new(Vec::new());
+
+/// Service every device on the line that has used buffers. PCI devices
+/// share INTx lines.
+fn handle_irq(irq: u32) {
+    let devices = DEVICE_IRQS.lock();
+    for device in devices.iter().filter(|device| device.irq == irq) {
+        if device.transport.interrupt_status() & VIRTIO_ISR_QUEUE != 0 {
+            for queue in &device.queues {
+                queue.handle_interrupt();
+            }
+            if let Some(on_interrupt) = device.on_interrupt {
+                on_interrupt();
+            }
         }
-        let req = VirtioBlkReq {
-            req_type: VIRTIO_BLK_T_IN,
-            reserved: 0,
-            sector,
+    }
+}
+
+/// A device set up by `setup_device`
+struct Device {
+    queues: Vec<Arc<Virtqueue>>,
+    /// Address of the device-specific configuration
+    device_cfg: usize,
+    /// Accepted device-specific features
+    features: u32,
+    /// VIRTIO_F_VERSION_1 was accepted
+    modern: bool,
+}
+
+/// Negotiate features and set up the first `num_queues` queues of a device
+/// found by `find_device`, with its interrupt routed to `handle_irq`.
+/// `wanted` are the device-specific features, in the first word of
+/// features, to accept if offered.
+fn setup_device(
+    transport: Box<dyn Transport>,
+    wanted: u32,
+    num_queues: u16,
+    on_interrupt: Option<fn()>,
+) -> Option<Device> {
+    transport.set_status(0);
+    let mut status = VIRTIO_STATUS_ACKNOWLEDGE;
+    transport.set_status(status);
+    status |= VIRTIO_STATUS_DRIVER;
+    transport.set_status(status);
+    kprintln!("Virtio: Status set to DRIVER");
+    let features = transport.device_features(0) & wanted;
+    transport.set_driver_features(0, features);
+    let version_1 = transport.device_features(1) & VIRTIO_F_VERSION_1;
+    transport.set_driver_features(1, version_1);
+    status |= VIRTIO_STATUS_FEATURES_OK;
+    transport.set_status(status);
+
+    // Check status
+    let check = transport.status();
+    if (check & VIRTIO_STATUS_FEATURES_OK) == 0 {
+        kprintln!("Virtio: Features NOT OK ({:x})", check);
+        return None;
+    }
+    kprintln!("Virtio: Features OK");
+
+    let mut queues = Vec::new();
+    for index in 0..num_queues {
+        let Some(queue) = transport.setup_queue(index) else {
+            kprintln!("Virtio: Device has no usable queue {}", index);
+            return None;
         };
-        unsafe {
-            core::ptr::write(self.req_buf, req);
-            *self.status_buf = 0xFF;
-            let h = self.allocate_desc();
-            let d = self.allocate_desc();
-            let s = self.allocate_desc();
-            (*self.desc.add(h as usize)) = VirtqDesc {
-                addr: self.req_buf as u64,
-                len: core::mem::size_of::<VirtioBlkReq>() as u32,
-                flags: VIRTQ_DESC_F_NEXT,
-                next: d,
-            };
-            (*self.desc.add(d as usize)) = VirtqDesc {
-                addr: buf.as_ptr() as u64,
-                len: buf.len() as u32,
-                flags: VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE,
-                next: s,
-            };
-            (*self.desc.add(s as usize)) = VirtqDesc {
-                addr: self.status_buf as u64,
-                len: 1,
-                flags: VIRTQ_DESC_F_WRITE,
-                next: 0,
-            };
-            cache_clean_range(self.req_buf as usize, 16);
-            cache_clean_range(self.desc as usize, 512);
-            cache_invalidate_range(buf.as_ptr() as usize, buf.len());
-            let a_idx = (*self.avail).idx;
-            (*self.avail).ring[(a_idx % QUEUE_SIZE as u16) as usize] = h;
-            core::sync::atomic::fence(core::sync::atomic::Ordering::SeqCst);
-            (*self.avail).idx = a_idx.wrapping_add(1);
-            core::sync::atomic::fence(core::sync::atomic::Ordering::SeqCst);
-            write_volatile(self.notify_addr as *mut u16, 0);
-            let mut count = 0;
-            while (*self.used).idx == self.last_used_idx {
-                core::sync::atomic::fence(core::sync::atomic::Ordering::SeqCst);
-                cache_invalidate_range(core::ptr::addr_of!((*self.used).idx) as usize, 2);
-                count += 1;
-                if count > 1000000 {
-                    return false;
+        queues.push(queue);
+    }
+    status |= VIRTIO_STATUS_DRIVER_OK;
+    transport.set_status(status);
+
+    let device_cfg = transport.device_cfg();
+    if let Some(irq) = transport.irq() {
+        DEVICE_IRQS.lock().push(DeviceIrq {
+            irq,
+            transport,
+            queues: queues.clone(),
+            on_interrupt,
+        });
+        crate::irq::register_handler(irq, handle_irq);
+        for queue in &queues {
+            queue.enable_interrupts();
+        }
+        kprintln!("Virtio: Interrupts on IRQ {}", irq);
+    }
+
+    Some(Device {
+        queues,
+        device_cfg,
+        features,
+        modern: version_1 != 0,
+    })
+}
+
+impl VirtioBlk {
+    pub fn new() -> Option<Self> {
+        let transport = find_device(
+            "blk",
+            [VIRTIO_BLK_DEVICE_ID_LEGACY, VIRTIO_BLK_DEVICE_ID_MODERN],
+            VIRTIO_ID_BLOCK,
+        )?;
+        let wanted =
+            VIRTIO_BLK_F_SIZE_MAX | VIRTIO_BLK_F_RO | VIRTIO_BLK_F_BLK_SIZE | VIRTIO_BLK_F_FLUSH;
+        let mut device = setup_device(transport, wanted, 1, None)?;
+        let features = device.features;
+
+        let config = |offset: usize| device.device_cfg + offset;
+        let (capacity, size_max, block_size) = unsafe {
+            (
+                // In halves, as virtio-mmio registers are at most 32 bits
+                read_volatile(config(VIRTIO_BLK_CFG_CAPACITY) as *const u32) as u64
+                    | (read_volatile(config(VIRTIO_BLK_CFG_CAPACITY + 4) as *const u32) as u64)
+                        << 32,
+                read_volatile(config(VIRTIO_BLK_CFG_SIZE_MAX) as *const u32),
+                read_volatile(config(VIRTIO_BLK_CFG_BLK_SIZE) as *const u32),
+            )
+        };
+        // A buffer never spans more than a request's data, so without a
+        // limit only the descriptor's length field bounds it
+        let size_max = if features & VIRTIO_BLK_F_SIZE_MAX != 0 {
+            size_max
+        } else {
+            u32::MAX
+        };
+        let blk = Self {
+            queue: device.queues.remove(0),
+            capacity,
+            size_max: size_max & !(SECTOR_SIZE as u32 - 1),
+            block_size: if features & VIRTIO_BLK_F_BLK_SIZE != 0 {
+                block_size
+            } else {
+                SECTOR_SIZE as u32
+            },
+            read_only: features & VIRTIO_BLK_F_RO != 0,
+            can_flush: features & VIRTIO_BLK_F_FLUSH != 0,
+        };
+        kprintln!(
+            "Virtio: Blk device ready, {} sectors, block size {}{}",
+            capacity,
+            blk.block_size,
+            if blk.read_only { ", read-only" } else { "" }
+        );
+        Some(blk)
+    }
+
+    /// Size of the disk in bytes
+    pub fn size(&self) -> u64 {
+        self.capacity * SECTOR_SIZE
+    }
+
+    /// Descriptor chain of `request`, with the data buffer at `data`, if any
+    fn chain(request: &mut BlkRequest, data: Option<(u64, u32)>) -> Vec<Buffer> {
+        request.status = 0xFF;
+        let request_addr = request as *mut BlkRequest as u64;
+        cache_clean_range(request_addr as usize, size_of::<BlkRequest>());
+
+        let mut bufs = Vec::with_capacity(3);
+        bufs.push(Buffer {
+            addr: request_addr,
+            len: size_of::<VirtioBlkReq>() as u32,
+            device_writes: false,
+        });
+        if let Some((addr, len)) = data {
+            let device_writes = request.header.req_type == VIRTIO_BLK_T_IN;
+            if device_writes {
+                cache_invalidate_range(addr as usize, len as usize);
+            } else {
+                cache_clean_range(addr as usize, len as usize);
+            }
+            bufs.push(Buffer {
+                addr,
+                len,
+                device_writes,
+            });
+        }
+        bufs.push(Buffer {
+            addr: &request.status as *const u8 as u64,
+            len: 1,
+            device_writes: true,
+        });
+        bufs
+    }
+
+    /// Wait for `request`, queued at `head`. True if it succeeded.
+    fn finish(&self, head: u16, request: &BlkRequest) -> bool {
+        self.queue.wait(head);
+        let status = &request.status;
+        cache_invalidate_range(status as *const u8 as usize, 1);
+        unsafe { read_volatile(status) == VIRTIO_BLK_S_OK }
+    }
+
+    /// Transfer `len` bytes at `addr` from or to the disk at `sector`,
+    /// split into requests of at most `size_max` that are in flight
+    /// together as far as the queue allows
+    fn transfer(&self, req_type: u32, sector: u64, addr: u64, len: usize) -> bool {
+        if !(len as u64).is_multiple_of(SECTOR_SIZE) {
+            return false;
+        }
+        let chunk_max = (self.size_max as usize).max(SECTOR_SIZE as usize);
+        let mut in_flight: Vec<(u16, Box<BlkRequest>)> = Vec::new();
+        let mut ok = true;
+        let mut done = 0;
+        while done < len {
+            let chunk = (len - done).min(chunk_max);
+            let mut request = Box::new(BlkRequest {
+                header: VirtioBlkReq {
+                    req_type,
+                    reserved: 0,
+                    sector: sector + done as u64 / SECTOR_SIZE,
+                },
+                status: 0xFF,
+            });
+            let chain = Self::chain(&mut request, Some((addr + done as u64, chunk as u32)));
+            let head = match unsafe { self.queue.try_add(&chain) } {
+                Some(head) => head,
+                None if !in_flight.is_empty() => {
+                    // Queue full: let the oldest of ours finish
+                    self.queue.notify();
+                    let (head, request) = in_flight.remove(0);
+                    ok &= self.finish(head, &request);
+                    continue;
                 }
+                None => unsafe { self.queue.add(&chain) },
+            };
+            in_flight.push((head, request));
+            done += chunk;
+        }
+        self.queue.notify();
+        for (head, request) in in_flight {
+            ok &= self.finish(head, &request);
+        }
+        if req_type == VIRTIO_BLK_T_IN {
+            cache_invalidate_range(addr as usize, len);
+        }
+        ok
+    }
+
+    pub fn read_sectors(&self, sector: u64, buf: &mut [u8]) -> bool {
+        self.transfer(VIRTIO_BLK_T_IN, sector, buf.as_mut_ptr() as u64, buf.len())
+    }
+
+    pub fn write_sectors(&self, sector: u64, buf: &[u8]) -> bool {
+        if self.read_only {
+            return false;
+        }
+        self.transfer(VIRTIO_BLK_T_OUT, sector, buf.as_ptr() as u64, buf.len())
+    }
+
+    /// Make completed writes durable. Without VIRTIO_BLK_F_FLUSH the device
+    /// writes through, so there is nothing to do.
+    pub fn flush(&self) -> bool {
+        if !self.can_flush {
+            return true;
+        }
+        let mut request = Box::new(BlkRequest {
+            header: VirtioBlkReq {
+                req_type: VIRTIO_BLK_T_FLUSH,
+                reserved: 0,
+                sector: 0,
+            },
+            status: 0xFF,
+        });
+        let chain = Self::chain(&mut request, None);
+        let head = unsafe { self.queue.add(&chain) };
+        self.queue.notify();
+        self.finish(head, &request)
+    }
+}
+
+impl VirtioRng {
+    pub fn new() -> Option<Self> {
+        let transport = find_device(
+            "rng",
+            [VIRTIO_RNG_DEVICE_ID_LEGACY, VIRTIO_RNG_DEVICE_ID_MODERN],
+            VIRTIO_ID_RNG,
+        )?;
+        let mut device = setup_device(transport, 0, 1, None)?;
+        kprintln!("Virtio: Rng device ready");
+        Some(Self {
+            queue: device.queues.remove(0),
+        })
+    }
+
+    /// Fill the start of `buf` with entropy. Returns the number of bytes the
+    /// device provided, which may be less than asked for. Polls, since the
+    /// entropy pool is locked.
+    pub fn read(&mut self, buf: &mut [u8]) -> usize {
+        cache_invalidate_range(buf.as_ptr() as usize, buf.len());
+        let chain = [Buffer {
+            addr: buf.as_ptr() as u64,
+            len: buf.len() as u32,
+            device_writes: true,
+        }];
+        let head = unsafe { self.queue.add(&chain) };
+        self.queue.notify();
+        let len = self.queue.poll_wait(head) as usize;
+        cache_invalidate_range(buf.as_ptr() as usize, buf.len());
+        len.min(buf.len())
+    }
+}
+
+impl VirtioNet {
+    /// Set up the first network card, with `on_interrupt` called when it
+    /// has received frames
+    pub fn new(on_interrupt: fn()) -> Option<Self> {
+        let transport = find_device(
+            "net",
+            [VIRTIO_NET_DEVICE_ID_LEGACY, VIRTIO_NET_DEVICE_ID_MODERN],
+            VIRTIO_ID_NET,
+        )?;
+        let mut device = setup_device(transport, VIRTIO_NET_F_MAC, 2, Some(on_interrupt))?;
+        let mut mac = [0u8; 6];
+        if device.features & VIRTIO_NET_F_MAC != 0 {
+            for (i, byte) in mac.iter_mut().enumerate() {
+                let addr = device.device_cfg + VIRTIO_NET_CFG_MAC + i;
+                *byte = unsafe { read_volatile(addr as *const u8) };
+            }
+        } else {
+            // A random, locally administered unicast address
+            crate::entropy::fill(&mut mac);
+            mac[0] = (mac[0] & !0x01) | 0x02;
+        }
+        let tx = device.queues.remove(VIRTIO_NET_TX_QUEUE);
+        let rx = device.queues.remove(VIRTIO_NET_RX_QUEUE);
+        let mut net = Self {
+            rx,
+            tx,
+            mac,
+            hdr_len: if device.modern {
+                VIRTIO_NET_HDR_LEN
+            } else {
+                VIRTIO_NET_HDR_LEN_LEGACY
+            },
+            rx_posted: VecDeque::new(),
+            tx_pending: VecDeque::new(),
+        };
+        for _ in 0..QUEUE_SIZE {
+            net.post_rx(NetBuffer::new());
+        }
+        net.rx.notify();
+        kprintln!(
+            "Virtio: Net device ready, MAC {:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
+            mac[0],
+            mac[1],
+            mac[2],
+            mac[3],
+            mac[4],
+            mac[5]
+        );
+        Some(net)
+    }
+
+    pub fn mac(&self) -> [u8; 6] {
+        self.mac
+    }
+
+    /// Give the device a buffer to receive into
+    fn post_rx(&mut self, buffer: Box<NetBuffer>) {
+        cache_invalidate_range(buffer.addr() as usize, NET_BUFFER_SIZE);
+        let chain = [Buffer {
+            addr: buffer.addr(),
+            len: NET_BUFFER_SIZE as u32,
+            device_writes: true,
+        }];
+        // There are never more buffers than descriptors
+        if let Some(head) = unsafe { self.rx.try_add(&chain) } {
+            self.rx_posted.push_back((head, buffer));
+        }
+    }
+
+    /// The oldest frame received, without the virtio header
+    pub fn take_frame(&mut self) -> Option<Vec<u8>> {
+        let (index, len) = (0..self.rx_posted.len())
+            .find_map(|i| Some((i, self.rx.try_take(self.rx_posted[i].0)? as usize)))?;
+        let (_, buffer) = self.rx_posted.remove(index)?;
+        let len = len.clamp(self.hdr_len, NET_BUFFER_SIZE);
+        cache_invalidate_range(buffer.addr() as usize, len);
+        let frame = buffer.0[self.hdr_len..len].to_vec();
+        self.post_rx(buffer);
+        self.rx.notify();
+        Some(frame)
+    }
+
+    /// Whether there is room to queue a frame
+    pub fn can_send(&mut self) -> bool {
+        let tx = &self.tx;
+        self.tx_pending
+            .retain(|&(head, _)| tx.try_take(head).is_none());
+        self.tx_pending.len() < QUEUE_SIZE
+    }
+
+    /// Send a frame of `len` bytes, which `fill` writes. A frame that
+    /// doesn't fit in the queue is dropped, as on a busy wire.
+    pub fn send_frame<R>(&mut self, len: usize, fill: impl FnOnce(&mut [u8]) -> R) -> R {
+        let len = len.min(ETHERNET_FRAME_MAX);
+        let mut buffer = NetBuffer::new();
+        let result = fill(&mut buffer.0[self.hdr_len..self.hdr_len + len]);
+        let total = self.hdr_len + len;
+        cache_clean_range(buffer.addr() as usize, total);
+        let chain = [Buffer {
+            addr: buffer.addr(),
+            len: total as u32,
+            device_writes: false,
+        }];
+        match unsafe { self.tx.try_add(&chain) } {
+            Some(head) => {
+                self.tx_pending.push_back((head, buffer));
+                self.tx.notify();
             }
-            self.last_used_idx = (*self.used).idx;
-            cache_invalidate_range(buf.as_ptr() as usize, buf.len());
-            // kprintln!("Virtio: read_sectors complete");
-            *self.status_buf == 0
+            None => kprintln!("Virtio: Net transmit queue full, frame dropped"),
         }
+        result
     }
 }
 
-impl BlockReader for Mutex<VirtioBlk> {
+impl BlockReader for VirtioBlk {
     fn read_at(&self, offset: u64, buf: &mut [u8]) -> bool {
-        let mut blk = self.lock();
-        let start_sector = offset / 512;
-        if offset.is_multiple_of(512) && buf.len().is_multiple_of(512) {
-            return blk.read_sectors(start_sector, buf);
+        let start_sector = offset / SECTOR_SIZE;
+        if offset.is_multiple_of(SECTOR_SIZE) && (buf.len() as u64).is_multiple_of(SECTOR_SIZE) {
+            return self.read_sectors(start_sector, buf);
         }
-        let end_sector = (offset + buf.len() as u64).div_ceil(512);
-        let mut temp = vec![0u8; ((end_sector - start_sector) * 512) as usize];
-        if blk.read_sectors(start_sector, &mut temp) {
-            let off = (offset % 512) as usize;
+        let end_sector = (offset + buf.len() as u64).div_ceil(SECTOR_SIZE);
+        let mut temp = vec![0u8; ((end_sector - start_sector) * SECTOR_SIZE) as usize];
+        if self.read_sectors(start_sector, &mut temp) {
+            let off = (offset % SECTOR_SIZE) as usize;
             buf.copy_from_slice(&temp[off..off + buf.len()]);
             true
         } else {
@@ -442,6 +1094,44 @@ impl BlockReader for Mutex<VirtioBlk> {
     }
 }
 
+impl BlockDevice for VirtioBlk {
+    fn write_at(&self, offset: u64, buf: &[u8]) -> bool {
+        let start_sector = offset / SECTOR_SIZE;
+        if offset.is_multiple_of(SECTOR_SIZE) && (buf.len() as u64).is_multiple_of(SECTOR_SIZE) {
+            return self.write_sectors(start_sector, buf);
+        }
+        // Read-modify-write of the partial sectors at either end. Callers
+        // writing the same sectors concurrently must serialize themselves.
+        let end_sector = (offset + buf.len() as u64).div_ceil(SECTOR_SIZE);
+        let mut temp = vec![0u8; ((end_sector - start_sector) * SECTOR_SIZE) as usize];
+        let last = temp.len() - SECTOR_SIZE as usize;
+        let edges_read = self.read_sectors(start_sector, &mut temp[..SECTOR_SIZE as usize])
+            && self.read_sectors(end_sector - 1, &mut temp[last..]);
+        if !edges_read {
+            return false;
+        }
+        let off = (offset % SECTOR_SIZE) as usize;
+        temp[off..off + buf.len()].copy_from_slice(buf);
+        self.write_sectors(start_sector, &temp)
+    }
+
+    fn flush(&self) -> bool {
+        VirtioBlk::flush(self)
+    }
+
+    fn size(&self) -> u64 {
+        VirtioBlk::size(self)
+    }
+
+    fn block_size(&self) -> u32 {
+        self.block_size
+    }
+
+    fn read_only(&self) -> bool {
+        self.read_only
+    }
+}
+
 pub fn init() -> Option<VirtioBlk> {
     VirtioBlk::new()
 }
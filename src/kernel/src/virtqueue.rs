@@ -0,0 +1,355 @@
+//! Virtqueues, the rings virtio drivers share with their devices
+//!
+//! Drivers add chains of buffers and wait for the device to use them. The
+//! device's interrupt handler collects used chains and wakes the processes
+//! waiting for them. Callers that can't block, during boot or with a spin
+//! lock held, poll the used ring instead, as do all callers of a queue whose
+//! device has no interrupt.
+
+use crate::scheduler;
+use crate::waitqueue::WaitQueue;
+use alloc::vec::Vec;
+use core::arch::asm;
+use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{AtomicBool, Ordering, fence};
+use spin::Mutex;
+
+pub const QUEUE_SIZE: usize = 16;
+
+/// Alignment of the used ring that legacy devices, which are only told
+/// where the descriptor table is, expect
+pub const LEGACY_QUEUE_ALIGN: usize = 4096;
+
+const VIRTQ_DESC_F_NEXT: u16 = 1;
+const VIRTQ_DESC_F_WRITE: u16 = 2;
+
+/// Clean cache range for DMA
+pub fn cache_clean_range(addr: usize, len: usize) {
+    let mut curr = addr & !63;
+    let end = addr + len;
+    while curr < end {
+        unsafe {
+            asm!("dc cvac, {0}", in(reg) curr);
+        }
+        curr += 64;
+    }
+    unsafe {
+        asm!("dsb sy");
+    }
+}
+
+pub fn cache_invalidate_range(addr: usize, len: usize) {
+    let mut curr = addr & !63;
+    let end = addr + len;
+    while curr < end {
+        unsafe {
+            asm!("dc ivac, {0}", in(reg) curr);
+        }
+        curr += 64;
+    }
+    unsafe {
+        asm!("dsb sy");
+    }
+}
+
+#[repr(C)]
+struct VirtqDesc {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+#[repr(C)]
+struct VirtqAvail {
+    flags: u16,
+    idx: u16,
+    ring: [u16; QUEUE_SIZE],
+}
+
+#[repr(C)]
+struct VirtqUsedElem {
+    id: u32,
+    len: u32,
+}
+
+#[repr(C)]
+struct VirtqUsed {
+    flags: u16,
+    idx: u16,
+    ring: [VirtqUsedElem; QUEUE_SIZE],
+}
+
+/// A buffer in a descriptor chain
+pub struct Buffer {
+    pub addr: u64,
+    pub len: u32,
+    /// The device writes the buffer rather than reading it
+    pub device_writes: bool,
+}
+
+/// The register a queue's notifications are written to, with the queue's
+/// index
+#[derive(Clone, Copy)]
+pub enum Notifier {
+    /// 16 bits wide, as virtio-pci has
+    Register16(usize),
+    /// 32 bits wide, as virtio-mmio has
+    Register32(usize),
+}
+
+/// The rings, and which chains are outstanding
+struct Ring {
+    desc: *mut VirtqDesc,
+    avail: *mut VirtqAvail,
+    used: *mut VirtqUsed,
+    last_used_idx: u16,
+    /// First free descriptor. Free descriptors are chained through `next`.
+    free_head: u16,
+    num_free: u16,
+    /// Bytes written by the device for used chains nobody has taken yet,
+    /// by head. Their descriptors are freed when they are taken.
+    done: [Option<u32>; QUEUE_SIZE],
+}
+
+// The rings are only touched with the queue locked
+unsafe impl Send for Ring {}
+
+impl Ring {
+    fn add(&mut self, bufs: &[Buffer]) -> Option<u16> {
+        if bufs.len() > self.num_free as usize {
+            return None;
+        }
+        let head = self.free_head;
+        unsafe {
+            let mut idx = head;
+            for (i, buf) in bufs.iter().enumerate() {
+                let desc = &mut *self.desc.add(idx as usize);
+                let next = desc.next;
+                desc.addr = buf.addr;
+                desc.len = buf.len;
+                desc.flags = if buf.device_writes {
+                    VIRTQ_DESC_F_WRITE
+                } else {
+                    0
+                };
+                if i + 1 < bufs.len() {
+                    desc.flags |= VIRTQ_DESC_F_NEXT;
+                }
+                idx = next;
+            }
+            self.free_head = idx;
+            self.num_free -= bufs.len() as u16;
+            cache_clean_range(self.desc as usize, size_of::<VirtqDesc>() * QUEUE_SIZE);
+
+            let a_idx = (*self.avail).idx;
+            (*self.avail).ring[(a_idx % QUEUE_SIZE as u16) as usize] = head;
+            fence(Ordering::SeqCst);
+            (*self.avail).idx = a_idx.wrapping_add(1);
+            cache_clean_range(self.avail as usize, size_of::<VirtqAvail>());
+        }
+        Some(head)
+    }
+
+    /// Move chains the device has used to `done`. Returns how many there
+    /// were.
+    fn collect(&mut self) -> usize {
+        let mut count = 0;
+        unsafe {
+            let used_idx = core::ptr::addr_of!((*self.used).idx);
+            loop {
+                cache_invalidate_range(used_idx as usize, 2);
+                if read_volatile(used_idx) == self.last_used_idx {
+                    return count;
+                }
+                fence(Ordering::SeqCst);
+                let elem = &(*self.used).ring[(self.last_used_idx % QUEUE_SIZE as u16) as usize];
+                cache_invalidate_range(elem as *const VirtqUsedElem as usize, 8);
+                let head = read_volatile(&elem.id) as usize;
+                self.done[head] = Some(read_volatile(&elem.len));
+                self.last_used_idx = self.last_used_idx.wrapping_add(1);
+                count += 1;
+            }
+        }
+    }
+
+    /// Take the result of the used chain at `head` and free its descriptors
+    fn take(&mut self, head: u16) -> Option<u32> {
+        let len = self.done[head as usize].take()?;
+        let mut idx = head;
+        let mut count = 1;
+        unsafe {
+            while (*self.desc.add(idx as usize)).flags & VIRTQ_DESC_F_NEXT != 0 {
+                idx = (*self.desc.add(idx as usize)).next;
+                count += 1;
+            }
+            (*self.desc.add(idx as usize)).next = self.free_head;
+        }
+        self.free_head = head;
+        self.num_free += count;
+        Some(len)
+    }
+}
+
+pub struct Virtqueue {
+    ring: Mutex<Ring>,
+    index: u16,
+    notifier: Notifier,
+    /// Set once the device's interrupt is routed to `handle_interrupt`
+    interrupts: AtomicBool,
+    /// Processes waiting for their chains to be used
+    completions: WaitQueue,
+    /// Processes waiting for free descriptors
+    space: WaitQueue,
+}
+
+impl Virtqueue {
+    /// Queue `index` of a device, with zeroed rings laid out the way legacy
+    /// devices expect. `notifier` is the register the device is told about
+    /// new chains through.
+    pub fn new(index: u16, notifier: Notifier) -> Self {
+        let desc_size = size_of::<VirtqDesc>() * QUEUE_SIZE;
+        // The available ring ends with a used_event field we don't use
+        let avail_size = size_of::<VirtqAvail>() + size_of::<u16>();
+        let used_offset = (desc_size + avail_size).next_multiple_of(LEGACY_QUEUE_ALIGN);
+        let used_size = size_of::<VirtqUsed>();
+        let queue_mem: Vec<u8> = alloc::vec![0u8; used_offset + used_size + 4096];
+        let ptr = queue_mem.as_ptr() as usize;
+        let aligned = (ptr + 4095) & !4095;
+        core::mem::forget(queue_mem);
+
+        let desc = aligned as *mut VirtqDesc;
+        for i in 0..QUEUE_SIZE - 1 {
+            unsafe { (*desc.add(i)).next = i as u16 + 1 };
+        }
+        let ring = Ring {
+            desc,
+            avail: (aligned + desc_size) as *mut VirtqAvail,
+            used: (aligned + used_offset) as *mut VirtqUsed,
+            last_used_idx: 0,
+            free_head: 0,
+            num_free: QUEUE_SIZE as u16,
+            done: [None; QUEUE_SIZE],
+        };
+        Self {
+            ring: Mutex::new(ring),
+            index,
+            notifier,
+            interrupts: AtomicBool::new(false),
+            completions: WaitQueue::new(),
+            space: WaitQueue::new(),
+        }
+    }
+
+    /// Addresses of the descriptor table, available ring and used ring, for
+    /// the transport to give the device
+    pub fn ring_addrs(&self) -> (u64, u64, u64) {
+        let ring = self.ring.lock();
+        (ring.desc as u64, ring.avail as u64, ring.used as u64)
+    }
+
+    /// Let waiters block, now that the device's interrupt calls
+    /// `handle_interrupt`
+    pub fn enable_interrupts(&self) {
+        self.interrupts.store(true, Ordering::Release);
+    }
+
+    /// Chain `bufs` and make the chain available, without notifying the
+    /// device. Returns the head, or None if there aren't enough free
+    /// descriptors.
+    ///
+    /// # Safety
+    /// The buffers must stay valid until the chain has been waited for.
+    pub unsafe fn try_add(&self, bufs: &[Buffer]) -> Option<u16> {
+        self.ring.lock().add(bufs)
+    }
+
+    /// Like `try_add`, but waits for free descriptors. Chains added
+    /// earlier are made visible to the device first, so a caller must not
+    /// hold results it hasn't taken, or it may wait for itself.
+    ///
+    /// # Safety
+    /// The buffers must stay valid until the chain has been waited for.
+    pub unsafe fn add(&self, bufs: &[Buffer]) -> u16 {
+        assert!(bufs.len() <= QUEUE_SIZE, "descriptor chain too long");
+        if let Some(head) = self.ring.lock().add(bufs) {
+            return head;
+        }
+        self.notify();
+        self.wait_on(&self.space, true, |ring| ring.add(bufs))
+    }
+
+    /// Tell the device there are new chains
+    pub fn notify(&self) {
+        fence(Ordering::SeqCst);
+        match self.notifier {
+            Notifier::Register16(addr) => unsafe { write_volatile(addr as *mut u16, self.index) },
+            Notifier::Register32(addr) => unsafe {
+                write_volatile(addr as *mut u32, self.index as u32)
+            },
+        }
+    }
+
+    /// Wait for the device to use the chain starting at `head`, blocking if
+    /// possible. Returns the number of bytes the device wrote.
+    pub fn wait(&self, head: u16) -> u32 {
+        let len = self.wait_on(&self.completions, true, |ring| ring.take(head));
+        self.space.wake_all();
+        len
+    }
+
+    /// Like `wait`, but never blocks, for callers holding spin locks
+    pub fn poll_wait(&self, head: u16) -> u32 {
+        let len = self.wait_on(&self.completions, false, |ring| ring.take(head));
+        self.space.wake_all();
+        len
+    }
+
+    /// Take the result of the chain at `head` if the device has used it,
+    /// without waiting
+    pub fn try_take(&self, head: u16) -> Option<u32> {
+        let len = {
+            let mut ring = self.ring.lock();
+            ring.collect();
+            ring.take(head)?
+        };
+        self.space.wake_all();
+        Some(len)
+    }
+
+    /// Collect used chains and wake their waiters. Called from the device's
+    /// interrupt handler.
+    pub fn handle_interrupt(&self) {
+        if self.ring.lock().collect() > 0 {
+            self.completions.wake_all();
+        }
+    }
+
+    /// Wait on `queue` until `cond` returns Some. Blocks if `may_block` and
+    /// the interrupt will wake us, polls otherwise.
+    fn wait_on<T>(
+        &self,
+        queue: &WaitQueue,
+        may_block: bool,
+        mut cond: impl FnMut(&mut Ring) -> Option<T>,
+    ) -> T {
+        if may_block && self.interrupts.load(Ordering::Acquire) && scheduler::can_block() {
+            return queue.wait_until(|| cond(&mut self.ring.lock()));
+        }
+        loop {
+            let (result, collected) = {
+                let mut ring = self.ring.lock();
+                let collected = ring.collect();
+                (cond(&mut ring), collected)
+            };
+            if collected > 0 {
+                // Some may belong to blocked waiters
+                self.completions.wake_all();
+            }
+            if let Some(result) = result {
+                return result;
+            }
+            core::hint::spin_loop();
+        }
+    }
+}
@@ -0,0 +1,174 @@
+//! Wait queues for processes blocked in the kernel, and a sleeping lock
+//! built on them
+//!
+//! Waiting on many objects at once, as kevent and select do, uses the
+//! wakeups of every queue: each `wake_all` counts one and wakes the
+//! processes in `wait_any`, which then check their objects again.
+
+use crate::{scheduler, timer};
+use alloc::vec::Vec;
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use spin::Mutex;
+
+/// Processes in `wait_any`
+static ANY: WaitQueue = WaitQueue::new();
+/// Number of wakeups so far, so `wait_any` can't miss one that comes between
+/// checking objects and sleeping
+static WAKEUPS: AtomicU64 = AtomicU64::new(0);
+/// Counter value at which the earliest timed `wait_any` ends
+static NEXT_DEADLINE: AtomicU64 = AtomicU64::new(u64::MAX);
+
+/// Processes waiting for some condition to become true
+pub struct WaitQueue {
+    waiters: Mutex<Vec<u64>>,
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        Self {
+            waiters: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Block the current process until `cond` returns Some. `cond` is
+    /// evaluated with the queue locked, so a `wake_all` issued after the
+    /// condition changes can't be missed.
+    pub fn wait_until<T>(&self, mut cond: impl FnMut() -> Option<T>) -> T {
+        loop {
+            {
+                let mut waiters = self.waiters.lock();
+                if let Some(val) = cond() {
+                    return val;
+                }
+                let pid = scheduler::this_cpu().lock().current_pid();
+                if !waiters.contains(&pid) {
+                    waiters.push(pid);
+                }
+            }
+            scheduler::block_current();
+        }
+    }
+
+    /// Wake every waiting process. They re-check their condition when they run.
+    pub fn wake_all(&self) {
+        self.wake_waiters();
+        wake_any();
+    }
+
+    /// Wake the processes waiting on this queue only
+    fn wake_waiters(&self) {
+        let waiters = core::mem::take(&mut *self.waiters.lock());
+        for pid in waiters {
+            scheduler::wake(pid);
+        }
+    }
+}
+
+/// Count a wakeup for processes in `wait_any`, for events that have no
+/// queue of their own
+pub fn wake_any() {
+    WAKEUPS.fetch_add(1, Ordering::Release);
+    ANY.wake_waiters();
+}
+
+/// The wakeup count, to pass to `wait_any` after checking objects
+pub fn wakeups() -> u64 {
+    WAKEUPS.load(Ordering::Acquire)
+}
+
+/// Block until a queue has been woken since the wakeup count was `since`,
+/// or until the counter reaches `deadline`. Returns false on timeout.
+pub fn wait_any(since: u64, deadline: Option<u64>) -> bool {
+    ANY.wait_until(|| {
+        if WAKEUPS.load(Ordering::Acquire) != since {
+            return Some(true);
+        }
+        let deadline = deadline?;
+        if timer::counter() >= deadline {
+            return Some(false);
+        }
+        NEXT_DEADLINE.fetch_min(deadline, Ordering::Relaxed);
+        None
+    })
+}
+
+/// End timed waits that are due. Called from the timer tick.
+pub fn tick() {
+    if timer::counter() >= NEXT_DEADLINE.load(Ordering::Relaxed) {
+        // Waiters still early set their deadline again when they recheck
+        NEXT_DEADLINE.store(u64::MAX, Ordering::Relaxed);
+        ANY.wake_waiters();
+    }
+}
+
+/// A mutex for data held across blocking I/O. Contending processes block
+/// rather than spin, so the holder can sleep without stalling the CPU it
+/// would be woken on. Callers that can't block spin.
+pub struct SleepLock<T> {
+    locked: AtomicBool,
+    waiters: WaitQueue,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Send for SleepLock<T> {}
+unsafe impl<T: Send> Sync for SleepLock<T> {}
+
+impl<T> SleepLock<T> {
+    pub const fn new(data: T) -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+            waiters: WaitQueue::new(),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    fn try_acquire(&self) -> Option<()> {
+        (!self.locked.swap(true, Ordering::Acquire)).then_some(())
+    }
+
+    /// Take the lock if it is free
+    pub fn try_lock(&self) -> Option<SleepLockGuard<'_, T>> {
+        self.try_acquire()?;
+        Some(SleepLockGuard { lock: self })
+    }
+
+    pub fn lock(&self) -> SleepLockGuard<'_, T> {
+        if self.try_acquire().is_none() {
+            if scheduler::can_block() {
+                self.waiters.wait_until(|| self.try_acquire());
+            } else {
+                while self.try_acquire().is_none() {
+                    core::hint::spin_loop();
+                }
+            }
+        }
+        SleepLockGuard { lock: self }
+    }
+}
+
+pub struct SleepLockGuard<'a, T> {
+    lock: &'a SleepLock<T>,
+}
+
+impl<T> Deref for SleepLockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T> DerefMut for SleepLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T> Drop for SleepLockGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.locked.store(false, Ordering::Release);
+        self.lock.waiters.wake_waiters();
+    }
+}
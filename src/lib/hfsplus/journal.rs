@@ -0,0 +1,757 @@
+//! Journal replay
+//!
+//! A journaled volume (kHFSVolumeJournaledBit) logs metadata writes to a
+//! circular journal before making them in place. If the volume was not
+//! unmounted cleanly, transactions between the journal's start and end may
+//! not have reached their blocks yet. `replay` reads them into an
+//! `Overlay`, and `Journaled` lays that over the volume, so replaying never
+//! writes to the volume.
+//!
+//! The journal header and every block list header carry a checksum, as do
+//! the blocks themselves when the block list asks for it. Replay stops at
+//! the first block list that fails its checks, or whose sequence number
+//! goes back: that is where a transaction was torn, and nothing after it was
+//! committed.
+
+use crate::{Error, HFSPlusVolumeHeader, Read, ReadExt, Result, Seek, SeekFrom};
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+
+/// kHFSVolumeJournaledBit of `HFSPlusVolumeHeader::attributes`
+pub const K_HFS_VOLUME_JOURNALED_MASK: u32 = 1 << 13;
+
+// JournalInfoBlock flags
+pub const K_JI_JOURNAL_IN_FS_MASK: u32 = 0x0000_0001;
+pub const K_JI_JOURNAL_ON_OTHER_DEVICE_MASK: u32 = 0x0000_0002;
+pub const K_JI_JOURNAL_NEED_INIT_MASK: u32 = 0x0000_0004;
+
+const JOURNAL_HEADER_MAGIC: u32 = 0x4a4e_4c78;
+/// Journals from before checksums
+const OLD_JOURNAL_HEADER_MAGIC: u32 = 0x4a48_4452;
+const ENDIAN_MAGIC: u32 = 0x1234_5678;
+
+/// Bytes of the journal header, and the part its checksum covers
+const JOURNAL_HEADER_SIZE: usize = 48;
+const JOURNAL_HEADER_CKSUM_SIZE: usize = 44;
+/// Bytes of a block list header before its block_info array, and the part
+/// its checksum covers: the header and the first block_info
+const BLHDR_SIZE: usize = 16;
+const BLHDR_CHECKSUM_SIZE: usize = 32;
+const BLOCK_INFO_SIZE: usize = 16;
+/// Block list flag: each block_info has a checksum of its block
+const BLHDR_CHECK_CHECKSUMS: u32 = 0x0001;
+/// Block number of a block that was written and then released in the same
+/// transaction
+const KILLED_BLOCK: u64 = u64::MAX;
+/// Largest block list header accepted. xnu makes them a sector of
+/// block_info per sector, which is 1 MiB for 4 KiB sectors.
+const MAX_BLHDR_SIZE: u32 = 4 * 1024 * 1024;
+/// Largest journaled block accepted. They are B-tree nodes and allocation
+/// blocks, which are much smaller.
+const MAX_BLOCK_SIZE: u64 = 1024 * 1024;
+
+/// Where the journal is, from the volume's journal info block
+#[derive(Debug, Copy, Clone)]
+pub struct JournalInfoBlock {
+    pub flags: u32,
+    pub device_signature: [u32; 8],
+    /// Byte offset of the journal from the start of the volume
+    pub offset: u64,
+    pub size: u64,
+}
+
+impl JournalInfoBlock {
+    pub fn import(source: &mut dyn Read) -> Result<Self> {
+        let flags = source.read_u32_be()?;
+        let mut device_signature = [0; 8];
+        for word in &mut device_signature {
+            *word = source.read_u32_be()?;
+        }
+        Ok(Self {
+            flags,
+            device_signature,
+            offset: source.read_u64_be()?,
+            size: source.read_u64_be()?,
+        })
+    }
+}
+
+/// Byte order of the journal, which is that of the machine that wrote it
+#[derive(Copy, Clone)]
+struct Endian {
+    little: bool,
+}
+
+impl Endian {
+    fn u16(self, bytes: &[u8]) -> u16 {
+        let bytes = [bytes[0], bytes[1]];
+        if self.little {
+            u16::from_le_bytes(bytes)
+        } else {
+            u16::from_be_bytes(bytes)
+        }
+    }
+
+    fn u32(self, bytes: &[u8]) -> u32 {
+        let bytes = [bytes[0], bytes[1], bytes[2], bytes[3]];
+        if self.little {
+            u32::from_le_bytes(bytes)
+        } else {
+            u32::from_be_bytes(bytes)
+        }
+    }
+
+    fn u64(self, bytes: &[u8]) -> u64 {
+        let bytes = bytes[..8].try_into().unwrap();
+        if self.little {
+            u64::from_le_bytes(bytes)
+        } else {
+            u64::from_be_bytes(bytes)
+        }
+    }
+}
+
+/// The journal header, at the start of the journal. Offsets are from the
+/// start of the journal; the space after the header is circular.
+#[derive(Debug, Copy, Clone)]
+pub struct JournalHeader {
+    /// Offset of the first transaction not yet known to be in place
+    pub start: u64,
+    /// Offset past the last transaction
+    pub end: u64,
+    pub size: u64,
+    /// Size of each block list header, with its block_info array
+    pub blhdr_size: u32,
+    /// Size of the header, which is also the unit of block numbers
+    pub jhdr_size: u32,
+    pub sequence_num: u32,
+}
+
+/// The journal's checksum: a shift and exclusive-or over the bytes
+fn calc_checksum(data: &[u8]) -> u32 {
+    !data.iter().fold(0u32, |sum, &byte| {
+        (sum << 8) ^ sum.wrapping_add(byte as u32)
+    })
+}
+
+fn invalid(what: &str) -> Error {
+    Error::InvalidData(String::from(what))
+}
+
+impl JournalHeader {
+    /// Parse and check the header. Returns it with the journal's byte order.
+    fn parse(bytes: &[u8; JOURNAL_HEADER_SIZE], journal_size: u64) -> Result<(Self, Endian)> {
+        let magic = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        let endian = Endian {
+            little: ![JOURNAL_HEADER_MAGIC, OLD_JOURNAL_HEADER_MAGIC].contains(&magic),
+        };
+        let magic = endian.u32(&bytes[0..]);
+        if ![JOURNAL_HEADER_MAGIC, OLD_JOURNAL_HEADER_MAGIC].contains(&magic)
+            || endian.u32(&bytes[4..]) != ENDIAN_MAGIC
+        {
+            return Err(invalid("Bad journal header magic"));
+        }
+        if magic == JOURNAL_HEADER_MAGIC {
+            let mut summed = [0; JOURNAL_HEADER_CKSUM_SIZE];
+            summed.copy_from_slice(&bytes[..JOURNAL_HEADER_CKSUM_SIZE]);
+            summed[36..40].fill(0);
+            if calc_checksum(&summed) != endian.u32(&bytes[36..]) {
+                return Err(invalid("Bad journal header checksum"));
+            }
+        }
+        let header = Self {
+            start: endian.u64(&bytes[8..]),
+            end: endian.u64(&bytes[16..]),
+            size: endian.u64(&bytes[24..]),
+            blhdr_size: endian.u32(&bytes[32..]),
+            jhdr_size: endian.u32(&bytes[40..]),
+            sequence_num: endian.u32(&bytes[44..]),
+        };
+        let jhdr_size = header.jhdr_size as u64;
+        let circular = jhdr_size..header.size;
+        if !header.jhdr_size.is_power_of_two()
+            || !(512..=65536).contains(&header.jhdr_size)
+            || header.size > journal_size
+            || !circular.contains(&header.start)
+            || !circular.contains(&header.end)
+            || (header.blhdr_size as usize) < BLHDR_CHECKSUM_SIZE
+            || header.blhdr_size > MAX_BLHDR_SIZE
+            || !header.blhdr_size.is_multiple_of(header.jhdr_size)
+            || header.blhdr_size as u64 >= header.size - jhdr_size
+        {
+            return Err(invalid("Bad journal header"));
+        }
+        Ok((header, endian))
+    }
+
+    /// The offset `len` bytes after `offset`, wrapping past the end
+    fn advance(&self, offset: u64, len: u64) -> u64 {
+        let circular = self.size - self.jhdr_size as u64;
+        self.jhdr_size as u64 + (offset - self.jhdr_size as u64 + len) % circular
+    }
+}
+
+/// Blocks of replayed transactions, as 512-byte or larger sectors of the
+/// volume. Later transactions replace the sectors of earlier ones.
+#[derive(Default)]
+pub struct Overlay {
+    sector_size: u64,
+    sectors: BTreeMap<u64, Vec<u8>>,
+    /// Block lists replayed. A transaction has one or more.
+    pub transactions: usize,
+}
+
+impl Overlay {
+    pub fn is_empty(&self) -> bool {
+        self.sectors.is_empty()
+    }
+
+    /// Copy in a block of whole sectors at byte `offset` of the volume
+    fn insert(&mut self, offset: u64, data: &[u8]) {
+        let first = offset / self.sector_size;
+        for (index, sector) in data.chunks(self.sector_size as usize).enumerate() {
+            self.sectors.insert(first + index as u64, Vec::from(sector));
+        }
+    }
+
+    /// Replace the parts of `buf`, read from byte `offset` of the volume,
+    /// that the journal has newer contents for
+    pub fn apply(&self, offset: u64, buf: &mut [u8]) {
+        if self.sectors.is_empty() || buf.is_empty() {
+            return;
+        }
+        let end = offset + buf.len() as u64;
+        let first = offset / self.sector_size;
+        let last = (end - 1) / self.sector_size;
+        for (&sector, data) in self.sectors.range(first..=last) {
+            let start = sector * self.sector_size;
+            let from = start.max(offset);
+            let to = (start + self.sector_size).min(end);
+            buf[(from - offset) as usize..(to - offset) as usize]
+                .copy_from_slice(&data[(from - start) as usize..(to - start) as usize]);
+        }
+    }
+}
+
+/// A block list that passed its checks
+struct BlockList {
+    /// Its blocks, by byte offset on the volume
+    blocks: Vec<(u64, Vec<u8>)>,
+    /// Bytes of the journal it takes, with its header
+    bytes_used: u64,
+    /// Sequence number of its transaction, or 0 if not set
+    sequence_num: u32,
+}
+
+/// The journal of a volume, for reading its circular part
+struct Journal<'a, F: Read + Seek> {
+    file: &'a mut F,
+    /// Byte offset of the journal on the volume
+    base: u64,
+    header: JournalHeader,
+}
+
+impl<F: Read + Seek> Journal<'_, F> {
+    /// Fill `buf` from `offset`, wrapping past the end. Returns the offset
+    /// after what was read.
+    fn read(&mut self, mut offset: u64, buf: &mut [u8]) -> Result<u64> {
+        let mut done = 0;
+        while done < buf.len() {
+            let len = ((self.header.size - offset) as usize).min(buf.len() - done);
+            self.file.seek(SeekFrom::Start(self.base + offset))?;
+            self.file.read_exact(&mut buf[done..done + len])?;
+            done += len;
+            offset = self.header.advance(offset, len as u64);
+        }
+        Ok(offset)
+    }
+
+    /// The block list at `offset`. None if it fails its checksums or
+    /// doesn't make sense, which ends the replay.
+    fn block_list(&mut self, offset: u64, endian: Endian) -> Result<Option<BlockList>> {
+        let header = self.header;
+        let mut blhdr = vec![0; header.blhdr_size as usize];
+        let mut data_offset = self.read(offset, &mut blhdr)?;
+
+        let max_blocks = endian.u16(&blhdr[0..]) as usize;
+        let num_blocks = endian.u16(&blhdr[2..]) as usize;
+        let bytes_used = endian.u32(&blhdr[4..]) as u64;
+        let checksum = endian.u32(&blhdr[8..]);
+        let flags = endian.u32(&blhdr[12..]);
+        // Kept in block_info 0 where a block has its checksum
+        let sequence_num = endian.u32(&blhdr[28..]);
+        let mut summed = [0; BLHDR_CHECKSUM_SIZE];
+        summed.copy_from_slice(&blhdr[..BLHDR_CHECKSUM_SIZE]);
+        summed[8..12].fill(0);
+        if calc_checksum(&summed) != checksum
+            || num_blocks == 0
+            || num_blocks > max_blocks
+            || BLHDR_SIZE + num_blocks * BLOCK_INFO_SIZE > blhdr.len()
+            || bytes_used < header.blhdr_size as u64
+            || bytes_used > header.size - header.jhdr_size as u64
+        {
+            return Ok(None);
+        }
+
+        // block_info 0 belongs to the header; the blocks follow it
+        let mut blocks = Vec::new();
+        let mut total = header.blhdr_size as u64;
+        for index in 1..num_blocks {
+            let info = &blhdr[BLHDR_SIZE + index * BLOCK_INFO_SIZE..];
+            let bnum = endian.u64(info);
+            let bsize = endian.u32(&info[8..]) as u64;
+            let cksum = endian.u32(&info[12..]);
+            total += bsize;
+            if total > bytes_used
+                || bsize > MAX_BLOCK_SIZE
+                || !bsize.is_multiple_of(header.jhdr_size as u64)
+            {
+                return Ok(None);
+            }
+            let mut data = vec![0; bsize as usize];
+            data_offset = self.read(data_offset, &mut data)?;
+            if bnum == KILLED_BLOCK {
+                continue;
+            }
+            // A zero checksum wasn't filled in
+            if flags & BLHDR_CHECK_CHECKSUMS != 0 && cksum != 0 && calc_checksum(&data) != cksum {
+                return Ok(None);
+            }
+            let Some(volume_offset) = bnum.checked_mul(header.jhdr_size as u64) else {
+                return Ok(None);
+            };
+            blocks.push((volume_offset, data));
+        }
+        Ok(Some(BlockList {
+            blocks,
+            bytes_used,
+            sequence_num,
+        }))
+    }
+}
+
+/// Read the committed transactions in the journal of the volume with
+/// `volume_header`. The overlay is empty if the volume isn't journaled or
+/// was unmounted cleanly. Fails if the journal is on another device or its
+/// header is damaged.
+pub fn replay<F: Read + Seek>(
+    file: &mut F,
+    volume_header: &HFSPlusVolumeHeader,
+) -> Result<Overlay> {
+    if volume_header.attributes & K_HFS_VOLUME_JOURNALED_MASK == 0
+        || volume_header.journal_info_block == 0
+    {
+        return Ok(Overlay::default());
+    }
+    let block_size = volume_header.block_size as u64;
+    file.seek(SeekFrom::Start(
+        volume_header.journal_info_block as u64 * block_size,
+    ))?;
+    let info = JournalInfoBlock::import(file)?;
+    if info.flags & K_JI_JOURNAL_ON_OTHER_DEVICE_MASK != 0 {
+        return Err(Error::UnsupportedOperation);
+    }
+    if info.flags & K_JI_JOURNAL_IN_FS_MASK == 0 || info.flags & K_JI_JOURNAL_NEED_INIT_MASK != 0 {
+        return Ok(Overlay::default());
+    }
+
+    let mut bytes = [0; JOURNAL_HEADER_SIZE];
+    file.seek(SeekFrom::Start(info.offset))?;
+    file.read_exact(&mut bytes)?;
+    let (header, endian) = JournalHeader::parse(&bytes, info.size)?;
+    let mut overlay = Overlay {
+        sector_size: header.jhdr_size as u64,
+        ..Overlay::default()
+    };
+    let mut journal = Journal {
+        file,
+        base: info.offset,
+        header,
+    };
+
+    // Block lists that chain around the journal more than once, or whose
+    // transactions go out of order, are stale or damaged. As in xnu, the
+    // block lists of a transaction share its sequence number.
+    let circular = header.size - header.jhdr_size as u64;
+    let mut replayed = 0;
+    let mut last_sequence_num = 0;
+    let mut offset = header.start;
+    while offset != header.end {
+        let Some(list) = journal.block_list(offset, endian)? else {
+            break;
+        };
+        let sequence_num = list.sequence_num;
+        replayed += list.bytes_used;
+        if replayed > circular
+            || last_sequence_num != 0
+                && sequence_num != 0
+                && sequence_num != last_sequence_num
+                && sequence_num != last_sequence_num.wrapping_add(1)
+        {
+            break;
+        }
+        last_sequence_num = sequence_num;
+        for (volume_offset, data) in &list.blocks {
+            overlay.insert(*volume_offset, data);
+        }
+        overlay.transactions += 1;
+        offset = header.advance(offset, list.bytes_used);
+    }
+    Ok(overlay)
+}
+
+/// A volume read through the overlay of its replayed journal
+pub struct Journaled<F: Read + Seek> {
+    inner: F,
+    overlay: Overlay,
+    pos: u64,
+}
+
+impl<F: Read + Seek> Journaled<F> {
+    pub fn new(mut inner: F, overlay: Overlay) -> Result<Self> {
+        let pos = inner.seek(SeekFrom::Start(0))?;
+        Ok(Self {
+            inner,
+            overlay,
+            pos,
+        })
+    }
+
+    pub fn overlay(&self) -> &Overlay {
+        &self.overlay
+    }
+}
+
+impl<F: Read + Seek> Read for Journaled<F> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.overlay.apply(self.pos, &mut buf[..read]);
+        self.pos += read as u64;
+        Ok(read)
+    }
+}
+
+impl<F: Read + Seek> Seek for Journaled<F> {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+        // Readers may not report their position after a relative seek
+        let pos = match pos {
+            SeekFrom::Current(offset) => SeekFrom::Start(
+                self.pos
+                    .checked_add_signed(offset)
+                    .ok_or_else(|| invalid("Invalid seek"))?,
+            ),
+            pos => pos,
+        };
+        self.pos = self.inner.seek(pos)?;
+        Ok(self.pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECTOR: usize = 512;
+    const BLOCK_SIZE: usize = 4096;
+    const JIB_BLOCK: usize = 1;
+    const JOURNAL_OFFSET: usize = 2 * BLOCK_SIZE;
+    const JOURNAL_SIZE: usize = 16 * 1024;
+    const BLHDR_SIZE_ON_DISK: usize = 2 * SECTOR;
+    const VOLUME_SIZE: usize = JOURNAL_OFFSET + JOURNAL_SIZE + 16 * BLOCK_SIZE;
+    /// Block number of the first sector past the journal
+    const DATA_SECTOR: u64 = ((JOURNAL_OFFSET + JOURNAL_SIZE) / SECTOR) as u64;
+
+    struct Cursor {
+        data: Vec<u8>,
+        pos: u64,
+    }
+
+    impl Read for Cursor {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+            let start = (self.pos as usize).min(self.data.len());
+            let len = buf.len().min(self.data.len() - start);
+            buf[..len].copy_from_slice(&self.data[start..start + len]);
+            self.pos += len as u64;
+            Ok(len)
+        }
+    }
+
+    impl Seek for Cursor {
+        fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+            self.pos = match pos {
+                SeekFrom::Start(offset) => offset,
+                SeekFrom::Current(offset) => self.pos.checked_add_signed(offset).unwrap(),
+                SeekFrom::End(offset) => {
+                    (self.data.len() as u64).checked_add_signed(offset).unwrap()
+                }
+            };
+            Ok(self.pos)
+        }
+    }
+
+    /// A volume with an empty journal, written in either byte order
+    struct Volume {
+        data: Vec<u8>,
+        little: bool,
+        start: u64,
+        end: u64,
+    }
+
+    impl Volume {
+        fn new(little: bool, start: u64) -> Self {
+            let mut data = vec![0; VOLUME_SIZE];
+            data[1024..1026].copy_from_slice(&crate::HFSP_SIGNATURE.to_be_bytes());
+            data[1028..1032].copy_from_slice(&K_HFS_VOLUME_JOURNALED_MASK.to_be_bytes());
+            data[1036..1040].copy_from_slice(&(JIB_BLOCK as u32).to_be_bytes());
+            data[1064..1068].copy_from_slice(&(BLOCK_SIZE as u32).to_be_bytes());
+            let jib = JIB_BLOCK * BLOCK_SIZE;
+            data[jib..jib + 4].copy_from_slice(&K_JI_JOURNAL_IN_FS_MASK.to_be_bytes());
+            data[jib + 36..jib + 44].copy_from_slice(&(JOURNAL_OFFSET as u64).to_be_bytes());
+            data[jib + 44..jib + 52].copy_from_slice(&(JOURNAL_SIZE as u64).to_be_bytes());
+            let mut volume = Self {
+                data,
+                little,
+                start,
+                end: start,
+            };
+            volume.write_header();
+            volume
+        }
+
+        fn u16(&self, value: u16) -> [u8; 2] {
+            if self.little {
+                value.to_le_bytes()
+            } else {
+                value.to_be_bytes()
+            }
+        }
+
+        fn u32(&self, value: u32) -> [u8; 4] {
+            if self.little {
+                value.to_le_bytes()
+            } else {
+                value.to_be_bytes()
+            }
+        }
+
+        fn u64(&self, value: u64) -> [u8; 8] {
+            if self.little {
+                value.to_le_bytes()
+            } else {
+                value.to_be_bytes()
+            }
+        }
+
+        fn write_header(&mut self) {
+            let mut header = [0; JOURNAL_HEADER_SIZE];
+            header[0..4].copy_from_slice(&self.u32(JOURNAL_HEADER_MAGIC));
+            header[4..8].copy_from_slice(&self.u32(ENDIAN_MAGIC));
+            header[8..16].copy_from_slice(&self.u64(self.start));
+            header[16..24].copy_from_slice(&self.u64(self.end));
+            header[24..32].copy_from_slice(&self.u64(JOURNAL_SIZE as u64));
+            header[32..36].copy_from_slice(&self.u32(BLHDR_SIZE_ON_DISK as u32));
+            header[40..44].copy_from_slice(&self.u32(SECTOR as u32));
+            let checksum = calc_checksum(&header[..JOURNAL_HEADER_CKSUM_SIZE]);
+            header[36..40].copy_from_slice(&self.u32(checksum));
+            self.data[JOURNAL_OFFSET..JOURNAL_OFFSET + JOURNAL_HEADER_SIZE]
+                .copy_from_slice(&header);
+        }
+
+        /// Write `bytes` at `offset` of the journal, wrapping past the end
+        fn write_journal(&mut self, mut offset: u64, bytes: &[u8]) {
+            for &byte in bytes {
+                self.data[JOURNAL_OFFSET + offset as usize] = byte;
+                offset += 1;
+                if offset == JOURNAL_SIZE as u64 {
+                    offset = SECTOR as u64;
+                }
+            }
+        }
+
+        /// Append a block list of `blocks`, by sector number, and commit it
+        fn append(&mut self, sequence_num: u32, blocks: &[(u64, Vec<u8>)]) {
+            let mut blhdr = vec![0; BLHDR_SIZE_ON_DISK];
+            let bytes_used =
+                BLHDR_SIZE_ON_DISK + blocks.iter().map(|(_, data)| data.len()).sum::<usize>();
+            let max_blocks = (BLHDR_SIZE_ON_DISK / BLOCK_INFO_SIZE - 1) as u16;
+            blhdr[0..2].copy_from_slice(&self.u16(max_blocks));
+            blhdr[2..4].copy_from_slice(&self.u16(blocks.len() as u16 + 1));
+            blhdr[4..8].copy_from_slice(&self.u32(bytes_used as u32));
+            blhdr[12..16].copy_from_slice(&self.u32(BLHDR_CHECK_CHECKSUMS));
+            blhdr[28..32].copy_from_slice(&self.u32(sequence_num));
+            for (index, (bnum, data)) in blocks.iter().enumerate() {
+                let info = BLHDR_SIZE + (index + 1) * BLOCK_INFO_SIZE;
+                blhdr[info..info + 8].copy_from_slice(&self.u64(*bnum));
+                blhdr[info + 8..info + 12].copy_from_slice(&self.u32(data.len() as u32));
+                blhdr[info + 12..info + 16].copy_from_slice(&self.u32(calc_checksum(data)));
+            }
+            let checksum = calc_checksum(&blhdr[..BLHDR_CHECKSUM_SIZE]);
+            blhdr[8..12].copy_from_slice(&self.u32(checksum));
+
+            let header = self.header();
+            let mut offset = self.end;
+            self.write_journal(offset, &blhdr);
+            offset = header.advance(offset, blhdr.len() as u64);
+            for (_, data) in blocks {
+                self.write_journal(offset, data);
+                offset = header.advance(offset, data.len() as u64);
+            }
+            self.end = offset;
+            self.write_header();
+        }
+
+        fn header(&self) -> JournalHeader {
+            let bytes = self.data[JOURNAL_OFFSET..JOURNAL_OFFSET + JOURNAL_HEADER_SIZE]
+                .try_into()
+                .unwrap();
+            JournalHeader::parse(bytes, JOURNAL_SIZE as u64).unwrap().0
+        }
+
+        fn replay(&self) -> Result<Overlay> {
+            let mut file = Cursor {
+                data: self.data.clone(),
+                pos: 1024,
+            };
+            let header = HFSPlusVolumeHeader::import(&mut file)?;
+            replay(&mut file, &header)
+        }
+    }
+
+    fn sector(byte: u8) -> Vec<u8> {
+        vec![byte; SECTOR]
+    }
+
+    /// The first byte of sector `bnum` as read through `overlay`
+    fn read(overlay: &Overlay, bnum: u64) -> u8 {
+        let mut buf = [0];
+        overlay.apply(bnum * SECTOR as u64, &mut buf);
+        buf[0]
+    }
+
+    #[test]
+    fn test_checksum() {
+        assert_eq!(calc_checksum(&[]), 0xffff_ffff);
+        assert_eq!(calc_checksum(&[1]), !1);
+        assert_eq!(calc_checksum(&[1, 2]), !((1 << 8) ^ 3));
+    }
+
+    #[test]
+    fn test_replay() {
+        for little in [false, true] {
+            let mut volume = Volume::new(little, SECTOR as u64);
+            assert!(volume.replay().unwrap().is_empty());
+
+            volume.append(1, &[(DATA_SECTOR, sector(1)), (DATA_SECTOR + 1, sector(2))]);
+            volume.append(2, &[(DATA_SECTOR, sector(3))]);
+            let overlay = volume.replay().unwrap();
+            assert_eq!(overlay.transactions, 2);
+            assert_eq!(read(&overlay, DATA_SECTOR), 3);
+            assert_eq!(read(&overlay, DATA_SECTOR + 1), 2);
+            assert_eq!(read(&overlay, DATA_SECTOR + 2), 0);
+
+            // A read across sectors takes each from the journal
+            let mut buf = [0; 4];
+            overlay.apply((DATA_SECTOR + 1) * SECTOR as u64 - 2, &mut buf);
+            assert_eq!(buf, [3, 3, 2, 2]);
+        }
+    }
+
+    #[test]
+    fn test_replay_wrapped() {
+        // The block list header starts a sector before the end
+        let mut volume = Volume::new(false, (JOURNAL_SIZE - SECTOR) as u64);
+        volume.append(7, &[(DATA_SECTOR, sector(1)), (DATA_SECTOR + 1, sector(2))]);
+        volume.append(8, &[(DATA_SECTOR + 2, sector(3))]);
+        assert!(volume.end < volume.start);
+        let overlay = volume.replay().unwrap();
+        assert_eq!(overlay.transactions, 2);
+        assert_eq!(read(&overlay, DATA_SECTOR), 1);
+        assert_eq!(read(&overlay, DATA_SECTOR + 1), 2);
+        assert_eq!(read(&overlay, DATA_SECTOR + 2), 3);
+    }
+
+    #[test]
+    fn test_replay_torn() {
+        let mut volume = Volume::new(true, SECTOR as u64);
+        volume.append(1, &[(DATA_SECTOR, sector(1))]);
+        let torn = volume.end;
+        volume.append(2, &[(DATA_SECTOR, sector(2)), (DATA_SECTOR + 1, sector(2))]);
+        volume.append(3, &[(DATA_SECTOR + 2, sector(3))]);
+
+        // The second block's data never made it
+        let data = JOURNAL_OFFSET + torn as usize + BLHDR_SIZE_ON_DISK + SECTOR;
+        volume.data[data] ^= 0xff;
+        let overlay = volume.replay().unwrap();
+        assert_eq!(overlay.transactions, 1);
+        assert_eq!(read(&overlay, DATA_SECTOR), 1);
+        assert_eq!(read(&overlay, DATA_SECTOR + 1), 0);
+        assert_eq!(read(&overlay, DATA_SECTOR + 2), 0);
+
+        // Nor did the block list header
+        volume.data[data] ^= 0xff;
+        volume.data[JOURNAL_OFFSET + torn as usize + 4] ^= 0xff;
+        assert_eq!(volume.replay().unwrap().transactions, 1);
+    }
+
+    #[test]
+    fn test_replay_killed_block() {
+        let mut volume = Volume::new(false, SECTOR as u64);
+        volume.append(1, &[(KILLED_BLOCK, sector(1)), (DATA_SECTOR, sector(2))]);
+        let overlay = volume.replay().unwrap();
+        assert_eq!(overlay.transactions, 1);
+        assert_eq!(overlay.sectors.len(), 1);
+        assert_eq!(read(&overlay, DATA_SECTOR), 2);
+    }
+
+    #[test]
+    fn test_replay_sequence() {
+        let mut volume = Volume::new(false, SECTOR as u64);
+        volume.append(5, &[(DATA_SECTOR, sector(1))]);
+        volume.append(5, &[(DATA_SECTOR + 1, sector(2))]);
+        volume.append(6, &[(DATA_SECTOR + 2, sector(3))]);
+        volume.append(4, &[(DATA_SECTOR, sector(4))]);
+        let overlay = volume.replay().unwrap();
+        assert_eq!(overlay.transactions, 3);
+        assert_eq!(read(&overlay, DATA_SECTOR), 1);
+    }
+
+    #[test]
+    fn test_replay_loop() {
+        // One block list as big as the journal leads back to itself, never
+        // reaching the end
+        let mut volume = Volume::new(false, SECTOR as u64);
+        let blocks = (JOURNAL_SIZE - SECTOR - BLHDR_SIZE_ON_DISK) / SECTOR;
+        let blocks: Vec<_> = (0..blocks as u64)
+            .map(|index| (DATA_SECTOR + index, sector(1)))
+            .collect();
+        volume.append(1, &blocks);
+        assert_eq!(volume.end, volume.start);
+        volume.end = 2 * SECTOR as u64;
+        volume.write_header();
+        assert_eq!(volume.replay().unwrap().transactions, 1);
+    }
+
+    #[test]
+    fn test_bad_header() {
+        let mut volume = Volume::new(true, SECTOR as u64);
+        volume.append(1, &[(DATA_SECTOR, sector(1))]);
+        volume.data[JOURNAL_OFFSET + 8] ^= 1;
+        assert!(matches!(volume.replay(), Err(Error::InvalidData(_))));
+
+        // Block list headers must be whole sectors
+        let mut volume = Volume::new(false, SECTOR as u64);
+        volume.data[JOURNAL_OFFSET + 32..JOURNAL_OFFSET + 36]
+            .copy_from_slice(&1000u32.to_be_bytes());
+        let checksum = {
+            let mut header = [0; JOURNAL_HEADER_CKSUM_SIZE];
+            header.copy_from_slice(&volume.data[JOURNAL_OFFSET..][..JOURNAL_HEADER_CKSUM_SIZE]);
+            header[36..40].fill(0);
+            calc_checksum(&header)
+        };
+        volume.data[JOURNAL_OFFSET + 36..JOURNAL_OFFSET + 40]
+            .copy_from_slice(&checksum.to_be_bytes());
+        assert!(matches!(volume.replay(), Err(Error::InvalidData(_))));
+    }
+}
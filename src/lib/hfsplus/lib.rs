@@ -21,9 +21,11 @@ use zlib_rs::inflate::{InflateConfig, InflateStream, end, inflate, init, reset};
 
 mod hfs_strings;
 pub mod internal;
+pub mod journal;
 
 pub use crate::internal::*;
 use hfs_strings::fast_unicode_compare;
+use journal::Journaled;
 
 pub enum SeekFrom {
     Start(u64),
@@ -1191,8 +1193,11 @@ pub struct HFSVolume<F: Read + Seek> {
     pub extents_btree: Option<BTreeArc<Fork<F>, ExtentKey, ExtentRecord>>,
 }
 
-impl<F: Read + Seek> HFSVolume<F> {
-    pub fn load(mut file: F) -> Result<Arc<Mutex<HFSVolume<F>>>> {
+impl<F: Read + Seek> HFSVolume<Journaled<F>> {
+    /// Load the volume, replaying its journal into an overlay so it reads as
+    /// it would after a clean unmount. A journal that can't be replayed, such
+    /// as one on another device, is left out, as the kernel does.
+    pub fn load(mut file: F) -> Result<Arc<Mutex<HFSVolume<Journaled<F>>>>> {
         file.seek(SeekFrom::Start(1024))?;
 
         let header = HFSPlusVolumeHeader::import(&mut file)?;
@@ -1201,6 +1206,22 @@ impl<F: Read + Seek> HFSVolume<F> {
             return Err(Error::InvalidData(String::from("Invalid volume signature")));
         }
 
+        let overlay = journal::replay(&mut file, &header).unwrap_or_default();
+
+        let mut file = Journaled::new(file, overlay)?;
+
+        // The journal may have a newer volume header
+        file.seek(SeekFrom::Start(1024))?;
+
+        let header = HFSPlusVolumeHeader::import(&mut file)?;
+
+        HFSVolume::open(file, header)
+    }
+}
+
+impl<F: Read + Seek> HFSVolume<F> {
+    /// Load the volume with `header` as it is on `file`, without its journal
+    pub fn open(file: F, header: HFSPlusVolumeHeader) -> Result<Arc<Mutex<HFSVolume<F>>>> {
         let file_arc = Arc::new(Mutex::new(file));
 
         let volume = Arc::new(Mutex::new(HFSVolume {
@@ -1326,38 +1347,73 @@ impl<F: Read + Seek> HFSVolume<F> {
         }
 
         for (i, part) in parts.iter().enumerate() {
-            let name_utf16: Vec<u16> = part.nfd().collect::<String>().encode_utf16().collect();
-
-            let key = CatalogKey {
-                _case_match: false,
-
-                parent_id: current_folder_id,
-
-                node_name: S::from_vec(name_utf16),
-            };
-
-            let record = btree.get_record(&key)?;
-
-            current_record = Some((*record).clone());
+            let record = self.get_child_record_impl(current_folder_id, part, btree)?;
 
             match &record.body {
                 CatalogBody::Folder(f) => {
                     current_folder_id = f.folder_id;
                 }
 
-                CatalogBody::File(_) => {
+                _ => {
                     if i != parts.len() - 1 {
                         return Err(Error::KeyNotFound);
                     }
                 }
-
-                _ => return Err(Error::InvalidRecordType),
             }
+
+            current_record = Some(record);
         }
 
         current_record.ok_or(Error::KeyNotFound)
     }
 
+    /// The record of the file or folder `name` in the folder `parent_id`
+    pub fn get_child_record(
+        &self,
+        parent_id: HFSCatalogNodeID,
+        name: &str,
+    ) -> Result<CatalogRecord> {
+        match self.catalog_btree.as_ref().unwrap() {
+            CatalogBTreeEnum::CaseFolding(btree) => {
+                self.get_child_record_impl(parent_id, name, &mut *btree.lock())
+            }
+
+            CatalogBTreeEnum::Binary(btree) => {
+                let rec = self.get_child_record_impl(parent_id, name, &mut *btree.lock())?;
+
+                Ok(convert_record(rec))
+            }
+        }
+    }
+
+    fn get_child_record_impl<S>(
+        &self,
+        parent_id: HFSCatalogNodeID,
+        name: &str,
+        btree: &mut BTree<Fork<F>, CatalogKey<S>, CatalogRecord<S>>,
+    ) -> Result<CatalogRecord<S>>
+    where
+        S: HFSStringTrait,
+    {
+        let name_utf16: Vec<u16> = name.nfd().collect::<String>().encode_utf16().collect();
+
+        let key = CatalogKey {
+            _case_match: false,
+
+            parent_id,
+
+            node_name: S::from_vec(name_utf16),
+        };
+
+        let record = btree.get_record(&key)?;
+
+        match &record.body {
+            CatalogBody::Folder(_) | CatalogBody::File(_) => Ok((*record).clone()),
+
+            _ => Err(Error::InvalidRecordType),
+        }
+    }
+
     pub fn list_dir(&self, path: &str) -> Result<Vec<(String, CatalogRecord)>> {
         let record = self.get_path_record(path)?;
 
@@ -0,0 +1,731 @@
+//! The tree of an in-memory filesystem, as the kernel's tmpfs keeps it
+//!
+//! Nodes live in memory until they are removed and no file has them open.
+//! The tree has a size limit counted in 4 KiB blocks: a node takes one block
+//! for its inode plus its contents rounded up to blocks. Growing past the
+//! limit, or while the host is short of memory, fails with `NoSpace`.
+//!
+//! Paths are relative to the root, with or without a leading slash. The
+//! caller resolves symbolic links and keeps the tree under a lock.
+
+#![no_std]
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+
+// File types in the mode, as in stat
+pub const S_IFMT: u16 = 0o170000;
+pub const S_IFIFO: u16 = 0o010000;
+pub const S_IFDIR: u16 = 0o040000;
+pub const S_IFREG: u16 = 0o100000;
+pub const S_IFLNK: u16 = 0o120000;
+pub const S_IFSOCK: u16 = 0o140000;
+
+/// Unit of the size limit and of file contents
+pub const BLOCK_SIZE: u64 = 4096;
+/// Inode number of the root directory, as on HFS+
+pub const ROOT_INO: u64 = 2;
+/// Longest name in a directory
+const NAME_MAX: usize = 255;
+
+/// Why an operation failed, for the caller to turn into an errno
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Error {
+    NotFound,
+    Exists,
+    NotDir,
+    IsDir,
+    NotEmpty,
+    NameTooLong,
+    /// Not possible on this kind of node, or a bad argument
+    Invalid,
+    /// Removing a directory as a file
+    Permission,
+    NoSpace,
+}
+
+pub type Result<T> = core::result::Result<T, Error>;
+
+/// What the tree needs from its host
+pub trait Host: Send + Sync {
+    /// Seconds since the Unix epoch
+    fn now(&self) -> i64;
+
+    /// Whether `bytes` more can be allocated for file contents
+    fn has_room(&self, bytes: u64) -> bool;
+}
+
+/// Contents of a regular file, allocated a block at a time
+#[derive(Default)]
+struct Contents {
+    size: u64,
+    blocks: Vec<Box<[u8]>>,
+}
+
+impl Contents {
+    fn read(&self, offset: u64, buf: &mut [u8]) -> usize {
+        if offset >= self.size {
+            return 0;
+        }
+        let len = buf.len().min((self.size - offset) as usize);
+        let mut done = 0;
+        while done < len {
+            let pos = offset + done as u64;
+            let start = (pos % BLOCK_SIZE) as usize;
+            let count = (BLOCK_SIZE as usize - start).min(len - done);
+            let block = &self.blocks[(pos / BLOCK_SIZE) as usize];
+            buf[done..done + count].copy_from_slice(&block[start..start + count]);
+            done += count;
+        }
+        len
+    }
+
+    /// Copy `buf` in at `offset`, which the file must already cover
+    fn write(&mut self, offset: u64, buf: &[u8]) {
+        let mut done = 0;
+        while done < buf.len() {
+            let pos = offset + done as u64;
+            let start = (pos % BLOCK_SIZE) as usize;
+            let count = (BLOCK_SIZE as usize - start).min(buf.len() - done);
+            let block = &mut self.blocks[(pos / BLOCK_SIZE) as usize];
+            block[start..start + count].copy_from_slice(&buf[done..done + count]);
+            done += count;
+        }
+    }
+
+    /// Set the size, allocating or freeing blocks. Bytes past the old end
+    /// read as zeros.
+    fn resize(&mut self, size: u64) {
+        let count = size.div_ceil(BLOCK_SIZE) as usize;
+        if size < self.size && !size.is_multiple_of(BLOCK_SIZE) {
+            self.blocks[count - 1][(size % BLOCK_SIZE) as usize..].fill(0);
+        }
+        self.blocks.truncate(count);
+        while self.blocks.len() < count {
+            self.blocks
+                .push(vec![0; BLOCK_SIZE as usize].into_boxed_slice());
+        }
+        self.size = size;
+    }
+}
+
+enum Data {
+    File(Contents),
+    /// Inode numbers by name
+    Dir(BTreeMap<String, u64>),
+    Symlink(String),
+    /// A FIFO or socket, whose pipe or endpoint the caller keeps
+    Special,
+}
+
+struct Node {
+    /// Type and permission bits
+    mode: u16,
+    parent: u64,
+    /// Open files, which keep a removed file's contents
+    opens: usize,
+    /// Whether a directory still holds the node
+    linked: bool,
+    // Times in seconds since the Unix epoch
+    atime: i64,
+    mtime: i64,
+    ctime: i64,
+    birthtime: i64,
+    data: Data,
+}
+
+impl Node {
+    fn new(mode: u16, parent: u64, data: Data, now: i64) -> Self {
+        Self {
+            mode,
+            parent,
+            opens: 0,
+            linked: true,
+            atime: now,
+            mtime: now,
+            ctime: now,
+            birthtime: now,
+            data,
+        }
+    }
+
+    /// Blocks charged to the node: the inode and the file contents
+    fn blocks(&self) -> u64 {
+        match &self.data {
+            Data::File(contents) => 1 + contents.blocks.len() as u64,
+            _ => 1,
+        }
+    }
+
+    /// Note a change to the contents
+    fn modified(&mut self, now: i64) {
+        self.mtime = now;
+        self.ctime = now;
+    }
+}
+
+/// Attributes of a node
+#[derive(Debug, Clone, Default)]
+pub struct Stat {
+    pub ino: u64,
+    pub parent_ino: u64,
+    pub mode: u16,
+    /// Zero once the node is removed
+    pub nlink: u32,
+    pub size: u64,
+    /// Blocks of contents, in 512-byte units
+    pub blocks: u64,
+    pub atime: i64,
+    pub mtime: i64,
+    pub ctime: i64,
+    pub birthtime: i64,
+    /// Entries of a directory
+    pub entries: u32,
+}
+
+/// An entry of a directory
+pub struct DirEntry {
+    pub name: String,
+    pub ino: u64,
+    /// Type and permission bits of the node it names
+    pub mode: u16,
+}
+
+/// Every node of a filesystem, which the caller keeps under one lock so
+/// that renames are atomic
+pub struct Tree {
+    nodes: BTreeMap<u64, Node>,
+    next_ino: u64,
+    /// Blocks in use, and the most that may be
+    used: u64,
+    limit: u64,
+    host: &'static dyn Host,
+}
+
+impl Tree {
+    /// An empty tree that may use up to `size` bytes
+    pub fn new(size: u64, host: &'static dyn Host) -> Self {
+        let mut nodes = BTreeMap::new();
+        nodes.insert(
+            ROOT_INO,
+            Node::new(
+                S_IFDIR | 0o1777,
+                ROOT_INO,
+                Data::Dir(BTreeMap::new()),
+                host.now(),
+            ),
+        );
+        Self {
+            nodes,
+            next_ino: ROOT_INO + 1,
+            used: 1,
+            limit: (size / BLOCK_SIZE).max(1),
+            host,
+        }
+    }
+
+    fn node(&self, ino: u64) -> &Node {
+        &self.nodes[&ino]
+    }
+
+    fn node_mut(&mut self, ino: u64) -> &mut Node {
+        self.nodes.get_mut(&ino).expect("memfs inode missing")
+    }
+
+    /// Entries of the directory `ino`
+    fn entries(&self, ino: u64) -> &BTreeMap<String, u64> {
+        match &self.node(ino).data {
+            Data::Dir(entries) => entries,
+            _ => unreachable!("memfs inode {} is not a directory", ino),
+        }
+    }
+
+    fn entries_mut(&mut self, ino: u64) -> &mut BTreeMap<String, u64> {
+        match &mut self.node_mut(ino).data {
+            Data::Dir(entries) => entries,
+            _ => unreachable!("memfs inode {} is not a directory", ino),
+        }
+    }
+
+    /// Inode number of the node at `path`
+    pub fn find(&self, path: &str) -> Result<u64> {
+        let mut ino = ROOT_INO;
+        for name in path.split('/').filter(|name| !name.is_empty()) {
+            match &self.node(ino).data {
+                Data::Dir(entries) => ino = *entries.get(name).ok_or(Error::NotFound)?,
+                _ => return Err(Error::NotDir),
+            }
+        }
+        Ok(ino)
+    }
+
+    /// The directory that holds, or would hold, the node at `path`, and
+    /// the node's name in it
+    fn parent<'p>(&self, path: &'p str) -> Result<(u64, &'p str)> {
+        let (dir, name) = path.rsplit_once('/').unwrap_or(("", path));
+        if name.is_empty() {
+            return Err(Error::Exists);
+        }
+        if name.len() > NAME_MAX {
+            return Err(Error::NameTooLong);
+        }
+        let dir = self.find(dir)?;
+        match self.node(dir).data {
+            Data::Dir(_) => Ok((dir, name)),
+            _ => Err(Error::NotDir),
+        }
+    }
+
+    /// Account for a node going from `old` to `new` blocks
+    fn charge(&mut self, old: u64, new: u64) -> Result<()> {
+        if new > old {
+            let more = new - old;
+            if self.used + more > self.limit || !self.host.has_room(more * BLOCK_SIZE) {
+                return Err(Error::NoSpace);
+            }
+        }
+        self.used = self.used + new - old;
+        Ok(())
+    }
+
+    /// Make a node at `path`, which must not exist yet
+    fn add(&mut self, path: &str, mode: u16, data: Data) -> Result<()> {
+        let (dir, name) = self.parent(path)?;
+        if self.entries(dir).contains_key(name) {
+            return Err(Error::Exists);
+        }
+        let now = self.host.now();
+        let node = Node::new(mode, dir, data, now);
+        self.charge(0, node.blocks())?;
+        let ino = self.next_ino;
+        self.next_ino += 1;
+        self.nodes.insert(ino, node);
+        self.entries_mut(dir).insert(String::from(name), ino);
+        self.node_mut(dir).modified(now);
+        Ok(())
+    }
+
+    /// Take `name` out of directory `dir`. The node goes once no file has
+    /// it open.
+    fn unlink(&mut self, dir: u64, name: &str) {
+        let Some(ino) = self.entries_mut(dir).remove(name) else {
+            return;
+        };
+        let now = self.host.now();
+        self.node_mut(dir).modified(now);
+        let node = self.node_mut(ino);
+        node.linked = false;
+        node.ctime = now;
+        if node.opens == 0 {
+            self.free(ino);
+        }
+    }
+
+    fn free(&mut self, ino: u64) {
+        if let Some(node) = self.nodes.remove(&ino) {
+            self.used -= node.blocks();
+        }
+    }
+
+    pub fn create(&mut self, path: &str, mode: u16) -> Result<()> {
+        let data = Data::File(Contents::default());
+        self.add(path, S_IFREG | mode, data)
+    }
+
+    pub fn mkdir(&mut self, path: &str, mode: u16) -> Result<()> {
+        self.add(path, S_IFDIR | mode, Data::Dir(BTreeMap::new()))
+    }
+
+    /// Make a FIFO or socket. `mode` has its type.
+    pub fn mknod(&mut self, path: &str, mode: u16) -> Result<()> {
+        match mode & S_IFMT {
+            S_IFIFO | S_IFSOCK => self.add(path, mode, Data::Special),
+            _ => Err(Error::Invalid),
+        }
+    }
+
+    pub fn symlink(&mut self, path: &str, target: &str) -> Result<()> {
+        let data = Data::Symlink(String::from(target));
+        self.add(path, S_IFLNK | 0o755, data)
+    }
+
+    /// Remove the node at `path`: a directory, which must be empty, if
+    /// `dir`, else anything else
+    pub fn remove(&mut self, path: &str, dir: bool) -> Result<()> {
+        let (parent, name) = self.parent(path)?;
+        let ino = *self.entries(parent).get(name).ok_or(Error::NotFound)?;
+        match (&self.node(ino).data, dir) {
+            (Data::Dir(entries), true) if !entries.is_empty() => return Err(Error::NotEmpty),
+            (Data::Dir(_), false) => return Err(Error::Permission),
+            (Data::Dir(_), true) => {}
+            (_, true) => return Err(Error::NotDir),
+            (_, false) => {}
+        }
+        self.unlink(parent, name);
+        Ok(())
+    }
+
+    /// Move the node at `from` to `to`, replacing what is there
+    pub fn rename(&mut self, from: &str, to: &str) -> Result<()> {
+        let (from_dir, from_name) = self.parent(from)?;
+        let ino = *self
+            .entries(from_dir)
+            .get(from_name)
+            .ok_or(Error::NotFound)?;
+        let (to_dir, to_name) = self.parent(to)?;
+        let is_dir = matches!(self.node(ino).data, Data::Dir(_));
+        if let Some(&old) = self.entries(to_dir).get(to_name) {
+            if old == ino {
+                return Ok(());
+            }
+            match (&self.node(old).data, is_dir) {
+                (Data::Dir(entries), true) if !entries.is_empty() => {
+                    return Err(Error::NotEmpty);
+                }
+                (Data::Dir(_), true) => {}
+                (Data::Dir(_), false) => return Err(Error::IsDir),
+                (_, true) => return Err(Error::NotDir),
+                (_, false) => {}
+            }
+        }
+        // A directory can't be moved inside itself
+        let mut dir = to_dir;
+        while is_dir && dir != ROOT_INO {
+            if dir == ino {
+                return Err(Error::Invalid);
+            }
+            dir = self.node(dir).parent;
+        }
+
+        self.unlink(to_dir, to_name);
+        self.entries_mut(from_dir).remove(from_name);
+        self.entries_mut(to_dir).insert(String::from(to_name), ino);
+        let now = self.host.now();
+        self.node_mut(from_dir).modified(now);
+        self.node_mut(to_dir).modified(now);
+        let node = self.node_mut(ino);
+        node.parent = to_dir;
+        node.ctime = now;
+        Ok(())
+    }
+
+    /// Type and permission bits of node `ino`
+    pub fn mode(&self, ino: u64) -> u16 {
+        self.node(ino).mode
+    }
+
+    pub fn readlink(&self, ino: u64) -> Result<String> {
+        match &self.node(ino).data {
+            Data::Symlink(target) => Ok(target.clone()),
+            _ => Err(Error::Invalid),
+        }
+    }
+
+    pub fn readdir(&self, ino: u64) -> Result<Vec<DirEntry>> {
+        let Data::Dir(entries) = &self.node(ino).data else {
+            return Err(Error::NotDir);
+        };
+        Ok(entries
+            .iter()
+            .map(|(name, &ino)| DirEntry {
+                name: name.clone(),
+                ino,
+                mode: self.node(ino).mode,
+            })
+            .collect())
+    }
+
+    pub fn chmod(&mut self, ino: u64, mode: u16) {
+        let now = self.host.now();
+        let node = self.node_mut(ino);
+        node.mode = (node.mode & S_IFMT) | mode;
+        node.ctime = now;
+    }
+
+    pub fn utimes(&mut self, ino: u64, atime: i64, mtime: i64) {
+        let now = self.host.now();
+        let node = self.node_mut(ino);
+        node.atime = atime;
+        node.mtime = mtime;
+        node.ctime = now;
+    }
+
+    pub fn stat(&self, ino: u64) -> Stat {
+        let node = self.node(ino);
+        let (size, nlink, entries) = match &node.data {
+            Data::File(contents) => (contents.size, 1, 0),
+            Data::Dir(entries) => {
+                let subdirs = entries
+                    .values()
+                    .filter(|&&child| matches!(self.node(child).data, Data::Dir(_)))
+                    .count();
+                (0, 2 + subdirs as u32, entries.len() as u32)
+            }
+            Data::Symlink(target) => (target.len() as u64, 1, 0),
+            Data::Special => (0, 1, 0),
+        };
+        Stat {
+            ino,
+            parent_ino: node.parent,
+            mode: node.mode,
+            nlink: if node.linked { nlink } else { 0 },
+            size,
+            blocks: (node.blocks() - 1) * BLOCK_SIZE / 512,
+            atime: node.atime,
+            mtime: node.mtime,
+            ctime: node.ctime,
+            birthtime: node.birthtime,
+            entries,
+        }
+    }
+
+    /// Blocks in the size limit, and how many of them are free
+    pub fn usage(&self) -> (u64, u64) {
+        (self.limit, self.limit - self.used)
+    }
+
+    /// Note that regular file `ino` was opened. It keeps its contents until
+    /// `close`, even if it is removed.
+    pub fn open(&mut self, ino: u64) -> Result<()> {
+        let node = self.node_mut(ino);
+        match node.data {
+            Data::File(_) => {
+                node.opens += 1;
+                Ok(())
+            }
+            Data::Dir(_) => Err(Error::IsDir),
+            _ => Err(Error::Invalid),
+        }
+    }
+
+    /// Undo an `open` of `ino`, freeing it if it was removed
+    pub fn close(&mut self, ino: u64) {
+        let node = self.node_mut(ino);
+        node.opens -= 1;
+        if node.opens == 0 && !node.linked {
+            self.free(ino);
+        }
+    }
+
+    /// Size of regular file `ino`
+    pub fn size(&self, ino: u64) -> u64 {
+        match &self.node(ino).data {
+            Data::File(contents) => contents.size,
+            _ => 0,
+        }
+    }
+
+    /// Read regular file `ino` from `offset`. Returns the bytes read.
+    pub fn read(&mut self, ino: u64, offset: u64, buf: &mut [u8]) -> usize {
+        let now = self.host.now();
+        let node = self.node_mut(ino);
+        node.atime = now;
+        match &node.data {
+            Data::File(contents) => contents.read(offset, buf),
+            _ => 0,
+        }
+    }
+
+    /// Write `buf` to regular file `ino` at `offset`, growing it to fit
+    pub fn write(&mut self, ino: u64, offset: u64, buf: &[u8]) -> Result<usize> {
+        let end = offset.checked_add(buf.len() as u64).ok_or(Error::Invalid)?;
+        let Data::File(contents) = &self.node(ino).data else {
+            return Err(Error::Invalid);
+        };
+        if end > contents.size {
+            self.resize(ino, end)?;
+        }
+        let now = self.host.now();
+        let node = self.node_mut(ino);
+        if let Data::File(contents) = &mut node.data {
+            contents.write(offset, buf);
+        }
+        node.modified(now);
+        Ok(buf.len())
+    }
+
+    /// Set the size of regular file `ino`
+    pub fn resize(&mut self, ino: u64, size: u64) -> Result<()> {
+        let old = match &self.node(ino).data {
+            Data::File(contents) => contents.blocks.len() as u64,
+            Data::Dir(_) => return Err(Error::IsDir),
+            Data::Symlink(_) | Data::Special => return Err(Error::Invalid),
+        };
+        self.charge(old, size.div_ceil(BLOCK_SIZE))?;
+        let now = self.host.now();
+        let node = self.node_mut(ino);
+        if let Data::File(contents) = &mut node.data {
+            contents.resize(size);
+        }
+        node.modified(now);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::sync::atomic::{AtomicBool, Ordering};
+
+    struct TestHost {
+        room: AtomicBool,
+    }
+
+    impl Host for TestHost {
+        fn now(&self) -> i64 {
+            1_000_000
+        }
+
+        fn has_room(&self, _bytes: u64) -> bool {
+            self.room.load(Ordering::Relaxed)
+        }
+    }
+
+    static HOST: TestHost = TestHost {
+        room: AtomicBool::new(true),
+    };
+
+    fn free_blocks(tree: &Tree) -> u64 {
+        tree.usage().1
+    }
+
+    #[test]
+    fn test_contents_resize() {
+        let mut contents = Contents::default();
+        contents.resize(BLOCK_SIZE + 10);
+        contents.write(BLOCK_SIZE - 2, &[1, 2, 3, 4]);
+        assert_eq!(contents.blocks.len(), 2);
+
+        let mut buf = [0xff; 4];
+        assert_eq!(contents.read(BLOCK_SIZE - 2, &mut buf), 4);
+        assert_eq!(buf, [1, 2, 3, 4]);
+        assert_eq!(contents.read(BLOCK_SIZE + 8, &mut buf), 2);
+        assert_eq!(contents.read(BLOCK_SIZE + 10, &mut buf), 0);
+
+        // Bytes cut off by a shrink read as zeros when the file grows back
+        contents.resize(BLOCK_SIZE + 1);
+        contents.resize(3 * BLOCK_SIZE);
+        assert_eq!(contents.blocks.len(), 3);
+        assert_eq!(contents.read(BLOCK_SIZE - 2, &mut buf), 4);
+        assert_eq!(buf, [1, 2, 3, 0]);
+
+        contents.resize(0);
+        assert!(contents.blocks.is_empty());
+        assert_eq!(contents.read(0, &mut buf), 0);
+    }
+
+    #[test]
+    fn test_paths() {
+        let mut tree = Tree::new(64 * BLOCK_SIZE, &HOST);
+        tree.mkdir("a", 0o755).unwrap();
+        tree.create("/a/f", 0o644).unwrap();
+        tree.symlink("a/l", "f").unwrap();
+        assert_eq!(tree.find("/"), Ok(ROOT_INO));
+        assert_eq!(tree.create("a/f", 0o644), Err(Error::Exists));
+        assert_eq!(tree.create("a/f/g", 0o644), Err(Error::NotDir));
+        assert_eq!(tree.find("a/f/g"), Err(Error::NotDir));
+        assert_eq!(tree.find("b"), Err(Error::NotFound));
+        let long = "x".repeat(NAME_MAX + 1);
+        assert_eq!(tree.create(&long, 0o644), Err(Error::NameTooLong));
+
+        let link = tree.find("a/l").unwrap();
+        assert_eq!(tree.readlink(link).as_deref(), Ok("f"));
+        assert_eq!(tree.mode(link) & S_IFMT, S_IFLNK);
+        let names: Vec<_> = tree
+            .readdir(tree.find("a").unwrap())
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.name)
+            .collect();
+        assert_eq!(names, ["f", "l"]);
+        let a = tree.stat(tree.find("a").unwrap());
+        assert_eq!((a.nlink, a.entries), (2, 2));
+
+        assert_eq!(tree.remove("a", true), Err(Error::NotEmpty));
+        assert_eq!(tree.remove("a", false), Err(Error::Permission));
+        assert_eq!(tree.remove("a/f", true), Err(Error::NotDir));
+        assert_eq!(tree.mknod("a/p", S_IFREG), Err(Error::Invalid));
+    }
+
+    #[test]
+    fn test_rename_into_self() {
+        let mut tree = Tree::new(64 * BLOCK_SIZE, &HOST);
+        tree.mkdir("a", 0o755).unwrap();
+        tree.mkdir("a/b", 0o755).unwrap();
+        assert_eq!(tree.rename("a", "a/b/c"), Err(Error::Invalid));
+        assert_eq!(tree.rename("a", "a/c"), Err(Error::Invalid));
+        assert_eq!(tree.rename("a", "a"), Ok(()));
+
+        tree.mkdir("d", 0o755).unwrap();
+        assert_eq!(tree.rename("d", "a"), Err(Error::NotEmpty));
+        tree.rename("a/b", "d").unwrap();
+        let d = tree.find("d").unwrap();
+        assert_eq!(tree.stat(d).parent_ino, ROOT_INO);
+        assert_eq!(tree.find("a/b"), Err(Error::NotFound));
+    }
+
+    #[test]
+    fn test_unlinked_open_file() {
+        let mut tree = Tree::new(64 * BLOCK_SIZE, &HOST);
+        let empty = free_blocks(&tree);
+        tree.create("f", 0o644).unwrap();
+        let file = tree.find("f").unwrap();
+        tree.open(file).unwrap();
+        assert_eq!(tree.write(file, 0, b"hello"), Ok(5));
+        tree.remove("f", false).unwrap();
+        assert_eq!(tree.find("f"), Err(Error::NotFound));
+
+        // The open file keeps its contents and its blocks
+        let mut buf = [0; 5];
+        assert_eq!(tree.read(file, 0, &mut buf), 5);
+        assert_eq!(&buf, b"hello");
+        assert_eq!(tree.stat(file).nlink, 0);
+        assert_eq!(free_blocks(&tree), empty - 2);
+
+        tree.close(file);
+        assert_eq!(free_blocks(&tree), empty);
+        assert_eq!(tree.nodes.len(), 1);
+    }
+
+    #[test]
+    fn test_enospc() {
+        // The root directory takes one of the four blocks
+        let mut tree = Tree::new(4 * BLOCK_SIZE, &HOST);
+        tree.create("f", 0o644).unwrap();
+        let file = tree.find("f").unwrap();
+        assert_eq!(tree.write(file, 0, &[1; 2 * BLOCK_SIZE as usize]), Ok(8192));
+        assert_eq!(free_blocks(&tree), 0);
+
+        assert_eq!(tree.write(file, 2 * BLOCK_SIZE, &[1]), Err(Error::NoSpace));
+        assert_eq!(tree.size(file), 2 * BLOCK_SIZE);
+        assert_eq!(tree.create("g", 0o644), Err(Error::NoSpace));
+        assert_eq!(tree.write(file, u64::MAX, &[1]), Err(Error::Invalid));
+
+        tree.resize(file, BLOCK_SIZE).unwrap();
+        assert_eq!(free_blocks(&tree), 1);
+        tree.create("g", 0o644).unwrap();
+        let g = tree.find("g").unwrap();
+        assert_eq!(tree.resize(g, 1), Err(Error::NoSpace));
+        assert_eq!(tree.resize(ROOT_INO, 0), Err(Error::IsDir));
+    }
+
+    #[test]
+    fn test_host_out_of_memory() {
+        let mut tree = Tree::new(64 * BLOCK_SIZE, &HOST);
+        tree.create("f", 0o644).unwrap();
+        let file = tree.find("f").unwrap();
+        HOST.room.store(false, Ordering::Relaxed);
+        let result = tree.write(file, 0, &[1]);
+        // Shrinking needs no memory
+        let shrunk = tree.resize(file, 0);
+        HOST.room.store(true, Ordering::Relaxed);
+        assert_eq!(result, Err(Error::NoSpace));
+        assert_eq!(shrunk, Ok(()));
+        assert_eq!(tree.write(file, 0, &[1]), Ok(1));
+    }
+}
@@ -0,0 +1,11 @@
+{ craneLib, commonArgs, ... }:
+
+craneLib.buildPackage (
+  commonArgs
+  // {
+    pname = "memfs";
+    version = "0.1.0";
+    cargoExtraArgs = "-p memfs";
+    doCheck = false;
+  }
+)
@@ -0,0 +1,485 @@
+//! Partition tables: GPT, with the Apple Partition Map and MBR as fallbacks
+//!
+//! Partitions are numbered from 1 in table order, as Darwin numbers the
+//! `diskNsM` slices of a disk.
+
+#![no_std]
+
+extern crate alloc;
+
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+
+/// GPT type GUID of HFS+ and HFSX volumes, 48465300-0000-11AA-AA11-00306543ECAC,
+/// in its on-disk byte order
+const GPT_HFS_TYPE: [u8; 16] = [
+    0x00, 0x53, 0x46, 0x48, 0x00, 0x00, 0xAA, 0x11, 0xAA, 0x11, 0x00, 0x30, 0x65, 0x43, 0xEC, 0xAC,
+];
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+/// Size of the GPT header fields, which its CRC covers at least
+const GPT_HEADER_SIZE: usize = 92;
+/// GPT entries are a multiple of this size, and at most MAX_ENTRY_SIZE
+const GPT_ENTRY_SIZE: usize = 128;
+const MAX_ENTRY_SIZE: usize = 4096;
+/// Most entries read from a table, against corrupt headers
+const MAX_ENTRIES: u32 = 1024;
+
+const APM_DRIVER_SIGNATURE: &[u8; 2] = b"ER";
+const APM_ENTRY_SIGNATURE: &[u8; 2] = b"PM";
+const APM_HFS_TYPES: [&str; 2] = ["Apple_HFS", "Apple_HFSX"];
+
+const MBR_SIGNATURE: [u8; 2] = [0x55, 0xAA];
+const MBR_HFS_TYPE: u8 = 0xAF;
+const MBR_GPT_PROTECTIVE_TYPE: u8 = 0xEE;
+const MBR_EXTENDED_TYPES: [u8; 3] = [0x05, 0x0F, 0x85];
+
+const SECTOR_SIZE: u64 = 512;
+
+/// A disk to read partition tables from
+pub trait Disk {
+    /// Fill `buf` from byte `offset`. Returns false if the read fails or
+    /// runs past the end.
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> bool;
+
+    /// Size in bytes
+    fn size(&self) -> u64;
+
+    /// Preferred transfer size in bytes, also tried as the GPT block size
+    fn block_size(&self) -> u32 {
+        SECTOR_SIZE as u32
+    }
+}
+
+/// How a partition table identifies a partition's contents
+pub enum PartitionType {
+    Gpt([u8; 16]),
+    Apm(String),
+    Mbr(u8),
+}
+
+/// A partition found by `scan`
+pub struct PartitionInfo {
+    /// Number of the partition, from 1
+    pub index: u32,
+    /// Offset on the disk in bytes
+    pub start: u64,
+    /// Size in bytes
+    pub size: u64,
+    pub kind: PartitionType,
+    /// Name given in the table, empty for MBR
+    pub name: String,
+}
+
+impl PartitionInfo {
+    /// Whether the table says the partition holds an HFS+ or HFSX volume
+    pub fn is_hfs(&self) -> bool {
+        match &self.kind {
+            PartitionType::Gpt(guid) => *guid == GPT_HFS_TYPE,
+            PartitionType::Apm(kind) => APM_HFS_TYPES.contains(&kind.as_str()),
+            PartitionType::Mbr(kind) => *kind == MBR_HFS_TYPE,
+        }
+    }
+}
+
+fn read_block(disk: &dyn Disk, offset: u64, len: usize) -> Option<Vec<u8>> {
+    let mut buf = vec![0u8; len];
+    disk.read_at(offset, &mut buf).then_some(buf)
+}
+
+fn u16_be(buf: &[u8], offset: usize) -> u16 {
+    u16::from_be_bytes(buf[offset..offset + 2].try_into().unwrap())
+}
+
+fn u32_be(buf: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+fn u32_le(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_le(buf: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(buf[offset..offset + 8].try_into().unwrap())
+}
+
+/// CRC-32 as used by GPT: the reflected IEEE polynomial, inverted
+fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0u32, |crc, &byte| {
+        (0..8).fold(crc ^ byte as u32, |crc, _| {
+            (crc >> 1) ^ (0xEDB8_8320 & (crc & 1).wrapping_neg())
+        })
+    })
+}
+
+/// NUL-padded ASCII name of an APM entry
+fn ascii_name(bytes: &[u8]) -> String {
+    let len = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..len]).into()
+}
+
+/// The partitions of a GUID Partition Table with `lba_size` blocks, from
+/// the primary table or, if it is damaged, the backup at the end of the
+/// disk
+fn scan_gpt(disk: &dyn Disk, lba_size: u64, disk_size: u64) -> Option<Vec<PartitionInfo>> {
+    let last_lba = (disk_size / lba_size).checked_sub(1)?;
+    read_gpt(disk, 1, lba_size, disk_size).or_else(|| read_gpt(disk, last_lba, lba_size, disk_size))
+}
+
+/// The partitions of the GPT whose header is at `lba`, if the header and
+/// its entries pass their CRCs
+fn read_gpt(
+    disk: &dyn Disk,
+    lba: u64,
+    lba_size: u64,
+    disk_size: u64,
+) -> Option<Vec<PartitionInfo>> {
+    let mut header = read_block(disk, lba.checked_mul(lba_size)?, lba_size as usize)?;
+    if &header[..8] != GPT_SIGNATURE {
+        return None;
+    }
+    let header_size = u32_le(&header, 12) as usize;
+    if !(GPT_HEADER_SIZE..=header.len()).contains(&header_size) {
+        return None;
+    }
+    let header_crc = u32_le(&header, 16);
+    header[16..20].fill(0);
+    if crc32(&header[..header_size]) != header_crc || u64_le(&header, 24) != lba {
+        return None;
+    }
+    let entries_lba = u64_le(&header, 72);
+    let count = u32_le(&header, 80);
+    let entry_size = u32_le(&header, 84) as usize;
+    if count > MAX_ENTRIES
+        || entry_size == 0
+        || !entry_size.is_multiple_of(GPT_ENTRY_SIZE)
+        || entry_size > MAX_ENTRY_SIZE
+    {
+        return None;
+    }
+    let table = read_block(
+        disk,
+        entries_lba.checked_mul(lba_size)?,
+        count as usize * entry_size,
+    )?;
+    if crc32(&table) != u32_le(&header, 88) {
+        return None;
+    }
+
+    let mut partitions = Vec::new();
+    for (i, entry) in table.chunks_exact(entry_size).enumerate() {
+        let type_guid: [u8; 16] = entry[..16].try_into().unwrap();
+        if type_guid == [0; 16] {
+            continue;
+        }
+        let Some(first) = u64_le(entry, 32).checked_mul(lba_size) else {
+            continue;
+        };
+        let Some(end) = u64_le(entry, 40)
+            .checked_add(1)
+            .and_then(|end| end.checked_mul(lba_size))
+        else {
+            continue;
+        };
+        if first >= end || end > disk_size {
+            continue;
+        }
+        let name: Vec<u16> = entry[56..GPT_ENTRY_SIZE]
+            .chunks_exact(2)
+            .map(|c| u16::from_le_bytes([c[0], c[1]]))
+            .take_while(|&c| c != 0)
+            .collect();
+        partitions.push(PartitionInfo {
+            index: i as u32 + 1,
+            start: first,
+            size: end - first,
+            kind: PartitionType::Gpt(type_guid),
+            name: String::from_utf16_lossy(&name),
+        });
+    }
+    Some(partitions)
+}
+
+/// The partitions of an Apple Partition Map. The map is itself the first
+/// partition.
+fn scan_apm(disk: &dyn Disk, disk_size: u64) -> Option<Vec<PartitionInfo>> {
+    let driver = read_block(disk, 0, SECTOR_SIZE as usize)?;
+    if &driver[..2] != APM_DRIVER_SIGNATURE {
+        return None;
+    }
+    let block_size = match u16_be(&driver, 2) as u64 {
+        0 => SECTOR_SIZE,
+        size => size,
+    };
+    let first = read_block(disk, block_size, SECTOR_SIZE as usize)?;
+    if &first[..2] != APM_ENTRY_SIGNATURE {
+        return None;
+    }
+    let count = u32_be(&first, 4);
+
+    let mut partitions = Vec::new();
+    for index in 1..=count.min(MAX_ENTRIES) {
+        let entry = read_block(disk, index as u64 * block_size, SECTOR_SIZE as usize)?;
+        if &entry[..2] != APM_ENTRY_SIGNATURE {
+            break;
+        }
+        let start = u32_be(&entry, 8) as u64 * block_size;
+        let size = u32_be(&entry, 12) as u64 * block_size;
+        if size == 0 || start + size > disk_size {
+            continue;
+        }
+        partitions.push(PartitionInfo {
+            index,
+            start,
+            size,
+            kind: PartitionType::Apm(ascii_name(&entry[48..80])),
+            name: ascii_name(&entry[16..48]),
+        });
+    }
+    Some(partitions)
+}
+
+/// The primary partitions of a Master Boot Record. Logical partitions in
+/// extended partitions aren't listed.
+fn scan_mbr(disk: &dyn Disk, disk_size: u64) -> Option<Vec<PartitionInfo>> {
+    let mbr = read_block(disk, 0, SECTOR_SIZE as usize)?;
+    if mbr[510..512] != MBR_SIGNATURE {
+        return None;
+    }
+    let mut partitions = Vec::new();
+    for (i, entry) in mbr[446..510].chunks_exact(16).enumerate() {
+        let kind = entry[4];
+        let start = u32_le(entry, 8) as u64 * SECTOR_SIZE;
+        let size = u32_le(entry, 12) as u64 * SECTOR_SIZE;
+        if kind == 0 || MBR_EXTENDED_TYPES.contains(&kind) || size == 0 {
+            continue;
+        }
+        if kind == MBR_GPT_PROTECTIVE_TYPE || start + size > disk_size {
+            // A GPT we couldn't read, or not an MBR at all
+            return None;
+        }
+        partitions.push(PartitionInfo {
+            index: i as u32 + 1,
+            start,
+            size,
+            kind: PartitionType::Mbr(kind),
+            name: String::new(),
+        });
+    }
+    Some(partitions)
+}
+
+/// The partitions of `disk`, empty if it has no partition table
+pub fn scan(disk: &dyn Disk) -> Vec<PartitionInfo> {
+    let size = disk.size();
+    let mut lba_sizes = vec![SECTOR_SIZE];
+    if disk.block_size() as u64 != SECTOR_SIZE {
+        lba_sizes.push(disk.block_size() as u64);
+    }
+    lba_sizes
+        .into_iter()
+        .find_map(|lba_size| scan_gpt(disk, lba_size, size))
+        .or_else(|| scan_apm(disk, size))
+        .or_else(|| scan_mbr(disk, size))
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DISK_SIZE: usize = 1024 * 1024;
+    const LAST_LBA: u64 = DISK_SIZE as u64 / SECTOR_SIZE - 1;
+
+    struct Image(Vec<u8>);
+
+    impl Disk for Image {
+        fn read_at(&self, offset: u64, buf: &mut [u8]) -> bool {
+            let Some(end) = offset.checked_add(buf.len() as u64) else {
+                return false;
+            };
+            if end > self.0.len() as u64 {
+                return false;
+            }
+            buf.copy_from_slice(&self.0[offset as usize..end as usize]);
+            true
+        }
+
+        fn size(&self) -> u64 {
+            self.0.len() as u64
+        }
+    }
+
+    fn gpt_entry(type_guid: [u8; 16], first: u64, last: u64, name: &str) -> Vec<u8> {
+        let mut entry = vec![0; GPT_ENTRY_SIZE];
+        entry[..16].copy_from_slice(&type_guid);
+        entry[32..40].copy_from_slice(&first.to_le_bytes());
+        entry[40..48].copy_from_slice(&last.to_le_bytes());
+        for (i, c) in name.encode_utf16().enumerate() {
+            entry[56 + i * 2..58 + i * 2].copy_from_slice(&c.to_le_bytes());
+        }
+        entry
+    }
+
+    /// Write a GPT header at `lba` and its `entries` at `entries_lba`
+    fn put_gpt(disk: &mut [u8], lba: u64, entries_lba: u64, entries: &[u8], entry_size: u32) {
+        let table = entries_lba as usize * SECTOR_SIZE as usize;
+        disk[table..table + entries.len()].copy_from_slice(entries);
+        let count = entries.len() as u32 / entry_size;
+        let start = lba as usize * SECTOR_SIZE as usize;
+        let header = &mut disk[start..start + SECTOR_SIZE as usize];
+        header.fill(0);
+        header[..8].copy_from_slice(GPT_SIGNATURE);
+        header[12..16].copy_from_slice(&(GPT_HEADER_SIZE as u32).to_le_bytes());
+        header[24..32].copy_from_slice(&lba.to_le_bytes());
+        header[72..80].copy_from_slice(&entries_lba.to_le_bytes());
+        header[80..84].copy_from_slice(&count.to_le_bytes());
+        header[84..88].copy_from_slice(&entry_size.to_le_bytes());
+        header[88..92].copy_from_slice(&crc32(entries).to_le_bytes());
+        let crc = crc32(&header[..GPT_HEADER_SIZE]);
+        header[16..20].copy_from_slice(&crc.to_le_bytes());
+    }
+
+    fn gpt_disk(name: &str) -> Vec<u8> {
+        let mut disk = vec![0; DISK_SIZE];
+        let mut entries = gpt_entry(GPT_HFS_TYPE, 40, 1999, name);
+        entries.extend(vec![0; GPT_ENTRY_SIZE * 3]);
+        put_gpt(&mut disk, 1, 2, &entries, GPT_ENTRY_SIZE as u32);
+        entries[56..58].copy_from_slice(&(b'B' as u16).to_le_bytes());
+        put_gpt(
+            &mut disk,
+            LAST_LBA,
+            LAST_LBA - 32,
+            &entries,
+            GPT_ENTRY_SIZE as u32,
+        );
+        disk
+    }
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn test_gpt() {
+        let partitions = scan(&Image(gpt_disk("Data")));
+        assert_eq!(partitions.len(), 1);
+        let partition = &partitions[0];
+        assert_eq!(partition.index, 1);
+        assert_eq!(partition.start, 40 * SECTOR_SIZE);
+        assert_eq!(partition.size, 1960 * SECTOR_SIZE);
+        assert_eq!(partition.name, "Data");
+        assert!(partition.is_hfs());
+    }
+
+    #[test]
+    fn test_gpt_backup() {
+        let mut disk = gpt_disk("Data");
+        // Damage the primary header, then the primary entries
+        disk[SECTOR_SIZE as usize + 40] ^= 1;
+        assert_eq!(scan(&Image(disk.clone()))[0].name, "Bata");
+        disk[SECTOR_SIZE as usize + 40] ^= 1;
+        disk[2 * SECTOR_SIZE as usize + 56] ^= 1;
+        assert_eq!(scan(&Image(disk))[0].name, "Bata");
+    }
+
+    #[test]
+    fn test_gpt_entry_size() {
+        for entry_size in [0, 100, 8192, 0x8000_0000] {
+            let mut disk = vec![0; DISK_SIZE];
+            let entries = gpt_entry(GPT_HFS_TYPE, 40, 1999, "Data");
+            put_gpt(&mut disk, 1, 2, &entries, GPT_ENTRY_SIZE as u32);
+            let header = SECTOR_SIZE as usize;
+            disk[header + 84..header + 88].copy_from_slice(&u32::to_le_bytes(entry_size));
+            disk[header + 16..header + 20].fill(0);
+            let crc = crc32(&disk[header..header + GPT_HEADER_SIZE]);
+            disk[header + 16..header + 20].copy_from_slice(&crc.to_le_bytes());
+            assert!(read_gpt(&Image(disk), 1, SECTOR_SIZE, DISK_SIZE as u64).is_none());
+        }
+    }
+
+    #[test]
+    fn test_gpt_overflow() {
+        let mut disk = vec![0; DISK_SIZE];
+        let mut entries = gpt_entry(GPT_HFS_TYPE, 40, u64::MAX, "End");
+        entries.extend(gpt_entry(
+            GPT_HFS_TYPE,
+            u64::MAX / 2,
+            u64::MAX / 2 + 1,
+            "Start",
+        ));
+        entries.extend(gpt_entry(GPT_HFS_TYPE, 40, 1999, "Data"));
+        put_gpt(&mut disk, 1, 2, &entries, GPT_ENTRY_SIZE as u32);
+        let partitions = scan(&Image(disk));
+        assert_eq!(partitions.len(), 1);
+        assert_eq!(partitions[0].index, 3);
+    }
+
+    fn apm_entry(
+        disk: &mut [u8],
+        index: usize,
+        count: u32,
+        start: u32,
+        size: u32,
+        name: &str,
+        kind: &str,
+    ) {
+        let entry = &mut disk[index * 512..(index + 1) * 512];
+        entry[..2].copy_from_slice(APM_ENTRY_SIGNATURE);
+        entry[4..8].copy_from_slice(&count.to_be_bytes());
+        entry[8..12].copy_from_slice(&start.to_be_bytes());
+        entry[12..16].copy_from_slice(&size.to_be_bytes());
+        entry[16..16 + name.len()].copy_from_slice(name.as_bytes());
+        entry[48..48 + kind.len()].copy_from_slice(kind.as_bytes());
+    }
+
+    #[test]
+    fn test_apm() {
+        let mut disk = vec![0; DISK_SIZE];
+        disk[..2].copy_from_slice(APM_DRIVER_SIGNATURE);
+        disk[2..4].copy_from_slice(&512u16.to_be_bytes());
+        apm_entry(&mut disk, 1, 3, 1, 63, "Apple", "Apple_partition_map");
+        apm_entry(&mut disk, 2, 3, 64, 1000, "Data", "Apple_HFS");
+        // Runs past the end of the disk
+        apm_entry(&mut disk, 3, 3, 64, 0xFFFF_FFFF, "Big", "Apple_HFS");
+        let partitions = scan(&Image(disk));
+        assert_eq!(partitions.len(), 2);
+        assert!(!partitions[0].is_hfs());
+        assert_eq!(partitions[1].index, 2);
+        assert_eq!(partitions[1].start, 64 * 512);
+        assert_eq!(partitions[1].size, 1000 * 512);
+        assert_eq!(partitions[1].name, "Data");
+        assert!(partitions[1].is_hfs());
+    }
+
+    fn mbr_disk(kinds: &[(u8, u32, u32)]) -> Vec<u8> {
+        let mut disk = vec![0; DISK_SIZE];
+        for (i, &(kind, start, size)) in kinds.iter().enumerate() {
+            let entry = &mut disk[446 + i * 16..462 + i * 16];
+            entry[4] = kind;
+            entry[8..12].copy_from_slice(&start.to_le_bytes());
+            entry[12..16].copy_from_slice(&size.to_le_bytes());
+        }
+        disk[510..512].copy_from_slice(&MBR_SIGNATURE);
+        disk
+    }
+
+    #[test]
+    fn test_mbr() {
+        let disk = mbr_disk(&[(0x05, 1, 100), (MBR_HFS_TYPE, 200, 1000)]);
+        let partitions = scan(&Image(disk));
+        assert_eq!(partitions.len(), 1);
+        assert_eq!(partitions[0].index, 2);
+        assert_eq!(partitions[0].start, 200 * SECTOR_SIZE);
+        assert!(partitions[0].is_hfs());
+    }
+
+    #[test]
+    fn test_mbr_protective() {
+        let disk = mbr_disk(&[(MBR_GPT_PROTECTIVE_TYPE, 1, 2047)]);
+        assert!(scan_mbr(&Image(disk), DISK_SIZE as u64).is_none());
+        let disk = mbr_disk(&[(MBR_HFS_TYPE, 1, 0xFFFF_FFFF)]);
+        assert!(scan_mbr(&Image(disk), DISK_SIZE as u64).is_none());
+    }
+}
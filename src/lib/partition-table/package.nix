@@ -0,0 +1,11 @@
+{ craneLib, commonArgs, ... }:
+
+craneLib.buildPackage (
+  commonArgs
+  // {
+    pname = "partition-table";
+    version = "0.1.0";
+    cargoExtraArgs = "-p partition-table";
+    doCheck = false;
+  }
+)
@@ -3,6 +3,7 @@ def _qemu_run_impl(ctx):
 
     kernel = ctx.attrs.kernel[DefaultInfo].default_outputs[0]
     disk = ctx.attrs.disk
+    netdev = ",".join(["user,id=net0"] + ["hostfwd=" + rule for rule in ctx.attrs.hostfwd])
 
     # Create the command line, ensuring spaces between arguments
     command = cmd_args(
@@ -12,7 +13,9 @@ def _qemu_run_impl(ctx):
         "-m", "1024",
         "-kernel", kernel,
         "-drive", cmd_args(disk, format="if=none,file={},id=hd0,format=raw,file.locking=off"),
-        "-device", "virtio-blk-pci,drive=hd0",
+        "-device", cmd_args(ctx.attrs.disk_device, format="{},drive=hd0"),
+        "-netdev", netdev,
+        "-device", "virtio-net-pci,netdev=net0",
         "-serial", "stdio",
         "-display", "none",
         "\"$@\"",
@@ -36,5 +39,9 @@ qemu_run = rule(
     attrs = {
         "kernel": attrs.dep(),
         "disk": attrs.source(),
+        # virtio-blk-device puts the disk on virtio-mmio instead
+        "disk_device": attrs.string(default = "virtio-blk-pci"),
+        # Host ports forwarded to the guest, like "tcp::8080-:80"
+        "hostfwd": attrs.list(attrs.string(), default = []),
     },
 )